@@ -153,12 +153,13 @@ impl MockStorage {
         id
     }
 
-    pub fn flush_sst_to_new_tier(&mut self) {
+    pub fn flush_sst_to_new_tier(&mut self) -> usize {
         let id = self.generate_sst_id();
         self.snapshot.levels.insert(0, (id, vec![id]));
         self.file_list.insert(id, id);
         self.total_flushes += 1;
         self.total_writes += 1;
+        id
     }
 
     pub fn remove(&mut self, files_to_remove: &[usize]) {
@@ -410,7 +411,18 @@ fn main() {
             let mut max_space = 0;
             for i in 0..iterations {
                 println!("=== Iteration {i} ===");
-                storage.flush_sst_to_new_tier();
+                let id = storage.flush_sst_to_new_tier();
+                // the controller compares the tiers by size, with SSTs of the same size here
+                let (first_key, last_key) = generate_random_key_range();
+                storage.snapshot.sstables.insert(
+                    id,
+                    Arc::new(SsTable::create_meta_only(
+                        id,
+                        1024 * 1024,
+                        first_key,
+                        last_key,
+                    )),
+                );
                 println!("--- After Flush ---");
                 if size_only {
                     storage.dump_size_only();
@@ -436,6 +448,17 @@ fn main() {
                             sst_ids.push(new_sst_id);
                             storage.file_list.insert(new_sst_id, *file);
                             storage.total_writes += 1;
+                            let sst = &storage.snapshot.sstables[file];
+                            let new_sst = SsTable::create_meta_only(
+                                new_sst_id,
+                                sst.table_size(),
+                                sst.first_key().clone(),
+                                sst.last_key().clone(),
+                            );
+                            storage
+                                .snapshot
+                                .sstables
+                                .insert(new_sst_id, Arc::new(new_sst));
                         }
                         print!("L{} {:?} ", tier_id, files);
                     }
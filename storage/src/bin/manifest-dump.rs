@@ -0,0 +1,231 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod wrapper;
+use wrapper::mini_lsm_wrapper;
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::{Parser, ValueEnum};
+use mini_lsm_wrapper::compact::{
+    CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+    TieredCompactionOptions,
+};
+use mini_lsm_wrapper::lsm_storage::ManifestReplay;
+use mini_lsm_wrapper::manifest::{Manifest, ManifestRecord};
+
+#[derive(Debug, Clone, ValueEnum)]
+enum CompactionStrategy {
+    Simple,
+    Leveled,
+    Tiered,
+    None,
+}
+
+/// Print the records of the manifest of a DB, and the state recovery rebuilds from them. Like
+/// recovery, drops a partly written last record and the manifests `CURRENT` doesn't name.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path of the DB directory.
+    path: PathBuf,
+    /// The compaction strategy the DB was created with, for a manifest that doesn't record it.
+    #[arg(long)]
+    compaction: Option<CompactionStrategy>,
+    /// Check the SSTs of the state against the SST files in the directory, fails if some are
+    /// missing or not referred to.
+    #[arg(long)]
+    verify: bool,
+}
+
+/// The options of the strategy, with the levels `mini-lsm-cli` creates.
+fn compaction_options(strategy: &CompactionStrategy) -> CompactionOptions {
+    match strategy {
+        CompactionStrategy::None => CompactionOptions::NoCompaction,
+        CompactionStrategy::Simple => CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+        }),
+        CompactionStrategy::Tiered => CompactionOptions::Tiered(TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: None,
+        }),
+        CompactionStrategy::Leveled => CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            base_level_size_mb: 128,
+            level_size_multiplier: 2,
+        }),
+    }
+}
+
+fn print_record(seq: usize, offset: usize, record: &ManifestRecord) {
+    match record {
+        ManifestRecord::Flush(sst_id) => println!("#{} @{}: flush {}", seq, offset, sst_id),
+        ManifestRecord::NewMemtable(memtable_id) => {
+            println!("#{} @{}: new memtable {}", seq, offset, memtable_id)
+        }
+        ManifestRecord::Compaction(task, output) => {
+            println!(
+                "#{} @{}: compaction {:?} -> {:?}",
+                seq, offset, task, output
+            )
+        }
+        ManifestRecord::FlushMerged(memtable_ids, sst_id) => println!(
+            "#{} @{}: flush merged {:?} -> {}",
+            seq, offset, memtable_ids, sst_id
+        ),
+        ManifestRecord::Ingest(level, sst_ids) => {
+            println!("#{} @{}: ingest {:?} to {}", seq, offset, sst_ids, level)
+        }
+        ManifestRecord::DeleteSsts(sst_ids) => {
+            println!("#{} @{}: delete SSTs {:?}", seq, offset, sst_ids)
+        }
+        ManifestRecord::SstMetas(sst_metas) => {
+            for sst_meta in sst_metas {
+                println!(
+                    "#{} @{}: SST {} {:?}@{}..={:?}@{}, {} bytes",
+                    seq,
+                    offset,
+                    sst_meta.sst_id,
+                    String::from_utf8_lossy(&sst_meta.first_key),
+                    sst_meta.first_ts,
+                    String::from_utf8_lossy(&sst_meta.last_key),
+                    sst_meta.last_ts,
+                    sst_meta.size
+                )
+            }
+        }
+        record => println!("#{} @{}: {:?}", seq, offset, record),
+    }
+}
+
+fn print_state(replay: &ManifestReplay) {
+    if !replay.state.l0_sstables.is_empty() {
+        println!(
+            "L0 ({}): {:?}",
+            replay.state.l0_sstables.len(),
+            replay.state.l0_sstables,
+        );
+    }
+    for (level, files) in &replay.state.levels {
+        println!("L{level} ({}): {:?}", files.len(), files);
+    }
+    println!("memtables: {:?}", replay.memtables);
+    if !replay.pending_delete.is_empty() {
+        println!("pending delete: {:?}", replay.pending_delete);
+    }
+    println!("max id: {}", replay.max_id);
+    if let Some(watermark) = replay.watermark {
+        println!("watermark: {}", watermark);
+    }
+    if !replay.reserved.is_empty() {
+        println!("reserved by a compaction: {:?}", replay.reserved);
+    }
+}
+
+/// The ids of the SST files in the directory.
+fn sst_files(path: &Path) -> Result<BTreeSet<usize>> {
+    let mut sst_ids = BTreeSet::new();
+    for entry in std::fs::read_dir(path)? {
+        let file_path = entry?.path();
+        if file_path.extension().is_none_or(|ext| ext != "sst") {
+            continue;
+        }
+        if let Some(sst_id) = file_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            sst_ids.insert(sst_id);
+        }
+    }
+    Ok(sst_ids)
+}
+
+/// Returns the number of problems found.
+fn verify(path: &Path, replay: &ManifestReplay) -> Result<usize> {
+    let files = sst_files(path)?;
+    let mut referred = BTreeSet::new();
+    let mut problems = 0;
+    for sst_id in replay.sst_ids() {
+        if !referred.insert(sst_id) {
+            println!("SST {} is in the state more than once", sst_id);
+            problems += 1;
+        }
+        if !files.contains(&sst_id) {
+            println!("missing SST {}", sst_id);
+            problems += 1;
+        }
+    }
+    for sst_id in files.difference(&referred) {
+        if replay.pending_delete.contains(sst_id) {
+            // recovery removes it
+            println!("compacted SST {} not removed yet", sst_id);
+        } else if replay.reserved.contains(sst_id) {
+            // and the output of a compaction that didn't finish
+            println!("SST {} of a compaction that didn't finish", sst_id);
+        } else {
+            println!("orphan SST {}", sst_id);
+            problems += 1;
+        }
+    }
+    Ok(problems)
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let (_, records) = Manifest::recover_with_offsets(&args.path)
+        .with_context(|| format!("failed to recover the manifest of {}", args.path.display()))?;
+    for (seq, (offset, record)) in records.iter().enumerate() {
+        print_record(seq, *offset, record);
+    }
+
+    let recorded = records.iter().rev().find_map(|(_, record)| match record {
+        ManifestRecord::Options(options) => Some(options.compaction_options.clone()),
+        ManifestRecord::Snapshot(snapshot) => snapshot
+            .options
+            .as_ref()
+            .map(|options| options.compaction_options.clone()),
+        _ => None,
+    });
+    let compaction_options = match (recorded, &args.compaction) {
+        (Some(recorded), _) => recorded,
+        (None, Some(strategy)) => compaction_options(strategy),
+        (None, None) => {
+            bail!("the manifest doesn't record the compaction options, pass --compaction")
+        }
+    };
+    let replay = ManifestReplay::replay(
+        &compaction_options,
+        records.into_iter().map(|(_, record)| record),
+    );
+    print_state(&replay);
+
+    if args.verify {
+        let problems = verify(&args.path, &replay)?;
+        if problems > 0 {
+            bail!("{} problems found", problems);
+        }
+        println!("OK: {} SSTs verified", replay.sst_ids().count());
+    }
+    Ok(())
+}
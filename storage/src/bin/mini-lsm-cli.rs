@@ -25,7 +25,14 @@ use mini_lsm_wrapper::compact::{
     TieredCompactionOptions,
 };
 use mini_lsm_wrapper::iterators::StorageIterator;
-use mini_lsm_wrapper::lsm_storage::{LsmStorageOptions, MiniLsm};
+use mini_lsm_wrapper::lsm_storage::{
+    DEFAULT_BLOCK_CACHE_CAPACITY, DEFAULT_BLOOM_FALSE_POSITIVE_RATE,
+    DEFAULT_COMPACTION_READAHEAD_SIZE, DEFAULT_MAX_MANIFEST_SIZE, LsmStorageOptions, MiniLsm,
+};
+use mini_lsm_wrapper::manifest::{ManifestFormat, ManifestSyncMode};
+use mini_lsm_wrapper::mem_table::MAX_KEY_VALUE_LEN;
+use mini_lsm_wrapper::table::{ChecksumVerification, CompressionOptions};
+use mini_lsm_wrapper::wal::WalSyncPolicy;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -37,6 +44,13 @@ enum CompactionStrategy {
     None,
 }
 
+#[derive(Debug, Clone, ValueEnum)]
+enum CompressionStrategy {
+    None,
+    Lz4,
+    Zstd,
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -48,6 +62,8 @@ struct Args {
     enable_wal: bool,
     #[arg(long)]
     serializable: bool,
+    #[arg(long, default_value = "none")]
+    compression: CompressionStrategy,
 }
 
 struct ReplHandler {
@@ -127,6 +143,24 @@ impl ReplHandler {
                 self.lsm.dump_structure();
                 println!("dump success");
             }
+            Command::Stats => {
+                let stats = self.lsm.memtable_stats();
+                println!(
+                    "memtables: {} immutable, {} entries, {} tombstones",
+                    stats.num_imm_memtables, stats.num_entries, stats.num_tombstones
+                );
+                let or_unknown = |x: Option<String>| x.unwrap_or_else(|| "unknown".to_string());
+                for stats in self.lsm.level_stats() {
+                    println!(
+                        "L{}: {} files, {} bytes, {} entries, tombstone ratio {}",
+                        stats.level,
+                        stats.num_files,
+                        stats.total_size,
+                        or_unknown(stats.num_entries.map(|x| x.to_string())),
+                        or_unknown(stats.tombstone_ratio().map(|x| format!("{:.3}", x))),
+                    );
+                }
+            }
             Command::Flush => {
                 self.lsm.force_flush()?;
                 println!("flush success");
@@ -165,6 +199,7 @@ enum Command {
     },
 
     Dump,
+    Stats,
     Flush,
     FullCompaction,
     Quit,
@@ -238,6 +273,7 @@ impl Command {
                 get,
                 scan,
                 map(tag_no_case("dump"), |_| Command::Dump),
+                map(tag_no_case("stats"), |_| Command::Stats),
                 map(tag_no_case("flush"), |_| Command::Flush),
                 map(tag_no_case("full_compaction"), |_| Command::FullCompaction),
                 map(tag_no_case("quit"), |_| Command::Quit),
@@ -334,6 +370,9 @@ fn main() -> Result<()> {
             block_size: 4096,
             target_sst_size: 2 << 20, // 2MB
             num_memtable_limit: 3,
+            memtable_shards: 1,
+            flush_merge_imm: false,
+            write_stall_timeout: None,
             compaction_options: match args.compaction {
                 CompactionStrategy::None => CompactionOptions::NoCompaction,
                 CompactionStrategy::Simple => {
@@ -360,7 +399,40 @@ fn main() -> Result<()> {
                 }
             },
             enable_wal: args.enable_wal,
+            wal_compression: CompressionOptions::None,
+            wal_sync_policy: WalSyncPolicy::Manual,
+            wal_preallocate_size: 0,
+            recycle_wal: false,
             serializable: args.serializable,
+            max_entry_size: None,
+            max_key_size: MAX_KEY_VALUE_LEN,
+            max_value_size: MAX_KEY_VALUE_LEN,
+            large_value_threshold: None,
+            block_cache_capacity: DEFAULT_BLOCK_CACHE_CAPACITY,
+            bloom_false_positive_rate: Some(DEFAULT_BLOOM_FALSE_POSITIVE_RATE),
+            bloom_per_block: false,
+            mmap: false,
+            max_open_files: None,
+            prewarm_on_open: false,
+            prefix_extractor: None,
+            paranoid_checks: false,
+            verify_checksums: ChecksumVerification::OnFill,
+            use_direct_io_for_flush_and_compaction: false,
+            compaction_readahead_size: DEFAULT_COMPACTION_READAHEAD_SIZE,
+            trash_orphan_ssts: false,
+            max_manifest_size: DEFAULT_MAX_MANIFEST_SIZE,
+            manifest_format: ManifestFormat::Binary,
+            manifest_sync_mode: ManifestSyncMode::Always,
+            watermark_record_interval: None,
+            index_partition_len: None,
+            compression_dict_size: 0,
+            merge_user_properties: None,
+            encryption: None,
+            compression: match args.compression {
+                CompressionStrategy::None => CompressionOptions::None,
+                CompressionStrategy::Lz4 => CompressionOptions::Lz4,
+                CompressionStrategy::Zstd => CompressionOptions::Zstd { level: 0 },
+            },
         },
     )?;
 
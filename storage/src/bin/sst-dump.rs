@@ -0,0 +1,199 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod wrapper;
+use wrapper::mini_lsm_wrapper;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use mini_lsm_wrapper::iterators::StorageIterator;
+use mini_lsm_wrapper::key::{KeySlice, TS_RANGE_BEGIN};
+use mini_lsm_wrapper::table::{FileObject, Footer, SsTable, SsTableIterator};
+
+/// Inspect an SST file. Prints the meta section if no other option is given.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path of the SST file.
+    path: PathBuf,
+    /// Print the footer, the number of blocks, the key range and the bloom filter.
+    #[arg(long)]
+    meta: bool,
+    /// Print the offset, size and first key of each block.
+    #[arg(long)]
+    blocks: bool,
+    /// Print the entries, optionally only the ones between `--from` and `--to` (both included).
+    #[arg(long)]
+    scan: bool,
+    #[arg(long, requires = "scan")]
+    from: Option<String>,
+    #[arg(long, requires = "scan")]
+    to: Option<String>,
+    /// Check the checksum of every block and that the keys are sorted, fails if they aren't.
+    #[arg(long)]
+    verify: bool,
+}
+
+fn escape(data: &[u8]) -> String {
+    data.escape_ascii().to_string()
+}
+
+fn print_meta(sst: &SsTable, footer: &Footer) {
+    println!("format version: {}", footer.version);
+    println!("file size: {}", sst.table_size());
+    println!("meta offset: {}", footer.block_meta_offset);
+    println!("bloom offset: {}", footer.bloom_offset);
+    println!("meta checksum: {:#010x}", footer.meta_checksum);
+    if footer.has_partitioned_index() {
+        println!(
+            "partitioned index: at {}, {} blocks per partition",
+            footer.index_offset, footer.index_partition_len
+        );
+    }
+    if let Some(dict) = sst.compression_dict() {
+        println!(
+            "compression dict: at {}, {} bytes",
+            footer.compression_dict_offset,
+            dict.raw().len()
+        );
+    }
+    println!("blocks: {}", sst.num_of_blocks());
+    println!(
+        "first key: {}@{}",
+        escape(sst.first_key().key_ref()),
+        sst.first_key().ts()
+    );
+    println!(
+        "last key: {}@{}",
+        escape(sst.last_key().key_ref()),
+        sst.last_key().ts()
+    );
+    println!("min ts: {}", sst.min_ts());
+    println!("max ts: {}", sst.max_ts());
+    match sst.bloom() {
+        Some(bloom) => println!(
+            "bloom filter: {} bits, {} hashes",
+            bloom.num_bits(),
+            bloom.num_hashes()
+        ),
+        None if footer.block_filter_offset != 0 => {
+            println!("bloom filter: per block, at {}", footer.block_filter_offset)
+        }
+        None => println!("bloom filter: none"),
+    }
+    match sst.prefix_bloom() {
+        Some(prefix_bloom) => println!(
+            "prefix bloom filter: {:?}, {} bits, {} hashes",
+            prefix_bloom.extractor,
+            prefix_bloom.bloom.num_bits(),
+            prefix_bloom.bloom.num_hashes()
+        ),
+        None => println!("prefix bloom filter: none"),
+    }
+    match sst.properties() {
+        Some(properties) => println!(
+            "entries: {}, tombstones: {}, raw key size: {}, raw value size: {}",
+            properties.num_entries,
+            properties.num_tombstones,
+            properties.raw_key_size,
+            properties.raw_value_size
+        ),
+        None => println!("properties: none"),
+    }
+    // sorted, so that the output is stable
+    let mut user_properties = sst.user_properties().iter().collect::<Vec<_>>();
+    user_properties.sort();
+    for (key, value) in user_properties {
+        println!("user property: {}={}", key, escape(value));
+    }
+}
+
+fn print_blocks(sst: &SsTable, footer: &Footer) -> Result<()> {
+    for idx in 0..sst.num_of_blocks() {
+        let meta = sst.block_meta(idx)?;
+        let end = if idx + 1 < sst.num_of_blocks() {
+            sst.block_meta(idx + 1)?.offset
+        } else {
+            footer.block_meta_offset as usize
+        };
+        println!(
+            "block {}: offset={} size={} first_key={}@{}",
+            idx,
+            meta.offset,
+            end - meta.offset,
+            escape(meta.first_key.key_ref()),
+            meta.first_key.ts()
+        );
+    }
+    Ok(())
+}
+
+fn scan(sst: Arc<SsTable>, from: Option<&str>, to: Option<&str>) -> Result<()> {
+    let mut iter = match from {
+        Some(from) => SsTableIterator::create_and_seek_to_key(
+            sst,
+            KeySlice::from_slice(from.as_bytes(), TS_RANGE_BEGIN),
+        )?,
+        None => SsTableIterator::create_and_seek_to_first(sst)?,
+    };
+    while iter.is_valid() {
+        let key = iter.key();
+        if to.is_some_and(|to| key.key_ref() > to.as_bytes()) {
+            break;
+        }
+        if iter.value().is_empty() {
+            println!("{}@{} (deleted)", escape(key.key_ref()), key.ts());
+        } else {
+            println!(
+                "{}@{} = {}",
+                escape(key.key_ref()),
+                key.ts(),
+                escape(iter.value())
+            );
+        }
+        iter.next()?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let mut args = Args::parse();
+    if !(args.meta || args.blocks || args.scan || args.verify) {
+        args.meta = true;
+    }
+
+    let file = FileObject::open(&args.path)
+        .with_context(|| format!("failed to open {}", args.path.display()))?;
+    let footer = Footer::read(&file)?;
+    // also checks the meta section
+    let sst = Arc::new(SsTable::open(0, None, file)?);
+
+    if args.meta {
+        print_meta(&sst, &footer);
+    }
+    if args.blocks {
+        print_blocks(&sst, &footer)?;
+    }
+    if args.scan {
+        scan(sst.clone(), args.from.as_deref(), args.to.as_deref())?;
+    }
+    if args.verify {
+        sst.verify()?;
+        println!("OK: {} blocks verified", sst.num_of_blocks());
+    }
+    Ok(())
+}
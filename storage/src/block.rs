@@ -13,50 +13,297 @@
 // limitations under the License.
 
 mod builder;
+mod cache;
 mod iterator;
+pub(crate) mod varint;
 
-pub use builder::BlockBuilder;
+use anyhow::{Result, bail};
+pub use builder::{BlockAddResult, BlockBuilder};
 use bytes::{Buf, BufMut, Bytes};
-pub use iterator::BlockIterator;
+pub use cache::{BlockCache, BlockCacheStats, CachedBlock};
+pub use iterator::{BlockIterator, SeekResult};
 
 // 16 bits -> 2 bytes
 pub(crate) const SIZEOF_U16: usize = std::mem::size_of::<u16>();
 pub(crate) const SIZEOF_U32: usize = std::mem::size_of::<u32>();
 pub(crate) const SIZEOF_U64: usize = std::mem::size_of::<u64>();
 
+/// Blocks written before the format byte existed, all lengths are fixed-size u16.
+pub(crate) const BLOCK_FORMAT_LEGACY: u8 = 0;
+/// Key overlap, key and value lengths are LEB128 varints.
+pub(crate) const BLOCK_FORMAT_VARINT: u8 = 1;
+/// Same entries as `BLOCK_FORMAT_VARINT`, but keys are prefix-compressed against the closest
+/// restart point instead of the first key, and the restart points are stored after the offsets.
+pub(crate) const BLOCK_FORMAT_RESTART: u8 = 2;
+/// Same as `BLOCK_FORMAT_RESTART`, but the lowest bit of the rest key length marks a tombstone,
+/// which has no value length at all.
+pub(crate) const BLOCK_FORMAT_TOMBSTONE: u8 = 3;
+/// The format new blocks are written in, and the newest one this build can read.
+pub(crate) const BLOCK_FORMAT_CURRENT: u8 = BLOCK_FORMAT_TOMBSTONE;
+/// Set on `num_of_elements` when a format byte is stored right before it. Legacy blocks can't
+/// have this bit set since their u16 offsets never address that many entries.
+const BLOCK_FORMAT_FLAG: u16 = 1 << 15;
+
 /// A block is the smallest unit of read and caching in LSM tree. It is a collection of sorted key-value pairs.
 pub struct Block {
-    pub(crate) data: Vec<u8>,
+    /// Shares the buffer the block was decoded from, so values can be handed out without copying.
+    pub(crate) data: Bytes,
     pub(crate) offsets: Vec<u16>,
+    /// Indices of the entries storing their full key, always starting with 0. Blocks written
+    /// before restart points only have the first entry.
+    pub(crate) restarts: Vec<u16>,
+    /// How the entries in `data` are encoded.
+    pub(crate) format: u8,
+}
+
+/// Where the fields of an entry are located in `Block::data`.
+pub(crate) struct Entry {
+    /// Number of bytes shared with the key of the restart point.
+    pub(crate) key_overlap_len: usize,
+    /// Range of the rest of the key.
+    pub(crate) rest_key: (usize, usize),
+    pub(crate) ts: u64,
+    /// Range of the value.
+    pub(crate) value: (usize, usize),
 }
 
 impl Block {
+    // ----------------------------------------------------------------------------------------------------------------
+    // |             Data Section             |              Offset Section             |            Extra            |
+    // ----------------------------------------------------------------------------------------------------------------
+    // | Entry #1 | Entry #2 | ... | Entry #N | Offset #1 | Offset #2 | ... | Offset #N | format (u8) | num_of_elements |
+    // ----------------------------------------------------------------------------------------------------------------
+    //
+    // `BLOCK_FORMAT_RESTART` has the restart points between the offsets and the extra section:
+    // | Restart #1 | ... | Restart #M | num_of_restarts (u16) |
+    /// crc32 of the decoded block, so that a cached block can be checked again without the bytes
+    /// it was read from.
+    pub(crate) fn content_checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.data);
+        for offset in self.offsets.iter().chain(self.restarts.iter()) {
+            hasher.update(&offset.to_be_bytes());
+        }
+        hasher.update(&[self.format]);
+        hasher.finalize()
+    }
+
     /// Encode the internal data to the data layout illustrated in the course
     /// Note: You may want to recheck if any of the expected field is missing from your output
     pub fn encode(&self) -> Bytes {
-        let mut buff = self.data.clone();
-        for offset in self.offsets.iter() {
-            buff.put_u16(*offset);
-        }
-        // this stands for how many key-value pairs are there.
-        buff.put_u16(self.offsets.len() as u16);
-        return Bytes::from(buff);
+        let mut buff = Vec::new();
+        self.encode_into(&mut buff);
+        Bytes::from(buff)
+    }
+
+    /// Same as `encode`, but appends to `buf` instead of allocating a new buffer.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        encode_block(&self.data, &self.offsets, &self.restarts, self.format, buf);
     }
 
     /// Decode from the data layout, transform the input `data` to a single `Block`
-    pub fn decode(data: &[u8]) -> Self {
-        let num_elements = (&data[data.len() - SIZEOF_U16..]).get_u16() as usize;
-        let data_end = data.len() - SIZEOF_U16 - num_elements * SIZEOF_U16;
-        let offsets_raw = &data[data_end..data.len() - SIZEOF_U16];
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        Self::decode_bytes(Bytes::copy_from_slice(data))
+    }
+
+    /// Same as `decode`, but the entries keep pointing into `data` instead of being copied out.
+    pub fn decode_bytes(data: Bytes) -> Result<Self> {
+        if data.len() < SIZEOF_U16 {
+            bail!("block is too short: {} bytes", data.len());
+        }
+        let num_elements = (&data[data.len() - SIZEOF_U16..]).get_u16();
+        let (format, extra_len) = if num_elements & BLOCK_FORMAT_FLAG != 0 {
+            if data.len() < SIZEOF_U16 + 1 {
+                bail!("block is too short: {} bytes", data.len());
+            }
+            (data[data.len() - SIZEOF_U16 - 1], SIZEOF_U16 + 1)
+        } else {
+            (BLOCK_FORMAT_LEGACY, SIZEOF_U16)
+        };
+        // written by a newer version, don't try to interpret anything else
+        if format > BLOCK_FORMAT_CURRENT {
+            bail!(
+                "unsupported block format {}, the newest supported one is {}",
+                format,
+                BLOCK_FORMAT_CURRENT
+            );
+        }
+        let num_elements = (num_elements & !BLOCK_FORMAT_FLAG) as usize;
+        if num_elements == 0 {
+            bail!("block has no entries");
+        }
+        let mut trailer_end = data.len() - extra_len;
+        let restarts = match format {
+            // the first entry is the only restart point
+            BLOCK_FORMAT_LEGACY | BLOCK_FORMAT_VARINT => vec![0],
+            BLOCK_FORMAT_RESTART | BLOCK_FORMAT_TOMBSTONE => {
+                if trailer_end < SIZEOF_U16 {
+                    bail!("block is too short: {} bytes", data.len());
+                }
+                let num_restarts = (&data[trailer_end - SIZEOF_U16..]).get_u16() as usize;
+                let Some(restarts_begin) =
+                    (trailer_end - SIZEOF_U16).checked_sub(num_restarts * SIZEOF_U16)
+                else {
+                    bail!(
+                        "block of {} bytes cannot hold {} restart points",
+                        data.len(),
+                        num_restarts
+                    );
+                };
+                let restarts = decode_u16s(&data[restarts_begin..trailer_end - SIZEOF_U16]);
+                trailer_end = restarts_begin;
+                restarts
+            }
+            _ => unreachable!(),
+        };
+        let Some(data_end) = trailer_end.checked_sub(num_elements * SIZEOF_U16) else {
+            bail!(
+                "block of {} bytes cannot hold {} offsets",
+                data.len(),
+                num_elements
+            );
+        };
         // get each offsets
-        let offsets: Vec<u16> = offsets_raw
-            .chunks(SIZEOF_U16)
-            .map(|mut x| x.get_u16())
-            .collect();
+        let offsets = decode_u16s(&data[data_end..trailer_end]);
 
-        Self {
-            data: data[0..data_end].to_vec(),
-            offsets: offsets,
+        let block = Self {
+            data: data.slice(0..data_end),
+            offsets,
+            restarts,
+            format,
+        };
+        block.validate()?;
+        Ok(block)
+    }
+
+    /// Parse the entry stored in `data[offset..end]`, returns `None` if it doesn't fit exactly.
+    pub(crate) fn parse_entry(&self, offset: usize, end: usize) -> Option<Entry> {
+        let mut entry = self.data.get(offset..end)?;
+        let read_len = |entry: &mut &[u8]| -> Option<usize> {
+            if self.format == BLOCK_FORMAT_LEGACY {
+                if entry.remaining() < SIZEOF_U16 {
+                    return None;
+                }
+                Some(entry.get_u16() as usize)
+            } else {
+                varint::get_varint(entry).map(|x| x as usize)
+            }
+        };
+        // key_overlap_len | rest_key_len | rest_key | ts | value_len | value
+        // or key_overlap_len | rest_key_len << 1 | 1 | rest_key | ts for a tombstone
+        let key_overlap_len = read_len(&mut entry)?;
+        let mut rest_key_len = read_len(&mut entry)?;
+        let mut is_tombstone = false;
+        if self.format >= BLOCK_FORMAT_TOMBSTONE {
+            is_tombstone = rest_key_len & 1 == 1;
+            rest_key_len >>= 1;
+        }
+        if entry.remaining() < rest_key_len.checked_add(SIZEOF_U64)? {
+            return None;
         }
+        let rest_key_begin = end - entry.remaining();
+        entry.advance(rest_key_len);
+        let ts = entry.get_u64();
+        let value_len = if is_tombstone {
+            0
+        } else {
+            read_len(&mut entry)?
+        };
+        if entry.remaining() != value_len {
+            return None;
+        }
+        Some(Entry {
+            key_overlap_len,
+            rest_key: (rest_key_begin, rest_key_begin + rest_key_len),
+            ts,
+            value: (end - value_len, end),
+        })
+    }
+
+    /// The end of the entry at `idx` in `data`, which is where the next entry begins.
+    pub(crate) fn entry_end(&self, idx: usize) -> usize {
+        self.offsets
+            .get(idx + 1)
+            .map_or(self.data.len(), |x| *x as usize)
+    }
+
+    /// The index in `restarts` of the restart point that `idx` is prefix-compressed against.
+    pub(crate) fn restart_of(&self, idx: usize) -> usize {
+        self.restarts.partition_point(|x| *x as usize <= idx) - 1
+    }
+
+    /// Check that every offset points to a complete entry inside the data section, so that
+    /// iterators over this block never read out of bounds.
+    fn validate(&self) -> Result<()> {
+        if self.offsets[0] != 0 {
+            bail!("first offset should be 0, got {}", self.offsets[0]);
+        }
+        if self.restarts.first() != Some(&0) {
+            bail!("first restart point should be entry 0");
+        }
+        if self.restarts.windows(2).any(|x| x[0] >= x[1])
+            || *self.restarts.last().unwrap() as usize >= self.offsets.len()
+        {
+            bail!("restart points are not increasing entry indices");
+        }
+        let mut restart_key_len = 0;
+        for idx in 0..self.offsets.len() {
+            let offset = self.offsets[idx] as usize;
+            let entry_end = self.entry_end(idx);
+            if entry_end <= offset && idx + 1 < self.offsets.len() {
+                bail!("offsets are not increasing at entry {}", idx);
+            }
+            if entry_end > self.data.len() {
+                bail!("offset of entry {} is out of the data section", idx + 1);
+            }
+            let Some(entry) = self.parse_entry(offset, entry_end) else {
+                bail!("entry {} is malformed", idx);
+            };
+            if self.restarts[self.restart_of(idx)] as usize == idx {
+                if entry.key_overlap_len != 0 {
+                    bail!("restart entry {} should not have an overlap", idx);
+                }
+                restart_key_len = entry.rest_key.1 - entry.rest_key.0;
+            } else if entry.key_overlap_len > restart_key_len {
+                bail!("entry {} overlaps more than its restart key", idx);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends the encoded block made of these parts to `buf`, see `Block::encode` for the layout.
+pub(crate) fn encode_block(
+    data: &[u8],
+    offsets: &[u16],
+    restarts: &[u16],
+    format: u8,
+    buf: &mut Vec<u8>,
+) {
+    buf.reserve(data.len() + (offsets.len() + restarts.len() + 3) * SIZEOF_U16);
+    buf.put(data);
+    for offset in offsets.iter() {
+        buf.put_u16(*offset);
+    }
+    if format >= BLOCK_FORMAT_RESTART {
+        for restart in restarts.iter() {
+            buf.put_u16(*restart);
+        }
+        buf.put_u16(restarts.len() as u16);
+    }
+    // this stands for how many key-value pairs are there.
+    if format == BLOCK_FORMAT_LEGACY {
+        buf.put_u16(offsets.len() as u16);
+    } else {
+        buf.put_u8(format);
+        buf.put_u16(offsets.len() as u16 | BLOCK_FORMAT_FLAG);
+    }
+}
+
+fn decode_u16s(mut buf: &[u8]) -> Vec<u16> {
+    let mut values = Vec::with_capacity(buf.len() / SIZEOF_U16);
+    while buf.has_remaining() {
+        values.push(buf.get_u16());
     }
+    values
 }
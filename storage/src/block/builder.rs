@@ -13,7 +13,10 @@
 // limitations under the License.
 
 use crate::{
-    block::SIZEOF_U16,
+    block::{
+        BLOCK_FORMAT_CURRENT, SIZEOF_U16, SIZEOF_U64, encode_block,
+        varint::{put_varint, varint_len},
+    },
     key::{KeySlice, KeyVec},
 };
 use bytes::BufMut;
@@ -26,10 +29,30 @@ pub struct BlockBuilder {
     offsets: Vec<u16>,
     /// All serialized key-value pairs in the block.
     data: Vec<u8>,
+    /// Indices of the entries storing their full key.
+    restarts: Vec<u16>,
     /// The expected block size.
     block_size: usize,
-    /// The first key in the block
-    first_key: KeyVec,
+    /// A restart point is placed every `restart_interval` entries.
+    restart_interval: usize,
+    /// The key of the latest restart point, following keys are prefix-compressed against it
+    restart_key: KeyVec,
+    /// The last key added to the block, later keys must be greater than it
+    last_key: KeyVec,
+}
+
+/// The outcome of `BlockBuilder::add`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub enum BlockAddResult {
+    /// The entry is stored in the block.
+    Added,
+    /// The block is full, the entry should go into the next one.
+    BlockFull,
+    /// The entry alone exceeds `block_size`, it can only go into an empty block of its own.
+    EntryTooLarge,
+    /// The key isn't greater than the last key in the block.
+    OutOfOrder,
 }
 
 // return index which means all things before index are same.
@@ -49,64 +72,120 @@ fn compute_overlap(first_key: KeySlice, key: KeySlice) -> usize {
     i
 }
 
+/// Entries between two restart points when not configured.
+pub(crate) const DEFAULT_RESTART_INTERVAL: usize = 16;
+/// Used to guess how many entries fit in a block when pre-sizing the offsets.
+const ESTIMATED_ENTRY_SIZE: usize = 32;
+
 impl BlockBuilder {
-    /// Creates a new block builder.
+    /// Creates a new block builder, with buffers sized for a full block.
     pub fn new(block_size: usize) -> Self {
+        let estimated_entries = block_size / ESTIMATED_ENTRY_SIZE + 1;
         Self {
-            offsets: Vec::new(),
-            data: Vec::new(),
+            offsets: Vec::with_capacity(estimated_entries),
+            data: Vec::with_capacity(block_size),
+            restarts: Vec::with_capacity(estimated_entries / DEFAULT_RESTART_INTERVAL + 1),
             block_size: block_size,
-            first_key: KeyVec::new(),
+            restart_interval: DEFAULT_RESTART_INTERVAL,
+            restart_key: KeyVec::new(),
+            last_key: KeyVec::new(),
         }
     }
 
-    // ----------------------------------------------------------------------------------------------------
-    // |             Data Section             |              Offset Section             |      Extra      |
-    // ----------------------------------------------------------------------------------------------------
-    // | Entry #1 | Entry #2 | ... | Entry #N | Offset #1 | Offset #2 | ... | Offset #N | num_of_elements |
-    // ----------------------------------------------------------------------------------------------------
-    fn estimated_size(&self) -> usize {
-        self.data.len() + self.offsets.len() * SIZEOF_U16 + SIZEOF_U16
+    /// Stores a full key every `restart_interval` entries. 1 disables prefix compression, while
+    /// an interval larger than the number of entries compresses everything against the first key.
+    pub fn with_restart_interval(mut self, restart_interval: usize) -> Self {
+        assert!(restart_interval > 0, "restart_interval should be positive");
+        self.restart_interval = restart_interval;
+        self
+    }
+
+    // ----------------------------------------------------------------------------------------------------------------
+    // |             Data Section             |              Offset Section             |            Extra            |
+    // ----------------------------------------------------------------------------------------------------------------
+    // | Entry #1 | Entry #2 | ... | Entry #N | Offset #1 | Offset #2 | ... | Offset #N | format (u8) | num_of_elements |
+    // ----------------------------------------------------------------------------------------------------------------
+    // with | Restart #1 | ... | Restart #M | num_of_restarts (u16) | right before the extra section.
+    pub(crate) fn estimated_size(&self) -> usize {
+        self.data.len()
+            + self.offsets.len() * SIZEOF_U16
+            + self.restarts.len() * SIZEOF_U16
+            + SIZEOF_U16
+            + 1
+            + SIZEOF_U16
     }
 
-    /// Adds a key-value pair to the block. Returns false when the block is full.
+    /// Adds a key-value pair to the block. Returns why the entry is rejected if it isn't added.
+    /// An empty block accepts any entry so that an oversized one still gets a block of its own.
     /// You may find the `bytes::BufMut` trait useful for manipulating binary data.
-    #[must_use]
-    pub fn add(&mut self, key: KeySlice, value: &[u8]) -> bool {
-        let total_size = self.estimated_size() + key.raw_len() + value.len() + 3 * SIZEOF_U16; /* key_len + value_len + offset */
+    pub fn add(&mut self, key: KeySlice, value: &[u8]) -> BlockAddResult {
+        if !self.is_empty() && key <= self.last_key.as_key_slice() {
+            return BlockAddResult::OutOfOrder;
+        }
+
+        // only the rest of the key (the part that doesn't overlap with restart_key) is stored,
+        // and a restart point stores the full key.
+        let is_restart = self.offsets.len().is_multiple_of(self.restart_interval);
+        let overlap = if is_restart {
+            0
+        } else {
+            compute_overlap(self.restart_key.as_key_slice(), key)
+        };
+        let rest_key_len = key.key_len() - overlap;
+        // a tombstone is flagged in the rest key length and doesn't store a value length
+        let is_tombstone = value.is_empty();
+        let rest_key_len_field = (rest_key_len << 1) | is_tombstone as usize;
+        let entry_size = varint_len(overlap as u64)
+            + varint_len(rest_key_len_field as u64)
+            + rest_key_len
+            + SIZEOF_U64
+            + if is_tombstone {
+                0
+            } else {
+                varint_len(value.len() as u64) + value.len()
+            };
+        let total_size = self.estimated_size()
+            + entry_size
+            + SIZEOF_U16 /* offset */
+            + if is_restart { SIZEOF_U16 } else { 0 };
 
         // for the first calculation this is inaccurate
         // since we don't have data and we shouldn't add SIZEOF_U16 for num_of_elements field
-        if total_size >= self.block_size && !self.is_empty() {
-            return false;
+        // offsets are u16, so the next entry must also start within the first 64KB.
+        if !self.is_empty() {
+            if entry_size > self.block_size {
+                return BlockAddResult::EntryTooLarge;
+            }
+            if total_size >= self.block_size || self.data.len() > u16::MAX as usize {
+                return BlockAddResult::BlockFull;
+            }
         }
 
+        if is_restart {
+            self.restarts.push(self.offsets.len() as u16);
+            self.restart_key.set_from_slice(key);
+        }
         self.offsets.push(self.data.len() as u16);
 
         // add key and value
-        let overlap = compute_overlap(self.first_key.as_key_slice(), key);
-        // key_overlap_len (u16) | rest_key_len (u16) | key (rest_key_len) | timestamp (u64)
+        // key_overlap_len (varint) | rest_key_len << 1 | is_tombstone (varint) | key (rest_key_len) | timestamp (u64)
         // example:
-        // first_key = mini-something, above keys are 5|3|LSM
+        // restart_key = mini-something, above keys are 5|6|LSM
         // mini-LSM
-        self.data.put_u16(overlap as u16);
-        self.data.put_u16((key.key_len() - overlap) as u16);
+        put_varint(&mut self.data, overlap as u64);
+        put_varint(&mut self.data, rest_key_len_field as u64);
         self.data.put(&key.key_ref()[overlap..]);
         self.data.put_u64(key.ts());
 
-        self.data.put_u16(value.len() as u16);
-        self.data.put(value);
-
-        // why moved it here?
-        // for the first key, our expected overlap would be 0
-        // so we should set this first_key at later to avoid issues.
-        // check how Block::get_first_key() was implemented.
-        if self.first_key.is_empty() {
-            // record the first_key
-            self.first_key = key.to_key_vec();
+        // value_len (varint) | value, omitted for a tombstone
+        if !is_tombstone {
+            put_varint(&mut self.data, value.len() as u64);
+            self.data.put(value);
         }
 
-        return true;
+        self.last_key.set_from_slice(key);
+
+        BlockAddResult::Added
     }
 
     /// Check if there is no key-value pair in the block.
@@ -114,14 +193,50 @@ impl BlockBuilder {
         return self.data.is_empty();
     }
 
+    /// Clears the block so that the builder can be reused, keeping the allocated buffers.
+    pub fn reset(&mut self) {
+        self.offsets.clear();
+        self.data.clear();
+        self.restarts.clear();
+        self.restart_key.clear();
+        self.last_key.clear();
+    }
+
+    /// The address and capacity of each buffer, which `reset` keeps.
+    #[cfg(test)]
+    pub(crate) fn buffers_for_test(&self) -> [(usize, usize); 3] {
+        [
+            (self.offsets.as_ptr() as usize, self.offsets.capacity()),
+            (self.data.as_ptr() as usize, self.data.capacity()),
+            (self.restarts.as_ptr() as usize, self.restarts.capacity()),
+        ]
+    }
+
+    /// Appends the encoded block to `buf` without consuming the builder, same as
+    /// `build().encode()`. Call `reset` afterwards to start the next block.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        if self.is_empty() {
+            panic!("block should not be empty!");
+        }
+        encode_block(
+            &self.data,
+            &self.offsets,
+            &self.restarts,
+            BLOCK_FORMAT_CURRENT,
+            buf,
+        );
+    }
+
     /// Finalize the block.
     pub fn build(self) -> Block {
         if self.is_empty() {
             panic!("block should not be empty!");
         }
         Block {
-            data: self.data,
+            data: self.data.into(),
             offsets: self.offsets,
+            restarts: self.restarts,
+            format: BLOCK_FORMAT_CURRENT,
         }
     }
 }
@@ -0,0 +1,199 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Result, anyhow};
+use moka::sync::ConcurrentCacheExt;
+
+use super::{Block, SIZEOF_U16};
+use crate::table::bloom::Bloom;
+
+/// A cached block, along with whether its checksum was verified when it was read.
+#[derive(Clone)]
+pub struct CachedBlock {
+    pub block: Arc<Block>,
+    /// `Block::content_checksum` of a verified block, `None` if it was read without verification.
+    pub content_checksum: Option<u32>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum CacheKey {
+    Block(usize, usize),
+    BlockFilter(usize, usize),
+}
+
+#[derive(Clone)]
+enum CacheEntry {
+    Block(CachedBlock),
+    BlockFilter(Arc<Bloom>),
+}
+
+impl CacheEntry {
+    fn into_block(self) -> CachedBlock {
+        match self {
+            CacheEntry::Block(cached) => cached,
+            CacheEntry::BlockFilter(_) => {
+                unreachable!("a block filter is cached under a block key")
+            }
+        }
+    }
+
+    fn into_block_filter(self) -> Arc<Bloom> {
+        match self {
+            CacheEntry::BlockFilter(filter) => filter,
+            CacheEntry::Block(_) => unreachable!("a block is cached under a block filter key"),
+        }
+    }
+}
+
+/// Caches decoded blocks keyed by `(sst_id, block_idx)`, bounded by the total size of the blocks.
+/// The per-block bloom filters of SSTs that have them are cached alongside, under the index of
+/// their block, and count towards the same capacity.
+pub struct BlockCache {
+    cache: moka::sync::Cache<CacheKey, CacheEntry>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: Arc<AtomicU64>,
+}
+
+/// A snapshot of the block cache counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks dropped to stay within the capacity.
+    pub evictions: u64,
+    pub entry_count: u64,
+    /// Total size in bytes of the cached blocks.
+    pub size: u64,
+}
+
+/// The memory a cached block takes, roughly its encoded size.
+fn block_weight(block: &Block) -> u32 {
+    let size = block.data.len() + (block.offsets.len() + block.restarts.len()) * SIZEOF_U16;
+    size.try_into().unwrap_or(u32::MAX)
+}
+
+fn entry_weight(entry: &CacheEntry) -> u32 {
+    match entry {
+        CacheEntry::Block(cached) => block_weight(&cached.block),
+        CacheEntry::BlockFilter(filter) => filter.filter.len().try_into().unwrap_or(u32::MAX),
+    }
+}
+
+impl BlockCache {
+    /// Creates a cache holding at most `capacity` bytes of blocks.
+    pub fn new(capacity: u64) -> Self {
+        let evictions = Arc::new(AtomicU64::new(0));
+        let cache = {
+            let evictions = evictions.clone();
+            moka::sync::Cache::builder()
+                .max_capacity(capacity)
+                .weigher(|_, entry: &CacheEntry| entry_weight(entry))
+                .eviction_listener(move |_, _, cause| {
+                    if cause.was_evicted() {
+                        evictions.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+                .build()
+        };
+        Self {
+            cache,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions,
+        }
+    }
+
+    fn try_get_entry_with(
+        &self,
+        key: CacheKey,
+        init: impl FnOnce() -> Result<CacheEntry>,
+    ) -> Result<CacheEntry> {
+        let mut loaded = false;
+        let entry = self
+            .cache
+            .try_get_with(key, || {
+                loaded = true;
+                init()
+            })
+            .map_err(|e| anyhow!("{}", e))?;
+        if loaded {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(entry)
+    }
+
+    /// Returns the cached block, or loads it with `init` and caches it.
+    pub fn try_get_with(
+        &self,
+        (sst_id, block_idx): (usize, usize),
+        init: impl FnOnce() -> Result<CachedBlock>,
+    ) -> Result<CachedBlock> {
+        let entry = self.try_get_entry_with(CacheKey::Block(sst_id, block_idx), || {
+            init().map(CacheEntry::Block)
+        })?;
+        Ok(entry.into_block())
+    }
+
+    /// Returns the cached bloom filter of a block, or loads it with `init` and caches it.
+    pub fn try_get_block_filter_with(
+        &self,
+        (sst_id, block_idx): (usize, usize),
+        init: impl FnOnce() -> Result<Bloom>,
+    ) -> Result<Arc<Bloom>> {
+        let entry = self.try_get_entry_with(CacheKey::BlockFilter(sst_id, block_idx), || {
+            init().map(|filter| CacheEntry::BlockFilter(Arc::new(filter)))
+        })?;
+        Ok(entry.into_block_filter())
+    }
+
+    /// Returns the cached block without loading it on a miss.
+    pub fn get(&self, &(sst_id, block_idx): &(usize, usize)) -> Option<CachedBlock> {
+        let block = self
+            .cache
+            .get(&CacheKey::Block(sst_id, block_idx))
+            .map(CacheEntry::into_block);
+        if block.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        block
+    }
+
+    /// Replaces the cached block, e.g. with the verified one.
+    pub fn insert(&self, (sst_id, block_idx): (usize, usize), cached: CachedBlock) {
+        self.cache.insert(
+            CacheKey::Block(sst_id, block_idx),
+            CacheEntry::Block(cached),
+        );
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        // apply pending evictions so that the counters and sizes are up to date
+        self.cache.sync();
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entry_count: self.cache.entry_count(),
+            size: self.cache.weighted_size(),
+        }
+    }
+}
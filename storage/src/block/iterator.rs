@@ -14,12 +14,9 @@
 
 use std::sync::Arc;
 
-use bytes::Buf;
+use bytes::Bytes;
 
-use crate::{
-    block::{SIZEOF_U16, SIZEOF_U64},
-    key::{KeySlice, KeyVec},
-};
+use crate::key::{KeySlice, KeyVec};
 
 use super::Block;
 
@@ -33,32 +30,45 @@ pub struct BlockIterator {
     value_range: (usize, usize),
     /// Current index of the key-value pair, should be in range of [0, num_of_elements)
     idx: usize,
-    /// The first key in the block
-    first_key: KeyVec,
+    /// Index in `block.restarts` of the restart point the current key is compressed against
+    restart: usize,
+    /// The key of that restart point
+    restart_key: KeyVec,
+}
+
+/// Where a seek positioned the iterator relative to the target key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekResult {
+    /// On an entry of the same user key, which may be another version of it.
+    Exact,
+    /// On the first entry of a greater user key.
+    After,
+    /// All entries are smaller, the iterator is invalid.
+    NotFound,
 }
 
 impl Block {
-    fn get_first_key(&self) -> KeyVec {
-        let mut buf = &self.data[..];
-        // for the first key, there is no overlap;
-        buf.get_u16();
-        let key_len = buf.get_u16();
-        let key_buf = &buf[..key_len as usize];
-        buf.advance(key_len as usize);
-        let ts = (&buf[..SIZEOF_U64]).get_u64();
-        let key = KeyVec::from_vec_with_ts(key_buf.to_vec(), ts);
-        key
+    /// The full key stored at the restart point `restart`.
+    fn get_restart_key(&self, restart: usize) -> KeyVec {
+        // for a restart point, there is no overlap;
+        let idx = self.restarts[restart] as usize;
+        let entry = self
+            .parse_entry(self.offsets[idx] as usize, self.entry_end(idx))
+            .unwrap();
+        let key_buf = &self.data[entry.rest_key.0..entry.rest_key.1];
+        KeyVec::from_vec_with_ts(key_buf.to_vec(), entry.ts)
     }
 }
 
 impl BlockIterator {
     fn new(block: Arc<Block>) -> Self {
         Self {
-            first_key: block.get_first_key(),
+            restart_key: block.get_restart_key(0),
             block: block,
             key: KeyVec::new(),
             value_range: (0, 0),
             idx: 0,
+            restart: 0,
         }
     }
 
@@ -69,6 +79,13 @@ impl BlockIterator {
         iter
     }
 
+    /// Creates a block iterator and seek to the last entry.
+    pub fn create_and_seek_to_last(block: Arc<Block>) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_to_last();
+        iter
+    }
+
     /// Creates a block iterator and seek to the first key that >= `key`.
     pub fn create_and_seek_to_key(block: Arc<Block>, key: KeySlice) -> Self {
         let mut iter = Self::new(block);
@@ -86,6 +103,13 @@ impl BlockIterator {
         &self.block.data[self.value_range.0..self.value_range.1]
     }
 
+    /// Returns the value of the current entry as a slice of the block buffer, without copying.
+    pub fn value_bytes(&self) -> Bytes {
+        self.block
+            .data
+            .slice(self.value_range.0..self.value_range.1)
+    }
+
     /// Returns true if the iterator is valid.
     /// Note: You may want to make use of `key`
     pub fn is_valid(&self) -> bool {
@@ -97,50 +121,49 @@ impl BlockIterator {
         self.seek_to(0);
     }
 
+    /// Seeks to the last key in the block.
+    pub fn seek_to_last(&mut self) {
+        self.seek_to(self.block.offsets.len() - 1);
+    }
+
     // go to entry by index (i.e., offset)
     fn seek_to(&mut self, index: usize) {
         if index >= self.block.offsets.len() {
-            // already reached the end of the block, clear everything
+            // already reached the end of the block, clear everything but remember we are past the
+            // end so that `prev` can step back onto the last entry.
             self.key.clear();
             self.value_range = (0, 0);
+            self.idx = self.block.offsets.len();
             return;
         }
 
         let offset = self.block.offsets[index] as usize;
-        self.seek_to_offset(offset);
+        self.seek_to_offset(index, offset);
         // update the index instead of offset!!!
         self.idx = index;
     }
 
     // update the key and value accroding to the data entry
-    fn seek_to_offset(&mut self, offset: usize) {
-        // get the entry starting from offset
-        let mut entry = &self.block.data[offset..];
-
-        // build key with overlap
-        let overlap_len = entry.get_u16() as usize;
-
-        let key_len = entry.get_u16() as usize;
-        let key = &entry[..key_len];
-        // this is not same get_u16
-        // so we have advance the cursor explicitly
-        entry.advance(key_len);
+    fn seek_to_offset(&mut self, index: usize, offset: usize) {
+        let restart = self.block.restart_of(index);
+        if restart != self.restart {
+            self.restart = restart;
+            self.restart_key = self.block.get_restart_key(restart);
+        }
 
-        // get timestamp
-        let ts = entry.get_u64();
+        // the block has been validated when decoded or built by us
+        let entry = self
+            .block
+            .parse_entry(offset, self.block.entry_end(index))
+            .unwrap();
 
-        self.key.clear();
+        // build key with overlap
         let mut new_key: Vec<u8> = Vec::new();
-        new_key.extend(&self.first_key.key_ref()[..overlap_len]);
-        new_key.extend(key);
-        self.key = KeyVec::from_vec_with_ts(new_key, ts);
-
-        let value_len = entry.get_u16() as usize;
-
-        let value_start_index =
-            offset + SIZEOF_U16 + SIZEOF_U16 + key_len + SIZEOF_U16 + SIZEOF_U64;
+        new_key.extend(&self.restart_key.key_ref()[..entry.key_overlap_len]);
+        new_key.extend(&self.block.data[entry.rest_key.0..entry.rest_key.1]);
+        self.key = KeyVec::from_vec_with_ts(new_key, entry.ts);
 
-        self.value_range = (value_start_index, value_start_index + value_len);
+        self.value_range = entry.value;
     }
 
     /// Move to the next key in the block.
@@ -149,40 +172,70 @@ impl BlockIterator {
         self.seek_to(self.idx);
     }
 
+    /// Move to the previous key in the block. The iterator becomes invalid when moving before the
+    /// first key, and needs to be re-positioned by one of the seek functions after that. Calling
+    /// it once the iterator moved past the end lands on the last key.
+    pub fn prev(&mut self) {
+        if self.idx == 0 {
+            self.key.clear();
+            self.value_range = (0, 0);
+            return;
+        }
+        self.seek_to(self.idx - 1);
+    }
+
     /// Seek to the first key that >= `key`.
     /// Note: You should assume the key-value pairs in the block are sorted when being added by
     /// callers.
     pub fn seek_to_key(&mut self, key: KeySlice) {
-        // use binary search to speed up since it's sorted.
-        let mut left = 0;
-        let mut right = self.block.offsets.len();
+        let _ = self.seek_to_key_ret(key);
+    }
 
-        while left + 1 < right {
+    /// Same as `seek_to_key`, and tells whether it landed on `key`.
+    pub fn seek_to_key_ret(&mut self, key: KeySlice) -> SeekResult {
+        // binary search the restart points for the last one whose key is < `key`, the first key
+        // >= `key` is either in the interval starting there or the next restart point.
+        let mut left = 1;
+        let mut right = self.block.restarts.len();
+        while left < right {
+            let mid = left + (right - left) / 2;
+            if self.block.get_restart_key(mid).as_key_slice() < key {
+                left = mid + 1;
+            } else {
+                right = mid;
+            }
+        }
+        let restart = left - 1;
+        let mut left = self.block.restarts[restart] as usize;
+        let mut right = self
+            .block
+            .restarts
+            .get(restart + 1)
+            .map_or(self.block.offsets.len(), |x| *x as usize);
+        // then binary search within the interval, entries in it share the same restart key.
+        while left < right {
             let mid = left + (right - left) / 2;
             self.seek_to(mid);
-            if self.is_valid() {
-                if self.key() >= key {
-                    right = mid;
-                } else {
-                    left = mid;
-                }
+            if self.key() < key {
+                left = mid + 1;
+            } else {
+                right = mid;
             }
         }
-        // try the left first
+        // if all keys are smaller than `key`, left is the number of entries and the iterator
+        // becomes invalid.
         self.seek_to(left);
-        if self.is_valid() && self.key() >= key {
-            return;
-        }
-        self.seek_to(right);
-        if self.is_valid() && self.key() >= key {
-            return;
-        }
+        self.seek_result(key)
+    }
 
-        // let mut index = 0;
-        // TOO SLOW!!!
-        // while self.key.is_empty() || self.key().cmp(&key) == std::cmp::Ordering::Less {
-        //     self.seek_to(index);
-        //     index += 1;
-        // }
+    /// How the current position relates to the `key` that was sought.
+    pub(crate) fn seek_result(&self, key: KeySlice) -> SeekResult {
+        if !self.is_valid() {
+            SeekResult::NotFound
+        } else if self.key.key_ref() == key.key_ref() {
+            SeekResult::Exact
+        } else {
+            SeekResult::After
+        }
     }
 }
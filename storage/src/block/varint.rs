@@ -0,0 +1,48 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! LEB128 varints: 7 bits per byte, the high bit marks that more bytes follow.
+
+use bytes::BufMut;
+
+/// Number of bytes `value` takes once encoded.
+pub(crate) fn varint_len(value: u64) -> usize {
+    let bits = 64 - (value | 1).leading_zeros() as usize;
+    bits.div_ceil(7)
+}
+
+pub(crate) fn put_varint(buf: &mut impl BufMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+/// Decode a varint from the front of `buf` and advance it, returns `None` if `buf` ends before
+/// the varint does or the value doesn't fit into an u64.
+pub(crate) fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (idx, byte) in buf.iter().enumerate() {
+        if idx >= 10 || (idx == 9 && *byte > 1) {
+            return None;
+        }
+        value |= ((byte & 0x7f) as u64) << (idx * 7);
+        if byte & 0x80 == 0 {
+            *buf = &buf[idx + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
@@ -19,10 +19,13 @@ mod leveled;
 mod simple_leveled;
 mod tiered;
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use anyhow::Result;
+use bytes::Bytes;
 pub use leveled::{LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask};
 use serde::{Deserialize, Serialize};
 pub use simple_leveled::{
@@ -36,16 +39,19 @@ use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::key::KeySlice;
 use crate::lsm_storage::{CompactionFilter, LsmStorageInner, LsmStorageState};
-use crate::manifest::ManifestRecord;
+use crate::manifest::{ManifestRecord, SstMeta};
 use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
+use crate::wal::WalSyncPolicy;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CompactionTask {
     Leveled(LeveledCompactionTask),
     Tiered(TieredCompactionTask),
     Simple(SimpleLeveledCompactionTask),
+    /// Merges L0 and every level, or every tier, into the bottom one.
     ForceFullCompaction {
         l0_sstables: Vec<usize>,
+        /// The SSTs of all the levels or tiers, each a sorted run of them in the state.
         l1_sstables: Vec<usize>,
     },
 }
@@ -59,6 +65,57 @@ impl CompactionTask {
             CompactionTask::Tiered(task) => task.bottom_tier_included,
         }
     }
+
+    /// The SSTs the task compacts.
+    fn input_sst_ids(&self) -> Vec<usize> {
+        match self {
+            CompactionTask::ForceFullCompaction {
+                l0_sstables,
+                l1_sstables,
+            } => [l0_sstables.as_slice(), l1_sstables].concat(),
+            CompactionTask::Leveled(task) => [
+                task.upper_level_sst_ids.as_slice(),
+                &task.lower_level_sst_ids,
+            ]
+            .concat(),
+            CompactionTask::Simple(task) => [
+                task.upper_level_sst_ids.as_slice(),
+                &task.lower_level_sst_ids,
+            ]
+            .concat(),
+            CompactionTask::Tiered(task) => task
+                .tiers
+                .iter()
+                .flat_map(|(_, sst_ids)| sst_ids.iter().copied())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The step of a compaction that fails right after it's done, to test a crash in between: 1
+    /// once its intent is recorded, 2 once its first SST is built, and 3 once its result is.
+    pub(crate) static COMPACTION_FAIL_AFTER: std::cell::Cell<Option<usize>> =
+        const { std::cell::Cell::new(None) };
+}
+
+/// Fails the step of a compaction if a test asked it to.
+fn compaction_step_done(_step: usize) -> Result<()> {
+    #[cfg(test)]
+    if COMPACTION_FAIL_AFTER.with(|step| step.get()) == Some(_step) {
+        anyhow::bail!("failed after step {} of the compaction", _step);
+    }
+    Ok(())
+}
+
+/// The output ids a compaction reserved with `ManifestRecord::CompactionIntent`, which it builds
+/// its SSTs with in order.
+struct ReservedSstIds<'a> {
+    task: &'a CompactionTask,
+    ids: VecDeque<usize>,
+    /// How many ids are reserved each time it runs out, enough for the size of the inputs.
+    batch: usize,
 }
 
 pub(crate) enum CompactionController {
@@ -69,6 +126,21 @@ pub(crate) enum CompactionController {
 }
 
 impl CompactionController {
+    pub(crate) fn new(options: &CompactionOptions) -> Self {
+        match options {
+            CompactionOptions::Leveled(options) => {
+                Self::Leveled(LeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Tiered(options) => {
+                Self::Tiered(TieredCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Simple(options) => {
+                Self::Simple(SimpleLeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::NoCompaction => Self::NoCompaction,
+        }
+    }
+
     pub fn generate_compaction_task(&self, snapshot: &LsmStorageState) -> Option<CompactionTask> {
         match self {
             CompactionController::Leveled(ctrl) => ctrl
@@ -101,9 +173,54 @@ impl CompactionController {
             (CompactionController::Tiered(ctrl), CompactionTask::Tiered(task)) => {
                 ctrl.apply_compaction_result(snapshot, task, output)
             }
+            (
+                _,
+                CompactionTask::ForceFullCompaction {
+                    l0_sstables,
+                    l1_sstables,
+                },
+            ) => self.apply_full_compaction_result(snapshot, l0_sstables, l1_sstables, output),
             _ => unreachable!(),
         }
     }
+
+    /// Removes the compacted SSTs, keeping those flushed to L0 since, and makes `output` the bottom
+    /// level, or the last tier.
+    fn apply_full_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        l0_sstables: &[usize],
+        l1_sstables: &[usize],
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        let compacted = l0_sstables
+            .iter()
+            .chain(l1_sstables)
+            .copied()
+            .collect::<HashSet<_>>();
+        snapshot.l0_sstables.retain(|id| !compacted.contains(id));
+        for (_, sst_ids) in snapshot.levels.iter_mut() {
+            sst_ids.retain(|id| !compacted.contains(id));
+        }
+        if self.flush_to_l0() {
+            let (_, bottom_level) = snapshot
+                .levels
+                .last_mut()
+                .expect("the levels to compact to");
+            assert!(bottom_level.is_empty(), "the bottom level is compacted too");
+            bottom_level.extend(output);
+        } else {
+            snapshot.levels.retain(|(_, sst_ids)| !sst_ids.is_empty());
+            if let Some(&tier_id) = output.first() {
+                snapshot.levels.push((tier_id, output.to_vec()));
+            }
+        }
+        (
+            snapshot,
+            l0_sstables.iter().chain(l1_sstables).copied().collect(),
+        )
+    }
 }
 
 impl CompactionController {
@@ -115,7 +232,7 @@ impl CompactionController {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CompactionOptions {
     /// Leveled compaction with partial compaction + dynamic level support (= RocksDB's Leveled
     /// Compaction)
@@ -129,27 +246,77 @@ pub enum CompactionOptions {
 }
 
 impl LsmStorageInner {
+    /// The user properties of the SSTs written by a compaction of `sst_ids`, see
+    /// `LsmStorageOptions::merge_user_properties`.
+    fn merge_user_properties<'a>(
+        &self,
+        snapshot: &LsmStorageState,
+        sst_ids: impl IntoIterator<Item = &'a usize>,
+    ) -> HashMap<String, Bytes> {
+        let Some(merge) = self.options.merge_user_properties else {
+            return HashMap::new();
+        };
+        let inputs = sst_ids
+            .into_iter()
+            .map(|sst_id| snapshot.sstables[sst_id].user_properties())
+            .collect::<Vec<_>>();
+        merge(&inputs)
+    }
+
     fn compact_generate_sst_from_iter(
         &self,
         mut iter: impl for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
         is_lower_level_bottom_level: bool,
+        user_properties: &HashMap<String, Bytes>,
+        reserved: &mut ReservedSstIds,
     ) -> Result<Vec<Arc<SsTable>>> {
         let mut new_ssts = Vec::new();
 
         // also need to handle builder
-        let mut builder = None;
+        let mut builder: Option<SsTableBuilder> = None;
         let mut last_key = Vec::<u8>::new();
 
+        self.record_watermark(self.options.watermark_record_interval.unwrap_or_default())?;
         let watermark = self.mvcc().watermark();
         let mut first_key_below_watermark = false;
 
         let filters = self.compaction_filters.lock();
         'outer: while iter.is_valid() {
+            let is_same_key = iter.key().key_ref() == &last_key;
+
+            // Q: Do I need to do control how many ssts we should have here?
+            // A: we use self.options.target_sst_size
+            // with MVCC: all versions of a key go to the same file, so we only split right before
+            // a new key even if the size is already greater than target_sst_size
+            if !is_same_key
+                && let Some(builder_inner) = builder.as_ref()
+                && builder_inner.estimated_size() >= self.options.target_sst_size
+            {
+                // WARNING: this will take the builder and leave it with None
+                new_ssts.push(self.build_sst(builder.take().unwrap(), reserved)?);
+            }
+
             if builder.is_none() {
-                builder = Some(SsTableBuilder::new(self.options.block_size));
+                builder = Some(
+                    SsTableBuilder::new(self.options.block_size)
+                        .with_max_entry_size(self.options.max_entry_size)
+                        .with_compression(self.options.compression)
+                        .with_bloom_false_positive_rate(self.options.bloom_false_positive_rate)
+                        .with_bloom_per_block(self.options.bloom_per_block)
+                        .with_prefix_extractor(self.options.prefix_extractor)
+                        .with_paranoid_checks(self.options.paranoid_checks)
+                        .with_direct_io(self.options.use_direct_io_for_flush_and_compaction)
+                        .with_index_partition_len(self.options.index_partition_len)
+                        .with_compression_dict_size(self.options.compression_dict_size)
+                        .with_encryption(self.options.encryption.clone())
+                        .with_mmap(self.options.mmap)
+                        .with_file_cache(self.file_cache.clone()),
+                );
+                for (key, value) in user_properties {
+                    builder.as_mut().unwrap().add_property(key, value);
+                }
             }
 
-            let is_same_key = iter.key().key_ref() == &last_key;
             let builder_inner = builder.as_mut().unwrap();
 
             // Prior to MVCC: if it's in bottom level, we can ignore the empty values
@@ -209,25 +376,7 @@ impl LsmStorageInner {
                 }
             }
 
-            builder_inner.add(iter.key(), iter.value());
-
-            // Q: Do I need to do control how many ssts we should have here?
-            // A: we use self.options.target_sst_size
-            // with MVCC: we'd like to put same key in same file even if the size is greater than
-            // target_sst_size
-            if builder_inner.estimated_size() >= self.options.target_sst_size && !is_same_key {
-                // Q: how to get the id?
-                // A: next_sst_id()
-                let sst_id = self.next_sst_id();
-                // WARNING: this will take the builder and leave it with None
-                let builder = builder.take().unwrap();
-                let sst = Arc::new(builder.build(
-                    sst_id,
-                    Some(self.block_cache.clone()),
-                    self.path_of_sst(sst_id),
-                )?);
-                new_ssts.push(sst);
-            }
+            builder_inner.add(iter.key(), iter.value())?;
 
             // update the prev_key;
             if !is_same_key {
@@ -238,19 +387,70 @@ impl LsmStorageInner {
             iter.next()?;
         }
 
-        if let Some(builder) = builder {
-            let sst_id = self.next_sst_id();
-            let sst = Arc::new(builder.build(sst_id, None, self.path_of_sst(sst_id))?);
-            new_ssts.push(sst);
+        // every key may have been dropped since the last split
+        if let Some(builder) = builder
+            && !builder.is_empty()
+        {
+            new_ssts.push(self.build_sst(builder, reserved)?);
         }
         Ok(new_ssts)
     }
 
+    // Q: how to get the id?
+    // A: the next one the compaction reserved
+    fn build_sst(
+        &self,
+        builder: SsTableBuilder,
+        reserved: &mut ReservedSstIds,
+    ) -> Result<Arc<SsTable>> {
+        if reserved.ids.is_empty() {
+            self.reserve_sst_ids(reserved)?;
+        }
+        let sst_id = reserved.ids.pop_front().unwrap();
+        let sst = builder.build(
+            sst_id,
+            Some(self.block_cache.clone()),
+            self.path_of_sst(sst_id),
+        )?;
+        compaction_step_done(2)?;
+        if self.options.prewarm_on_open {
+            sst.prewarm_first_block()?;
+        }
+        Ok(Arc::new(sst))
+    }
+
+    /// Reserves another batch of output ids for the compaction, recorded in the manifest before
+    /// any file is built with them.
+    fn reserve_sst_ids(&self, reserved: &mut ReservedSstIds) -> Result<()> {
+        let ids = (0..reserved.batch)
+            .map(|_| self.next_sst_id())
+            .collect::<Vec<_>>();
+        // before the record, so that a snapshot taken after it has them
+        self.reserved_sst_ids.lock().extend(&ids);
+        self.manifest.as_ref().unwrap().add_records_when_init(vec![
+            ManifestRecord::CompactionIntent(reserved.task.clone(), ids.clone()),
+        ])?;
+        reserved.ids.extend(ids);
+        Ok(())
+    }
+
     fn compact(&self, _task: &CompactionTask) -> Result<Vec<Arc<SsTable>>> {
         let snapshot = {
             let guard = self.state.read();
             Arc::clone(&guard)
         };
+        let input_size = _task
+            .input_sst_ids()
+            .iter()
+            .map(|sst_id| snapshot.sstables[sst_id].table_size())
+            .sum::<u64>();
+        let mut reserved = ReservedSstIds {
+            task: _task,
+            ids: VecDeque::new(),
+            batch: (input_size / self.options.target_sst_size.max(1) as u64) as usize + 1,
+        };
+        self.reserve_sst_ids(&mut reserved)?;
+        compaction_step_done(1)?;
         match _task {
             CompactionTask::ForceFullCompaction {
                 l0_sstables,
@@ -258,50 +458,94 @@ impl LsmStorageInner {
             } => {
                 let mut l0_iters = Vec::with_capacity(l0_sstables.len());
                 for id in l0_sstables.iter() {
-                    l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
+                    l0_iters.push(Box::new(SsTableIterator::create_for_compaction(
                         snapshot.sstables[id].clone(),
+                        self.options.compaction_readahead_size,
                     )?));
                 }
-                let mut l1_ssts_to_concat = Vec::with_capacity(l1_sstables.len());
-                for id in l1_sstables.iter() {
-                    l1_ssts_to_concat.push(snapshot.sstables[id].clone());
+                // each level or tier is sorted, so it's concatenated
+                let compacted = l1_sstables.iter().collect::<HashSet<_>>();
+                let mut level_iters = Vec::with_capacity(snapshot.levels.len());
+                for (_, sst_ids) in snapshot.levels.iter() {
+                    let ssts_to_concat = sst_ids
+                        .iter()
+                        .filter(|sst_id| compacted.contains(sst_id))
+                        .map(|sst_id| snapshot.sstables[sst_id].clone())
+                        .collect::<Vec<_>>();
+                    if !ssts_to_concat.is_empty() {
+                        level_iters.push(Box::new(SstConcatIterator::create_for_compaction(
+                            ssts_to_concat,
+                            self.options.compaction_readahead_size,
+                        )?));
+                    }
                 }
                 let iter = TwoMergeIterator::create(
                     MergeIterator::create(l0_iters),
-                    SstConcatIterator::create_and_seek_to_first(l1_ssts_to_concat)?,
+                    MergeIterator::create(level_iters),
                 )?;
 
-                self.compact_generate_sst_from_iter(iter, _task.compact_to_bottom_level())
+                let user_properties =
+                    self.merge_user_properties(&snapshot, l0_sstables.iter().chain(l1_sstables));
+                self.compact_generate_sst_from_iter(
+                    iter,
+                    _task.compact_to_bottom_level(),
+                    &user_properties,
+                    &mut reserved,
+                )
             }
+            // a leveled task compacts some SSTs of the levels, the same way
             CompactionTask::Simple(SimpleLeveledCompactionTask {
                 upper_level,
                 upper_level_sst_ids,
                 lower_level,
                 lower_level_sst_ids,
                 ..
+            })
+            | CompactionTask::Leveled(LeveledCompactionTask {
+                upper_level,
+                upper_level_sst_ids,
+                lower_level,
+                lower_level_sst_ids,
+                ..
             }) => match upper_level {
                 Some(_) => {
                     let mut upper_ssts = Vec::with_capacity(upper_level_sst_ids.len());
                     for sst_id in upper_level_sst_ids.iter() {
                         upper_ssts.push(snapshot.sstables[sst_id].clone());
                     }
-                    let upper_iter = SstConcatIterator::create_and_seek_to_first(upper_ssts)?;
+                    let upper_iter = SstConcatIterator::create_for_compaction(
+                        upper_ssts,
+                        self.options.compaction_readahead_size,
+                    )?;
 
                     let mut lower_ssts = Vec::with_capacity(lower_level_sst_ids.len());
                     for sst_id in lower_level_sst_ids.iter() {
                         lower_ssts.push(snapshot.sstables[sst_id].clone());
                     }
-                    let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
+                    let lower_iter = SstConcatIterator::create_for_compaction(
+                        lower_ssts,
+                        self.options.compaction_readahead_size,
+                    )?;
 
                     let iter = TwoMergeIterator::create(upper_iter, lower_iter)?;
-                    self.compact_generate_sst_from_iter(iter, _task.compact_to_bottom_level())
+                    let user_properties = self.merge_user_properties(
+                        &snapshot,
+                        upper_level_sst_ids.iter().chain(lower_level_sst_ids),
+                    );
+                    self.compact_generate_sst_from_iter(
+                        iter,
+                        _task.compact_to_bottom_level(),
+                        &user_properties,
+                        &mut reserved,
+                    )
                 }
                 None => {
                     // use MergeIterator for L0 since it's not sorted
                     let mut upper_iters = Vec::with_capacity(upper_level_sst_ids.len());
                     for sst_id in upper_level_sst_ids.iter() {
-                        upper_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
+                        upper_iters.push(Box::new(SsTableIterator::create_for_compaction(
                             snapshot.sstables[sst_id].clone(),
+                            self.options.compaction_readahead_size,
                         )?));
                     }
 
@@ -311,10 +555,22 @@ impl LsmStorageInner {
                     for sst_id in lower_level_sst_ids.iter() {
                         lower_ssts.push(snapshot.sstables[sst_id].clone());
                     }
-                    let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
+                    let lower_iter = SstConcatIterator::create_for_compaction(
+                        lower_ssts,
+                        self.options.compaction_readahead_size,
+                    )?;
 
                     let iter = TwoMergeIterator::create(upper_iter, lower_iter)?;
-                    self.compact_generate_sst_from_iter(iter, _task.compact_to_bottom_level())
+                    let user_properties = self.merge_user_properties(
+                        &snapshot,
+                        upper_level_sst_ids.iter().chain(lower_level_sst_ids),
+                    );
+                    self.compact_generate_sst_from_iter(
+                        iter,
+                        _task.compact_to_bottom_level(),
+                        &user_properties,
+                        &mut reserved,
+                    )
                 }
             },
             CompactionTask::Tiered(TieredCompactionTask {
@@ -327,70 +583,50 @@ impl LsmStorageInner {
                     for sst_id in sst_ids {
                         ssts_to_concat.push(snapshot.sstables[sst_id].clone());
                     }
-                    iters.push(Box::new(SstConcatIterator::create_and_seek_to_first(
+                    iters.push(Box::new(SstConcatIterator::create_for_compaction(
                         ssts_to_concat,
+                        self.options.compaction_readahead_size,
                     )?));
                 }
                 let iter = MergeIterator::create(iters);
-                self.compact_generate_sst_from_iter(iter, *bottom_tier_included)
-            }
-            _ => {
-                unimplemented!()
+                let user_properties = self.merge_user_properties(
+                    &snapshot,
+                    tiers.iter().flat_map(|(_, sst_ids)| sst_ids),
+                );
+                self.compact_generate_sst_from_iter(
+                    iter,
+                    *bottom_tier_included,
+                    &user_properties,
+                    &mut reserved,
+                )
             }
         }
     }
 
+    /// Compacts L0 and all the levels into the bottom level, dropping deleted keys.
     pub fn force_full_compaction(&self) -> Result<()> {
-        let l0_sstables;
-        let l1_sstables;
-        {
+        let _compaction_lock = self.compaction_lock.lock();
+        let task = {
             let snapshot = self.state.read();
-            l0_sstables = snapshot.l0_sstables.clone();
-            l1_sstables = snapshot.levels[0].1.clone();
-        };
-        let compaction_task = &CompactionTask::ForceFullCompaction {
-            l0_sstables: l0_sstables.clone(),
-            l1_sstables: l1_sstables.clone(),
-        };
-
-        println!("force full compaction: {:?}", compaction_task);
-
-        let new_ssts = self.compact(compaction_task)?;
-        // grab the state lock and update it
-        {
-            let state_lock = self.state_lock.lock();
-            let mut guard = self.state.write();
-            let mut snapshot = guard.as_ref().clone();
-
-            for id in snapshot.l0_sstables.iter() {
-                snapshot.sstables.remove(id);
-            }
-            snapshot.l0_sstables.clear();
-
-            for id in snapshot.levels[0].1.iter() {
-                snapshot.sstables.remove(id);
-            }
-            snapshot.levels[0].1.clear();
-            for new_sst in new_ssts.iter() {
-                snapshot.levels[0].1.push(new_sst.sst_id());
-                snapshot.sstables.insert(new_sst.sst_id(), new_sst.clone());
+            CompactionTask::ForceFullCompaction {
+                l0_sstables: snapshot.l0_sstables.clone(),
+                l1_sstables: snapshot
+                    .levels
+                    .iter()
+                    .flat_map(|(_, sst_ids)| sst_ids.iter().copied())
+                    .collect(),
             }
-            *guard = Arc::new(snapshot);
-        }
-
-        // also remove all old files
-        for sst_id in l0_sstables.iter().chain(l1_sstables.iter()) {
-            std::fs::remove_file(self.path_of_sst(*sst_id))?;
-        }
-
-        Ok(())
+        };
+        println!("force full compaction: {:?}", task);
+        self.run_compaction(task)
     }
 
-    fn trigger_compaction(&self) -> Result<()> {
+    pub(crate) fn trigger_compaction(&self) -> Result<()> {
         // 1. trigger compaction with task
         // 2. call controller.apply_compaction_result to update interal states: l0_sstables, levels
         // 3. update snapshot sstables and related info
         // 4. remove all old files
+        let _compaction_lock = self.compaction_lock.lock();
         let snapshot = {
             let guard = self.state.read();
             guard.clone()
@@ -404,7 +640,12 @@ impl LsmStorageInner {
         let task = task.unwrap();
         self.dump_structure();
         println!("Running compaction task: {:?}", task);
+        self.run_compaction(task)
+    }
 
+    /// Runs `task`, installs and records its result, and removes its inputs. The caller holds
+    /// `compaction_lock`.
+    fn run_compaction(&self, task: CompactionTask) -> Result<()> {
         let new_ssts = self.compact(&task)?;
         let files_added = new_ssts.len();
 
@@ -414,6 +655,20 @@ impl LsmStorageInner {
             //  grab the state_lock since we will update snapshot interal state;
             let _state_lock = self.state_lock.lock();
 
+            // the controller may sort the output by key, so the state has it first
+            let mut snapshot = self.state.read().as_ref().clone();
+            let mut new_sst_ids = Vec::new();
+            let mut new_sst_metas = Vec::new();
+            for file_to_add in new_ssts.iter() {
+                new_sst_ids.push(file_to_add.sst_id());
+                new_sst_metas.push(SstMeta::new(file_to_add));
+                let result = snapshot
+                    .sstables
+                    .insert(file_to_add.sst_id(), file_to_add.clone());
+                // ensure this is the new key
+                assert!(result.is_none());
+            }
+
             let (mut new_snapshot, to_be_removed) = self
                 .compaction_controller
                 // WARN: we need to grab lock here!!!
@@ -431,7 +686,7 @@ impl LsmStorageInner {
                 // is incorrect!!!
                 // flushed 3.sst with size=1070533
                 // Also check the comments at SimpleLeveledCompactionController::apply_compaction_result(...)
-                .apply_compaction_result(&self.state.read(), &task, &output, false);
+                .apply_compaction_result(&snapshot, &task, &output, false);
             let mut ssts_to_remove = Vec::with_capacity(to_be_removed.len());
             for file_to_remove in to_be_removed.iter() {
                 let result = new_snapshot.sstables.remove(file_to_remove);
@@ -439,26 +694,19 @@ impl LsmStorageInner {
                 ssts_to_remove.push(result.unwrap());
             }
 
-            let mut new_sst_ids = Vec::new();
-            for file_to_add in new_ssts.iter() {
-                new_sst_ids.push(file_to_add.sst_id());
-                let result = new_snapshot
-                    .sstables
-                    .insert(file_to_add.sst_id(), file_to_add.clone());
-                // ensure this is the new key
-                assert!(result.is_none());
-            }
-
             let mut guard = self.state.write();
             *guard = Arc::new(new_snapshot);
             drop(guard);
 
             self.sync_dir()?;
             // record the compaction task & results into Manifest file.
-            self.manifest
-                .as_ref()
-                .unwrap()
-                .add_record_when_init(ManifestRecord::Compaction(task, new_sst_ids))?;
+            self.manifest.as_ref().unwrap().add_records_when_init(vec![
+                ManifestRecord::Compaction(task, new_sst_ids),
+                ManifestRecord::SstMetas(new_sst_metas),
+            ])?;
+            self.reserved_sst_ids.lock().clear();
+            compaction_step_done(3)?;
+            self.pending_delete_ssts.lock().extend(&to_be_removed);
 
             ssts_to_remove
         };
@@ -469,9 +717,28 @@ impl LsmStorageInner {
             files_added,
             output,
         );
+        // the inputs are needed until the compaction is durable
+        self.manifest.as_ref().unwrap().sync()?;
         for file_to_remove in ssts_to_remove.iter() {
+            // scans may still be reading it
+            file_to_remove.pin_file()?;
             std::fs::remove_file(self.path_of_sst(file_to_remove.sst_id()))?;
         }
+        self.sync_dir()?;
+        {
+            let state_lock = self.state_lock.lock();
+            let sst_ids = ssts_to_remove
+                .iter()
+                .map(|sst| sst.sst_id())
+                .collect::<Vec<_>>();
+            self.pending_delete_ssts
+                .lock()
+                .retain(|sst_id| !sst_ids.contains(sst_id));
+            self.manifest
+                .as_ref()
+                .unwrap()
+                .add_record(&state_lock, ManifestRecord::DeleteSsts(sst_ids))?;
+        }
 
         Ok(())
     }
@@ -502,16 +769,25 @@ impl LsmStorageInner {
     }
 
     fn trigger_flush(&self) -> Result<()> {
-        let total_memtables;
-        {
-            let guard = self.state.read();
-            total_memtables = guard.imm_memtables.len() + 1;
-        }
+        loop {
+            #[cfg(test)]
+            while self.pause_flush.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            // checked under the state lock, as `MiniLsm::flush` may flush the memtables meanwhile
+            let state_lock = self.state_lock.lock();
+            let total_memtables;
+            {
+                let guard = self.state.read();
+                total_memtables = guard.imm_memtables.len() + 1;
+            }
 
-        if total_memtables >= self.options.num_memtable_limit {
-            self.force_flush_next_imm_memtable()?;
+            if total_memtables < self.options.num_memtable_limit
+                || self.flush_next_imm_memtable(&state_lock)?.is_none()
+            {
+                return Ok(());
+            }
         }
-        Ok(())
     }
 
     pub(crate) fn spawn_flush_thread(
@@ -519,16 +795,33 @@ impl LsmStorageInner {
         rx: crossbeam_channel::Receiver<()>,
     ) -> Result<Option<std::thread::JoinHandle<()>>> {
         let this = self.clone();
+        self.has_flush_thread.store(true, Ordering::SeqCst);
         let handle = std::thread::spawn(move || {
             let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+            let sync_ticker = match this.options.wal_sync_policy {
+                WalSyncPolicy::EveryMillis(millis) if this.options.enable_wal => {
+                    crossbeam_channel::tick(Duration::from_millis(millis))
+                }
+                _ => crossbeam_channel::never(),
+            };
             loop {
                 crossbeam_channel::select! {
                     recv(ticker) -> _ => if let Err(e) = this.trigger_flush() {
                         eprintln!("flush failed: {}", e);
                     },
-                    recv(rx) -> _ => return
+                    recv(this.flush_requests) -> _ => if let Err(e) = this.trigger_flush() {
+                        eprintln!("flush failed: {}", e);
+                    },
+                    recv(sync_ticker) -> _ => if let Err(e) = this.sync() {
+                        eprintln!("wal sync failed: {}", e);
+                    },
+                    recv(rx) -> _ => break
                 }
             }
+            // the stalled writes go on without waiting for flushes
+            this.has_flush_thread.store(false, Ordering::SeqCst);
+            let _state_lock = this.state_lock.lock();
+            this.memtable_flushed.notify_all();
         });
         Ok(Some(handle))
     }
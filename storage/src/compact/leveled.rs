@@ -12,11 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashSet;
+
 use serde::{Deserialize, Serialize};
 
 use crate::lsm_storage::LsmStorageState;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LeveledCompactionTask {
     // if upper_level is `None`, then it is L0 compaction
     pub upper_level: Option<usize>,
@@ -26,7 +28,7 @@ pub struct LeveledCompactionTask {
     pub is_lower_level_bottom_level: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct LeveledCompactionOptions {
     pub level_size_multiplier: usize,
     pub level0_file_num_compaction_trigger: usize,
@@ -43,22 +45,141 @@ impl LeveledCompactionController {
         Self { options }
     }
 
+    /// The SSTs in `_in_level` whose key ranges overlap the range `_sst_ids` span, in the order of
+    /// the level.
     fn find_overlapping_ssts(
         &self,
         _snapshot: &LsmStorageState,
         _sst_ids: &[usize],
         _in_level: usize,
     ) -> Vec<usize> {
-        unimplemented!()
+        let begin = _sst_ids
+            .iter()
+            .map(|sst_id| _snapshot.sstables[sst_id].first_key().key_ref())
+            .min()
+            .unwrap();
+        let end = _sst_ids
+            .iter()
+            .map(|sst_id| _snapshot.sstables[sst_id].last_key().key_ref())
+            .max()
+            .unwrap();
+        _snapshot.levels[_in_level - 1]
+            .1
+            .iter()
+            .copied()
+            .filter(|sst_id| {
+                let sst = &_snapshot.sstables[sst_id];
+                sst.first_key().key_ref() <= end && sst.last_key().key_ref() >= begin
+            })
+            .collect()
+    }
+
+    /// The target size in bytes of each level, L1 first, and the base level, the one L0 is
+    /// compacted to. The bottom level is as large as it is, and each level above it a
+    /// `level_size_multiplier`th of the one below, until the one below is no larger than
+    /// `base_level_size_mb`; the levels above that are empty, 0, and skipped.
+    fn target_level_sizes(&self, real_level_sizes: &[u64]) -> (Vec<u64>, usize) {
+        let max_levels = self.options.max_levels;
+        let base_level_size = self.options.base_level_size_mb as u64 * 1024 * 1024;
+        let mut target_level_sizes = vec![0; max_levels];
+        let mut base_level = max_levels;
+        target_level_sizes[max_levels - 1] = real_level_sizes[max_levels - 1].max(base_level_size);
+        for level in (0..max_levels - 1).rev() {
+            let lower_level_size = target_level_sizes[level + 1];
+            if lower_level_size > base_level_size {
+                target_level_sizes[level] =
+                    lower_level_size / self.options.level_size_multiplier as u64;
+            }
+            if target_level_sizes[level] > 0 {
+                base_level = level + 1;
+            }
+        }
+        (target_level_sizes, base_level)
     }
 
+    /// Generates a compaction task: L0 to the base level once it has
+    /// `level0_file_num_compaction_trigger` SSTs, or else the oldest SST of the level furthest
+    /// over its target size to the level below, with the SSTs there it overlaps.
+    ///
+    /// Returns `None` if no compaction needs to be scheduled.
     pub fn generate_compaction_task(
         &self,
         _snapshot: &LsmStorageState,
     ) -> Option<LeveledCompactionTask> {
-        unimplemented!()
+        let max_levels = self.options.max_levels;
+        let real_level_sizes = _snapshot
+            .levels
+            .iter()
+            .map(|(_, sst_ids)| {
+                sst_ids
+                    .iter()
+                    .map(|sst_id| _snapshot.sstables[sst_id].table_size())
+                    .sum::<u64>()
+            })
+            .collect::<Vec<_>>();
+        let (target_level_sizes, base_level) = self.target_level_sizes(&real_level_sizes);
+
+        // flushing L0 comes first
+        if _snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger {
+            println!(
+                "compaction triggered at level 0 because L0 has {} SSTs >= {}, to base level {}",
+                _snapshot.l0_sstables.len(),
+                self.options.level0_file_num_compaction_trigger,
+                base_level
+            );
+            return Some(LeveledCompactionTask {
+                upper_level: None,
+                upper_level_sst_ids: _snapshot.l0_sstables.clone(),
+                lower_level: base_level,
+                lower_level_sst_ids: self.find_overlapping_ssts(
+                    _snapshot,
+                    &_snapshot.l0_sstables,
+                    base_level,
+                ),
+                is_lower_level_bottom_level: base_level == max_levels,
+            });
+        }
+
+        // the bottom level is never over its target
+        let mut priorities = (0..max_levels - 1)
+            .map(|level| {
+                let ratio = real_level_sizes[level] as f64 / target_level_sizes[level] as f64;
+                (ratio, level + 1)
+            })
+            .filter(|(ratio, _)| *ratio > 1.0)
+            .collect::<Vec<_>>();
+        priorities.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let &(ratio, upper_level) = priorities.first()?;
+        println!(
+            "compaction triggered at level {} with size ratio {:.3} to its target",
+            upper_level, ratio
+        );
+        // the oldest SST, which has the smallest id
+        let selected_sst = _snapshot.levels[upper_level - 1]
+            .1
+            .iter()
+            .min()
+            .copied()
+            .unwrap();
+        let lower_level = upper_level + 1;
+        Some(LeveledCompactionTask {
+            upper_level: Some(upper_level),
+            upper_level_sst_ids: vec![selected_sst],
+            lower_level,
+            lower_level_sst_ids: self.find_overlapping_ssts(
+                _snapshot,
+                &[selected_sst],
+                lower_level,
+            ),
+            is_lower_level_bottom_level: lower_level == max_levels,
+        })
     }
 
+    /// Apply the compaction result.
+    ///
+    /// Removes the inputs from their levels, and adds the output to the lower level, which stays
+    /// sorted by first key. `_snapshot.sstables` must have the output, unless `_in_recovery`, when
+    /// no SST is opened and the levels are sorted once they are.
     pub fn apply_compaction_result(
         &self,
         _snapshot: &LsmStorageState,
@@ -66,6 +187,68 @@ impl LeveledCompactionController {
         _output: &[usize],
         _in_recovery: bool,
     ) -> (LsmStorageState, Vec<usize>) {
-        unimplemented!()
+        let mut snapshot = _snapshot.clone();
+        let mut to_be_removed = Vec::new();
+
+        // SSTs may be flushed to L0 while the task runs, so only the compacted ones are removed
+        let mut upper_level_sst_ids = _task
+            .upper_level_sst_ids
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        let upper_level_ssts = match _task.upper_level {
+            Some(upper_level) => &mut snapshot.levels[upper_level - 1].1,
+            None => &mut snapshot.l0_sstables,
+        };
+        upper_level_ssts.retain(|sst_id| !upper_level_sst_ids.remove(sst_id));
+        assert!(
+            upper_level_sst_ids.is_empty(),
+            "compacted SSTs {:?} aren't in the upper level",
+            upper_level_sst_ids
+        );
+        to_be_removed.extend(&_task.upper_level_sst_ids);
+
+        let mut lower_level_sst_ids = _task
+            .lower_level_sst_ids
+            .iter()
+            .copied()
+            .collect::<HashSet<_>>();
+        let mut lower_level_ssts = snapshot.levels[_task.lower_level - 1].1.clone();
+        lower_level_ssts.retain(|sst_id| !lower_level_sst_ids.remove(sst_id));
+        assert!(
+            lower_level_sst_ids.is_empty(),
+            "compacted SSTs {:?} aren't in the lower level",
+            lower_level_sst_ids
+        );
+        to_be_removed.extend(&_task.lower_level_sst_ids);
+
+        lower_level_ssts.extend(_output);
+        if !_in_recovery {
+            lower_level_ssts.sort_by(|a, b| {
+                snapshot.sstables[a]
+                    .first_key()
+                    .cmp(snapshot.sstables[b].first_key())
+            });
+            Self::check_sorted(&snapshot, _task.lower_level, &lower_level_ssts);
+        }
+        snapshot.levels[_task.lower_level - 1].1 = lower_level_ssts;
+
+        (snapshot, to_be_removed)
+    }
+
+    /// Checks that the SSTs of a level are sorted by key and don't overlap.
+    fn check_sorted(snapshot: &LsmStorageState, level: usize, sst_ids: &[usize]) {
+        for pair in sst_ids.windows(2) {
+            let (this_sst, next_sst) = (&snapshot.sstables[&pair[0]], &snapshot.sstables[&pair[1]]);
+            assert!(
+                this_sst.last_key().key_ref() < next_sst.first_key().key_ref(),
+                "SST {} ends at {:?}, after SST {} after it in level {} starts at {:?}",
+                pair[0],
+                this_sst.last_key().key_ref(),
+                pair[1],
+                level,
+                next_sst.first_key().key_ref()
+            );
+        }
     }
 }
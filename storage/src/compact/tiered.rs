@@ -18,17 +18,19 @@ use serde::{Deserialize, Serialize};
 
 use crate::lsm_storage::LsmStorageState;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TieredCompactionTask {
     pub tiers: Vec<(usize, Vec<usize>)>,
     pub bottom_tier_included: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TieredCompactionOptions {
     pub num_tiers: usize,
     pub max_size_amplification_percent: usize,
+    /// A tier is merged with the tiers above it once they're larger than it by this percent.
     pub size_ratio: usize,
+    /// The fewest tiers merged by the size ratio, counting the tier that triggered it.
     pub min_merge_width: usize,
     pub max_merge_width: Option<usize>,
 }
@@ -55,13 +57,21 @@ impl TieredCompactionController {
             return None;
         }
 
-        let mut size = 0;
-        for id in 0..(_snapshot.levels.len() - 1) {
-            size += _snapshot.levels[id].1.len();
-        }
+        // the sizes of the tiers in bytes, as their SSTs may be of any size
+        let tier_sizes = _snapshot
+            .levels
+            .iter()
+            .map(|(_, sst_ids)| {
+                sst_ids
+                    .iter()
+                    .map(|sst_id| _snapshot.sstables[sst_id].table_size())
+                    .sum::<u64>()
+            })
+            .collect::<Vec<_>>();
+        let (bottom_size, upper_sizes) = tier_sizes.split_last().unwrap();
+        let size = upper_sizes.iter().sum::<u64>();
         // case 1: Triggered by Space Amplification Ratio
-        let space_amp_ratio =
-            size as f64 / (_snapshot.levels.last().unwrap().1.len()) as f64 * 100.0;
+        let space_amp_ratio = size as f64 / *bottom_size as f64 * 100.0;
         if space_amp_ratio >= self.options.max_size_amplification_percent as f64 {
             println!(
                 "compaction triggered by space amplification ratio: {}",
@@ -74,41 +84,47 @@ impl TieredCompactionController {
         }
 
         // case 2: Triggered by Size Ratio
+        // tier i is merged together with all the tiers above it, as RocksDB's universal
+        // compaction includes it, once they're larger than it by size_ratio and that's at least
+        // min_merge_width tiers (i + 1 of them). Otherwise, it counts toward the tiers above the
+        // next one.
         let size_ratio_trigger = (100.0 + self.options.size_ratio as f64) / 100.0;
-        let mut prev_size = _snapshot.levels[0].1.len() as f64;
-        for i in 1.._snapshot.levels.len() {
-            let (tier_id, files) = &_snapshot.levels[i];
-            let current_size = files.len() as f64;
-            let size_ratio = current_size / prev_size;
-            if size_ratio > size_ratio_trigger && i >= self.options.min_merge_width {
+        let mut prev_size = tier_sizes[0] as f64;
+        for (i, &current_size) in tier_sizes.iter().enumerate().skip(1) {
+            let current_size = current_size as f64;
+            let size_ratio = prev_size / current_size;
+            if size_ratio > size_ratio_trigger && i + 1 >= self.options.min_merge_width {
                 println!(
                     "compaction triggered by size ratio: {} > {}",
                     size_ratio, size_ratio_trigger
                 );
                 return Some(TieredCompactionTask {
-                    tiers: (&_snapshot.levels[0..i]).to_vec(),
-                    // NOTE: for tiered, we always looking for previous levels, as i will be
-                    // end as levels.len() - 1, so we would never include bottom tier.
-                    bottom_tier_included: false,
+                    tiers: _snapshot.levels[0..=i].to_vec(),
+                    bottom_tier_included: i + 1 == _snapshot.levels.len(),
                 });
             }
             prev_size += current_size;
         }
 
         // case 3: reduce sorted run
-        // we will do a major compaction that merges SST files from the first up
-        // to max_merge_tiers tiers into one tier to reduce the number of tiers.
+        // only when there are more tiers than num_tiers, we will do a major compaction that merges
+        // the first tiers into one, just enough of them to be below num_tiers again, up to
+        // max_merge_width tiers.
+        if _snapshot.levels.len() <= self.options.num_tiers {
+            return None;
+        }
         let max_merge_iters = self
             .options
             .max_merge_width
             .unwrap_or(usize::MAX)
+            .min(_snapshot.levels.len() - self.options.num_tiers + 2)
             .min(_snapshot.levels.len());
         println!(
             "compaction triggered by max merge width: {}",
             max_merge_iters
         );
         Some(TieredCompactionTask {
-            tiers: (&_snapshot.levels[0..max_merge_iters]).to_vec(),
+            tiers: _snapshot.levels[0..max_merge_iters].to_vec(),
             bottom_tier_included: max_merge_iters >= _snapshot.levels.len(),
         })
     }
@@ -18,8 +18,8 @@ use anyhow::Result;
 
 use super::StorageIterator;
 use crate::{
-    key::KeySlice,
-    table::{SsTable, SsTableIterator},
+    key::{KeySlice, TS_RANGE_BEGIN},
+    table::{ChecksumVerification, SsTable, SsTableIterator},
 };
 
 /// Concat multiple iterators ordered in key order and their key ranges do not overlap. We do not want to create the
@@ -28,6 +28,11 @@ pub struct SstConcatIterator {
     current: Option<SsTableIterator>,
     next_sst_idx: usize,
     sstables: Vec<Arc<SsTable>>,
+    /// Whether blocks read from the disk are added to the block cache
+    fill_cache: bool,
+    verify_checksums: ChecksumVerification,
+    /// Bytes read at a time by compaction, see `SsTableIterator::with_readahead`
+    readahead_size: usize,
 }
 
 impl SstConcatIterator {
@@ -45,20 +50,35 @@ impl SstConcatIterator {
     }
 
     pub fn create_and_seek_to_first(sstables: Vec<Arc<SsTable>>) -> Result<Self> {
+        Self::create_and_seek_to_first_with_checksums(sstables, ChecksumVerification::default())
+    }
+
+    /// Same as `create_and_seek_to_first`, verifying block checksums as `verify_checksums` says.
+    pub fn create_and_seek_to_first_with_checksums(
+        sstables: Vec<Arc<SsTable>>,
+        verify_checksums: ChecksumVerification,
+    ) -> Result<Self> {
         Self::check_sst_valid(&sstables);
         if sstables.is_empty() {
             Ok(Self {
                 current: None,
                 next_sst_idx: 0,
                 sstables: sstables,
+                fill_cache: true,
+                verify_checksums,
+                readahead_size: 0,
             })
         } else {
+            let mut first =
+                SsTableIterator::new(sstables[0].clone(), true, TS_RANGE_BEGIN, verify_checksums);
+            first.seek_to_first()?;
             let mut iter = Self {
-                current: Some(SsTableIterator::create_and_seek_to_first(
-                    sstables[0].clone(),
-                )?),
+                current: Some(first),
                 next_sst_idx: 1,
                 sstables: sstables,
+                fill_cache: true,
+                verify_checksums,
+                readahead_size: 0,
             };
 
             iter.move_until_valid()?;
@@ -66,13 +86,51 @@ impl SstConcatIterator {
         }
     }
 
+    /// Same as `create_and_seek_to_first`, but doesn't add the blocks it reads to the block cache.
+    /// See `SsTableIterator::create_for_compaction` for `readahead_size`.
+    pub fn create_for_compaction(
+        sstables: Vec<Arc<SsTable>>,
+        readahead_size: usize,
+    ) -> Result<Self> {
+        Self::check_sst_valid(&sstables);
+        let mut iter = Self {
+            current: None,
+            next_sst_idx: 0,
+            sstables,
+            fill_cache: false,
+            verify_checksums: ChecksumVerification::default(),
+            readahead_size,
+        };
+        if let Some(table) = iter.sstables.first() {
+            iter.current = Some(SsTableIterator::create_for_compaction(
+                table.clone(),
+                readahead_size,
+            )?);
+            iter.next_sst_idx = 1;
+            iter.move_until_valid()?;
+        }
+        Ok(iter)
+    }
+
     pub fn create_and_seek_to_key(sstables: Vec<Arc<SsTable>>, key: KeySlice) -> Result<Self> {
+        Self::create_and_seek_to_key_with_checksums(sstables, key, ChecksumVerification::default())
+    }
+
+    /// Same as `create_and_seek_to_key`, verifying block checksums as `verify_checksums` says.
+    pub fn create_and_seek_to_key_with_checksums(
+        sstables: Vec<Arc<SsTable>>,
+        key: KeySlice,
+        verify_checksums: ChecksumVerification,
+    ) -> Result<Self> {
         Self::check_sst_valid(&sstables);
         if sstables.is_empty() {
             Ok(Self {
                 current: None,
                 next_sst_idx: 0,
                 sstables: sstables,
+                fill_cache: true,
+                verify_checksums,
+                readahead_size: 0,
             })
         } else {
             let idx = sstables
@@ -83,15 +141,23 @@ impl SstConcatIterator {
                     current: None,
                     next_sst_idx: 0,
                     sstables: sstables,
+                    fill_cache: true,
+                    verify_checksums,
+                    readahead_size: 0,
                 });
             }
             let mut iter = Self {
-                current: Some(SsTableIterator::create_and_seek_to_key(
+                current: Some(SsTableIterator::create_and_seek_to_key_with_ts(
                     sstables[idx].clone(),
                     key,
+                    TS_RANGE_BEGIN,
+                    verify_checksums,
                 )?),
                 next_sst_idx: idx + 1,
                 sstables: sstables,
+                fill_cache: true,
+                verify_checksums,
+                readahead_size: 0,
             };
             iter.move_until_valid()?;
             Ok(iter)
@@ -107,9 +173,20 @@ impl SstConcatIterator {
                 if self.next_sst_idx >= self.sstables.len() {
                     self.current = None;
                 } else {
-                    self.current = Some(SsTableIterator::create_and_seek_to_first(
-                        self.sstables[self.next_sst_idx].clone(),
-                    )?);
+                    let table = self.sstables[self.next_sst_idx].clone();
+                    let iter = if self.fill_cache {
+                        let mut iter = SsTableIterator::new(
+                            table,
+                            true,
+                            TS_RANGE_BEGIN,
+                            self.verify_checksums,
+                        );
+                        iter.seek_to_first()?;
+                        iter
+                    } else {
+                        SsTableIterator::create_for_compaction(table, self.readahead_size)?
+                    };
+                    self.current = Some(iter);
                     self.next_sst_idx += 1;
                 }
             } else {
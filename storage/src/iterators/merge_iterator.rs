@@ -153,6 +153,10 @@ impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIt
     }
 
     fn num_active_iterators(&self) -> usize {
-        self.iters.len() + 1
+        self.iters
+            .iter()
+            .chain(&self.current)
+            .map(|iter| iter.1.num_active_iterators())
+            .sum()
     }
 }
@@ -23,6 +23,7 @@ pub mod manifest;
 pub mod mem_table;
 pub mod mvcc;
 pub mod table;
+pub mod value_log;
 pub mod wal;
 
 #[cfg(test)]
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::ops::Bound;
+use std::sync::Arc;
 
 use anyhow::{Result, bail};
 use bytes::Bytes;
@@ -24,6 +25,7 @@ use crate::{
     },
     mem_table::MemTableIterator,
     table::SsTableIterator,
+    value_log::ValueLog,
 };
 
 /// Represents the internal type for an LSM iterator. This type will be changed across the course for multiple times.
@@ -38,6 +40,9 @@ pub struct LsmIterator {
     is_valid: bool,
     prev_key: Vec<u8>,
     read_ts: u64,
+    value_log: Arc<ValueLog>,
+    /// The current value read from the value log, `None` if it's stored in place.
+    value: Option<Bytes>,
 }
 
 impl LsmIterator {
@@ -45,6 +50,7 @@ impl LsmIterator {
         iter: LsmIteratorInner,
         end_bound: Bound<Bytes>,
         read_ts: u64,
+        value_log: Arc<ValueLog>,
     ) -> Result<Self> {
         let mut iter = Self {
             is_valid: iter.is_valid(),
@@ -52,7 +58,11 @@ impl LsmIterator {
             end_bound: end_bound,
             prev_key: Vec::new(),
             read_ts: read_ts,
+            value_log,
+            value: None,
         };
+        // the first key may already be past the end bound
+        iter.check_end_bound();
         // skip DELETED values
         // for the case, we had deletions at the beginning
         // and didn't even trigger the next() to call move_to_non_delete.
@@ -60,20 +70,34 @@ impl LsmIterator {
         // iter.move_to_non_delete()?;
 
         iter.move_to_non_delete_and_skip_same_key()?;
+        iter.resolve_value()?;
         Ok(iter)
     }
 
-    fn next_inner(&mut self) -> Result<()> {
-        self.inner.next()?;
+    fn resolve_value(&mut self) -> Result<()> {
+        self.value = if self.is_valid {
+            self.value_log.resolve(self.inner.value())?
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    fn check_end_bound(&mut self) {
         if !self.inner.is_valid() {
             self.is_valid = false;
-            return Ok(());
+            return;
         }
         match &self.end_bound {
             Bound::Included(key) => self.is_valid = self.inner.key().key_ref() <= key.as_ref(),
             Bound::Excluded(key) => self.is_valid = self.inner.key().key_ref() < key.as_ref(),
             Bound::Unbounded => {}
         }
+    }
+
+    fn next_inner(&mut self) -> Result<()> {
+        self.inner.next()?;
+        self.check_end_bound();
         Ok(())
     }
 
@@ -152,12 +176,16 @@ impl StorageIterator for LsmIterator {
     }
 
     fn value(&self) -> &[u8] {
-        self.inner.value()
+        match &self.value {
+            Some(value) => value,
+            None => self.inner.value(),
+        }
     }
 
     fn next(&mut self) -> Result<()> {
         self.next_inner()?;
         self.move_to_non_delete_and_skip_same_key()?;
+        self.resolve_value()?;
         Ok(())
     }
 
@@ -12,35 +12,46 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs::File;
+use std::io::{Read, Write};
 use std::ops::Bound;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use bytes::Bytes;
-use parking_lot::{Mutex, MutexGuard, RwLock};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
 
-use crate::block::Block;
 use crate::compact::{
-    CompactionController, CompactionOptions, LeveledCompactionController, LeveledCompactionOptions,
-    SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, TieredCompactionController,
+    CompactionController, CompactionOptions, LeveledCompactionOptions,
+    SimpleLeveledCompactionOptions,
 };
 use crate::iterators::StorageIterator;
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
-use crate::key::{KeySlice, TS_RANGE_BEGIN, TS_RANGE_END};
+use crate::key::{KeyBytes, KeySlice, TS_RANGE_BEGIN, TS_RANGE_END};
 use crate::lsm_iterator::{FusedIterator, LsmIterator};
-use crate::manifest::{Manifest, ManifestRecord};
-use crate::mem_table::{MemTable, map_bound, map_key_bound_plus_ts};
+use crate::manifest::{
+    Manifest, ManifestFormat, ManifestRecord, ManifestSnapshot, ManifestSyncMode, PersistedOptions,
+    SstMeta,
+};
+use crate::mem_table::{
+    MAX_KEY_VALUE_LEN, MEMTABLE_ENTRY_OVERHEAD, MemTable, map_bound, map_key_bound_plus_ts,
+};
 use crate::mvcc::LsmMvccInner;
 use crate::mvcc::txn::{Transaction, TxnIterator};
-use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::table::{
+    ChecksumVerification, CompressionOptions, EncryptionProvider, FileCache, FileObject,
+    PrefixExtractor, SsTable, SsTableBuilder, SsTableIterator, UserPropertiesMerger,
+};
+use crate::value_log::{ValueLog, ValuePointer};
+use crate::wal::{Wal, WalMetrics, WalMetricsSnapshot, WalSyncPolicy};
 
-pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
+pub use crate::block::{BlockCache, BlockCacheStats};
 
 /// Represents the state of the storage engine.
 #[derive(Clone)]
@@ -65,7 +76,17 @@ pub enum WriteBatchRecord<T: AsRef<[u8]>> {
 
 impl LsmStorageState {
     fn create(options: &LsmStorageOptions) -> Self {
-        let levels = match &options.compaction_options {
+        Self {
+            memtable: setup_memtable(MemTable::create(0), options),
+            imm_memtables: Vec::new(),
+            l0_sstables: Vec::new(),
+            levels: Self::empty_levels(&options.compaction_options),
+            sstables: Default::default(),
+        }
+    }
+
+    fn empty_levels(compaction_options: &CompactionOptions) -> Vec<(usize, Vec<usize>)> {
+        match compaction_options {
             CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
             | CompactionOptions::Simple(SimpleLeveledCompactionOptions { max_levels, .. }) => (1
                 ..=*max_levels)
@@ -73,28 +94,311 @@ impl LsmStorageState {
                 .collect::<Vec<_>>(),
             CompactionOptions::Tiered(_) => Vec::new(),
             CompactionOptions::NoCompaction => vec![(1, Vec::new())],
-        };
-        Self {
+        }
+    }
+
+    /// Applies `ManifestRecord::Ingest(level, sst_ids)`. With level 0 each SST goes to L0 like a
+    /// flush, or to a tier of its own. Otherwise `sst_ids` becomes the level with this id, the
+    /// ingested SSTs included, or a new tier with this id.
+    pub(crate) fn apply_ingest(&mut self, level: usize, sst_ids: &[usize], flush_to_l0: bool) {
+        if level == 0 {
+            for sst_id in sst_ids {
+                if flush_to_l0 {
+                    self.l0_sstables.insert(0, *sst_id);
+                } else {
+                    self.levels.insert(0, (*sst_id, vec![*sst_id]));
+                }
+            }
+        } else if flush_to_l0 {
+            let (_, level_sst_ids) = self
+                .levels
+                .iter_mut()
+                .find(|(level_id, _)| *level_id == level)
+                .expect("level not found");
+            *level_sst_ids = sst_ids.to_vec();
+        } else {
+            self.levels.insert(0, (level, sst_ids.to_vec()));
+        }
+    }
+}
+
+/// What replaying the records of a manifest recovers: the levels of the state, and the memtables
+/// and SSTs the records still refer to.
+pub struct ManifestReplay {
+    /// L0 and the levels, or tiers, of the recovered state; no SST is opened.
+    pub state: LsmStorageState,
+    /// The memtables not flushed yet, in the order they were created.
+    pub memtables: BTreeSet<usize>,
+    /// The memtables flushed, whose WALs can go.
+    pub flushed: HashSet<usize>,
+    /// The inputs of the compactions whose files may not have been unlinked.
+    pub pending_delete: BTreeSet<usize>,
+    /// The options last recorded, `None` for a manifest written before they were.
+    pub options: Option<PersistedOptions>,
+    /// The largest memtable or SST id in the records.
+    pub max_id: usize,
+    /// The key ranges and sizes recorded of the SSTs in the state, see `ManifestRecord::SstMetas`.
+    pub sst_metas: HashMap<usize, SstMeta>,
+    /// The watermark last recorded, see `ManifestRecord::Watermark`.
+    pub watermark: Option<u64>,
+    /// The output ids reserved by a compaction whose result isn't recorded, see
+    /// `ManifestRecord::CompactionIntent`.
+    pub reserved: BTreeSet<usize>,
+}
+
+impl ManifestReplay {
+    /// Replays `records` the way recovery does, with the levels laid out for
+    /// `compaction_options`.
+    pub fn replay(
+        compaction_options: &CompactionOptions,
+        records: impl IntoIterator<Item = ManifestRecord>,
+    ) -> Self {
+        Self::try_replay(compaction_options, records.into_iter().map(Ok))
+            .expect("replaying records that were read")
+    }
+
+    /// Like `replay`, for records read as they're replayed, stopping at the first that fails to.
+    pub fn try_replay(
+        compaction_options: &CompactionOptions,
+        records: impl IntoIterator<Item = Result<ManifestRecord>>,
+    ) -> Result<Self> {
+        let compaction_controller = CompactionController::new(compaction_options);
+        let mut state = LsmStorageState {
             memtable: Arc::new(MemTable::create(0)),
             imm_memtables: Vec::new(),
             l0_sstables: Vec::new(),
-            levels,
+            levels: LsmStorageState::empty_levels(compaction_options),
             sstables: Default::default(),
+        };
+        // this memtables means memtable and imm_memtables;
+        let mut memtables = BTreeSet::new();
+        let mut flushed = HashSet::new();
+        let mut pending_delete = BTreeSet::new();
+        let mut options = None;
+        let mut max_id = 0;
+        let mut sst_metas = HashMap::new();
+        let mut watermark = None;
+        let mut reserved = BTreeSet::new();
+        for record in records {
+            match record? {
+                // before match
+                ManifestRecord::Flush(sst_id) => {
+                    // this sst_id has been flushed to SST and no longer be part of memtables
+                    assert!(memtables.remove(&sst_id));
+                    flushed.insert(sst_id);
+                    // this Flush means from imm_memtables to l0_sstables
+                    if compaction_controller.flush_to_l0() {
+                        state.l0_sstables.insert(0, sst_id);
+                    } else {
+                        state.levels.insert(0, (sst_id, vec![sst_id]));
+                    }
+                    max_id = max_id.max(sst_id);
+                }
+                ManifestRecord::FlushMerged(memtable_ids, sst_id) => {
+                    for memtable_id in memtable_ids {
+                        assert!(memtables.remove(&memtable_id));
+                        flushed.insert(memtable_id);
+                    }
+                    if compaction_controller.flush_to_l0() {
+                        state.l0_sstables.insert(0, sst_id);
+                    } else {
+                        state.levels.insert(0, (sst_id, vec![sst_id]));
+                    }
+                    max_id = max_id.max(sst_id);
+                }
+                ManifestRecord::Compaction(task, output) => {
+                    // this call would modify l0_sstables and levels accordingly
+                    let (new_state, removed) =
+                        compaction_controller.apply_compaction_result(&state, &task, &output, true);
+                    state = new_state;
+                    pending_delete.extend(removed);
+                    // compactions run one at a time, so this is the result of every intent
+                    reserved.clear();
+                    max_id = max_id.max(output.iter().max().copied().unwrap_or_default());
+                }
+                ManifestRecord::Ingest(level, sst_ids) => {
+                    state.apply_ingest(level, &sst_ids, compaction_controller.flush_to_l0());
+                    max_id = max_id.max(sst_ids.iter().max().copied().unwrap_or_default());
+                }
+                ManifestRecord::NewMemtable(memtable_id) => {
+                    max_id = max_id.max(memtable_id);
+                    // record all memtables
+                    memtables.insert(memtable_id);
+                }
+                ManifestRecord::Snapshot(snapshot) => {
+                    memtables = snapshot.memtables.into_iter().collect();
+                    flushed = snapshot.flushed.into_iter().collect();
+                    state.l0_sstables = snapshot.l0_sstables;
+                    state.levels = snapshot.levels;
+                    max_id = max_id.max(snapshot.max_id);
+                    pending_delete = snapshot.pending_delete.into_iter().collect();
+                    options = snapshot.options;
+                    sst_metas = snapshot
+                        .sst_metas
+                        .into_iter()
+                        .map(|sst_meta| (sst_meta.sst_id, sst_meta))
+                        .collect();
+                    watermark = snapshot.watermark;
+                    reserved = snapshot.reserved.into_iter().collect();
+                }
+                ManifestRecord::DeleteSsts(sst_ids) => {
+                    for sst_id in sst_ids {
+                        pending_delete.remove(&sst_id);
+                    }
+                }
+                ManifestRecord::Options(recorded) => {
+                    options = Some(recorded);
+                }
+                ManifestRecord::SstMetas(recorded) => {
+                    for sst_meta in recorded {
+                        sst_metas.insert(sst_meta.sst_id, sst_meta);
+                    }
+                }
+                ManifestRecord::Watermark(ts) => {
+                    watermark = watermark.max(Some(ts));
+                }
+                ManifestRecord::CompactionIntent(_, output) => {
+                    max_id = max_id.max(output.iter().max().copied().unwrap_or_default());
+                    reserved.extend(output);
+                }
+            }
         }
+        let mut replay = Self {
+            state,
+            memtables,
+            flushed,
+            pending_delete,
+            options,
+            max_id,
+            sst_metas: HashMap::new(),
+            watermark,
+            reserved,
+        };
+        // those of the SSTs compacted away are left behind
+        replay.sst_metas = replay
+            .sst_ids()
+            .filter_map(|sst_id| sst_metas.remove(&sst_id))
+            .map(|sst_meta| (sst_meta.sst_id, sst_meta))
+            .collect();
+        Ok(replay)
+    }
+
+    /// The ids of the SSTs in L0 and the levels.
+    pub fn sst_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.state
+            .l0_sstables
+            .iter()
+            .chain(self.state.levels.iter().flat_map(|(_, files)| files))
+            .copied()
     }
 }
 
+/// 64MB of blocks
+pub const DEFAULT_BLOCK_CACHE_CAPACITY: u64 = 64 << 20;
+/// 2MB read at a time by compaction
+pub const DEFAULT_COMPACTION_READAHEAD_SIZE: usize = 2 << 20;
+/// Roughly 10 bits per key.
+pub const DEFAULT_BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+/// 4MB of manifest records
+pub const DEFAULT_MAX_MANIFEST_SIZE: u64 = 4 << 20;
+
 #[derive(Debug, Clone)]
 pub struct LsmStorageOptions {
     // Block size in bytes
     pub block_size: usize,
-    // SST size in bytes, also the approximate memtable capacity limit
+    // SST size in bytes, also the approximate memory a memtable takes before it is frozen
     pub target_sst_size: usize,
-    // Maximum number of memtables in memory, flush to L0 when exceeding this limit
+    // Maximum number of memtables in memory, flush to L0 when exceeding this limit. Writes stall
+    // while this many immutable memtables wait for the flush thread
     pub num_memtable_limit: usize,
+    // The number of skiplists the entries of a memtable are partitioned into by the hash of their
+    // key, so that concurrent writers contend less. Scans and flushes merge them
+    pub memtable_shards: usize,
+    // How long a write stalls before failing with `WriteStall`, `None` waits until a memtable is
+    // flushed
+    pub write_stall_timeout: Option<Duration>,
+    // Flush all the immutable memtables together into one SST, instead of one SST for each
+    pub flush_merge_imm: bool,
     pub compaction_options: CompactionOptions,
     pub enable_wal: bool,
+    // How the batches appended to the WAL are compressed, recovery reads them whichever it was
+    pub wal_compression: CompressionOptions,
+    // When the WAL is synced, which bounds the writes that can be lost on a crash
+    pub wal_sync_policy: WalSyncPolicy,
+    // Bytes allocated for each WAL file when it's created, so that syncing the writes within them
+    // doesn't sync the file's metadata as well. 0 grows the files as they're written
+    pub wal_preallocate_size: u64,
+    // Reuse the WAL files of the flushed memtables for new memtables, instead of deleting them
+    // and creating new ones
+    pub recycle_wal: bool,
     pub serializable: bool,
+    // Reject a single key-value pair larger than this many bytes when building SSTs
+    pub max_entry_size: Option<usize>,
+    // Reject a write with a key larger than this many bytes with `WriteError::KeyTooLarge`, at
+    // most `MAX_KEY_VALUE_LEN`
+    pub max_key_size: usize,
+    // Reject a write with a value larger than this many bytes with `WriteError::ValueTooLarge`.
+    // Only the values written to the value log may be larger than `MAX_KEY_VALUE_LEN`
+    pub max_value_size: usize,
+    // Write values larger than this many bytes to the value log, keeping only a pointer to them in
+    // the memtables and SSTs. `None` stores every value in place
+    pub large_value_threshold: Option<usize>,
+    // How data blocks are compressed when building SSTs
+    pub compression: CompressionOptions,
+    // Size in bytes of the blocks kept in the block cache
+    pub block_cache_capacity: u64,
+    // Target false positive rate of the bloom filter of each SST, `None` builds SSTs without one
+    pub bloom_false_positive_rate: Option<f64>,
+    // Build a bloom filter for each data block instead of one for the whole SST, so that cold
+    // filters can be evicted from the block cache
+    pub bloom_per_block: bool,
+    // Read SSTs through memory mappings instead of a read syscall per block
+    pub mmap: bool,
+    // Keep at most this many SST files open, closing the least recently read ones and reopening
+    // them on demand. `None` keeps every SST open
+    pub max_open_files: Option<usize>,
+    // Load the first data block of each SST opened on recovery, ingested or built by compaction
+    // into the block cache
+    pub prewarm_on_open: bool,
+    // Also build a bloom filter over the key prefixes it extracts, used by scans within one prefix
+    pub prefix_extractor: Option<PrefixExtractor>,
+    // Panic instead of returning an error when flush or compaction adds keys out of order, and
+    // check that recovery rebuilds the memtables in the order they were created
+    pub paranoid_checks: bool,
+    // When reads verify the checksums of data blocks, can be overridden by `ReadOptions`
+    pub verify_checksums: ChecksumVerification,
+    // Write the SSTs built by flush and compaction with O_DIRECT, so that they don't push hot data
+    // out of the page cache
+    pub use_direct_io_for_flush_and_compaction: bool,
+    // Bytes compaction reads from an SST at a time, 0 reads one block at a time
+    pub compaction_readahead_size: usize,
+    // Move the SSTs that the manifest doesn't refer to into `trash/` on recovery, instead of
+    // deleting them
+    pub trash_orphan_ssts: bool,
+    // Roll the manifest over to a snapshot of the state once its records grow past this many bytes,
+    // checked whenever a memtable is frozen and on recovery
+    pub max_manifest_size: u64,
+    // How the records of a new manifest are encoded. An existing manifest keeps its format
+    pub manifest_format: ManifestFormat,
+    // Whether each manifest record is synced as it's added, or buffered until the next flush,
+    // compaction or ingest, or `sync`
+    pub manifest_sync_mode: ManifestSyncMode,
+    // Record the MVCC watermark in the manifest whenever it's advanced by this much by the time of
+    // a compaction, and on close. Compaction never drops the versions visible at the one recorded,
+    // even after recovery. `None` doesn't record it
+    pub watermark_record_interval: Option<u64>,
+    // Split the index of each SST built by flush and compaction into partitions of this many
+    // blocks, only one of which is decoded per lookup. `None` builds a single-level index
+    pub index_partition_len: Option<usize>,
+    // Maximum size of the zstd dictionary trained over the values of each SST built by flush and
+    // compaction, 0 compresses each block on its own. Only used with `CompressionOptions::Zstd`
+    pub compression_dict_size: usize,
+    // Computes the user properties of the SSTs written by a compaction from those of the SSTs it
+    // reads. `None` drops them
+    pub merge_user_properties: Option<UserPropertiesMerger>,
+    // Encrypt the SSTs built by flush and compaction, and the WALs, at rest. SSTs and WAL records
+    // written without it, or before it was set, are still read in the clear
+    pub encryption: Option<Arc<dyn EncryptionProvider>>,
 }
 
 impl LsmStorageOptions {
@@ -104,8 +408,40 @@ impl LsmStorageOptions {
             target_sst_size: 2 << 20,
             compaction_options: CompactionOptions::NoCompaction,
             enable_wal: false,
+            wal_compression: CompressionOptions::None,
+            wal_sync_policy: WalSyncPolicy::Manual,
+            wal_preallocate_size: 0,
+            recycle_wal: false,
+            flush_merge_imm: false,
+            write_stall_timeout: None,
             num_memtable_limit: 50,
+            memtable_shards: 1,
             serializable: false,
+            max_entry_size: None,
+            max_key_size: MAX_KEY_VALUE_LEN,
+            max_value_size: MAX_KEY_VALUE_LEN,
+            large_value_threshold: None,
+            compression: CompressionOptions::None,
+            block_cache_capacity: DEFAULT_BLOCK_CACHE_CAPACITY,
+            bloom_false_positive_rate: Some(DEFAULT_BLOOM_FALSE_POSITIVE_RATE),
+            bloom_per_block: false,
+            mmap: false,
+            max_open_files: None,
+            prewarm_on_open: false,
+            prefix_extractor: None,
+            paranoid_checks: false,
+            verify_checksums: ChecksumVerification::OnFill,
+            use_direct_io_for_flush_and_compaction: false,
+            compaction_readahead_size: DEFAULT_COMPACTION_READAHEAD_SIZE,
+            trash_orphan_ssts: false,
+            max_manifest_size: DEFAULT_MAX_MANIFEST_SIZE,
+            manifest_format: ManifestFormat::Binary,
+            manifest_sync_mode: ManifestSyncMode::Always,
+            watermark_record_interval: None,
+            index_partition_len: None,
+            compression_dict_size: 0,
+            merge_user_properties: None,
+            encryption: None,
         }
     }
 
@@ -115,8 +451,40 @@ impl LsmStorageOptions {
             target_sst_size: 2 << 20,
             compaction_options: CompactionOptions::NoCompaction,
             enable_wal: false,
+            wal_compression: CompressionOptions::None,
+            wal_sync_policy: WalSyncPolicy::Manual,
+            wal_preallocate_size: 0,
+            recycle_wal: false,
+            flush_merge_imm: false,
+            write_stall_timeout: None,
             num_memtable_limit: 2,
+            memtable_shards: 1,
             serializable: false,
+            max_entry_size: None,
+            max_key_size: MAX_KEY_VALUE_LEN,
+            max_value_size: MAX_KEY_VALUE_LEN,
+            large_value_threshold: None,
+            compression: CompressionOptions::None,
+            block_cache_capacity: DEFAULT_BLOCK_CACHE_CAPACITY,
+            bloom_false_positive_rate: Some(DEFAULT_BLOOM_FALSE_POSITIVE_RATE),
+            bloom_per_block: false,
+            mmap: false,
+            max_open_files: None,
+            prewarm_on_open: false,
+            prefix_extractor: None,
+            paranoid_checks: false,
+            verify_checksums: ChecksumVerification::OnFill,
+            use_direct_io_for_flush_and_compaction: false,
+            compaction_readahead_size: DEFAULT_COMPACTION_READAHEAD_SIZE,
+            trash_orphan_ssts: false,
+            max_manifest_size: DEFAULT_MAX_MANIFEST_SIZE,
+            manifest_format: ManifestFormat::Binary,
+            manifest_sync_mode: ManifestSyncMode::Always,
+            watermark_record_interval: None,
+            index_partition_len: None,
+            compression_dict_size: 0,
+            merge_user_properties: None,
+            encryption: None,
         }
     }
 
@@ -126,8 +494,40 @@ impl LsmStorageOptions {
             target_sst_size: 1 << 20, // 1MB
             compaction_options,
             enable_wal: false,
+            wal_compression: CompressionOptions::None,
+            wal_sync_policy: WalSyncPolicy::Manual,
+            wal_preallocate_size: 0,
+            recycle_wal: false,
+            flush_merge_imm: false,
+            write_stall_timeout: None,
             num_memtable_limit: 2,
+            memtable_shards: 1,
             serializable: false,
+            max_entry_size: None,
+            max_key_size: MAX_KEY_VALUE_LEN,
+            max_value_size: MAX_KEY_VALUE_LEN,
+            large_value_threshold: None,
+            compression: CompressionOptions::None,
+            block_cache_capacity: DEFAULT_BLOCK_CACHE_CAPACITY,
+            bloom_false_positive_rate: Some(DEFAULT_BLOOM_FALSE_POSITIVE_RATE),
+            bloom_per_block: false,
+            mmap: false,
+            max_open_files: None,
+            prewarm_on_open: false,
+            prefix_extractor: None,
+            paranoid_checks: false,
+            verify_checksums: ChecksumVerification::OnFill,
+            use_direct_io_for_flush_and_compaction: false,
+            compaction_readahead_size: DEFAULT_COMPACTION_READAHEAD_SIZE,
+            trash_orphan_ssts: false,
+            max_manifest_size: DEFAULT_MAX_MANIFEST_SIZE,
+            manifest_format: ManifestFormat::Binary,
+            manifest_sync_mode: ManifestSyncMode::Always,
+            watermark_record_interval: None,
+            index_partition_len: None,
+            compression_dict_size: 0,
+            merge_user_properties: None,
+            encryption: None,
         }
     }
 }
@@ -137,7 +537,152 @@ pub enum CompactionFilter {
     Prefix(Bytes),
 }
 
-fn range_overlap(
+/// Options of a single `get` or `scan`, overriding those in `LsmStorageOptions` when set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReadOptions {
+    pub verify_checksums: Option<ChecksumVerification>,
+}
+
+/// Options of a single `put` or `write_batch`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    /// Skip the WAL. Such writes are lost on a crash until their memtable is flushed, which
+    /// `MiniLsm::close` does, while the others in the same memtable are still recovered.
+    pub disable_wal: bool,
+    /// Sync the WAL before returning, whatever `LsmStorageOptions::wal_sync_policy` is.
+    pub sync: bool,
+}
+
+/// Returned by a write that stalled for longer than `LsmStorageOptions::write_stall_timeout`.
+#[derive(Debug)]
+pub struct WriteStall;
+
+impl std::fmt::Display for WriteStall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "write stalled waiting for the immutable memtables to be flushed"
+        )
+    }
+}
+
+impl std::error::Error for WriteStall {}
+
+/// Returned by a write with a key or value over `LsmStorageOptions::max_key_size` or
+/// `LsmStorageOptions::max_value_size`, in which case nothing of the batch is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteError {
+    KeyTooLarge { size: usize, limit: usize },
+    ValueTooLarge { size: usize, limit: usize },
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::KeyTooLarge { size, limit } => {
+                write!(f, "key of {} bytes is larger than {} bytes", size, limit)
+            }
+            Self::ValueTooLarge { size, limit } => {
+                write!(f, "value of {} bytes is larger than {} bytes", size, limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+/// Returned by `LsmStorageInner::open` when the directory is open already, by this process or
+/// another, see `LOCK`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseLocked {
+    pub path: PathBuf,
+    /// The process that has it open, if it could be read from the lock file.
+    pub pid: Option<u32>,
+}
+
+impl std::fmt::Display for DatabaseLocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "{} is open by process {}", self.path.display(), pid),
+            None => write!(f, "{} is open by another process", self.path.display()),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseLocked {}
+
+/// The file in the directory that the storage holds an exclusive lock on while it's open, with the
+/// PID of the process. The OS releases the lock if the process dies.
+const LOCK: &str = "LOCK";
+
+/// What `MiniLsm::get_entry` found for a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Value(Bytes),
+    /// The key was deleted, and the deletion isn't compacted away yet.
+    Tombstone,
+}
+
+/// The result of looking up a key, layer by layer.
+pub(crate) enum Lookup {
+    Found(Bytes),
+    Deleted,
+    NotFound,
+}
+
+/// The SSTs of one level summed up, see `LsmStorageInner::level_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelStats {
+    /// 0 for L0, otherwise the id of the level (or tier) in `LsmStorageState::levels`.
+    pub level: usize,
+    pub num_files: usize,
+    /// Total size of the SST files in bytes.
+    pub total_size: u64,
+    /// `None` if any SST of the level has no properties.
+    pub num_entries: Option<u64>,
+    pub num_tombstones: Option<u64>,
+}
+
+/// The current and immutable memtables summed up, see `LsmStorageInner::memtable_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemtableStats {
+    pub num_imm_memtables: usize,
+    /// See `MemTable::len`.
+    pub num_entries: usize,
+    pub num_tombstones: usize,
+}
+
+impl LevelStats {
+    fn new(level: usize, ssts: &[Arc<SsTable>]) -> Self {
+        let properties = ssts
+            .iter()
+            .map(|sst| sst.properties())
+            .collect::<Option<Vec<_>>>();
+        Self {
+            level,
+            num_files: ssts.len(),
+            total_size: ssts.iter().map(|sst| sst.table_size()).sum(),
+            num_entries: properties
+                .as_ref()
+                .map(|properties| properties.iter().map(|x| x.num_entries).sum()),
+            num_tombstones: properties
+                .as_ref()
+                .map(|properties| properties.iter().map(|x| x.num_tombstones).sum()),
+        }
+    }
+
+    /// The share of tombstones in the entries, `None` if unknown or the level is empty.
+    pub fn tombstone_ratio(&self) -> Option<f64> {
+        match (self.num_entries, self.num_tombstones) {
+            (Some(num_entries), Some(num_tombstones)) if num_entries > 0 => {
+                Some(num_tombstones as f64 / num_entries as f64)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn range_overlap(
     user_lower: Bound<&[u8]>,
     user_upper: Bound<&[u8]>,
     table_lower: &[u8],
@@ -167,7 +712,18 @@ fn range_overlap(
     true
 }
 
-fn key_within(user_key: &[u8], table_lower: &[u8], table_upper: &[u8]) -> bool {
+/// Shards the memtable and filters its keys as the options say, for a memtable written to.
+fn setup_memtable(memtable: MemTable, options: &LsmStorageOptions) -> Arc<MemTable> {
+    // a memtable is frozen before it holds more entries than fit in its memory budget
+    let expected_keys = options.target_sst_size / MEMTABLE_ENTRY_OVERHEAD;
+    Arc::new(
+        memtable
+            .with_shards(options.memtable_shards)
+            .with_key_filter(expected_keys),
+    )
+}
+
+pub(crate) fn key_within(user_key: &[u8], table_lower: &[u8], table_upper: &[u8]) -> bool {
     user_key >= table_lower && user_key <= table_upper
 }
 
@@ -175,14 +731,44 @@ fn key_within(user_key: &[u8], table_lower: &[u8], table_upper: &[u8]) -> bool {
 pub(crate) struct LsmStorageInner {
     pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
     pub(crate) state_lock: Mutex<()>,
+    /// Held while compacting, so that ingesting SSTs doesn't change the levels being compacted.
+    pub(crate) compaction_lock: Mutex<()>,
     path: PathBuf,
     pub(crate) block_cache: Arc<BlockCache>,
+    /// Keeps the SST files open, `None` if every SST keeps its own file open.
+    pub(crate) file_cache: Option<Arc<FileCache>>,
     next_sst_id: AtomicUsize,
     pub(crate) options: Arc<LsmStorageOptions>,
     pub(crate) compaction_controller: CompactionController,
     pub(crate) manifest: Option<Manifest>,
     pub(crate) mvcc: Option<LsmMvccInner>,
     pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    /// Holds the values larger than `LsmStorageOptions::large_value_threshold`.
+    pub(crate) value_log: Arc<ValueLog>,
+    /// Counts the writes and syncs of the WALs of all the memtables.
+    pub(crate) wal_metrics: Arc<WalMetrics>,
+    /// Ids of the flushed memtables whose WAL files are kept for new memtables, see
+    /// `LsmStorageOptions::recycle_wal`.
+    recycled_wals: Mutex<Vec<usize>>,
+    /// Ids of the SSTs a compaction recorded in the manifest removed, until their files are
+    /// unlinked and `ManifestRecord::DeleteSsts` is written.
+    pub(crate) pending_delete_ssts: Mutex<Vec<usize>>,
+    /// The output ids reserved by the compaction running, see `ManifestRecord::CompactionIntent`.
+    pub(crate) reserved_sst_ids: Mutex<Vec<usize>>,
+    /// Whether WAL files were created or renamed in the directory since `sync` last synced it.
+    pub(crate) dir_unsynced: Mutex<bool>,
+    /// Wakes up the flush thread, sent to when a memtable is frozen.
+    flush_requested: crossbeam_channel::Sender<()>,
+    pub(crate) flush_requests: crossbeam_channel::Receiver<()>,
+    /// Whether the flush thread is running, writes only stall while it can make room for them.
+    pub(crate) has_flush_thread: AtomicBool,
+    /// Notified with the state lock held when an immutable memtable is flushed.
+    pub(crate) memtable_flushed: Condvar,
+    /// The locked `LOCK` file, unlocked on close or when dropped.
+    pub(crate) dir_lock: File,
+    /// Holds off the flush thread while set.
+    #[cfg(test)]
+    pub(crate) pause_flush: AtomicBool,
 }
 
 /// A thin wrapper for `LsmStorageInner` and the user interface for MiniLSM.
@@ -202,6 +788,13 @@ impl Drop for MiniLsm {
     fn drop(&mut self) {
         self.compaction_notifier.send(()).ok();
         self.flush_notifier.send(()).ok();
+        // the threads hold the storage, and with it the lock on the directory, until they stop
+        if let Some(compact_thread) = self.compaction_thread.lock().take() {
+            compact_thread.join().ok();
+        }
+        if let Some(flush_thread) = self.flush_thread.lock().take() {
+            flush_thread.join().ok();
+        }
     }
 }
 
@@ -223,15 +816,17 @@ impl MiniLsm {
         if let Some(flush_thread) = flush_thread.take() {
             flush_thread.join().unwrap();
         }
+        // synced along with the rest of the manifest below
+        self.inner.record_watermark(1)?;
 
         if !self.inner.options.enable_wal {
             // flush memtables to imm_memtables
             // TODO(xingyu): why we don't need the state_lock???
             if !self.inner.state.read().memtable.is_empty() {
-                self.inner
-                    .freeze_memtable_with_memtable(Arc::new(MemTable::create(
-                        self.inner.next_sst_id(),
-                    )))?;
+                self.inner.freeze_memtable_with_memtable(setup_memtable(
+                    MemTable::create(self.inner.next_sst_id()),
+                    &self.inner.options,
+                ))?;
             }
 
             // flush imm_memtables to disk (i.e., sstable)
@@ -241,14 +836,18 @@ impl MiniLsm {
             } {
                 self.inner.force_flush_next_imm_memtable()?;
             }
+            self.inner.manifest.as_ref().unwrap().sync()?;
             self.inner.sync_dir()?;
         } else {
             // with wal enabled, we don't need to flush memtables and wait for the next compaction
             // to complete in the future, and the data won't be lost since WAL ensures data
-            // persistency.
+            // persistency. Except for the writes that skipped it.
+            self.inner.flush_unlogged_memtables()?;
             self.inner.sync()?;
             self.inner.sync_dir()?;
         }
+        // another open may start once everything is durable
+        self.inner.dir_lock.unlock()?;
         Ok(())
     }
 
@@ -277,6 +876,14 @@ impl MiniLsm {
         self.inner.write_batch(batch)
     }
 
+    pub fn write_batch_with_options<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.inner.write_batch_with_options(batch, options)
+    }
+
     pub fn add_compaction_filter(&self, compaction_filter: CompactionFilter) {
         self.inner.add_compaction_filter(compaction_filter)
     }
@@ -285,14 +892,30 @@ impl MiniLsm {
         self.inner.get(key)
     }
 
+    pub fn get_with_options(&self, key: &[u8], options: &ReadOptions) -> Result<Option<Bytes>> {
+        self.inner.get_with_options(key, options)
+    }
+
+    /// Like `get`, and tells a deleted key from one that was never written. A key whose deletion
+    /// was compacted away is reported as never written.
+    pub fn get_entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        self.inner.get_entry(key)
+    }
+
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
         self.inner.put(key, value)
     }
 
+    pub fn put_with_options(&self, key: &[u8], value: &[u8], options: &WriteOptions) -> Result<()> {
+        self.inner.put_with_options(key, value, options)
+    }
+
     pub fn delete(&self, key: &[u8]) -> Result<()> {
         self.inner.delete(key)
     }
 
+    /// Returns once the writes made before it would survive a crash, whatever the
+    /// `wal_sync_policy`, see `LsmStorageInner::sync`.
     pub fn sync(&self) -> Result<()> {
         self.inner.sync()
     }
@@ -301,6 +924,43 @@ impl MiniLsm {
         self.inner.scan(lower, upper)
     }
 
+    pub fn scan_with_options(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        options: &ReadOptions,
+    ) -> Result<TxnIterator> {
+        self.inner.scan_with_options(lower, upper, options)
+    }
+
+    /// Hit, miss and eviction counters of the block cache.
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.inner.block_cache.stats()
+    }
+
+    pub fn level_stats(&self) -> Vec<LevelStats> {
+        self.inner.level_stats()
+    }
+
+    pub fn memtable_stats(&self) -> MemtableStats {
+        self.inner.memtable_stats()
+    }
+
+    /// Bytes and records written to the WALs and their syncs, over all the memtables since the
+    /// storage was opened or `reset_metrics` was called.
+    pub fn wal_metrics(&self) -> WalMetricsSnapshot {
+        self.inner.wal_metrics.snapshot()
+    }
+
+    /// Sets the counters of `wal_metrics` back to zero.
+    pub fn reset_metrics(&self) {
+        self.inner.wal_metrics.reset()
+    }
+
+    pub fn approximate_range_size(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<u64> {
+        self.inner.approximate_range_size(lower, upper)
+    }
+
     /// Only call this in test cases due to race conditions
     pub fn force_flush(&self) -> Result<()> {
         if !self.inner.state.read().memtable.is_empty() {
@@ -310,12 +970,27 @@ impl MiniLsm {
         if !self.inner.state.read().imm_memtables.is_empty() {
             self.inner.force_flush_next_imm_memtable()?;
         }
-        Ok(())
+        self.inner.flush_unlogged_memtables()
+    }
+
+    /// Freezes the current memtable into an immutable memtable, unless it's empty.
+    pub fn freeze_memtable(&self) -> Result<()> {
+        self.inner.freeze_memtable()
+    }
+
+    /// Freezes the current memtable and flushes the immutable memtables, returning the ids of the
+    /// SSTs built.
+    pub fn flush(&self) -> Result<Vec<usize>> {
+        self.inner.flush()
     }
 
     pub fn force_full_compaction(&self) -> Result<()> {
         self.inner.force_full_compaction()
     }
+
+    pub fn ingest_external_sst(&self, paths: &[PathBuf]) -> Result<()> {
+        self.inner.ingest_external_sst(paths)
+    }
 }
 
 impl LsmStorageInner {
@@ -333,77 +1008,85 @@ impl LsmStorageInner {
     pub(crate) fn open(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
         let path = path.as_ref();
         let manifest;
-        let block_cache = Arc::new(BlockCache::new(1 << 20));
+        let block_cache = Arc::new(BlockCache::new(options.block_cache_capacity));
+        let file_cache = options
+            .max_open_files
+            .map(|max_open_files| Arc::new(FileCache::new(max_open_files)));
         let mut state = LsmStorageState::create(&options);
         let mut next_sst_id = 1;
 
-        let compaction_controller = match &options.compaction_options {
-            CompactionOptions::Leveled(options) => {
-                CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Tiered(options) => {
-                CompactionController::Tiered(TieredCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Simple(options) => CompactionController::Simple(
-                SimpleLeveledCompactionController::new(options.clone()),
-            ),
-            CompactionOptions::NoCompaction => CompactionController::NoCompaction,
-        };
+        let compaction_controller = CompactionController::new(&options.compaction_options);
 
         if !path.exists() {
             std::fs::create_dir(path)?;
         }
+        let dir_lock = Self::lock_dir(path)?;
+        let value_log = Arc::new(ValueLog::open(path)?);
+        let wal_metrics = Arc::new(WalMetrics::default());
+        // one pending request is enough to wake the flush thread up
+        let (flush_requested, flush_requests) = crossbeam_channel::bounded(1);
 
         // record when the last txn committed.
         let mut last_committed_ts = 0;
+        let mut recorded_watermark = None;
         // recover from manifest file
-        let manifest_file = path.join("MANIFEST");
-        if !manifest_file.exists() {
-            manifest = Manifest::create(manifest_file)?;
+        if !Manifest::exists(path)? {
+            manifest = Manifest::create(path, options.manifest_format)?
+                .with_sync_mode(options.manifest_sync_mode);
+            manifest
+                .add_record_when_init(ManifestRecord::Options(PersistedOptions::new(&options)))?;
             // also check wal option and init wal based memtable if needed
             if options.enable_wal {
-                state.memtable = Arc::new(MemTable::create_with_wal(
-                    state.memtable.id(),
-                    Self::path_of_wal_static(path, state.memtable.id()),
-                )?);
+                let id = state.memtable.id();
+                state.memtable = setup_memtable(
+                    MemTable::with_wal(
+                        id,
+                        Wal::create(
+                            Self::path_of_wal_static(path, id),
+                            id as u64,
+                            options.wal_preallocate_size,
+                        )?
+                        .with_compression(options.wal_compression)
+                        .with_encryption(options.encryption.clone())
+                        .with_metrics(wal_metrics.clone()),
+                    ),
+                    &options,
+                );
             }
             // Q: why do we need this?
             // A: this would help us to record for unfrozen memtable (i.e., not yet freeze to
             // imm_memtables) and also record the memtable with id = 0.
             manifest.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
         } else {
-            let (m, records) = Manifest::recover(manifest_file)?;
-            // this memtables means memtable and imm_memtables;
-            let mut memtables = BTreeSet::new();
-            for record in records {
-                match record {
-                    // before match
-                    ManifestRecord::Flush(sst_id) => {
-                        // this sst_id has been flushed to SST and no longer be part of memtables
-                        assert!(memtables.remove(&sst_id));
-                        // this Flush means from imm_memtables to l0_sstables
-                        if compaction_controller.flush_to_l0() {
-                            state.l0_sstables.insert(0, sst_id);
-                        } else {
-                            state.levels.insert(0, (sst_id, vec![sst_id]));
-                        }
-                        next_sst_id = next_sst_id.max(sst_id);
-                    }
-                    ManifestRecord::Compaction(task, output) => {
-                        // this call would modify l0_sstables and levels accordingly
-                        let (new_state, _) = compaction_controller
-                            .apply_compaction_result(&state, &task, &output, true);
-                        state = new_state;
-                        next_sst_id =
-                            next_sst_id.max(output.iter().max().copied().unwrap_or_default());
-                    }
-                    ManifestRecord::NewMemtable(memtable_id) => {
-                        next_sst_id = next_sst_id.max(memtable_id);
-                        // record all memtables
-                        memtables.insert(memtable_id);
-                    }
-                }
-            }
+            // the records are replayed as they're read, rather than all held at once
+            let mut records = Manifest::recover_iter(path)?;
+            let ManifestReplay {
+                state: replayed,
+                memtables,
+                flushed,
+                pending_delete,
+                options: persisted_options,
+                max_id,
+                sst_metas,
+                watermark,
+                reserved,
+            } = ManifestReplay::try_replay(
+                &options.compaction_options,
+                records
+                    .by_ref()
+                    .map(|record| record.map(|(_, record)| record)),
+            )?;
+            let m = records.finish()?.with_sync_mode(options.manifest_sync_mode);
+            recorded_watermark = watermark;
+            state.l0_sstables = replayed.l0_sstables;
+            state.levels = replayed.levels;
+            next_sst_id = next_sst_id.max(max_id);
+            // a manifest written before the options were recorded is taken to match them
+            let new_options = PersistedOptions::new(&options);
+            let options_changed = match &persisted_options {
+                Some(persisted_options) => Self::check_options(persisted_options, &new_options)?,
+                None => true,
+            };
 
             let mut sst_count = 0;
             // recover SST, specifically for sstables (HashMap)
@@ -414,11 +1097,63 @@ impl LsmStorageInner {
                 .chain(state.levels.iter().map(|(_, files)| files).flatten())
             {
                 let sst_id = *sst_id;
-                let sst = SsTable::open(
-                    sst_id,
-                    Some(block_cache.clone()),
-                    FileObject::open(&Self::path_of_sst_static(path, sst_id))?,
-                )?;
+                let sst_path = Self::path_of_sst_static(path, sst_id);
+                let open_sst = {
+                    let sst_path = sst_path.clone();
+                    let file_cache = file_cache.clone();
+                    let block_cache = block_cache.clone();
+                    let mmap = options.mmap;
+                    let encryption = options.encryption.clone();
+                    move || {
+                        FileObject::open_with_file_cache(sst_id, &sst_path, file_cache.clone())
+                            .and_then(|file| file.with_mmap(mmap))
+                            .and_then(|file| {
+                                SsTable::open_with_encryption(
+                                    sst_id,
+                                    Some(block_cache.clone()),
+                                    file,
+                                    encryption.clone(),
+                                )
+                            })
+                            .with_context(|| {
+                                format!("failed to open SST {} at {}", sst_id, sst_path.display())
+                            })
+                    }
+                };
+                // the recorded key range stands in for the SST until it is read, unless the file
+                // is not the one recorded
+                let sst_meta = sst_metas.get(&sst_id).filter(|sst_meta| {
+                    !options.prewarm_on_open
+                        && std::fs::metadata(&sst_path)
+                            .is_ok_and(|metadata| metadata.len() == sst_meta.size)
+                });
+                let sst = match sst_meta {
+                    Some(sst_meta) => SsTable::open_lazily(
+                        sst_id,
+                        KeyBytes::from_bytes_with_ts(
+                            Bytes::copy_from_slice(&sst_meta.first_key),
+                            sst_meta.first_ts,
+                        ),
+                        KeyBytes::from_bytes_with_ts(
+                            Bytes::copy_from_slice(&sst_meta.last_key),
+                            sst_meta.last_ts,
+                        ),
+                        sst_meta.size,
+                        sst_meta.max_ts,
+                        SsTable::encryption_for(
+                            sst_meta.encryption_key_id,
+                            options.encryption.clone(),
+                        )
+                        .with_context(|| {
+                            format!("failed to open SST {} at {}", sst_id, sst_path.display())
+                        })?,
+                        open_sst,
+                    ),
+                    None => open_sst()?,
+                };
+                if options.prewarm_on_open {
+                    sst.prewarm_first_block()?;
+                }
 
                 last_committed_ts = last_committed_ts.max(sst.max_ts());
 
@@ -427,6 +1162,22 @@ impl LsmStorageInner {
                 next_sst_id = next_sst_id.max(sst_id);
             }
             println!("{} SSTs opened", sst_count);
+            if let CompactionOptions::Leveled(_) = &options.compaction_options {
+                // the compactions replayed added their output to the levels unsorted
+                let sstables = &state.sstables;
+                for (_, sst_ids) in state.levels.iter_mut() {
+                    sst_ids.sort_by(|a, b| sstables[a].first_key().cmp(sstables[b].first_key()));
+                }
+            }
+            next_sst_id = next_sst_id.max(Self::max_file_id(path)?);
+            Self::remove_orphan_ssts(
+                path,
+                &state,
+                &pending_delete,
+                &reserved,
+                options.trash_orphan_ssts,
+            )?;
+            Self::remove_flushed_wals(path, &flushed)?;
 
             next_sst_id += 1;
 
@@ -435,16 +1186,26 @@ impl LsmStorageInner {
                 let mut wal_count = 0;
 
                 // if enable_wal is true, we should recover it from the correspoding wal file.
+                // `memtables` is ordered by id, which is the order they were created and frozen in,
+                // so each one is inserted in front of the older ones, newest first as in the state
                 for id in memtables.iter() {
-                    let memtable =
-                        MemTable::recover_from_wal(*id, Self::path_of_wal_static(path, *id))?;
+                    let memtable = MemTable::recover_from_wal_with_encryption(
+                        *id,
+                        Self::path_of_wal_static(path, *id),
+                        options.encryption.clone(),
+                    )?;
+                    let mut max_ts = 0;
+                    let mut iter = memtable.scan(Bound::Unbounded, Bound::Unbounded);
+                    while iter.is_valid() {
+                        if let Some(pointer) = ValuePointer::decode(iter.value()) {
+                            value_log
+                                .validate(&pointer)
+                                .with_context(|| format!("WAL {} refers to a missing value", id))?;
+                        }
+                        max_ts = max_ts.max(iter.key().ts());
+                        iter.next()?;
+                    }
                     if !memtable.is_empty() {
-                        let max_ts = memtable
-                            .map
-                            .iter()
-                            .map(|entry| entry.key().ts())
-                            .max()
-                            .unwrap_or_default();
                         state.imm_memtables.insert(0, Arc::new(memtable));
                         wal_count += 1;
                         last_committed_ts = last_committed_ts.max(max_ts);
@@ -452,16 +1213,47 @@ impl LsmStorageInner {
                 }
                 println!("{} WALs recovered", wal_count);
 
-                state.memtable = Arc::new(MemTable::create_with_wal(
-                    next_sst_id,
-                    Self::path_of_wal_static(path, next_sst_id),
-                )?);
+                state.memtable = setup_memtable(
+                    MemTable::with_wal(
+                        next_sst_id,
+                        Wal::create(
+                            Self::path_of_wal_static(path, next_sst_id),
+                            next_sst_id as u64,
+                            options.wal_preallocate_size,
+                        )?
+                        .with_compression(options.wal_compression)
+                        .with_encryption(options.encryption.clone())
+                        .with_metrics(wal_metrics.clone()),
+                    ),
+                    &options,
+                );
             } else {
-                state.memtable = Arc::new(MemTable::create(next_sst_id));
+                state.memtable = setup_memtable(MemTable::create(next_sst_id), &options);
+            }
+
+            if options.paranoid_checks {
+                let ids = std::iter::once(state.memtable.id())
+                    .chain(state.imm_memtables.iter().map(|memtable| memtable.id()))
+                    .collect::<Vec<_>>();
+                assert!(
+                    ids.is_sorted_by(|newer, older| newer > older),
+                    "memtables {:?} are recovered out of order",
+                    ids
+                );
             }
 
             // do one more record for the current memtable in manifest
             m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            if options_changed {
+                m.add_record_when_init(ManifestRecord::Options(new_options))?;
+            }
+            if !pending_delete.is_empty() {
+                // the unlinks must be durable before the record that they're done
+                File::open(path)?.sync_all()?;
+                m.add_record_when_init(ManifestRecord::DeleteSsts(
+                    pending_delete.into_iter().collect(),
+                ))?;
+            }
 
             next_sst_id += 1;
 
@@ -471,23 +1263,112 @@ impl LsmStorageInner {
         let storage = Self {
             state: Arc::new(RwLock::new(Arc::new(state))),
             state_lock: Mutex::new(()),
+            compaction_lock: Mutex::new(()),
             path: path.to_path_buf(),
             block_cache: block_cache,
+            file_cache,
             next_sst_id: AtomicUsize::new(next_sst_id),
             compaction_controller,
             manifest: Some(manifest),
             options: options.into(),
             mvcc: Some(LsmMvccInner::new(last_committed_ts)),
             compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            value_log,
+            wal_metrics,
+            recycled_wals: Mutex::new(Vec::new()),
+            pending_delete_ssts: Mutex::new(Vec::new()),
+            reserved_sst_ids: Mutex::new(Vec::new()),
+            dir_unsynced: Mutex::new(false),
+            flush_requested,
+            flush_requests,
+            has_flush_thread: AtomicBool::new(false),
+            memtable_flushed: Condvar::new(),
+            dir_lock,
+            #[cfg(test)]
+            pause_flush: AtomicBool::new(false),
         };
 
+        if storage.options.watermark_record_interval.is_some() {
+            // compaction keeps every version until a watermark is recorded
+            storage
+                .mvcc()
+                .set_watermark_floor(recorded_watermark.unwrap_or(0));
+        }
+        storage.maybe_roll_over_manifest(&storage.state_lock.lock())?;
+        storage.manifest.as_ref().unwrap().sync()?;
         storage.sync_dir()?;
 
         Ok(storage)
     }
 
+    /// Locks the `LOCK` file of the directory and writes the PID to it, or fails with
+    /// `DatabaseLocked` if it's locked already.
+    fn lock_dir(path: &Path) -> Result<File> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.join(LOCK))
+            .context("failed to open LOCK")?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(DatabaseLocked {
+                    path: path.to_path_buf(),
+                    pid: pid.trim().parse().ok(),
+                }
+                .into());
+            }
+            Err(std::fs::TryLockError::Error(err)) => {
+                return Err(err).context("failed to lock LOCK");
+            }
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(file)
+    }
+
+    /// Records the watermark in the manifest if it's advanced by at least `min_advance` since it
+    /// was last recorded, see `LsmStorageOptions::watermark_record_interval`, and lets compaction
+    /// drop the versions below it once it's recorded.
+    pub(crate) fn record_watermark(&self, min_advance: u64) -> Result<()> {
+        if self.options.watermark_record_interval.is_none() {
+            return Ok(());
+        }
+        let mvcc = self.mvcc();
+        let watermark = mvcc.live_watermark();
+        let recorded = mvcc.watermark_floor().unwrap_or(0);
+        if watermark < recorded.saturating_add(min_advance.max(1)) {
+            return Ok(());
+        }
+        self.manifest
+            .as_ref()
+            .unwrap()
+            .add_record_when_init(ManifestRecord::Watermark(watermark))?;
+        mvcc.set_watermark_floor(watermark);
+        Ok(())
+    }
+
+    /// Makes the writes made before it durable: syncs the manifest, which names the memtables
+    /// recovered, the WALs of the memtables, with the writes buffered in them, and the directory
+    /// if WALs were created in it since the last call. Writes racing with it may or may not be
+    /// synced. Writes that skipped the WAL aren't, see `flush_unlogged_memtables`.
     pub fn sync(&self) -> Result<()> {
-        self.state.read().memtable.sync_wal()
+        self.manifest.as_ref().unwrap().sync()?;
+        let snapshot = self.state.read().clone();
+        // a memtable frozen after the writes may not have been synced by the freeze yet
+        for memtable in std::iter::once(&snapshot.memtable).chain(snapshot.imm_memtables.iter()) {
+            memtable.sync_wal()?;
+        }
+        let mut dir_unsynced = self.dir_unsynced.lock();
+        if *dir_unsynced {
+            self.sync_dir()?;
+            *dir_unsynced = false;
+        }
+        Ok(())
     }
 
     pub fn add_compaction_filter(&self, compaction_filter: CompactionFilter) {
@@ -500,105 +1381,140 @@ impl LsmStorageInner {
         txn.get(_key)
     }
 
-    /// Get a key from the storage. In day 7, this can be further optimized by using a bloom filter.
-    pub(crate) fn get_with_ts(&self, _key: &[u8], read_ts: u64) -> Result<Option<Bytes>> {
-        let snapshot = {
-            let guard = self.state.read();
-            Arc::clone(&guard)
-        };
-
-        // ts-based memtable/imm_memtables retrieve
-        let mut memtable_iters = Vec::with_capacity(snapshot.imm_memtables.len() + 1);
+    pub fn get_with_options(
+        self: &Arc<Self>,
+        key: &[u8],
+        options: &ReadOptions,
+    ) -> Result<Option<Bytes>> {
+        let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+        txn.get_with_options(key, options)
+    }
 
-        memtable_iters.push(Box::new(snapshot.memtable.scan(
-            Bound::Included(KeySlice::from_slice(_key, TS_RANGE_BEGIN)),
-            Bound::Included(KeySlice::from_slice(_key, TS_RANGE_END)),
-        )));
+    fn verify_checksums(&self, options: &ReadOptions) -> ChecksumVerification {
+        options
+            .verify_checksums
+            .unwrap_or(self.options.verify_checksums)
+    }
 
-        for imm_table in snapshot.imm_memtables.iter() {
-            memtable_iters.push(Box::new(imm_table.scan(
-                Bound::Included(KeySlice::from_slice(_key, TS_RANGE_BEGIN)),
-                Bound::Included(KeySlice::from_slice(_key, TS_RANGE_END)),
-            )));
+    /// Get a key from the storage. SSTs whose bloom filter rules out the key, or whose versions are
+    /// all newer than `read_ts`, are skipped.
+    pub(crate) fn get_with_ts(
+        &self,
+        key: &[u8],
+        read_ts: u64,
+        options: &ReadOptions,
+    ) -> Result<Option<Bytes>> {
+        match self.lookup(key, read_ts, options)? {
+            Lookup::Found(value) => Ok(Some(value)),
+            Lookup::Deleted | Lookup::NotFound => Ok(None),
         }
+    }
 
-        let mem_merge_iter = MergeIterator::create(memtable_iters);
-        // a convenient function to check if key might be in SST.
-        let is_valid_table = |_key: &[u8], sstable: &SsTable| -> bool {
-            if key_within(
-                _key,
-                sstable.first_key().key_ref(),
-                sstable.last_key().key_ref(),
-            ) {
-                if let Some(bloom) = sstable.bloom.as_ref() {
-                    if bloom.may_contain(farmhash::fingerprint32(_key)) {
-                        return true;
-                    }
-                } else {
-                    // in case, we don't have bloom, we just move forward
-                    return true;
-                }
-            }
-            false
+    /// Whether `key` is live, deleted or absent in a snapshot read at `read_ts`.
+    pub(crate) fn get_entry(self: &Arc<Self>, key: &[u8]) -> Result<Option<Entry>> {
+        // a transaction keeps the versions at its read ts from being compacted away
+        let txn = self.mvcc().new_txn(self.clone(), false);
+        Ok(
+            match self.lookup(key, txn.read_ts, &ReadOptions::default())? {
+                Lookup::Found(value) => Some(Entry::Value(value)),
+                Lookup::Deleted => Some(Entry::Tombstone),
+                Lookup::NotFound => None,
+            },
+        )
+    }
+
+    /// Finds the newest version of `key` visible at `read_ts`. The memtables are searched from the
+    /// latest one and the first version found there wins, skipping the immutable ones whose key
+    /// filter rules the key out. An SST is only searched if it may have a
+    /// newer version than the one found so far, which ingested SSTs can.
+    fn lookup(&self, key: &[u8], read_ts: u64, options: &ReadOptions) -> Result<Lookup> {
+        let verify_checksums = self.verify_checksums(options);
+        let snapshot = {
+            let guard = self.state.read();
+            Arc::clone(&guard)
         };
 
-        let mut l0_iters = Vec::with_capacity(snapshot.l0_sstables.len());
-        // check the sstables
-        for sst_id in snapshot.l0_sstables.iter() {
-            let sstable = snapshot.sstables[sst_id].clone();
-            if is_valid_table(_key, &sstable) {
-                l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_key(
-                    sstable,
-                    KeySlice::from_slice(_key, TS_RANGE_BEGIN),
-                )?));
+        // (ts, value) of the newest version found
+        let mut newest: Option<(u64, Bytes)> = None;
+        for (idx, memtable) in std::iter::once(&snapshot.memtable)
+            .chain(snapshot.imm_memtables.iter())
+            .enumerate()
+        {
+            // the current memtable is probed anyway, as most reads of recent writes find it there
+            if idx > 0 && !memtable.may_contain(key) {
+                continue;
+            }
+            #[cfg(test)]
+            crate::mem_table::NUM_PROBED.with(|x| x.set(x.get() + 1));
+            let iter = memtable.scan(
+                Bound::Included(KeySlice::from_slice(key, read_ts)),
+                Bound::Included(KeySlice::from_slice(key, TS_RANGE_END)),
+            );
+            if iter.is_valid() {
+                newest = Some((iter.key().ts(), Bytes::copy_from_slice(iter.value())));
+                break;
             }
         }
 
-        // expand this read from L1(i.e., levels[0]) to all levels
-        let mut iters_after_l0 = Vec::new();
-        for (_, sst_ids) in snapshot.levels.iter() {
-            let mut ssts_to_concat = Vec::with_capacity(sst_ids.len());
-            for sst_id in sst_ids {
-                let sstable = &snapshot.sstables[sst_id];
-                if is_valid_table(_key, sstable) {
-                    ssts_to_concat.push(sstable.clone());
-                }
+        let may_have_newer = |newest: &Option<(u64, Bytes)>, sstable: &SsTable| -> Result<bool> {
+            // none of the checks reads a block, only the per-block bloom filter may have to be
+            // read, after the others
+            Ok(newest.as_ref().is_none_or(|(ts, _)| sstable.max_ts() > *ts)
+                && key_within(
+                    key,
+                    sstable.first_key().key_ref(),
+                    sstable.last_key().key_ref(),
+                )
+                && sstable.has_versions_visible_at(read_ts)
+                && sstable.may_contain(key)
+                && sstable.block_may_contain(key)?)
+        };
+        let l0_ssts = snapshot.l0_sstables.iter();
+        let level_ssts = snapshot
+            .levels
+            .iter()
+            .flat_map(|(_, sst_ids)| sst_ids.iter());
+        for sst_id in l0_ssts.chain(level_ssts) {
+            let sstable = &snapshot.sstables[sst_id];
+            if !may_have_newer(&newest, sstable)? {
+                continue;
+            }
+            let iter = SsTableIterator::create_and_seek_to_key_with_ts(
+                sstable.clone(),
+                KeySlice::from_slice(key, read_ts),
+                read_ts,
+                verify_checksums,
+            )?;
+            // the bloom filter can be wrong
+            if iter.is_valid()
+                && iter.key().key_ref() == key
+                && newest.as_ref().is_none_or(|(ts, _)| iter.key().ts() > *ts)
+            {
+                newest = Some((iter.key().ts(), Bytes::copy_from_slice(iter.value())));
             }
-
-            iters_after_l0.push(Box::new(SstConcatIterator::create_and_seek_to_key(
-                ssts_to_concat,
-                KeySlice::from_slice(_key, TS_RANGE_BEGIN),
-            )?));
         }
 
-        let merge_iter_after_l0 = MergeIterator::create(iters_after_l0);
-
-        let iter = LsmIterator::new(
-            TwoMergeIterator::create(
-                TwoMergeIterator::create(mem_merge_iter, MergeIterator::create(l0_iters))?,
-                merge_iter_after_l0,
-            )?,
-            Bound::Unbounded,
-            read_ts,
-        )?;
-
-        // the iter will skip empty value and always return the valid key, even if the key
-        // may doesn't match with _key, but we can have the condition check and ensure the value
-        // it returns, otherwise, we just return None for this _key. (that's why we did
-        // move_to_non_delete() in lsm_iterator)
-        //
-        // we don't need to walk through all items since we only care if the first key is _key
-        if iter.is_valid() && iter.key() == _key && !iter.value().is_empty() {
-            return Ok(Some(Bytes::copy_from_slice(iter.value())));
+        match newest {
+            None => Ok(Lookup::NotFound),
+            Some((_, value)) if value.is_empty() => Ok(Lookup::Deleted),
+            Some((_, value)) => Ok(Lookup::Found(
+                self.value_log.resolve(&value)?.unwrap_or(value),
+            )),
         }
-
-        Ok(None)
     }
 
     /// Write a batch of data into the storage and return ts for txn to commit
     pub fn write_batch<T: AsRef<[u8]>>(
         self: &Arc<Self>,
         _batch: &[WriteBatchRecord<T>],
+    ) -> Result<()> {
+        self.write_batch_with_options(_batch, &WriteOptions::default())
+    }
+
+    pub fn write_batch_with_options<T: AsRef<[u8]>>(
+        self: &Arc<Self>,
+        _batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
     ) -> Result<()> {
         if self.options.serializable {
             // create batch through txn APIs
@@ -613,74 +1529,189 @@ impl LsmStorageInner {
                     }
                 }
             }
-            txn.commit()?;
+            txn.commit_with_options(options)?;
         } else {
             // regular APIs
-            self.write_batch_inner(_batch)?;
+            self.write_batch_inner(_batch, options)?;
         }
         Ok(())
     }
 
     /// Write a batch of data into the storage. Implement in week 2 day 7.
-    pub fn write_batch_inner<T: AsRef<[u8]>>(&self, _batch: &[WriteBatchRecord<T>]) -> Result<u64> {
-        let _state_lock = self.mvcc().write_lock.lock();
+    ///
+    /// The batch goes to the WAL as one record and into a single memtable, all at the same
+    /// timestamp, so that it's recovered and read either as a whole or not at all. The memtable
+    /// is frozen after the batch if it's full, never in the middle of it.
+    ///
+    /// The WAL is synced after the write lock is released, so that concurrent writers share a
+    /// sync, and only then is the batch made visible.
+    pub fn write_batch_inner<T: AsRef<[u8]>>(
+        &self,
+        _batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
+    ) -> Result<u64> {
+        self.check_batch(_batch)?;
+        self.wait_for_flush()?;
+
+        // append to the value log before taking the lock, as it syncs every value
+        let pointers = _batch
+            .iter()
+            .map(|record| match record {
+                WriteBatchRecord::Put(_, value) if self.is_large_value(value.as_ref()) => {
+                    Ok(Some(self.value_log.append(value.as_ref())?.encode()))
+                }
+                _ => Ok(None),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let state_lock = self.mvcc().write_lock.lock();
 
-        let ts = self.mvcc().latest_commit_ts() + 1;
-        for record in _batch {
-            match record {
+        let ts = self.mvcc().next_write_ts();
+        let entries = _batch
+            .iter()
+            .zip(&pointers)
+            .map(|(record, pointer)| match record {
                 WriteBatchRecord::Put(key, value) => {
                     let key = key.as_ref();
                     let value = value.as_ref();
                     assert!(!key.is_empty());
                     assert!(!value.is_empty());
-
-                    let size;
-                    {
-                        let snapshot = self.state.read();
-                        snapshot
-                            .memtable
-                            .put(KeySlice::from_slice(key, ts), value)?;
-                        // check if we need to force_freeze_memtable
-                        size = snapshot.memtable.approximate_size();
-                    }
-                    self.try_freeze(size)?;
+                    (
+                        KeySlice::from_slice(key, ts),
+                        pointer.as_deref().unwrap_or(value),
+                    )
                 }
                 WriteBatchRecord::Del(key) => {
                     let key = key.as_ref();
                     assert!(!key.is_empty());
-
-                    let size;
-                    {
-                        let snapshot = self.state.read();
-                        snapshot.memtable.put(KeySlice::from_slice(key, ts), b"")?;
-                        // check if we need to force_freeze_memtable
-                        size = snapshot.memtable.approximate_size();
-                    }
-                    self.try_freeze(size)?;
+                    (KeySlice::from_slice(key, ts), &b""[..])
                 }
+            })
+            .collect::<Vec<_>>();
+
+        let memtable;
+        {
+            let snapshot = self.state.read();
+            if options.disable_wal {
+                snapshot.memtable.put_batch_without_wal(&entries)?;
+            } else {
+                snapshot.memtable.put_batch(&entries)?;
             }
+            memtable = snapshot.memtable.clone();
+        }
+        // check if we need to force_freeze_memtable
+        self.try_freeze(memtable.approximate_size())?;
+        drop(state_lock);
+
+        if options.sync || self.options.wal_sync_policy == WalSyncPolicy::Always {
+            // the memtable may be one the manifest doesn't have synced yet
+            self.manifest.as_ref().unwrap().sync()?;
+            memtable.sync_wal()?;
         }
         self.mvcc().update_commit_ts(ts);
         Ok(ts)
     }
 
+    /// Rejects a batch with a key or value over the limits, before any of it is written.
+    fn check_batch<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<()> {
+        for record in batch {
+            let (key, value) = match record {
+                WriteBatchRecord::Put(key, value) => (key.as_ref(), value.as_ref()),
+                WriteBatchRecord::Del(key) => (key.as_ref(), &b""[..]),
+            };
+            if key.len() > self.options.max_key_size {
+                return Err(WriteError::KeyTooLarge {
+                    size: key.len(),
+                    limit: self.options.max_key_size,
+                }
+                .into());
+            }
+            if value.len() > self.options.max_value_size {
+                return Err(WriteError::ValueTooLarge {
+                    size: value.len(),
+                    limit: self.options.max_value_size,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the value is written to the value log. A value that looks like a `ValuePointer` is
+    /// always written there, so that it isn't read as one.
+    fn is_large_value(&self, value: &[u8]) -> bool {
+        self.options
+            .large_value_threshold
+            .is_some_and(|threshold| value.len() > threshold)
+            || ValuePointer::decode(value).is_some()
+    }
+
     /// Put a key-value pair into the storage by writing into the current memtable.
     pub fn put(self: &Arc<Self>, _key: &[u8], _value: &[u8]) -> Result<()> {
         self.write_batch(&[WriteBatchRecord::Put(_key, _value)])
     }
 
+    pub fn put_with_options(
+        self: &Arc<Self>,
+        key: &[u8],
+        value: &[u8],
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.write_batch_with_options(&[WriteBatchRecord::Put(key, value)], options)
+    }
+
     /// Remove a key from the storage by writing an empty value.
     pub fn delete(self: &Arc<Self>, _key: &[u8]) -> Result<()> {
         self.write_batch(&[WriteBatchRecord::Del(_key)])
     }
 
+    /// Whether writes have to wait for the flush thread, instead of freezing another memtable.
+    fn is_write_stalled(&self) -> bool {
+        self.has_flush_thread
+            .load(std::sync::atomic::Ordering::SeqCst)
+            && self.state.read().imm_memtables.len() >= self.options.num_memtable_limit
+    }
+
+    /// Blocks while writes are stalled, up to `LsmStorageOptions::write_stall_timeout`.
+    fn wait_for_flush(&self) -> Result<()> {
+        if !self.is_write_stalled() {
+            return Ok(());
+        }
+        let deadline = self
+            .options
+            .write_stall_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let mut state_lock = self.state_lock.lock();
+        while self.is_write_stalled() {
+            match deadline {
+                Some(deadline) => {
+                    if self
+                        .memtable_flushed
+                        .wait_until(&mut state_lock, deadline)
+                        .timed_out()
+                        && self.is_write_stalled()
+                    {
+                        return Err(WriteStall.into());
+                    }
+                }
+                None => self.memtable_flushed.wait(&mut state_lock),
+            }
+        }
+        Ok(())
+    }
+
     fn try_freeze(&self, estimated_size: usize) -> Result<()> {
         if estimated_size >= self.options.target_sst_size {
             let state_lock = self.state_lock.lock();
             // read it again from the CURRENT state
             let guard = self.state.read();
 
-            if guard.memtable.approximate_size() >= self.options.target_sst_size {
+            // the memtable keeps growing by the writes that got past `wait_for_flush` meanwhile
+            let stalled = self
+                .has_flush_thread
+                .load(std::sync::atomic::Ordering::SeqCst)
+                && guard.imm_memtables.len() >= self.options.num_memtable_limit;
+            if guard.memtable.approximate_size() >= self.options.target_sst_size && !stalled {
                 // need to drop guard here explicitly
                 drop(guard);
                 self.force_freeze_memtable(&state_lock)?;
@@ -697,6 +1728,142 @@ impl LsmStorageInner {
         Self::path_of_sst_static(&self.path, id)
     }
 
+    /// The id in the name of an SST or WAL, `None` for any other file.
+    fn file_id_of(file_path: &Path, extension: &str) -> Option<usize> {
+        if file_path.extension().is_none_or(|ext| ext != extension) {
+            return None;
+        }
+        file_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<usize>().ok())
+    }
+
+    /// Checks the options the DB is opened with against those recorded in the manifest, and
+    /// returns whether any changed. Another compaction strategy, or another number of levels,
+    /// would misread the levels recovered from the manifest; the memtables it records are recovered
+    /// from WALs only if they were written with `enable_wal`; and the timestamps of the data are
+    /// written for the transactions of the `serializable` it was created with. So those are
+    /// refused, and the others only apply to the SSTs written from now on.
+    fn check_options(persisted: &PersistedOptions, options: &PersistedOptions) -> Result<bool> {
+        let max_levels = |compaction_options: &CompactionOptions| match compaction_options {
+            CompactionOptions::Leveled(options) => Some(options.max_levels),
+            CompactionOptions::Simple(options) => Some(options.max_levels),
+            CompactionOptions::Tiered(_) | CompactionOptions::NoCompaction => None,
+        };
+        if std::mem::discriminant(&persisted.compaction_options)
+            != std::mem::discriminant(&options.compaction_options)
+            || max_levels(&persisted.compaction_options) != max_levels(&options.compaction_options)
+        {
+            bail!(
+                "the DB was created with compaction options {:?}, which can't change to {:?}",
+                persisted.compaction_options,
+                options.compaction_options
+            );
+        }
+        if persisted.enable_wal != options.enable_wal {
+            bail!(
+                "the DB was created with enable_wal = {}, which can't change",
+                persisted.enable_wal
+            );
+        }
+        if persisted.serializable != options.serializable {
+            bail!(
+                "the DB was created with serializable = {}, which can't change",
+                persisted.serializable
+            );
+        }
+        if persisted == options {
+            return Ok(false);
+        }
+        println!(
+            "options changed since the DB was last opened: {:?} -> {:?}",
+            persisted, options
+        );
+        Ok(true)
+    }
+
+    /// The largest id of the SSTs and WALs in the directory and its trash. The manifest may not
+    /// know about all of them: a WAL is created before its `NewMemtable` record is written, and an
+    /// SST before its flush or compaction is recorded. Ids are allocated above it, so that a file
+    /// left behind by a crash is never reused for another memtable or SST.
+    fn max_file_id(path: &Path) -> Result<usize> {
+        let mut max_id = 0;
+        for dir in [path.to_path_buf(), path.join("trash")] {
+            if !dir.exists() {
+                continue;
+            }
+            for entry in std::fs::read_dir(dir)? {
+                let file_path = entry?.path();
+                if let Some(id) = Self::file_id_of(&file_path, "sst")
+                    .or_else(|| Self::file_id_of(&file_path, "wal"))
+                {
+                    max_id = max_id.max(id);
+                }
+            }
+        }
+        Ok(max_id)
+    }
+
+    /// Removes the SSTs in the directory that the state recovered from the manifest doesn't refer
+    /// to. Those in `pending_delete` were compacted away, and the crash came before their files were
+    /// all unlinked. Those in `reserved` are the output of a compaction that crashed before
+    /// recording its result; its inputs are still in the state, so they are kept. The others are
+    /// orphans, left behind by a flush that crashed the same way, or not known to the manifest at
+    /// all.
+    fn remove_orphan_ssts(
+        path: &Path,
+        state: &LsmStorageState,
+        pending_delete: &BTreeSet<usize>,
+        reserved: &BTreeSet<usize>,
+        trash: bool,
+    ) -> Result<()> {
+        for entry in std::fs::read_dir(path)? {
+            let file_path = entry?.path();
+            let Some(sst_id) = Self::file_id_of(&file_path, "sst") else {
+                continue;
+            };
+            if state.sstables.contains_key(&sst_id) {
+                continue;
+            }
+            if pending_delete.contains(&sst_id) {
+                std::fs::remove_file(&file_path)?;
+                println!("removed compacted SST {}", file_path.display());
+            } else if reserved.contains(&sst_id) {
+                std::fs::remove_file(&file_path)?;
+                println!(
+                    "removed SST {} of a compaction that didn't finish",
+                    file_path.display()
+                );
+            } else if trash {
+                let trash_dir = path.join("trash");
+                std::fs::create_dir_all(&trash_dir)?;
+                std::fs::rename(&file_path, trash_dir.join(file_path.file_name().unwrap()))?;
+                println!("moved orphan SST {} to trash", file_path.display());
+            } else {
+                std::fs::remove_file(&file_path)?;
+                println!("removed orphan SST {}", file_path.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the WALs of the memtables the manifest records as flushed, left behind by a flush
+    /// that crashed before removing them.
+    fn remove_flushed_wals(path: &Path, flushed: &HashSet<usize>) -> Result<()> {
+        for entry in std::fs::read_dir(path)? {
+            let file_path = entry?.path();
+            let Some(memtable_id) = Self::file_id_of(&file_path, "wal") else {
+                continue;
+            };
+            if flushed.contains(&memtable_id) {
+                std::fs::remove_file(&file_path)?;
+                println!("removed flushed WAL {}", file_path.display());
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) fn path_of_wal_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
         path.as_ref().join(format!("{:05}.wal", id))
     }
@@ -710,17 +1877,38 @@ impl LsmStorageInner {
         Ok(())
     }
 
+    /// Creates the WAL of a new memtable, in the file of a flushed one if there's any to recycle.
+    fn create_wal(&self, memtable_id: usize) -> Result<Wal> {
+        let recycled = self.recycled_wals.lock().pop();
+        let wal = match recycled {
+            Some(recycled) => Wal::recycle(
+                self.path_of_wal(recycled),
+                self.path_of_wal(memtable_id),
+                memtable_id as u64,
+                self.options.wal_preallocate_size,
+            )?,
+            None => Wal::create(
+                self.path_of_wal(memtable_id),
+                memtable_id as u64,
+                self.options.wal_preallocate_size,
+            )?,
+        };
+        *self.dir_unsynced.lock() = true;
+        Ok(wal
+            .with_compression(self.options.wal_compression)
+            .with_encryption(self.options.encryption.clone())
+            .with_metrics(self.wal_metrics.clone()))
+    }
+
     /// Force freeze the current memtable to an immutable memtable
     pub fn force_freeze_memtable(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
         let memtable_id = self.next_sst_id();
         let memtable = if self.options.enable_wal {
-            Arc::new(MemTable::create_with_wal(
-                memtable_id,
-                self.path_of_wal(memtable_id),
-            )?)
+            MemTable::with_wal(memtable_id, self.create_wal(memtable_id)?)
         } else {
-            Arc::new(MemTable::create(memtable_id))
+            MemTable::create(memtable_id)
         };
+        let memtable = setup_memtable(memtable, &self.options);
 
         self.freeze_memtable_with_memtable(memtable)?;
 
@@ -729,12 +1917,54 @@ impl LsmStorageInner {
             state_lock_observer,
             ManifestRecord::NewMemtable(memtable_id),
         )?;
+        self.maybe_roll_over_manifest(state_lock_observer)?;
 
         // TODO(xingyu): why do we need sync here?
         self.sync_dir()?;
+        self.flush_requested.try_send(()).ok();
         Ok(())
     }
 
+    /// Rolls the manifest over to a snapshot of the state once it's grown past
+    /// `LsmStorageOptions::max_manifest_size`. The state lock orders the records with the changes
+    /// to the state they record, so the state is what the records add up to while it's held.
+    fn maybe_roll_over_manifest(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
+        let manifest = self.manifest.as_ref().unwrap();
+        if !manifest.should_roll_over(self.options.max_manifest_size) {
+            return Ok(());
+        }
+        let snapshot = {
+            let state = self.state.read();
+            ManifestSnapshot {
+                memtables: state
+                    .imm_memtables
+                    .iter()
+                    .rev()
+                    .chain(std::iter::once(&state.memtable))
+                    .map(|memtable| memtable.id())
+                    .collect(),
+                flushed: self.recycled_wals.lock().clone(),
+                l0_sstables: state.l0_sstables.clone(),
+                levels: state.levels.clone(),
+                max_id: self.next_sst_id.load(std::sync::atomic::Ordering::SeqCst) - 1,
+                pending_delete: self.pending_delete_ssts.lock().clone(),
+                options: Some(PersistedOptions::new(&self.options)),
+                watermark: self.mvcc().watermark_floor(),
+                reserved: self.reserved_sst_ids.lock().clone(),
+                sst_metas: {
+                    let mut sst_metas: Vec<_> = state
+                        .sstables
+                        .values()
+                        .map(|sst| SstMeta::new(sst))
+                        .collect();
+                    sst_metas.sort_by_key(|sst_meta| sst_meta.sst_id);
+                    sst_metas
+                },
+            }
+        };
+        manifest.roll_over(state_lock_observer, snapshot)
+    }
+
     pub fn freeze_memtable_with_memtable(&self, memtable: Arc<MemTable>) -> Result<()> {
         let old_memtable;
         {
@@ -750,44 +1980,290 @@ impl LsmStorageInner {
             // old_memtable = snapshot.memtable.clone();
             // snapshot.memtable = memtable;
 
-            snapshot.imm_memtables.insert(0, old_memtable);
+            snapshot.imm_memtables.insert(0, old_memtable.clone());
 
             // Q: do we need to reset approximate_size???
             // A: this memtable input was init with default vaue, so it has reset.
             // update the snapshot
             *guard = Arc::new(snapshot)
         }
+        // the writes to the new memtable must not outlive those to the old one on a crash
+        old_memtable.sync_wal()?;
+        Ok(())
+    }
+
+    /// Adds SSTs built offline, e.g. by `SsTableBuilder`, without going through the write path.
+    /// The files are validated and hard-linked (or copied) into the directory under new SST ids,
+    /// so they must not be modified afterwards. Key-disjoint SSTs go to the lowest level such that
+    /// neither it nor any level above it overlaps with them, otherwise to L0.
+    ///
+    /// The keys keep the timestamps they were written with, and later writes get newer ones.
+    pub fn ingest_external_sst(&self, paths: &[PathBuf]) -> Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        // validate all of them before touching the directory
+        for path in paths {
+            FileObject::open(path)
+                .and_then(|file| {
+                    SsTable::open_with_encryption(0, None, file, self.options.encryption.clone())
+                })
+                .and_then(|sst| sst.verify())
+                .with_context(|| format!("failed to ingest {}", path.display()))?;
+        }
+
+        let mut ssts = Vec::with_capacity(paths.len());
+        let result = paths.iter().try_for_each(|path| {
+            let sst_id = self.next_sst_id();
+            ssts.push(self.link_external_sst(path, sst_id)?);
+            Ok(())
+        });
+        let result = result.and_then(|_| self.sync_dir()).and_then(|_| {
+            ssts.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+            self.add_ingested_ssts(&ssts)
+        });
+        if result.is_err() {
+            // none of them is in the LSM tree yet
+            for sst in ssts.iter() {
+                std::fs::remove_file(self.path_of_sst(sst.sst_id())).ok();
+            }
+        }
+        result
+    }
+
+    fn link_external_sst(&self, path: &Path, sst_id: usize) -> Result<Arc<SsTable>> {
+        let sst_path = self.path_of_sst(sst_id);
+        // hard links don't work across file systems
+        if std::fs::hard_link(path, &sst_path).is_err() {
+            std::fs::copy(path, &sst_path)?;
+            File::open(&sst_path)?.sync_all()?;
+        }
+        let sst = FileObject::open_with_file_cache(sst_id, &sst_path, self.file_cache.clone())
+            .and_then(|file| file.with_mmap(self.options.mmap))
+            .and_then(|file| {
+                SsTable::open_with_encryption(
+                    sst_id,
+                    Some(self.block_cache.clone()),
+                    file,
+                    self.options.encryption.clone(),
+                )
+            })
+            .with_context(|| format!("failed to open SST {} at {}", sst_id, sst_path.display()))?;
+        if self.options.prewarm_on_open {
+            sst.prewarm_first_block()?;
+        }
+        Ok(Arc::new(sst))
+    }
+
+    // `ssts` are sorted by their first key
+    fn add_ingested_ssts(&self, ssts: &[Arc<SsTable>]) -> Result<()> {
+        let _compaction_lock = self.compaction_lock.lock();
+        let state_lock = self.state_lock.lock();
+        let mut snapshot = self.state.read().as_ref().clone();
+        let flush_to_l0 = self.compaction_controller.flush_to_l0();
+
+        let level = Self::ingest_level(&snapshot, ssts, flush_to_l0);
+        for sst in ssts {
+            snapshot.sstables.insert(sst.sst_id(), sst.clone());
+        }
+        let mut sst_ids = ssts.iter().map(|sst| sst.sst_id()).collect::<Vec<_>>();
+        if level > 0 && flush_to_l0 {
+            // the ingested SSTs may go between the ones already in the level
+            let (_, level_sst_ids) = snapshot
+                .levels
+                .iter()
+                .find(|(level_id, _)| *level_id == level)
+                .unwrap();
+            sst_ids.extend(level_sst_ids);
+            sst_ids.sort_by(|a, b| {
+                snapshot.sstables[a]
+                    .first_key()
+                    .cmp(snapshot.sstables[b].first_key())
+            });
+        }
+
+        self.manifest
+            .as_ref()
+            .unwrap()
+            .add_record(&state_lock, ManifestRecord::Ingest(level, sst_ids.clone()))?;
+        snapshot.apply_ingest(level, &sst_ids, flush_to_l0);
+        *self.state.write() = Arc::new(snapshot);
+
+        // later writes should be newer than the ingested keys
+        let max_ts = ssts
+            .iter()
+            .map(|sst| sst.max_ts())
+            .max()
+            .unwrap_or_default();
+        let _write_lock = self.mvcc().write_lock.lock();
+        if max_ts > self.mvcc().latest_commit_ts() {
+            self.mvcc().update_commit_ts(max_ts);
+        }
         Ok(())
     }
 
+    // 0 is L0 (or a tier for each SST), otherwise the id of the level (or of the new tier)
+    fn ingest_level(snapshot: &LsmStorageState, ssts: &[Arc<SsTable>], flush_to_l0: bool) -> usize {
+        let disjoint = ssts
+            .windows(2)
+            .all(|pair| pair[0].last_key().key_ref() < pair[1].first_key().key_ref());
+        if !disjoint {
+            return 0;
+        }
+        if !flush_to_l0 {
+            return ssts[0].sst_id();
+        }
+        let overlaps = |sst_ids: &[usize]| {
+            sst_ids.iter().any(|sst_id| {
+                let table = &snapshot.sstables[sst_id];
+                ssts.iter().any(|sst| {
+                    range_overlap(
+                        Bound::Included(sst.first_key().key_ref()),
+                        Bound::Included(sst.last_key().key_ref()),
+                        table.first_key().key_ref(),
+                        table.last_key().key_ref(),
+                    )
+                })
+            })
+        };
+        let mut level = 0;
+        for (level_id, sst_ids) in snapshot.levels.iter() {
+            if overlaps(sst_ids) {
+                break;
+            }
+            level = *level_id;
+        }
+        level
+    }
+
     /// Force flush the earliest-created immutable memtable to disk
+    /// Flushes the memtables with writes that skipped the WAL, along with the memtables older
+    /// than them.
+    pub(crate) fn flush_unlogged_memtables(&self) -> Result<()> {
+        {
+            let state_lock = self.state_lock.lock();
+            if self.state.read().memtable.has_unlogged_writes() {
+                self.force_freeze_memtable(&state_lock)?;
+            }
+        }
+        while self
+            .state
+            .read()
+            .imm_memtables
+            .iter()
+            .any(|memtable| memtable.has_unlogged_writes())
+        {
+            self.force_flush_next_imm_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the oldest immutable memtable to an SST, or all of them into one with
+    /// `LsmStorageOptions::flush_merge_imm`.
     pub fn force_flush_next_imm_memtable(&self) -> Result<()> {
-        let _state_lock = self.state_lock.lock();
+        let state_lock = self.state_lock.lock();
+        let sst_id = self.flush_next_imm_memtable(&state_lock)?;
+        assert!(sst_id.is_some(), "no imm_memtable!");
+        Ok(())
+    }
 
-        let flush_memtable;
+    /// Freezes the current memtable unless it's empty.
+    pub(crate) fn freeze_memtable(&self) -> Result<()> {
+        let state_lock = self.state_lock.lock();
+        if !self.state.read().memtable.is_empty() {
+            self.force_freeze_memtable(&state_lock)?;
+        }
+        Ok(())
+    }
+
+    /// Freezes the current memtable and flushes it along with the immutable memtables, returning
+    /// the ids of the SSTs built. The memtables frozen by writes meanwhile may not be flushed.
+    pub(crate) fn flush(&self) -> Result<Vec<usize>> {
+        self.freeze_memtable()?;
+        let Some(newest_id) = self
+            .state
+            .read()
+            .imm_memtables
+            .first()
+            .map(|memtable| memtable.id())
+        else {
+            return Ok(Vec::new());
+        };
+        let mut sst_ids = Vec::new();
+        // the state lock is taken for each memtable, so that writers can freeze in between
+        while let Some(sst_id) = self.flush_next_imm_memtable(&self.state_lock.lock())? {
+            sst_ids.push(sst_id);
+            // an SST takes the id of the newest memtable in it
+            if sst_id >= newest_id {
+                break;
+            }
+        }
+        Ok(sst_ids)
+    }
+
+    /// Does the flush of `force_flush_next_imm_memtable`, returning the id of the SST built or
+    /// `None` if there's no immutable memtable.
+    pub(crate) fn flush_next_imm_memtable(
+        &self,
+        _state_lock_observer: &MutexGuard<'_, ()>,
+    ) -> Result<Option<usize>> {
+        // newest first
+        let flush_memtables;
 
         {
             let guard = self.state.read();
-            flush_memtable = guard
-                .imm_memtables
-                .last()
-                .expect("no imm_memtable!")
-                .clone();
+            if guard.imm_memtables.is_empty() {
+                return Ok(None);
+            }
+            flush_memtables = if self.options.flush_merge_imm {
+                guard.imm_memtables.clone()
+            } else {
+                guard.imm_memtables[guard.imm_memtables.len() - 1..].to_vec()
+            };
         }
 
         // generate sstables
-        let mut builder = SsTableBuilder::new(self.options.block_size);
-        flush_memtable.flush(&mut builder)?;
-        let sst_id = flush_memtable.id();
-        let sstable = Arc::new(builder.build(sst_id, None, self.path_of_sst(sst_id))?);
-
-        // update internal state, i.e., l0_sstables, sstables and also remove the
-        // imm_memtables.last()
+        let mut builder = SsTableBuilder::new(self.options.block_size)
+            .with_max_entry_size(self.options.max_entry_size)
+            .with_compression(self.options.compression)
+            .with_bloom_false_positive_rate(self.options.bloom_false_positive_rate)
+            .with_bloom_per_block(self.options.bloom_per_block)
+            .with_prefix_extractor(self.options.prefix_extractor)
+            .with_paranoid_checks(self.options.paranoid_checks)
+            .with_direct_io(self.options.use_direct_io_for_flush_and_compaction)
+            .with_index_partition_len(self.options.index_partition_len)
+            .with_compression_dict_size(self.options.compression_dict_size)
+            .with_encryption(self.options.encryption.clone())
+            .with_mmap(self.options.mmap)
+            .with_file_cache(self.file_cache.clone());
+        if let [flush_memtable] = &flush_memtables[..] {
+            flush_memtable.flush(&mut builder)?;
+        } else {
+            MemTable::flush_merged(&flush_memtables, &mut builder)?;
+        }
+        // the SST takes the id of the newest memtable in it
+        let sst_id = flush_memtables[0].id();
+        let memtable_ids = flush_memtables
+            .iter()
+            .map(|memtable| memtable.id())
+            .collect::<Vec<_>>();
+        let sstable = Arc::new(builder.build(
+            sst_id,
+            Some(self.block_cache.clone()),
+            self.path_of_sst(sst_id),
+        )?);
+        let sst_meta = SstMeta::new(&sstable);
+
+        // update internal state, i.e., l0_sstables, sstables and also remove the flushed
+        // imm_memtables from the end
         {
             let mut guard = self.state.write();
             let mut snapshot = guard.as_ref().clone();
-            let mem = snapshot.imm_memtables.pop().unwrap();
-            assert_eq!(mem.id(), sst_id);
+            for memtable_id in memtable_ids.iter().rev() {
+                let mem = snapshot.imm_memtables.pop().unwrap();
+                assert_eq!(mem.id(), *memtable_id);
+                mem.drop_key_filter();
+            }
             if self.compaction_controller.flush_to_l0() {
                 snapshot.l0_sstables.insert(0, sst_id);
             } else {
@@ -799,14 +2275,102 @@ impl LsmStorageInner {
             // update guard
             *guard = Arc::new(snapshot);
         }
+        self.memtable_flushed.notify_all();
 
         self.sync_dir()?;
         // record the flush behavior into Manifest file.
+        let record = if memtable_ids.len() == 1 {
+            ManifestRecord::Flush(sst_id)
+        } else {
+            ManifestRecord::FlushMerged(memtable_ids.clone(), sst_id)
+        };
         self.manifest
             .as_ref()
             .unwrap()
-            .add_record_when_init(ManifestRecord::Flush(sst_id))?;
-        Ok(())
+            .add_records_when_init(vec![record, ManifestRecord::SstMetas(vec![sst_meta])])?;
+
+        // the memtables are in the SST now, and recovery skips their WALs if we crash before this
+        if self.options.enable_wal {
+            // the WALs are needed until the flush is durable
+            self.manifest.as_ref().unwrap().sync()?;
+            let mut recycled_wals = self.recycled_wals.lock();
+            for memtable_id in memtable_ids {
+                // as many as the memtables that may be waiting for a flush at once
+                if self.options.recycle_wal && recycled_wals.len() < self.options.num_memtable_limit
+                {
+                    recycled_wals.push(memtable_id);
+                } else {
+                    std::fs::remove_file(self.path_of_wal(memtable_id))?;
+                }
+            }
+            drop(recycled_wals);
+            self.sync_dir()?;
+        }
+        Ok(Some(sst_id))
+    }
+
+    /// File counts, sizes and entry statistics of L0 and each level, in that order.
+    pub fn level_stats(&self) -> Vec<LevelStats> {
+        let snapshot = {
+            let guard = self.state.read();
+            Arc::clone(&guard)
+        };
+        let ssts_of = |sst_ids: &[usize]| -> Vec<Arc<SsTable>> {
+            sst_ids
+                .iter()
+                .map(|sst_id| snapshot.sstables[sst_id].clone())
+                .collect()
+        };
+        let mut stats = vec![LevelStats::new(0, &ssts_of(&snapshot.l0_sstables))];
+        for (level, sst_ids) in snapshot.levels.iter() {
+            stats.push(LevelStats::new(*level, &ssts_of(sst_ids)));
+        }
+        stats
+    }
+
+    /// Counts the entries and tombstones of the current and immutable memtables, without reading
+    /// them.
+    pub fn memtable_stats(&self) -> MemtableStats {
+        let snapshot = {
+            let guard = self.state.read();
+            Arc::clone(&guard)
+        };
+        let mut stats = MemtableStats {
+            num_imm_memtables: snapshot.imm_memtables.len(),
+            ..Default::default()
+        };
+        for memtable in std::iter::once(&snapshot.memtable).chain(snapshot.imm_memtables.iter()) {
+            stats.num_entries += memtable.len();
+            stats.num_tombstones += memtable.tombstone_count();
+        }
+        stats
+    }
+
+    /// Roughly how many bytes of the SSTs in L0 and every level hold keys in the range, to a
+    /// block of each SST, see `SsTable::approximate_size_of_range`. The memtables aren't counted.
+    pub fn approximate_range_size(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<u64> {
+        let snapshot = {
+            let guard = self.state.read();
+            Arc::clone(&guard)
+        };
+        let (lower_key, upper_key) = map_key_bound_plus_ts(lower, upper, TS_RANGE_BEGIN);
+        let mut size = 0;
+        let levels = snapshot.levels.iter().map(|(_, sst_ids)| sst_ids);
+        for sst_id in std::iter::once(&snapshot.l0_sstables)
+            .chain(levels)
+            .flatten()
+        {
+            let sstable = &snapshot.sstables[sst_id];
+            if range_overlap(
+                lower,
+                upper,
+                sstable.first_key().key_ref(),
+                sstable.last_key().key_ref(),
+            ) {
+                size += sstable.approximate_size_of_range(lower_key, upper_key)?;
+            }
+        }
+        Ok(size)
     }
 
     pub fn new_txn(self: &Arc<Self>) -> Result<Arc<Transaction>> {
@@ -824,13 +2388,25 @@ impl LsmStorageInner {
         txn.scan(_lower, _upper)
     }
 
+    pub fn scan_with_options(
+        self: &Arc<Self>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        options: &ReadOptions,
+    ) -> Result<TxnIterator> {
+        let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+        txn.scan_with_options(lower, upper, options)
+    }
+
     /// Create an iterator over a range of keys.
     pub(crate) fn scan_with_ts(
         &self,
         _lower: Bound<&[u8]>,
         _upper: Bound<&[u8]>,
         read_ts: u64,
+        options: &ReadOptions,
     ) -> Result<FusedIterator<LsmIterator>> {
+        let verify_checksums = self.verify_checksums(options);
         let snapshot = {
             let guard = self.state.read();
             Arc::clone(&guard)
@@ -855,17 +2431,23 @@ impl LsmStorageInner {
                 _upper,
                 sstable.first_key().key_ref(),
                 sstable.last_key().key_ref(),
-            ) {
+            ) && sstable.has_versions_visible_at(read_ts)
+                && sstable.may_contain_prefix(_lower, _upper)
+            {
                 // ensure the _lower for the sstables;
                 let iter = match _lower {
-                    Bound::Included(key) => SsTableIterator::create_and_seek_to_key(
+                    Bound::Included(key) => SsTableIterator::create_and_seek_to_key_with_ts(
                         sstable,
                         KeySlice::from_slice(key, TS_RANGE_BEGIN),
+                        read_ts,
+                        verify_checksums,
                     )?,
                     Bound::Excluded(key) => {
-                        let mut temp_iter = SsTableIterator::create_and_seek_to_key(
+                        let mut temp_iter = SsTableIterator::create_and_seek_to_key_with_ts(
                             sstable,
                             KeySlice::from_slice(key, TS_RANGE_BEGIN),
+                            read_ts,
+                            verify_checksums,
                         )?;
                         // we will have mutliple same keys (with different ts)
                         while temp_iter.is_valid() && temp_iter.key().key_ref() == key {
@@ -873,7 +2455,12 @@ impl LsmStorageInner {
                         }
                         temp_iter
                     }
-                    Bound::Unbounded => SsTableIterator::create_and_seek_to_first(sstable)?,
+                    Bound::Unbounded => {
+                        let mut iter =
+                            SsTableIterator::new(sstable, true, TS_RANGE_BEGIN, verify_checksums);
+                        iter.seek_to_first()?;
+                        iter
+                    }
                 };
                 l0_sst_iters.push(Box::new(iter));
             }
@@ -889,19 +2476,27 @@ impl LsmStorageInner {
                     _upper,
                     sstable.first_key().key_ref(),
                     sstable.last_key().key_ref(),
-                ) {
+                ) && sstable.has_versions_visible_at(read_ts)
+                    && sstable.may_contain_prefix(_lower, _upper)
+                {
                     ssts_to_concat.push(sstable.clone());
                 }
             }
+            // no table in this level overlaps with the range
+            if ssts_to_concat.is_empty() {
+                continue;
+            }
             let iter = match _lower {
-                Bound::Included(key) => SstConcatIterator::create_and_seek_to_key(
+                Bound::Included(key) => SstConcatIterator::create_and_seek_to_key_with_checksums(
                     ssts_to_concat,
                     KeySlice::from_slice(key, TS_RANGE_BEGIN),
+                    verify_checksums,
                 )?,
                 Bound::Excluded(key) => {
-                    let mut temp_iter = SstConcatIterator::create_and_seek_to_key(
+                    let mut temp_iter = SstConcatIterator::create_and_seek_to_key_with_checksums(
                         ssts_to_concat,
                         KeySlice::from_slice(key, TS_RANGE_BEGIN),
+                        verify_checksums,
                     )?;
                     // we will have mutliple same keys (with different ts)
                     while temp_iter.is_valid() && temp_iter.key().key_ref() == key {
@@ -909,7 +2504,10 @@ impl LsmStorageInner {
                     }
                     temp_iter
                 }
-                Bound::Unbounded => SstConcatIterator::create_and_seek_to_first(ssts_to_concat)?,
+                Bound::Unbounded => SstConcatIterator::create_and_seek_to_first_with_checksums(
+                    ssts_to_concat,
+                    verify_checksums,
+                )?,
             };
             iters_after_l0.push(Box::new(iter));
         }
@@ -935,6 +2533,7 @@ impl LsmStorageInner {
             iter,
             map_bound(_upper),
             read_ts,
+            self.value_log.clone(),
         )?))
     }
 }
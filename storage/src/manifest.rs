@@ -13,9 +13,10 @@
 // limitations under the License.
 
 use std::fs::OpenOptions;
-use std::io::Read;
-use std::path::Path;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::{fs::File, io::Write};
 
 use anyhow::{Context, Result, bail};
@@ -23,65 +24,825 @@ use bytes::{Buf, BufMut};
 use parking_lot::{Mutex, MutexGuard};
 use serde::{Deserialize, Serialize};
 
-use crate::compact::CompactionTask;
+use crate::block::SIZEOF_U32;
+use crate::compact::{CompactionOptions, CompactionTask};
+use crate::lsm_storage::LsmStorageOptions;
+use crate::table::SsTable;
+
+mod binary;
+
+/// How the records of a manifest are encoded, given by the byte the file starts with. Either way a
+/// record is framed by its length and checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    /// serde JSON, also what a manifest written before the format byte existed is in.
+    Json,
+    /// Varint encoded fields, see `binary`.
+    Binary,
+}
+
+impl ManifestFormat {
+    /// The format byte of a manifest whose records start with their type and version, and whose
+    /// checksums are chained.
+    fn header(self) -> u8 {
+        match self {
+            ManifestFormat::Json => 5,
+            ManifestFormat::Binary => 6,
+        }
+    }
+
+    /// The format of a manifest starting with `buf`, whether its records start with their type and
+    /// version, whether their checksums are chained, see `encode_record`, and the bytes of its
+    /// header. A manifest without the format byte starts with the length of its first JSON record,
+    /// whose first byte is 0. Those with format bytes 1 and 2 were written before records had a
+    /// type and version, and those up to 4 before checksums were chained, and are appended to the
+    /// way they were written.
+    fn detect(buf: &[u8]) -> Result<(Self, bool, bool, usize)> {
+        match buf.first() {
+            None | Some(0) => Ok((ManifestFormat::Json, false, false, 0)),
+            Some(1) => Ok((ManifestFormat::Json, false, false, 1)),
+            Some(2) => Ok((ManifestFormat::Binary, false, false, 1)),
+            Some(3) => Ok((ManifestFormat::Json, true, false, 1)),
+            Some(4) => Ok((ManifestFormat::Binary, true, false, 1)),
+            Some(5) => Ok((ManifestFormat::Json, true, true, 1)),
+            Some(6) => Ok((ManifestFormat::Binary, true, true, 1)),
+            Some(header) => bail!("unknown manifest format {}", header),
+        }
+    }
+}
+
+/// When the records added to a manifest are synced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestSyncMode {
+    /// Each record is written and synced as it's added.
+    Always,
+    /// Records are buffered in memory, and written and synced along with the next durability
+    /// critical one, a flush, compaction or ingest, or by `Manifest::sync`. A crash loses the
+    /// buffered ones.
+    Batched,
+}
+
+const RECORD_FLUSH: u8 = 0;
+const RECORD_NEW_MEMTABLE: u8 = 1;
+const RECORD_COMPACTION: u8 = 2;
+const RECORD_INGEST: u8 = 3;
+const RECORD_FLUSH_MERGED: u8 = 4;
+const RECORD_SNAPSHOT: u8 = 5;
+const RECORD_DELETE_SSTS: u8 = 6;
+const RECORD_OPTIONS: u8 = 7;
+const RECORD_SST_METAS: u8 = 8;
+const RECORD_WATERMARK: u8 = 9;
+const RECORD_COMPACTION_INTENT: u8 = 10;
+/// Set in the type of a record that a reader which doesn't know the type can skip, because the
+/// state recovered without it is still right.
+const RECORD_OPTIONAL: u8 = 0x80;
+/// The version of the records written, bumped for a type when its fields change. A record of a
+/// newer version than this is read like one of an unknown type.
+const RECORD_VERSION: u8 = 1;
 
 pub struct Manifest {
     file: Arc<Mutex<File>>,
+    dir: PathBuf,
+    /// The sequence number of the file, see `Manifest::path_of`.
+    seq: AtomicU64,
+    format: ManifestFormat,
+    /// Whether the records start with their type and version, see `ManifestFormat::detect`. Always
+    /// once rolled over.
+    tagged: AtomicBool,
+    /// Whether the checksums of the records are chained, see `encode_record`. Always once rolled
+    /// over.
+    chained: AtomicBool,
+    /// The checksum of the last record encoded, which that of the next one chains.
+    last_checksum: AtomicU32,
+    /// Bytes of the records in the file.
+    len: AtomicU64,
+    /// Bytes of the snapshot the file starts with, 0 if it doesn't.
+    snapshot_len: AtomicU64,
+    sync_mode: ManifestSyncMode,
+    /// The encoded records not written yet, with `ManifestSyncMode::Batched`. Locked after `file`.
+    unsynced: Mutex<Vec<u8>>,
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The step of `Manifest::roll_over` that fails right after it's done, to test a crash in
+    /// between.
+    pub(crate) static ROLL_OVER_FAIL_AFTER: std::cell::Cell<Option<usize>> =
+        const { std::cell::Cell::new(None) };
 }
 
-#[derive(Serialize, Deserialize)]
+/// The state the records of a manifest added up to when it was rolled over, see
+/// `Manifest::roll_over`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestSnapshot {
+    /// The memtables not flushed yet, oldest first, the last one being the current memtable.
+    pub memtables: Vec<usize>,
+    /// The flushed memtables whose WAL files may be left, to remove on recovery.
+    pub flushed: Vec<usize>,
+    pub l0_sstables: Vec<usize>,
+    pub levels: Vec<(usize, Vec<usize>)>,
+    /// The largest id of a memtable or SST so far.
+    pub max_id: usize,
+    /// The SSTs compacted away whose files may be left, see `ManifestRecord::DeleteSsts`.
+    #[serde(default)]
+    pub pending_delete: Vec<usize>,
+    /// The options last recorded, see `ManifestRecord::Options`.
+    #[serde(default)]
+    pub options: Option<PersistedOptions>,
+    /// The key ranges and sizes of the SSTs, see `ManifestRecord::SstMetas`.
+    #[serde(default)]
+    pub sst_metas: Vec<SstMeta>,
+    /// The watermark last recorded, see `ManifestRecord::Watermark`.
+    #[serde(default)]
+    pub watermark: Option<u64>,
+    /// The output ids reserved by the compaction running, see `ManifestRecord::CompactionIntent`.
+    #[serde(default)]
+    pub reserved: Vec<usize>,
+}
+
+/// The key range and size of an SST, which recovery opens the SST lazily with, see
+/// `SsTable::open_lazily`. Also the largest timestamp in it, to recover the latest commit from,
+/// and the key it's encrypted with, to refuse to open it without that key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SstMeta {
+    pub sst_id: usize,
+    pub first_key: Vec<u8>,
+    pub first_ts: u64,
+    pub last_key: Vec<u8>,
+    pub last_ts: u64,
+    pub size: u64,
+    pub max_ts: u64,
+    pub encryption_key_id: Option<u32>,
+}
+
+impl SstMeta {
+    pub fn new(sst: &SsTable) -> Self {
+        Self {
+            sst_id: sst.sst_id(),
+            first_key: sst.first_key().key_ref().to_vec(),
+            first_ts: sst.first_key().ts(),
+            last_key: sst.last_key().key_ref().to_vec(),
+            last_ts: sst.last_key().ts(),
+            size: sst.table_size(),
+            max_ts: sst.max_ts(),
+            encryption_key_id: sst.encryption_key_id(),
+        }
+    }
+}
+
+/// The `LsmStorageOptions` that the state in the manifest and the files depend on. The block cache
+/// and the other options only matter while the DB is open, so they aren't recorded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistedOptions {
+    pub compaction_options: CompactionOptions,
+    pub block_size: usize,
+    pub target_sst_size: usize,
+    pub enable_wal: bool,
+    pub serializable: bool,
+}
+
+impl PersistedOptions {
+    pub fn new(options: &LsmStorageOptions) -> Self {
+        Self {
+            compaction_options: options.compaction_options.clone(),
+            block_size: options.block_size,
+            target_sst_size: options.target_sst_size,
+            enable_wal: options.enable_wal,
+            serializable: options.serializable,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub enum ManifestRecord {
     Flush(usize),
     NewMemtable(usize),
     Compaction(CompactionTask, Vec<usize>),
+    /// External SSTs added by `ingest_external_sst`, see `LsmStorageState::apply_ingest`.
+    Ingest(usize, Vec<usize>),
+    /// Memtables flushed together into the SST with the given id, see
+    /// `LsmStorageOptions::flush_merge_imm`.
+    FlushMerged(Vec<usize>, usize),
+    /// The state to replay the records after it from, instead of the records before it.
+    Snapshot(ManifestSnapshot),
+    /// The files of SSTs a compaction removed from the state are unlinked. Until then recovery
+    /// removes them along with the orphan SSTs.
+    DeleteSsts(Vec<usize>),
+    /// The options the DB was created or last opened with, see `LsmStorageInner::check_options`.
+    Options(PersistedOptions),
+    /// The SSTs built by the flush or compaction recorded right before. An SST recovered without
+    /// its meta is opened right away.
+    SstMetas(Vec<SstMeta>),
+    /// The MVCC watermark, which compaction keeps the versions visible at from then on, even
+    /// after recovery, see `LsmStorageOptions::watermark_record_interval`.
+    Watermark(u64),
+    /// A compaction started, with the ids reserved for its output SSTs, before any of them is
+    /// built. Until the `Compaction` record of its result, recovery removes their files, and the
+    /// ids aren't reused. A compaction that runs out of ids reserves more with another one.
+    CompactionIntent(CompactionTask, Vec<usize>),
 }
 
-impl Manifest {
-    pub fn create(_path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self {
-            file: Arc::new(Mutex::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(_path)
-                    .context("failed to create Manifest file")?,
-            )),
-        })
+impl ManifestRecord {
+    /// The type of the record, with `RECORD_OPTIONAL` if it can be skipped.
+    fn record_type(&self) -> u8 {
+        match self {
+            ManifestRecord::Flush(_) => RECORD_FLUSH,
+            ManifestRecord::NewMemtable(_) => RECORD_NEW_MEMTABLE,
+            ManifestRecord::Compaction(..) => RECORD_COMPACTION,
+            ManifestRecord::Ingest(..) => RECORD_INGEST,
+            ManifestRecord::FlushMerged(..) => RECORD_FLUSH_MERGED,
+            ManifestRecord::Snapshot(_) => RECORD_SNAPSHOT,
+            // the orphan SSTs are removed on recovery without it
+            ManifestRecord::DeleteSsts(_) => RECORD_DELETE_SSTS | RECORD_OPTIONAL,
+            // only checks the options
+            ManifestRecord::Options(_) => RECORD_OPTIONS | RECORD_OPTIONAL,
+            // the SSTs without it are opened on recovery
+            ManifestRecord::SstMetas(_) => RECORD_SST_METAS | RECORD_OPTIONAL,
+            // compaction keeps more versions with it
+            ManifestRecord::Watermark(_) => RECORD_WATERMARK | RECORD_OPTIONAL,
+            // the output files are removed as orphans without it
+            ManifestRecord::CompactionIntent(..) => RECORD_COMPACTION_INTENT | RECORD_OPTIONAL,
+        }
     }
 
-    pub fn recover(_path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
-        let mut file = OpenOptions::new()
+    /// Whether the record is synced as soon as it's added with `ManifestSyncMode::Batched`: the
+    /// results of flushes, compactions and ingests, which files are removed or made visible after,
+    /// and the intents of compactions, which files are written after.
+    fn is_durability_critical(&self) -> bool {
+        matches!(
+            self,
+            ManifestRecord::Flush(_)
+                | ManifestRecord::FlushMerged(..)
+                | ManifestRecord::Compaction(..)
+                | ManifestRecord::Ingest(..)
+                | ManifestRecord::CompactionIntent(..)
+        )
+    }
+}
+
+// | len | type | version | JSON or binary record | checksum |, with the type and version only if
+// `tagged`. The checksum of a record is the CRC32 of the record from `chain`, the checksum of the
+// record before, if it's `Some`, and 0 for the first one, so that a record only matches its
+// checksum after the records it followed when it was written.
+fn encode_record(
+    record: &ManifestRecord,
+    format: ManifestFormat,
+    tagged: bool,
+    chain: Option<u32>,
+    buf: &mut Vec<u8>,
+) -> Result<u32> {
+    let mut encoded = Vec::new();
+    if tagged {
+        encoded.put_u8(record.record_type());
+        encoded.put_u8(RECORD_VERSION);
+    }
+    match format {
+        ManifestFormat::Json => serde_json::to_writer(&mut encoded, record)?,
+        ManifestFormat::Binary => {
+            if !tagged {
+                encoded.put_u8(record.record_type() & !RECORD_OPTIONAL);
+            }
+            binary::encode(record, &mut encoded);
+        }
+    }
+    let checksum = record_checksum(chain, &encoded);
+    buf.put_u32(encoded.len() as u32);
+    buf.put(&encoded[..]);
+    buf.put_u32(checksum);
+    Ok(checksum)
+}
+
+fn record_checksum(chain: Option<u32>, raw_record: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(chain.unwrap_or(0));
+    hasher.update(raw_record);
+    hasher.finalize()
+}
+
+/// The offset of a whole record in `buf` after `offset` whose checksum chains the 4 bytes before
+/// it, which are those of the record before, if there's one.
+fn find_chained_record(buf: &[u8], offset: usize) -> Option<usize> {
+    (offset + 1..buf.len()).find(|start| {
+        let Some(chain) = start.checked_sub(SIZEOF_U32) else {
+            return false;
+        };
+        let mut rbuf = &buf[*start..];
+        if rbuf.remaining() < SIZEOF_U32 {
+            return false;
+        }
+        let record_len = rbuf.get_u32() as usize;
+        if record_len == 0 || rbuf.remaining() < record_len + SIZEOF_U32 {
+            return false;
+        }
+        let chain = (&buf[chain..*start]).get_u32();
+        record_checksum(Some(chain), &rbuf[..record_len]) == (&rbuf[record_len..]).get_u32()
+    })
+}
+
+/// Decodes a record whose checksum matched, `None` if it's of a type or version this doesn't know
+/// but optional.
+fn decode_record(
+    mut raw_record: &[u8],
+    format: ManifestFormat,
+    tagged: bool,
+) -> Result<Option<ManifestRecord>> {
+    let record_type = if tagged {
+        if raw_record.len() < 2 {
+            bail!("record is too short for its type and version");
+        }
+        let record_type = raw_record.get_u8();
+        let version = raw_record.get_u8();
+        if record_type & !RECORD_OPTIONAL > RECORD_COMPACTION_INTENT || version > RECORD_VERSION {
+            if record_type & RECORD_OPTIONAL != 0 {
+                return Ok(None);
+            }
+            bail!(
+                "record of type {} version {} is required, but written by a newer version",
+                record_type,
+                version
+            );
+        }
+        Some(record_type & !RECORD_OPTIONAL)
+    } else {
+        None
+    };
+    let record = match format {
+        ManifestFormat::Json => serde_json::from_slice(raw_record)?,
+        ManifestFormat::Binary => {
+            let record_type = match record_type {
+                Some(record_type) => record_type,
+                None if raw_record.has_remaining() => raw_record.get_u8(),
+                None => bail!("record ends early"),
+            };
+            binary::decode(record_type, raw_record)?
+        }
+    };
+    Ok(Some(record))
+}
+
+/// Fails the step of `Manifest::roll_over` if a test asked it to.
+fn roll_over_step_done(_step: usize) -> Result<()> {
+    #[cfg(test)]
+    if ROLL_OVER_FAIL_AFTER.with(|step| step.get()) == Some(_step) {
+        bail!("failed after step {} of rolling the manifest over", _step);
+    }
+    Ok(())
+}
+
+/// The records of a manifest being recovered, read through a buffer one at a time, each with its
+/// offset. An error ends them.
+pub struct ManifestRecords {
+    reader: BufReader<File>,
+    dir: PathBuf,
+    seq: u64,
+    format: ManifestFormat,
+    tagged: bool,
+    chained: bool,
+    file_len: usize,
+    /// The offset of the next record.
+    offset: usize,
+    last_checksum: u32,
+    /// Whether a record was read yet, skipped ones aside.
+    read_any: bool,
+    snapshot_len: u64,
+    /// The offset of the record a crash left partly written, if there's one.
+    torn: Option<usize>,
+    done: bool,
+}
+
+impl ManifestRecords {
+    fn open(dir: &Path, seq: u64) -> Result<Self> {
+        let file = OpenOptions::new()
             .read(true)
-            .append(true)
-            .open(_path)
+            .write(true)
+            .open(Manifest::path_of(dir, seq))
             .context("failed to recover Manifest file")?;
+        let file_len = file.metadata()?.len() as usize;
+        let mut reader = BufReader::new(file);
+        let (format, tagged, chained, header_len) = ManifestFormat::detect(reader.fill_buf()?)?;
+        reader.consume(header_len);
+        Ok(Self {
+            reader,
+            dir: dir.to_path_buf(),
+            seq,
+            format,
+            tagged,
+            chained,
+            file_len,
+            offset: header_len,
+            last_checksum: 0,
+            read_any: false,
+            snapshot_len: 0,
+            torn: None,
+            done: false,
+        })
+    }
+
+    /// Reads into `buf` until it's full or the file ends, and gives the bytes read.
+    fn read_up_to(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.reader.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(read)
+    }
+
+    /// The next record, `None` once the whole ones are read.
+    fn read_record(&mut self) -> Result<Option<(usize, ManifestRecord)>> {
+        loop {
+            let offset = self.offset;
+            let mut len_buf = [0; SIZEOF_U32];
+            match self.read_up_to(&mut len_buf)? {
+                0 => return Ok(None),
+                SIZEOF_U32 => {}
+                // a crash in the middle of `add_record` leaves the last record cut short, or with
+                // the bytes of its checksum not all written
+                _ => {
+                    self.torn = Some(offset);
+                    return Ok(None);
+                }
+            }
+            let record_len = (&len_buf[..]).get_u32() as usize;
+            let end = offset + SIZEOF_U32 + record_len + SIZEOF_U32;
+            if end > self.file_len {
+                self.torn = Some(offset);
+                return Ok(None);
+            }
+            let mut raw_record = vec![0; record_len + SIZEOF_U32];
+            if self.read_up_to(&mut raw_record)? < raw_record.len() {
+                // the file shrank underneath
+                self.torn = Some(offset);
+                return Ok(None);
+            }
+            self.offset = end;
+            let checksum = (&raw_record[record_len..]).get_u32();
+            let raw_record = &raw_record[..record_len];
+            let chain = self.chained.then_some(self.last_checksum);
+            if checksum != record_checksum(chain, raw_record) {
+                if end == self.file_len {
+                    self.torn = Some(offset);
+                    return Ok(None);
+                }
+                bail!("checksum doesn't match at offset {}!", offset);
+            }
+            self.last_checksum = checksum;
+            let Some(record) = decode_record(raw_record, self.format, self.tagged)
+                .with_context(|| format!("failed to decode the record at offset {}", offset))?
+            else {
+                println!(
+                    "warning: skipping the optional record of an unknown type at offset {}",
+                    offset
+                );
+                continue;
+            };
+            if !self.read_any && matches!(record, ManifestRecord::Snapshot(_)) {
+                self.snapshot_len = end as u64;
+            }
+            self.read_any = true;
+            return Ok(Some((offset, record)));
+        }
+    }
+
+    /// Reads the records not iterated yet, and checks that the one a crash left partly written,
+    /// if there's one, is the last. Gives the number of records read.
+    fn validate(&mut self) -> Result<usize> {
+        let mut count = 0;
+        for record in self.by_ref() {
+            record?;
+            count += 1;
+        }
+        if let Some(offset) = self.torn
+            && self.chained
+        {
+            // a crash only tears the last write, so a whole record after the torn one means that
+            // the records in between were lost, and the state they add up to with them; only the
+            // bytes from the torn one on are read, with those of the checksum it chains
+            let base = offset.saturating_sub(SIZEOF_U32);
+            let file = self.reader.get_mut();
+            let mut tail = Vec::new();
+            file.seek(SeekFrom::Start(base as u64))?;
+            file.read_to_end(&mut tail)?;
+            if let Some(start) = find_chained_record(&tail, offset - base) {
+                bail!(
+                    "the manifest has a hole at offset {}, before the record at offset {}",
+                    offset,
+                    base + start
+                );
+            }
+        }
+        Ok(count)
+    }
+
+    /// Reads the records not iterated yet, truncates the one a crash left partly written, and
+    /// gives the manifest, which the records from now on are appended to.
+    pub fn finish(mut self) -> Result<Manifest> {
+        self.validate()?;
+        let mut file = self.reader.into_inner();
+        if let Some(offset) = self.torn {
+            Manifest::truncate_torn_record(&file, offset)?;
+        }
+        let validated_len = self.torn.unwrap_or(self.file_len) as u64;
+        // the records from now on follow the last whole one
+        file.seek(SeekFrom::Start(validated_len))?;
+
+        Ok(Manifest {
+            file: Arc::new(Mutex::new(file)),
+            dir: self.dir,
+            seq: AtomicU64::new(self.seq),
+            format: self.format,
+            tagged: AtomicBool::new(self.tagged),
+            chained: AtomicBool::new(self.chained),
+            last_checksum: AtomicU32::new(self.last_checksum),
+            len: AtomicU64::new(validated_len),
+            snapshot_len: AtomicU64::new(self.snapshot_len),
+            sync_mode: ManifestSyncMode::Always,
+            unsynced: Mutex::new(Vec::new()),
+        })
+    }
+}
 
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
-
-        let mut records = Vec::new();
-        let mut rbuf = buf.as_slice();
-        while rbuf.has_remaining() {
-            let record_len = rbuf.get_u32() as usize;
-            let raw_record = &rbuf[..record_len];
-            let record: ManifestRecord = serde_json::from_slice(raw_record)?;
-            rbuf.advance(record_len);
-            let checksum = rbuf.get_u32();
-            if checksum != crc32fast::hash(raw_record) {
-                bail!("checksum doesn't match!");
+impl Iterator for ManifestRecords {
+    type Item = Result<(usize, ManifestRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.read_record().transpose();
+        if !matches!(record, Some(Ok(_))) {
+            self.done = true;
+        }
+        record
+    }
+}
+
+/// The file holding the name of the manifest in use.
+const CURRENT: &str = "CURRENT";
+
+impl Manifest {
+    /// The path of the manifest with sequence number `seq`. 0 is the `MANIFEST` of a directory
+    /// created before manifests were rotated.
+    pub fn path_of(dir: impl AsRef<Path>, seq: u64) -> PathBuf {
+        if seq == 0 {
+            return dir.as_ref().join("MANIFEST");
+        }
+        dir.as_ref().join(format!("MANIFEST-{:06}", seq))
+    }
+
+    fn seq_of(name: &str) -> Option<u64> {
+        if name == "MANIFEST" {
+            return Some(0);
+        }
+        name.strip_prefix("MANIFEST-")?.parse().ok()
+    }
+
+    /// The sequence numbers of the manifests in the directory, in ascending order.
+    fn list(dir: &Path) -> Result<Vec<u64>> {
+        let mut seqs = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            if let Some(seq) = entry?.file_name().to_str().and_then(Self::seq_of) {
+                seqs.push(seq);
             }
-            records.push(record);
         }
+        seqs.sort();
+        Ok(seqs)
+    }
 
+    /// Whether the directory has a manifest to recover.
+    pub fn exists(dir: impl AsRef<Path>) -> Result<bool> {
+        let dir = dir.as_ref();
+        Ok(dir.join(CURRENT).exists() || !Self::list(dir)?.is_empty())
+    }
+
+    /// The sequence number of the manifest `CURRENT` names, `None` if there's no `CURRENT`.
+    fn current_seq(dir: &Path) -> Result<Option<u64>> {
+        if !dir.join(CURRENT).exists() {
+            return Ok(None);
+        }
+        let name = std::fs::read_to_string(dir.join(CURRENT)).context("failed to read CURRENT")?;
+        match Self::seq_of(name.trim_end()) {
+            Some(seq) => Ok(Some(seq)),
+            None => bail!("CURRENT names {:?}, which isn't a manifest", name),
+        }
+    }
+
+    /// The path of the manifest `CURRENT` names.
+    pub fn current_path(dir: impl AsRef<Path>) -> Result<PathBuf> {
+        let dir = dir.as_ref();
+        match Self::current_seq(dir)? {
+            Some(seq) => Ok(Self::path_of(dir, seq)),
+            None => bail!("no CURRENT in {}", dir.display()),
+        }
+    }
+
+    /// Points `CURRENT` at the manifest with sequence number `seq`: writes the name to a temporary
+    /// file and syncs it, then renames it over `CURRENT`. The switch is durable once the directory
+    /// is synced.
+    fn set_current(dir: &Path, seq: u64) -> Result<()> {
+        let temp_path = dir.join(format!("{}.tmp", CURRENT));
+        let mut file = File::create(&temp_path).context("failed to create CURRENT")?;
+        let path = Self::path_of(dir, seq);
+        writeln!(file, "{}", path.file_name().unwrap().to_str().unwrap())?;
+        file.sync_all()?;
+        roll_over_step_done(2)?;
+        std::fs::rename(&temp_path, dir.join(CURRENT)).context("failed to update CURRENT")?;
+        roll_over_step_done(3)
+    }
+
+    /// Creates a manifest whose records are encoded in `format` in the directory, and points
+    /// `CURRENT` at it.
+    pub fn create(dir: impl AsRef<Path>, format: ManifestFormat) -> Result<Self> {
+        let dir = dir.as_ref();
+        let path = Self::path_of(dir, 1);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .create_new(true)
+            .write(true)
+            .open(&path)
+            .context("failed to create Manifest file")?;
+        file.write_all(&[format.header()])?;
+        file.sync_all()?;
+        Self::set_current(dir, 1)?;
+        File::open(dir)?.sync_all()?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            dir: dir.to_path_buf(),
+            seq: AtomicU64::new(1),
+            format,
+            tagged: AtomicBool::new(true),
+            chained: AtomicBool::new(true),
+            last_checksum: AtomicU32::new(0),
+            len: AtomicU64::new(1),
+            snapshot_len: AtomicU64::new(0),
+            sync_mode: ManifestSyncMode::Always,
+            unsynced: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Recovers the manifest `CURRENT` names, and removes the other manifests, which a roll over
+    /// that crashed left behind. If `CURRENT` is missing or names a manifest that doesn't exist,
+    /// the newest manifest with a whole record is recovered instead, and `CURRENT` pointed at it.
+    pub fn recover(dir: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
+        let (manifest, records) = Self::recover_with_offsets(dir)?;
         Ok((
-            Self {
-                file: Arc::new(Mutex::new(file)),
-            },
-            records,
+            manifest,
+            records.into_iter().map(|(_, record)| record).collect(),
         ))
     }
 
+    /// Like `recover`, with the offset in the manifest of each record.
+    pub fn recover_with_offsets(
+        dir: impl AsRef<Path>,
+    ) -> Result<(Self, Vec<(usize, ManifestRecord)>)> {
+        let mut recovered = Self::recover_iter(dir)?;
+        let records = recovered.by_ref().collect::<Result<Vec<_>>>()?;
+        Ok((recovered.finish()?, records))
+    }
+
+    /// Like `recover`, but the records are read one at a time as they're iterated, along with
+    /// their offsets, and `ManifestRecords::finish` then gives the manifest.
+    pub fn recover_iter(dir: impl AsRef<Path>) -> Result<ManifestRecords> {
+        let dir = dir.as_ref();
+        // left by a roll over that crashed before renaming it, so `CURRENT` still names the old
+        // manifest, which is still there
+        let temp_path = dir.join(format!("{}.tmp", CURRENT));
+        if temp_path.exists() {
+            std::fs::remove_file(&temp_path)?;
+        }
+        let seqs = Self::list(dir)?;
+        let seq = match Self::current_seq(dir)? {
+            Some(seq) if seqs.contains(&seq) => seq,
+            _ => {
+                let mut recovered = None;
+                for seq in seqs.iter().rev() {
+                    // read through to check it's whole, without truncating a torn record
+                    let Ok(count) =
+                        ManifestRecords::open(dir, *seq).and_then(|mut records| records.validate())
+                    else {
+                        continue;
+                    };
+                    let complete = count > 0;
+                    if complete || recovered.is_none() {
+                        recovered = Some(*seq);
+                    }
+                    if complete {
+                        break;
+                    }
+                }
+                let Some(seq) = recovered else {
+                    bail!("no manifest to recover in {}", dir.display());
+                };
+                println!(
+                    "warning: CURRENT is missing or dangling, recovering {}",
+                    Self::path_of(dir, seq).display()
+                );
+                Self::set_current(dir, seq)?;
+                File::open(dir)?.sync_all()?;
+                seq
+            }
+        };
+        for stale in seqs.into_iter().filter(|stale| *stale != seq) {
+            std::fs::remove_file(Self::path_of(dir, stale))?;
+            println!(
+                "removed stale manifest {}",
+                Self::path_of(dir, stale).display()
+            );
+        }
+        ManifestRecords::open(dir, seq)
+    }
+
+    /// Drops the last record of the manifest, from `offset`, which a crash left partly written, so
+    /// that the records appended from now on follow the last whole one.
+    fn truncate_torn_record(file: &File, offset: usize) -> Result<()> {
+        println!(
+            "warning: truncating the partly written record at offset {} of the manifest",
+            offset
+        );
+        file.set_len(offset as u64)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    pub fn with_sync_mode(mut self, sync_mode: ManifestSyncMode) -> Self {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    /// The format of the records in the manifest.
+    pub fn format(&self) -> ManifestFormat {
+        self.format
+    }
+
+    /// The bytes of the manifest that `recover` validated, which the records from then on are
+    /// written after, and those written since.
+    pub fn validated_len(&self) -> u64 {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Whether the manifest grew past `max_size`, or twice the size of the snapshot it starts with
+    /// if that's larger, so that a large state isn't rolled over again on every record.
+    pub fn should_roll_over(&self, max_size: u64) -> bool {
+        let len = self.len.load(Ordering::Relaxed);
+        len > max_size && len > 2 * self.snapshot_len.load(Ordering::Relaxed)
+    }
+
+    /// Replaces the manifest with one that starts with `snapshot`, which must be the state all the
+    /// records so far add up to. The new manifest, with the next sequence number, is written and
+    /// synced, then `CURRENT` switched to it, and the old one removed once that's durable. A crash
+    /// before `CURRENT` is renamed leaves it naming the old manifest, and one after it the new
+    /// one; `recover` removes the other. The new manifest keeps the format of the old one.
+    pub fn roll_over(
+        &self,
+        _state_lock_observer: &MutexGuard<()>,
+        snapshot: ManifestSnapshot,
+    ) -> Result<()> {
+        let mut encoded = vec![self.format.header()];
+        let checksum = encode_record(
+            &ManifestRecord::Snapshot(snapshot),
+            self.format,
+            true,
+            Some(0),
+            &mut encoded,
+        )?;
+        let mut file = self.file.lock();
+        // the snapshot covers the records not written yet
+        self.unsynced.lock().clear();
+        let old_seq = self.seq.load(Ordering::Relaxed);
+        let new_seq = old_seq + 1;
+
+        let mut new_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(Self::path_of(&self.dir, new_seq))
+            .context("failed to create Manifest file")?;
+        new_file.write_all(&encoded)?;
+        new_file.sync_all()?;
+        roll_over_step_done(1)?;
+
+        Self::set_current(&self.dir, new_seq)?;
+        // `CURRENT` names the new file, so the records from now on must go to it
+        *file = new_file;
+        self.seq.store(new_seq, Ordering::Relaxed);
+        self.tagged.store(true, Ordering::Relaxed);
+        self.chained.store(true, Ordering::Relaxed);
+        self.last_checksum.store(checksum, Ordering::Relaxed);
+        self.len.store(encoded.len() as u64, Ordering::Relaxed);
+        self.snapshot_len
+            .store(encoded.len() as u64, Ordering::Relaxed);
+
+        File::open(&self.dir)?.sync_all()?;
+        roll_over_step_done(4)?;
+        std::fs::remove_file(Self::path_of(&self.dir, old_seq))?;
+        roll_over_step_done(5)?;
+        Ok(())
+    }
+
     pub fn add_record(
         &self,
         _state_lock_observer: &MutexGuard<()>,
@@ -90,20 +851,54 @@ impl Manifest {
         self.add_record_when_init(record)
     }
 
-    // | len | JSON record | checksum | len | JSON record | checksum | len | JSON record | checksum |
+    // | format | len | record | checksum | len | record | checksum | len | record | checksum |
     pub fn add_record_when_init(&self, _record: ManifestRecord) -> Result<()> {
-        let json_encoded = serde_json::to_vec(&_record)?;
-        let mut encoded = Vec::new();
-        encoded.put_u32(json_encoded.len() as u32);
-        encoded.put(&json_encoded[..]);
-        encoded.put_u32(crc32fast::hash(&json_encoded[..]));
+        self.add_records_when_init(vec![_record])
+    }
 
+    /// Adds the records with a single write and sync.
+    pub fn add_records_when_init(&self, records: Vec<ManifestRecord>) -> Result<()> {
+        // a roll over may change the framing
+        let mut file = self.file.lock();
+        let mut unsynced = self.unsynced.lock();
+        let unsynced_len = unsynced.len();
+        for record in &records {
+            let checksum = encode_record(
+                record,
+                self.format,
+                self.tagged.load(Ordering::Relaxed),
+                self.chained
+                    .load(Ordering::Relaxed)
+                    .then(|| self.last_checksum.load(Ordering::Relaxed)),
+                &mut unsynced,
+            )?;
+            self.last_checksum.store(checksum, Ordering::Relaxed);
+        }
+        self.len
+            .fetch_add((unsynced.len() - unsynced_len) as u64, Ordering::Relaxed);
+        if self.sync_mode == ManifestSyncMode::Always
+            || records.iter().any(ManifestRecord::is_durability_critical)
         {
-            let mut file = self.file.lock();
-            file.write(&encoded)?;
-            file.sync_all()?;
+            Self::write_unsynced(&mut file, &mut unsynced)?;
         }
+        Ok(())
+    }
+
+    /// Writes and syncs the records buffered with `ManifestSyncMode::Batched`, so that they
+    /// survive a crash. A file whose removal one of them allows must only be removed after this.
+    pub fn sync(&self) -> Result<()> {
+        let mut file = self.file.lock();
+        let mut unsynced = self.unsynced.lock();
+        if unsynced.is_empty() {
+            return Ok(());
+        }
+        Self::write_unsynced(&mut file, &mut unsynced)
+    }
 
+    fn write_unsynced(file: &mut File, unsynced: &mut Vec<u8>) -> Result<()> {
+        file.write_all(unsynced)?;
+        file.sync_all()?;
+        unsynced.clear();
         Ok(())
     }
 }
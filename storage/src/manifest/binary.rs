@@ -0,0 +1,391 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The records of `ManifestFormat::Binary`: the fields in order, after the type of the record,
+//! see `encode_record`. Ids, levels, lengths, timestamps and sizes are LEB128 varints, a list or a
+//! key is its length followed by its items, an `Option` is 0 for `None` or the value plus one, and
+//! a `bool` is a byte.
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut};
+
+use super::{
+    ManifestRecord, ManifestSnapshot, PersistedOptions, RECORD_COMPACTION,
+    RECORD_COMPACTION_INTENT, RECORD_DELETE_SSTS, RECORD_FLUSH, RECORD_FLUSH_MERGED, RECORD_INGEST,
+    RECORD_NEW_MEMTABLE, RECORD_OPTIONS, RECORD_SNAPSHOT, RECORD_SST_METAS, RECORD_WATERMARK,
+    SstMeta,
+};
+use crate::block::varint::{get_varint, put_varint};
+use crate::compact::{
+    CompactionOptions, CompactionTask, LeveledCompactionOptions, LeveledCompactionTask,
+    SimpleLeveledCompactionOptions, SimpleLeveledCompactionTask, TieredCompactionOptions,
+    TieredCompactionTask,
+};
+
+const TASK_LEVELED: u8 = 0;
+const TASK_TIERED: u8 = 1;
+const TASK_SIMPLE: u8 = 2;
+const TASK_FORCE_FULL: u8 = 3;
+
+const COMPACTION_NONE: u8 = 0;
+const COMPACTION_LEVELED: u8 = 1;
+const COMPACTION_TIERED: u8 = 2;
+const COMPACTION_SIMPLE: u8 = 3;
+
+fn put_id(buf: &mut Vec<u8>, id: usize) {
+    put_varint(buf, id as u64);
+}
+
+fn put_ids(buf: &mut Vec<u8>, ids: &[usize]) {
+    put_id(buf, ids.len());
+    for id in ids {
+        put_id(buf, *id);
+    }
+}
+
+fn put_levels(buf: &mut Vec<u8>, levels: &[(usize, Vec<usize>)]) {
+    put_id(buf, levels.len());
+    for (level, ids) in levels {
+        put_id(buf, *level);
+        put_ids(buf, ids);
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_id(buf, bytes.len());
+    buf.put_slice(bytes);
+}
+
+fn put_option(buf: &mut Vec<u8>, value: Option<usize>) {
+    put_id(buf, value.map_or(0, |value| value + 1));
+}
+
+fn put_task(buf: &mut Vec<u8>, task: &CompactionTask) {
+    match task {
+        CompactionTask::Leveled(task) => {
+            buf.put_u8(TASK_LEVELED);
+            put_option(buf, task.upper_level);
+            put_ids(buf, &task.upper_level_sst_ids);
+            put_id(buf, task.lower_level);
+            put_ids(buf, &task.lower_level_sst_ids);
+            buf.put_u8(task.is_lower_level_bottom_level as u8);
+        }
+        CompactionTask::Tiered(task) => {
+            buf.put_u8(TASK_TIERED);
+            put_levels(buf, &task.tiers);
+            buf.put_u8(task.bottom_tier_included as u8);
+        }
+        CompactionTask::Simple(task) => {
+            buf.put_u8(TASK_SIMPLE);
+            put_option(buf, task.upper_level);
+            put_ids(buf, &task.upper_level_sst_ids);
+            put_id(buf, task.lower_level);
+            put_ids(buf, &task.lower_level_sst_ids);
+            buf.put_u8(task.is_lower_level_bottom_level as u8);
+        }
+        CompactionTask::ForceFullCompaction {
+            l0_sstables,
+            l1_sstables,
+        } => {
+            buf.put_u8(TASK_FORCE_FULL);
+            put_ids(buf, l0_sstables);
+            put_ids(buf, l1_sstables);
+        }
+    }
+}
+
+fn put_persisted_options(buf: &mut Vec<u8>, options: &PersistedOptions) {
+    match &options.compaction_options {
+        CompactionOptions::NoCompaction => buf.put_u8(COMPACTION_NONE),
+        CompactionOptions::Leveled(options) => {
+            buf.put_u8(COMPACTION_LEVELED);
+            put_id(buf, options.level_size_multiplier);
+            put_id(buf, options.level0_file_num_compaction_trigger);
+            put_id(buf, options.max_levels);
+            put_id(buf, options.base_level_size_mb);
+        }
+        CompactionOptions::Tiered(options) => {
+            buf.put_u8(COMPACTION_TIERED);
+            put_id(buf, options.num_tiers);
+            put_id(buf, options.max_size_amplification_percent);
+            put_id(buf, options.size_ratio);
+            put_id(buf, options.min_merge_width);
+            put_option(buf, options.max_merge_width);
+        }
+        CompactionOptions::Simple(options) => {
+            buf.put_u8(COMPACTION_SIMPLE);
+            put_id(buf, options.size_ratio_percent);
+            put_id(buf, options.level0_file_num_compaction_trigger);
+            put_id(buf, options.max_levels);
+        }
+    }
+    put_id(buf, options.block_size);
+    put_id(buf, options.target_sst_size);
+    buf.put_u8(options.enable_wal as u8);
+    buf.put_u8(options.serializable as u8);
+}
+
+fn put_sst_metas(buf: &mut Vec<u8>, sst_metas: &[SstMeta]) {
+    put_id(buf, sst_metas.len());
+    for sst_meta in sst_metas {
+        put_id(buf, sst_meta.sst_id);
+        put_bytes(buf, &sst_meta.first_key);
+        put_varint(buf, sst_meta.first_ts);
+        put_bytes(buf, &sst_meta.last_key);
+        put_varint(buf, sst_meta.last_ts);
+        put_varint(buf, sst_meta.size);
+        put_varint(buf, sst_meta.max_ts);
+        put_option(
+            buf,
+            sst_meta.encryption_key_id.map(|key_id| key_id as usize),
+        );
+    }
+}
+
+pub(super) fn encode(record: &ManifestRecord, buf: &mut Vec<u8>) {
+    match record {
+        ManifestRecord::Flush(sst_id) => put_id(buf, *sst_id),
+        ManifestRecord::NewMemtable(memtable_id) => put_id(buf, *memtable_id),
+        ManifestRecord::Compaction(task, output)
+        | ManifestRecord::CompactionIntent(task, output) => {
+            put_task(buf, task);
+            put_ids(buf, output);
+        }
+        ManifestRecord::Ingest(level, sst_ids) => {
+            put_id(buf, *level);
+            put_ids(buf, sst_ids);
+        }
+        ManifestRecord::FlushMerged(memtable_ids, sst_id) => {
+            put_ids(buf, memtable_ids);
+            put_id(buf, *sst_id);
+        }
+        ManifestRecord::Snapshot(snapshot) => {
+            put_ids(buf, &snapshot.memtables);
+            put_ids(buf, &snapshot.flushed);
+            put_ids(buf, &snapshot.l0_sstables);
+            put_levels(buf, &snapshot.levels);
+            put_id(buf, snapshot.max_id);
+            put_ids(buf, &snapshot.pending_delete);
+            match &snapshot.options {
+                Some(options) => {
+                    buf.put_u8(1);
+                    put_persisted_options(buf, options);
+                }
+                None => buf.put_u8(0),
+            }
+            put_sst_metas(buf, &snapshot.sst_metas);
+            put_option(buf, snapshot.watermark.map(|ts| ts as usize));
+            put_ids(buf, &snapshot.reserved);
+        }
+        ManifestRecord::DeleteSsts(sst_ids) => put_ids(buf, sst_ids),
+        ManifestRecord::Options(options) => put_persisted_options(buf, options),
+        ManifestRecord::SstMetas(sst_metas) => put_sst_metas(buf, sst_metas),
+        ManifestRecord::Watermark(ts) => put_varint(buf, *ts),
+    }
+}
+
+/// Reads the fields of a record off the front of its bytes.
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn u8(&mut self) -> Result<u8> {
+        if !self.buf.has_remaining() {
+            bail!("record ends early");
+        }
+        Ok(self.buf.get_u8())
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => bail!("invalid bool {}", value),
+        }
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        match get_varint(&mut self.buf) {
+            Some(value) => Ok(value),
+            None => bail!("record ends early"),
+        }
+    }
+
+    fn id(&mut self) -> Result<usize> {
+        Ok(self.u64()? as usize)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.id()?;
+        if len > self.buf.len() {
+            bail!("{} bytes are longer than the record", len);
+        }
+        let bytes = self.buf[..len].to_vec();
+        self.buf.advance(len);
+        Ok(bytes)
+    }
+
+    fn ids(&mut self) -> Result<Vec<usize>> {
+        let len = self.id()?;
+        // every id takes at least a byte, so a corrupted length can't allocate much
+        if len > self.buf.len() {
+            bail!("list of {} ids is longer than the record", len);
+        }
+        (0..len).map(|_| self.id()).collect()
+    }
+
+    fn levels(&mut self) -> Result<Vec<(usize, Vec<usize>)>> {
+        let len = self.id()?;
+        if len > self.buf.len() {
+            bail!("list of {} levels is longer than the record", len);
+        }
+        (0..len).map(|_| Ok((self.id()?, self.ids()?))).collect()
+    }
+
+    fn option(&mut self) -> Result<Option<usize>> {
+        Ok(self.id()?.checked_sub(1))
+    }
+
+    fn task(&mut self) -> Result<CompactionTask> {
+        Ok(match self.u8()? {
+            TASK_LEVELED => CompactionTask::Leveled(LeveledCompactionTask {
+                upper_level: self.option()?,
+                upper_level_sst_ids: self.ids()?,
+                lower_level: self.id()?,
+                lower_level_sst_ids: self.ids()?,
+                is_lower_level_bottom_level: self.bool()?,
+            }),
+            TASK_TIERED => CompactionTask::Tiered(TieredCompactionTask {
+                tiers: self.levels()?,
+                bottom_tier_included: self.bool()?,
+            }),
+            TASK_SIMPLE => CompactionTask::Simple(SimpleLeveledCompactionTask {
+                upper_level: self.option()?,
+                upper_level_sst_ids: self.ids()?,
+                lower_level: self.id()?,
+                lower_level_sst_ids: self.ids()?,
+                is_lower_level_bottom_level: self.bool()?,
+            }),
+            TASK_FORCE_FULL => CompactionTask::ForceFullCompaction {
+                l0_sstables: self.ids()?,
+                l1_sstables: self.ids()?,
+            },
+            tag => bail!("unknown compaction task {}", tag),
+        })
+    }
+
+    fn sst_metas(&mut self) -> Result<Vec<SstMeta>> {
+        let len = self.id()?;
+        if len > self.buf.len() {
+            bail!("list of {} SSTs is longer than the record", len);
+        }
+        (0..len)
+            .map(|_| {
+                Ok(SstMeta {
+                    sst_id: self.id()?,
+                    first_key: self.bytes()?,
+                    first_ts: self.u64()?,
+                    last_key: self.bytes()?,
+                    last_ts: self.u64()?,
+                    size: self.u64()?,
+                    max_ts: self.u64()?,
+                    encryption_key_id: self.option()?.map(|key_id| key_id as u32),
+                })
+            })
+            .collect()
+    }
+
+    fn persisted_options(&mut self) -> Result<PersistedOptions> {
+        let compaction_options = match self.u8()? {
+            COMPACTION_NONE => CompactionOptions::NoCompaction,
+            COMPACTION_LEVELED => CompactionOptions::Leveled(LeveledCompactionOptions {
+                level_size_multiplier: self.id()?,
+                level0_file_num_compaction_trigger: self.id()?,
+                max_levels: self.id()?,
+                base_level_size_mb: self.id()?,
+            }),
+            COMPACTION_TIERED => CompactionOptions::Tiered(TieredCompactionOptions {
+                num_tiers: self.id()?,
+                max_size_amplification_percent: self.id()?,
+                size_ratio: self.id()?,
+                min_merge_width: self.id()?,
+                max_merge_width: self.option()?,
+            }),
+            COMPACTION_SIMPLE => CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: self.id()?,
+                level0_file_num_compaction_trigger: self.id()?,
+                max_levels: self.id()?,
+            }),
+            tag => bail!("unknown compaction strategy {}", tag),
+        };
+        Ok(PersistedOptions {
+            compaction_options,
+            block_size: self.id()?,
+            target_sst_size: self.id()?,
+            enable_wal: self.bool()?,
+            serializable: self.bool()?,
+        })
+    }
+}
+
+pub(super) fn decode(record_type: u8, buf: &[u8]) -> Result<ManifestRecord> {
+    let mut decoder = Decoder { buf };
+    let record = match record_type {
+        RECORD_FLUSH => ManifestRecord::Flush(decoder.id()?),
+        RECORD_NEW_MEMTABLE => ManifestRecord::NewMemtable(decoder.id()?),
+        RECORD_COMPACTION => ManifestRecord::Compaction(decoder.task()?, decoder.ids()?),
+        RECORD_INGEST => ManifestRecord::Ingest(decoder.id()?, decoder.ids()?),
+        RECORD_FLUSH_MERGED => ManifestRecord::FlushMerged(decoder.ids()?, decoder.id()?),
+        RECORD_SNAPSHOT => ManifestRecord::Snapshot(ManifestSnapshot {
+            memtables: decoder.ids()?,
+            flushed: decoder.ids()?,
+            l0_sstables: decoder.ids()?,
+            levels: decoder.levels()?,
+            max_id: decoder.id()?,
+            pending_delete: decoder.ids()?,
+            options: match decoder.bool()? {
+                true => Some(decoder.persisted_options()?),
+                false => None,
+            },
+            // snapshots taken before the SST metas were recorded end here
+            sst_metas: match decoder.buf.has_remaining() {
+                true => decoder.sst_metas()?,
+                false => Vec::new(),
+            },
+            // and those taken before the watermark was here
+            watermark: match decoder.buf.has_remaining() {
+                true => decoder.option()?.map(|ts| ts as u64),
+                false => None,
+            },
+            // and the reserved output ids
+            reserved: match decoder.buf.has_remaining() {
+                true => decoder.ids()?,
+                false => Vec::new(),
+            },
+        }),
+        RECORD_DELETE_SSTS => ManifestRecord::DeleteSsts(decoder.ids()?),
+        RECORD_OPTIONS => ManifestRecord::Options(decoder.persisted_options()?),
+        RECORD_SST_METAS => ManifestRecord::SstMetas(decoder.sst_metas()?),
+        RECORD_WATERMARK => ManifestRecord::Watermark(decoder.u64()?),
+        RECORD_COMPACTION_INTENT => {
+            ManifestRecord::CompactionIntent(decoder.task()?, decoder.ids()?)
+        }
+        tag => bail!("unknown record {}", tag),
+    };
+    if decoder.buf.has_remaining() {
+        bail!("{} bytes left after the record", decoder.buf.len());
+    }
+    Ok(record)
+}
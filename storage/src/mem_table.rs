@@ -15,28 +15,112 @@
 use std::ops::Bound;
 use std::path::Path;
 use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
-use anyhow::{Ok, Result};
+use anyhow::{Ok, Result, bail};
 use bytes::Bytes;
 use crossbeam_skiplist::SkipMap;
 use crossbeam_skiplist::map::Entry;
 use ouroboros::self_referencing;
+use parking_lot::RwLock;
 
 use crate::iterators::StorageIterator;
+use crate::iterators::merge_iterator::MergeIterator;
 use crate::key::{KeyBytes, KeySlice, TS_DEFAULT, TS_RANGE_BEGIN, TS_RANGE_END};
-use crate::table::SsTableBuilder;
+use crate::table::{EncryptionProvider, SsTableBuilder};
 use crate::wal::Wal;
 
+/// Memory an entry takes besides its key, timestamp and value: 88 bytes for the skiplist node with
+/// its tower and the key and value `Bytes`, measured with a counting allocator, and the allocator's
+/// own headers of the node, key and value.
+pub(crate) const MEMTABLE_ENTRY_OVERHEAD: usize = 128;
+
+/// The largest key or value that can be put, as the WAL stores their lengths in a u16.
+pub const MAX_KEY_VALUE_LEN: usize = u16::MAX as usize;
+
+/// Bits of the key filter of a mem-table for each key it's expected to hold, for about 1% false
+/// positives.
+const KEY_FILTER_BITS_PER_KEY: usize = 10;
+
+#[cfg(test)]
+thread_local! {
+    /// Number of mem-tables probed by point reads on the current thread, so that tests can check
+    /// which ones the key filters skipped.
+    pub(crate) static NUM_PROBED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 /// A basic mem-table based on crossbeam-skiplist.
 ///
 /// An initial implementation of memtable is part of week 1, day 1. It will be incrementally implemented in other
 /// chapters of week 1 and week 2.
 pub struct MemTable {
-    pub(crate) map: Arc<SkipMap<KeyBytes, Bytes>>,
-    wal: Option<Wal>,
+    /// The entries are partitioned by the hash of their key, so that all the versions of a key are
+    /// in the same shard. There's a single shard unless `with_shards` says otherwise.
+    shards: Vec<MemTableShard>,
+    pub(crate) wal: Option<Wal>,
     id: usize,
-    approximate_size: Arc<AtomicUsize>,
+    /// The buffer of the WAL, counted in `approximate_size`.
+    wal_buffer_size: usize,
+    /// Whether any entry skipped the WAL, see `put_batch_without_wal`.
+    has_unlogged_writes: AtomicBool,
+    /// Rules out the keys never put, see `with_key_filter`. Dropped once the mem-table is flushed.
+    key_filter: RwLock<Option<KeyFilter>>,
+}
+
+/// A bloom filter over the keys of a mem-table, hashed the way the bloom filters of SSTs are. Its
+/// bits are set as the keys are put, concurrently with the reads.
+struct KeyFilter {
+    bits: Vec<AtomicU64>,
+    num_hashes: u32,
+}
+
+impl KeyFilter {
+    fn new(expected_keys: usize) -> Self {
+        let num_bits = (expected_keys * KEY_FILTER_BITS_PER_KEY).max(64);
+        Self {
+            bits: (0..num_bits.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            num_hashes: (KEY_FILTER_BITS_PER_KEY as f64 * 0.69) as u32,
+        }
+    }
+
+    /// The bit indices of the key hash, as `Bloom` probes them.
+    fn bit_indices(&self, mut h: u32) -> impl Iterator<Item = usize> {
+        let num_bits = self.bits.len() * 64;
+        let delta = h.rotate_right(17);
+        (0..self.num_hashes).map(move |_| {
+            let idx = h as usize % num_bits;
+            h = h.wrapping_add(delta);
+            idx
+        })
+    }
+
+    fn insert(&self, key: &[u8]) {
+        for idx in self.bit_indices(farmhash::fingerprint32(key)) {
+            self.bits[idx / 64].fetch_or(1 << (idx % 64), Ordering::Relaxed);
+        }
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_indices(farmhash::fingerprint32(key))
+            .all(|idx| self.bits[idx / 64].load(Ordering::Relaxed) & (1 << (idx % 64)) != 0)
+    }
+}
+
+/// A skiplist of a mem-table with its own counters, so that writers to different shards don't
+/// contend on them either.
+#[derive(Default)]
+struct MemTableShard {
+    map: Arc<SkipMap<KeyBytes, Bytes>>,
+    /// The memory taken by the entries, see `MemTable::approximate_size`.
+    approximate_size: AtomicUsize,
+    /// The bytes of the keys, timestamps and values alone, see `MemTable::payload_size`.
+    payload_size: AtomicUsize,
+    /// See `MemTable::len`.
+    num_entries: AtomicUsize,
+    /// See `MemTable::tombstone_count`.
+    num_tombstones: AtomicUsize,
 }
 
 /// Create a bound of `Bytes` from a bound of `&[u8]`.
@@ -117,38 +201,96 @@ impl MemTable {
     /// Create a new mem-table.
     pub fn create(_id: usize) -> Self {
         Self {
-            map: Arc::new(SkipMap::new()),
+            shards: vec![MemTableShard::default()],
             wal: None,
             id: _id,
-            approximate_size: Arc::new(AtomicUsize::new(0)),
+            wal_buffer_size: 0,
+            has_unlogged_writes: AtomicBool::new(false),
+            key_filter: RwLock::new(None),
         }
     }
 
+    /// Partitions the entries of the empty mem-table into `num_shards` skiplists.
+    pub fn with_shards(mut self, num_shards: usize) -> Self {
+        assert!(num_shards > 0, "a mem-table needs at least one shard");
+        assert!(self.is_empty(), "can't shard a mem-table with entries");
+        self.shards = (0..num_shards).map(|_| MemTableShard::default()).collect();
+        self
+    }
+
+    /// Keeps a filter of the keys of the empty mem-table, sized for `expected_keys`, so that point
+    /// reads can skip it once it's immutable. The keys put past that many make it less selective.
+    pub fn with_key_filter(self, expected_keys: usize) -> Self {
+        assert!(
+            self.is_empty(),
+            "can't filter the keys of a mem-table with entries"
+        );
+        *self.key_filter.write() = Some(KeyFilter::new(expected_keys));
+        self
+    }
+
     /// Create a new mem-table with WAL
     pub fn create_with_wal(_id: usize, _path: impl AsRef<Path>) -> Result<Self> {
         let path = _path.as_ref();
-        let wal = Wal::create(path)?;
+        Ok(Self::with_wal(_id, Wal::create(path, _id as u64, 0)?))
+    }
 
-        Ok(Self {
-            map: Arc::new(SkipMap::new()),
+    /// Create a new mem-table logging to the WAL, whose log number must be the id.
+    pub fn with_wal(id: usize, wal: Wal) -> Self {
+        Self {
+            shards: vec![MemTableShard::default()],
+            wal_buffer_size: wal.buffer_capacity(),
+            has_unlogged_writes: AtomicBool::new(false),
+            key_filter: RwLock::new(None),
             wal: Some(wal),
-            id: _id,
-            approximate_size: Arc::new(AtomicUsize::new(0)),
-        })
+            id,
+        }
     }
 
-    /// Create a memtable from WAL
+    /// Create a memtable from WAL, with a single shard as it's only read from, and a filter of the
+    /// keys recovered.
     pub fn recover_from_wal(_id: usize, _path: impl AsRef<Path>) -> Result<Self> {
+        Self::recover_from_wal_with_encryption(_id, _path, None)
+    }
+
+    /// Recovers a memtable from a WAL whose batches may be encrypted with `encryption`.
+    pub fn recover_from_wal_with_encryption(
+        _id: usize,
+        _path: impl AsRef<Path>,
+        encryption: Option<Arc<dyn EncryptionProvider>>,
+    ) -> Result<Self> {
         let path = _path.as_ref();
         let skiplist = SkipMap::new();
 
-        let wal = Wal::recover(path, &skiplist)?;
-        Ok(Self {
+        let (mut payload_size, mut num_entries, mut num_tombstones) = (0, 0, 0);
+        let wal = Wal::recover_with_encryption(path, _id as u64, encryption, |key, value| {
+            payload_size += key.raw_len() + value.len();
+            num_entries += 1;
+            if value.is_empty() {
+                num_tombstones += 1;
+            }
+            skiplist.insert(key, value);
+        })?;
+        let key_filter = KeyFilter::new(num_entries);
+        for entry in skiplist.iter() {
+            key_filter.insert(entry.key().key_ref());
+        }
+        let shard = MemTableShard {
             map: Arc::new(skiplist),
+            approximate_size: AtomicUsize::new(
+                payload_size + num_entries * MEMTABLE_ENTRY_OVERHEAD,
+            ),
+            payload_size: AtomicUsize::new(payload_size),
+            num_entries: AtomicUsize::new(num_entries),
+            num_tombstones: AtomicUsize::new(num_tombstones),
+        };
+        Ok(Self {
+            shards: vec![shard],
+            wal_buffer_size: wal.buffer_capacity(),
+            has_unlogged_writes: AtomicBool::new(false),
+            key_filter: RwLock::new(Some(key_filter)),
             wal: Some(wal),
             id: _id,
-            // TODO(xingyu): probably update this?
-            approximate_size: Arc::new(AtomicUsize::new(0)),
         })
     }
 
@@ -174,11 +316,23 @@ impl MemTable {
         )
     }
 
+    fn shard_index(&self, key: &[u8]) -> usize {
+        if self.shards.len() == 1 {
+            return 0;
+        }
+        farmhash::hash32(key) as usize % self.shards.len()
+    }
+
+    fn shard_of(&self, key: &[u8]) -> &MemTableShard {
+        &self.shards[self.shard_index(key)]
+    }
+
     /// Get a value by key.
     pub fn get(&self, _key: KeySlice) -> Option<Bytes> {
         // convert slice data to static, so this _key would live all the time.
         let key_bytes = Bytes::from_static(unsafe { std::mem::transmute(_key.key_ref()) });
-        self.map
+        self.shard_of(_key.key_ref())
+            .map
             .get(&KeyBytes::from_bytes_with_ts(key_bytes, _key.ts()))
             .map(|e| e.value().clone())
     }
@@ -193,24 +347,76 @@ impl MemTable {
     }
 
     /// Implement this in week 3, day 5; if you want to implement this earlier, use `&[u8]` as the key type.
+    ///
+    /// Appends the batch to the WAL as one record and then inserts it. Nothing is appended or
+    /// inserted if a key or value is larger than `MAX_KEY_VALUE_LEN`.
     pub fn put_batch(&self, _data: &[(KeySlice, &[u8])]) -> Result<()> {
+        check_batch(_data)?;
         if let Some(wal) = &self.wal {
             wal.put_batch(_data)?;
         }
+        self.insert_batch(_data);
+        Ok(())
+    }
 
-        let mut size = 0;
+    /// Puts a batch without writing it to the WAL, so that it's lost on a crash unless the
+    /// memtable is flushed first.
+    pub fn put_batch_without_wal(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
+        check_batch(data)?;
+        if self.wal.is_some() {
+            self.has_unlogged_writes
+                .store(true, std::sync::atomic::Ordering::Release);
+        }
+        self.insert_batch(data);
+        Ok(())
+    }
+
+    /// Whether the WAL is missing some entries, which are only durable once flushed.
+    pub fn has_unlogged_writes(&self) -> bool {
+        self.has_unlogged_writes
+            .load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    fn insert_batch(&self, _data: &[(KeySlice, &[u8])]) {
+        // the keys are in the filter before they're in the skiplists, for the reads in between
+        if let Some(key_filter) = self.key_filter.read().as_ref() {
+            for (key, _) in _data {
+                key_filter.insert(key.key_ref());
+            }
+        }
+        // (payload size, number of entries, number of tombstones) of each shard, added to its
+        // counters once
+        let mut sizes = vec![(0, 0, 0); self.shards.len()];
         for (key, value) in _data {
-            size += key.raw_len() + value.len();
-            self.map.insert(
+            let idx = self.shard_index(key.key_ref());
+            self.shards[idx].map.insert(
                 key.to_key_vec().into_key_bytes(),
                 Bytes::copy_from_slice(value),
             );
+            sizes[idx].0 += key.raw_len() + value.len();
+            sizes[idx].1 += 1;
+            if value.is_empty() {
+                sizes[idx].2 += 1;
+            }
+        }
+        for (shard, (payload_size, num_entries, num_tombstones)) in self.shards.iter().zip(sizes) {
+            if num_entries == 0 {
+                continue;
+            }
+            shard
+                .num_entries
+                .fetch_add(num_entries, std::sync::atomic::Ordering::Relaxed);
+            shard
+                .num_tombstones
+                .fetch_add(num_tombstones, std::sync::atomic::Ordering::Relaxed);
+            shard
+                .payload_size
+                .fetch_add(payload_size, std::sync::atomic::Ordering::Relaxed);
+            shard.approximate_size.fetch_add(
+                payload_size + num_entries * MEMTABLE_ENTRY_OVERHEAD,
+                std::sync::atomic::Ordering::Relaxed,
+            );
         }
-
-        self.approximate_size
-            .fetch_add(size, std::sync::atomic::Ordering::Relaxed);
-
-        Ok(())
     }
 
     pub fn sync_wal(&self) -> Result<()> {
@@ -220,30 +426,68 @@ impl MemTable {
         Ok(())
     }
 
-    /// Get an iterator over a range of keys.
+    /// Get an iterator over a range of keys. The shards are merged, unless the range is within a
+    /// single key.
     pub fn scan(&self, _lower: Bound<KeySlice>, _upper: Bound<KeySlice>) -> MemTableIterator {
+        let single_key = match (_lower, _upper) {
+            (
+                Bound::Included(lower) | Bound::Excluded(lower),
+                Bound::Included(upper) | Bound::Excluded(upper),
+            ) if lower.key_ref() == upper.key_ref() => Some(lower.key_ref()),
+            _ => None,
+        };
         let lower = map_key_bound(_lower);
         let upper = map_key_bound(_upper);
-        let mut iter = MemTableIteratorBuilder {
-            // this is under shared Arc, we need to clone this to be fully owned by iter.
-            map: self.map.clone(),
-            iter_builder: |map| map.range((lower, upper)),
-            item: (KeyBytes::new(), Bytes::new()),
+        if let Some(key) = single_key {
+            return MemTableIterator::Shard(SkipMapIterator::create(
+                &self.shard_of(key).map,
+                lower,
+                upper,
+            ));
         }
-        .build();
-
-        let entry = iter.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
-        iter.with_mut(|x| *x.item = entry);
-        return iter;
+        if self.shards.len() == 1 {
+            return MemTableIterator::Shard(SkipMapIterator::create(
+                &self.shards[0].map,
+                lower,
+                upper,
+            ));
+        }
+        MemTableIterator::Merged(MergeIterator::create(
+            self.shards
+                .iter()
+                .map(|shard| {
+                    Box::new(SkipMapIterator::create(
+                        &shard.map,
+                        lower.clone(),
+                        upper.clone(),
+                    ))
+                })
+                .collect(),
+        ))
     }
 
     /// Flush the mem-table to SSTable. Implement in week 1 day 6.
+    /// Flushes the mem-tables into one SST, newest first, which keeps the value of the newest one
+    /// for a key put into several with the same timestamp.
+    pub fn flush_merged(memtables: &[Arc<MemTable>], builder: &mut SsTableBuilder) -> Result<()> {
+        let mut iter = MergeIterator::create(
+            memtables
+                .iter()
+                .map(|memtable| Box::new(memtable.scan(Bound::Unbounded, Bound::Unbounded)))
+                .collect(),
+        );
+        while iter.is_valid() {
+            builder.add(iter.key(), iter.value())?;
+            iter.next()?;
+        }
+        Ok(())
+    }
+
     pub fn flush(&self, _builder: &mut SsTableBuilder) -> Result<()> {
-        for entry in self.map.iter() {
-            _builder.add(
-                KeySlice::from_slice(entry.key().key_ref(), entry.key().ts()),
-                entry.value(),
-            );
+        let mut iter = self.scan(Bound::Unbounded, Bound::Unbounded);
+        while iter.is_valid() {
+            _builder.add(iter.key(), iter.value())?;
+            iter.next()?;
         }
         Ok(())
     }
@@ -252,17 +496,100 @@ impl MemTable {
         self.id
     }
 
+    /// The memory the mem-table takes, its entries with `MEMTABLE_ENTRY_OVERHEAD` each and the
+    /// buffer of its WAL, which is what the memtable is frozen on.
     pub fn approximate_size(&self) -> usize {
-        self.approximate_size
-            .load(std::sync::atomic::Ordering::Relaxed)
+        self.wal_buffer_size
+            + self
+                .shards
+                .iter()
+                .map(|shard| {
+                    shard
+                        .approximate_size
+                        .load(std::sync::atomic::Ordering::Relaxed)
+                })
+                .sum::<usize>()
+    }
+
+    /// The bytes of the keys, timestamps and values put, roughly how much data the mem-table
+    /// flushes to its SST before it's encoded.
+    pub fn payload_size(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .payload_size
+                    .load(std::sync::atomic::Ordering::Relaxed)
+            })
+            .sum()
+    }
+
+    /// Whether the key may have been put, `false` only if the key filter rules it out.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.key_filter
+            .read()
+            .as_ref()
+            .is_none_or(|key_filter| key_filter.may_contain(key))
+    }
+
+    /// Frees the key filter, once the mem-table is flushed and no longer read by point reads.
+    pub fn drop_key_filter(&self) {
+        self.key_filter.write().take();
+    }
+
+    /// The number of versions put in the mem-table, counting a version put again with the same
+    /// timestamp twice.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.num_entries.load(std::sync::atomic::Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The number of deletes among `len`, versions with an empty value.
+    pub fn tombstone_count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .num_tombstones
+                    .load(std::sync::atomic::Ordering::Relaxed)
+            })
+            .sum()
+    }
+
+    /// The number of skiplists the entries are partitioned into.
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
     }
 
     /// Only use this function when closing the database
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.shards.iter().all(|shard| shard.map.is_empty())
     }
 }
 
+/// Rejects a batch with a key or value the WAL can't store the length of.
+fn check_batch(data: &[(KeySlice, &[u8])]) -> Result<()> {
+    for (key, value) in data {
+        if key.key_len() > MAX_KEY_VALUE_LEN {
+            bail!(
+                "key of {} bytes is larger than {} bytes",
+                key.key_len(),
+                MAX_KEY_VALUE_LEN
+            );
+        }
+        if value.len() > MAX_KEY_VALUE_LEN {
+            bail!(
+                "value of {} bytes is larger than {} bytes",
+                value.len(),
+                MAX_KEY_VALUE_LEN
+            );
+        }
+    }
+    Ok(())
+}
+
 type SkipMapRangeIter<'a> = crossbeam_skiplist::map::Range<
     'a,
     KeyBytes,
@@ -276,7 +603,7 @@ type SkipMapRangeIter<'a> = crossbeam_skiplist::map::Range<
 ///
 /// This is part of week 1, day 2.
 #[self_referencing]
-pub struct MemTableIterator {
+pub struct SkipMapIterator {
     /// Stores a reference to the skipmap.
     map: Arc<SkipMap<KeyBytes, Bytes>>,
     /// Stores a skipmap iterator that refers to the lifetime of `MemTableIterator` itself.
@@ -287,7 +614,25 @@ pub struct MemTableIterator {
     item: (KeyBytes, Bytes),
 }
 
-impl MemTableIterator {
+impl SkipMapIterator {
+    fn create(
+        map: &Arc<SkipMap<KeyBytes, Bytes>>,
+        lower: Bound<KeyBytes>,
+        upper: Bound<KeyBytes>,
+    ) -> Self {
+        let mut iter = SkipMapIteratorBuilder {
+            // this is under shared Arc, we need to clone this to be fully owned by iter.
+            map: map.clone(),
+            iter_builder: |map| map.range((lower, upper)),
+            item: (KeyBytes::new(), Bytes::new()),
+        }
+        .build();
+
+        let entry = iter.with_iter_mut(|iter| SkipMapIterator::entry_to_item(iter.next()));
+        iter.with_mut(|x| *x.item = entry);
+        iter
+    }
+
     fn entry_to_item(entry: Option<Entry<'_, KeyBytes, Bytes>>) -> (KeyBytes, Bytes) {
         entry
             .map(|x| (x.key().clone(), x.value().clone()))
@@ -296,7 +641,7 @@ impl MemTableIterator {
     }
 }
 
-impl StorageIterator for MemTableIterator {
+impl StorageIterator for SkipMapIterator {
     type KeyType<'a> = KeySlice<'a>;
 
     fn value(&self) -> &[u8] {
@@ -312,8 +657,53 @@ impl StorageIterator for MemTableIterator {
     }
 
     fn next(&mut self) -> Result<()> {
-        let entry = self.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
+        let entry = self.with_iter_mut(|iter| SkipMapIterator::entry_to_item(iter.next()));
         self.with_mut(|x| *x.item = entry);
         Ok(())
     }
 }
+
+/// An iterator over a range of a mem-table, merging its shards if it has several.
+pub enum MemTableIterator {
+    Shard(SkipMapIterator),
+    Merged(MergeIterator<SkipMapIterator>),
+}
+
+impl StorageIterator for MemTableIterator {
+    type KeyType<'a> = KeySlice<'a>;
+
+    fn value(&self) -> &[u8] {
+        match self {
+            Self::Shard(iter) => iter.value(),
+            Self::Merged(iter) => iter.value(),
+        }
+    }
+
+    fn key(&self) -> KeySlice<'_> {
+        match self {
+            Self::Shard(iter) => iter.key(),
+            Self::Merged(iter) => iter.key(),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match self {
+            Self::Shard(iter) => iter.is_valid(),
+            Self::Merged(iter) => iter.is_valid(),
+        }
+    }
+
+    fn next(&mut self) -> Result<()> {
+        match self {
+            Self::Shard(iter) => iter.next(),
+            Self::Merged(iter) => iter.next(),
+        }
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        match self {
+            Self::Shard(iter) => iter.num_active_iterators(),
+            Self::Merged(iter) => iter.num_active_iterators(),
+        }
+    }
+}
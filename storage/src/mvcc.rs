@@ -17,7 +17,10 @@ pub mod watermark;
 
 use std::{
     collections::{BTreeMap, HashSet},
-    sync::{Arc, atomic::AtomicBool},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
 };
 
 use crossbeam_skiplist::SkipMap;
@@ -38,6 +41,11 @@ pub(crate) struct LsmMvccInner {
     pub(crate) write_lock: Mutex<()>,
     pub(crate) commit_lock: Mutex<()>,
     pub(crate) ts: Arc<Mutex<(u64, Watermark)>>,
+    /// The ts of the last write, ahead of the latest commit ts while writes are being synced.
+    last_write_ts: AtomicU64,
+    /// The watermark last recorded in the manifest, which `watermark` never goes past, `u64::MAX`
+    /// if it isn't recorded, see `LsmStorageOptions::watermark_record_interval`.
+    watermark_floor: AtomicU64,
     pub(crate) committed_txns: Arc<Mutex<BTreeMap<u64, CommittedTxnData>>>,
 }
 
@@ -47,6 +55,8 @@ impl LsmMvccInner {
             write_lock: Mutex::new(()),
             commit_lock: Mutex::new(()),
             ts: Arc::new(Mutex::new((initial_ts, Watermark::new()))),
+            last_write_ts: AtomicU64::new(initial_ts),
+            watermark_floor: AtomicU64::new(u64::MAX),
             committed_txns: Arc::new(Mutex::new(BTreeMap::new())),
         }
     }
@@ -55,16 +65,41 @@ impl LsmMvccInner {
         self.ts.lock().0
     }
 
+    /// The ts of the next write, called with `write_lock` held.
+    pub fn next_write_ts(&self) -> u64 {
+        self.last_write_ts.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Makes the writes up to `ts` visible. Writes can commit out of order once synced, since a
+    /// sync covers the writes before it too.
     pub fn update_commit_ts(&self, ts: u64) {
-        self.ts.lock().0 = ts;
+        let mut commit_ts = self.ts.lock();
+        commit_ts.0 = commit_ts.0.max(ts);
+        self.last_write_ts.fetch_max(ts, Ordering::SeqCst);
     }
 
     /// All ts (strictly) below this ts can be garbage collected.
     pub fn watermark(&self) -> u64 {
+        self.live_watermark()
+            .min(self.watermark_floor.load(Ordering::SeqCst))
+    }
+
+    /// The read ts of the oldest snapshot alive, or the latest commit ts if there's none.
+    pub fn live_watermark(&self) -> u64 {
         let ts = self.ts.lock();
         ts.1.watermark().unwrap_or(ts.0)
     }
 
+    /// The watermark last recorded in the manifest, `None` if it isn't recorded.
+    pub fn watermark_floor(&self) -> Option<u64> {
+        Some(self.watermark_floor.load(Ordering::SeqCst)).filter(|floor| *floor != u64::MAX)
+    }
+
+    /// Keeps `watermark` from going past `ts`, once it's recorded in the manifest.
+    pub fn set_watermark_floor(&self, ts: u64) {
+        self.watermark_floor.store(ts, Ordering::SeqCst);
+    }
+
     pub fn new_txn(&self, inner: Arc<LsmStorageInner>, serializable: bool) -> Arc<Transaction> {
         let mut ts = self.ts.lock();
         let read_ts = ts.0;
@@ -31,7 +31,7 @@ use parking_lot::Mutex;
 use crate::{
     iterators::{StorageIterator, two_merge_iterator::TwoMergeIterator},
     lsm_iterator::{FusedIterator, LsmIterator},
-    lsm_storage::{LsmStorageInner, WriteBatchRecord},
+    lsm_storage::{LsmStorageInner, ReadOptions, WriteBatchRecord, WriteOptions},
     mem_table::map_bound,
     mvcc::CommittedTxnData,
 };
@@ -47,6 +47,10 @@ pub struct Transaction {
 
 impl Transaction {
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.get_with_options(key, &ReadOptions::default())
+    }
+
+    pub fn get_with_options(&self, key: &[u8], options: &ReadOptions) -> Result<Option<Bytes>> {
         if self.committed.load(Ordering::SeqCst) {
             panic!("already committed!");
         }
@@ -64,10 +68,19 @@ impl Transaction {
             }
         }
 
-        self.inner.get_with_ts(key, self.read_ts)
+        self.inner.get_with_ts(key, self.read_ts, options)
     }
 
     pub fn scan(self: &Arc<Self>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
+        self.scan_with_options(lower, upper, &ReadOptions::default())
+    }
+
+    pub fn scan_with_options(
+        self: &Arc<Self>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        options: &ReadOptions,
+    ) -> Result<TxnIterator> {
         if self.committed.load(Ordering::SeqCst) {
             panic!("already committed!");
         }
@@ -87,7 +100,8 @@ impl Transaction {
             self.clone(),
             TwoMergeIterator::create(
                 local_iter,
-                self.inner.scan_with_ts(lower, upper, self.read_ts)?,
+                self.inner
+                    .scan_with_ts(lower, upper, self.read_ts, options)?,
             )?,
         )
     }
@@ -119,6 +133,10 @@ impl Transaction {
     }
 
     pub fn commit(&self) -> Result<()> {
+        self.commit_with_options(&WriteOptions::default())
+    }
+
+    pub fn commit_with_options(&self, options: &WriteOptions) -> Result<()> {
         self.committed
             .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
             .expect("cannot operate on committed txn!");
@@ -162,7 +180,7 @@ impl Transaction {
                 ));
             }
         }
-        let ts = self.inner.write_batch_inner(&records)?;
+        let ts = self.inner.write_batch_inner(&records, options)?;
 
         if serializable {
             // add this txn into committed_txns
@@ -12,24 +12,47 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-pub(crate) mod bloom;
+mod block_filter;
+pub mod bloom;
 mod builder;
-mod iterator;
+mod compression;
+pub(crate) mod direct_io;
+mod encryption;
+mod file_cache;
+pub(crate) mod iterator;
+mod lazy_meta;
+mod prefix;
+mod properties;
 
+use std::collections::HashMap;
 use std::fs::File;
-use std::path::Path;
-use std::sync::Arc;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
 pub use builder::SsTableBuilder;
-use bytes::Buf;
+use bytes::{Buf, Bytes};
+pub(crate) use compression::{COMPRESSION_NONE, compress_block, decompress_block};
+pub use compression::{CompressionDict, CompressionOptions};
+pub use encryption::{
+    ENCRYPTION_BLOOM_SECTION, ENCRYPTION_COMPRESSION_DICT_SECTION, ENCRYPTION_META_SECTION,
+    EncryptionProvider,
+};
+pub use file_cache::FileCache;
 pub use iterator::SsTableIterator;
+pub use prefix::{PrefixBloom, PrefixExtractor};
+pub(crate) use properties::SST_PROPERTIES_SIZE;
+pub use properties::{TableProperties, UserPropertiesMerger};
 
-use crate::block::{Block, SIZEOF_U32};
-use crate::key::{KeyBytes, KeySlice};
+use crate::block::varint::{get_varint, put_varint, varint_len};
+use crate::block::{Block, BlockIterator, CachedBlock, SIZEOF_U16, SIZEOF_U32, SIZEOF_U64};
+use crate::key::{KeyBytes, KeySlice, KeyVec, TS_RANGE_BEGIN};
 use crate::lsm_storage::BlockCache;
 
+use self::block_filter::BlockFilterIndex;
 use self::bloom::Bloom;
+use self::lazy_meta::LazyBlockMeta;
 use bytes::BufMut;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -40,100 +63,689 @@ pub struct BlockMeta {
     pub first_key: KeyBytes,
     /// The last key of the data block.
     pub last_key: KeyBytes,
+    /// The oldest version in the data block.
+    pub min_ts: u64,
+    /// The newest version in the data block.
+    pub max_ts: u64,
 }
 
-// -----------------------------------------------------------
-// |          Meta Section         |          Extra          |
-// -----------------------------------------------------------
-// |            metadata           | meta block offset (u32) |
-// -----------------------------------------------------------
+// -------------------------------------------------------------------------
+// |          Meta Section         |  Bloom Section  |        Footer        |
+// -------------------------------------------------------------------------
+// |            metadata           |  bloom filter   |  see `Footer`        |
+// -------------------------------------------------------------------------
 //   |
 //   |
-//   |---> | number of  meta block (u32) | metadata for block#1 | ... | metadata for block #N | checksum for metadata u32 | meta block offset u32 |
+//   |---> | number of meta block (u32) | metadata for block#1 | ... | metadata for block #N | max_ts u64 | checksum for metadata u32 |
 //                                              |
 //                                              |
 //                                              |
 //                                              |
 //                                              *
-// | offset for data block | first_key_len| first_key | first_key_ts | last_key_len | last_key | last_key_ts |
+// | offset delta (varint) | first_key_overlap (varint) | first_key_rest_len (varint) | first_key_rest | first_key_ts (u64) |
+// | last_key_overlap (varint) | last_key_rest_len (varint) | last_key_rest | last_key_ts (u64) |
+// | max_ts (varint) | max_ts - min_ts (varint) |
+//
+// The offset is relative to the previous block, the first key is prefix-compressed against the
+// first key of the previous block and the last key against the first key of the same block. The
+// number of meta blocks has `BLOCK_META_DELTA_FLAG` set, and the checksum covers it as well.
+// Sections without `BLOCK_META_TS_RANGE_FLAG` don't have the timestamp range of each block.
+//
+// With `BLOCK_META_CHUNKED_FLAG`, the metadata is split into chunks of `BLOCK_META_CHUNK_LEN`
+// blocks that don't refer to the previous chunk, so that each can be decoded on its own, followed
+// by an index of the chunks before max_ts:
+// | chunk index | chunk index offset (u32) | chunk index checksum (u32) |
+// with, for each chunk,
+// | chunk offset (u32) | chunk checksum (u32) | first_key_len (varint) | first_key | first_key_ts (u64) |
+// where offsets are relative to the start of the meta section. SSTs built with a partitioned index
+// have chunks of the partition length instead, see `FOOTER_PARTITIONED_INDEX`.
+//
+// Meta sections written before that have no flag, and store for each block
+// | offset (u32) | first_key_len (u16) | first_key | first_key_ts | last_key_len (u16) | last_key | last_key_ts |
+// with a checksum that doesn't cover the number of meta blocks.
+const BLOCK_META_DELTA_FLAG: u32 = 1 << 31;
+const BLOCK_META_TS_RANGE_FLAG: u32 = 1 << 30;
+const BLOCK_META_CHUNKED_FLAG: u32 = 1 << 29;
+const BLOCK_META_FLAGS: u32 =
+    BLOCK_META_DELTA_FLAG | BLOCK_META_TS_RANGE_FLAG | BLOCK_META_CHUNKED_FLAG;
+/// Number of blocks whose metadata is decoded at once when the meta section is loaded lazily.
+pub(crate) const BLOCK_META_CHUNK_LEN: usize = 512;
+/// Size of the chunk index offset, the chunk index checksum, max_ts and the checksum at the end of
+/// a chunked meta section.
+const CHUNKED_BLOCK_META_TAIL_SIZE: usize = SIZEOF_U32 * 3 + SIZEOF_U64;
+/// Meta sections at least this large are decoded lazily by `SsTable::open`.
+pub(crate) const LAZY_BLOCK_META_SIZE: u64 = 1 << 20;
+/// The timestamp range of blocks whose meta doesn't have it, which never excludes a version.
+const UNKNOWN_TS_RANGE: (u64, u64) = (0, u64::MAX);
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+fn put_prefix_compressed_key(buf: &mut Vec<u8>, prev: &[u8], key: KeySlice) {
+    let overlap = common_prefix_len(prev, key.key_ref());
+    put_varint(buf, overlap as u64);
+    put_varint(buf, (key.key_len() - overlap) as u64);
+    buf.put(&key.key_ref()[overlap..]);
+    buf.put_u64(key.ts());
+}
+
+/// Number of bytes `put_prefix_compressed_key` writes.
+pub(crate) fn prefix_compressed_key_len(prev: &[u8], key: KeySlice) -> usize {
+    let overlap = common_prefix_len(prev, key.key_ref());
+    let rest_len = key.key_len() - overlap;
+    varint_len(overlap as u64) + varint_len(rest_len as u64) + rest_len + SIZEOF_U64
+}
+
+fn get_prefix_compressed_key(buf: &mut &[u8], prev: &[u8]) -> Option<KeyBytes> {
+    let overlap = get_varint(buf)? as usize;
+    let rest_len = get_varint(buf)? as usize;
+    if overlap > prev.len() || buf.remaining() < rest_len.checked_add(SIZEOF_U64)? {
+        return None;
+    }
+    let mut key = Vec::with_capacity(overlap + rest_len);
+    key.extend_from_slice(&prev[..overlap]);
+    key.extend_from_slice(&buf[..rest_len]);
+    buf.advance(rest_len);
+    let ts = buf.get_u64();
+    Some(KeyBytes::from_bytes_with_ts(key.into(), ts))
+}
+
+fn get_ts_range(buf: &mut &[u8]) -> Option<(u64, u64)> {
+    let max_ts = get_varint(buf)?;
+    let min_ts = max_ts.checked_sub(get_varint(buf)?)?;
+    Some((min_ts, max_ts))
+}
+
 impl BlockMeta {
+    /// Number of bytes the meta of a block takes in the meta section, after the block at
+    /// `prev_offset` with `prev_first_key`, or after none at 0 with an empty key.
+    pub(crate) fn encoded_len(
+        prev_offset: usize,
+        prev_first_key: &[u8],
+        offset: usize,
+        first_key: KeySlice,
+        last_key: KeySlice,
+        min_ts: u64,
+        max_ts: u64,
+    ) -> usize {
+        varint_len((offset - prev_offset) as u64)
+            + prefix_compressed_key_len(prev_first_key, first_key)
+            + prefix_compressed_key_len(first_key.key_ref(), last_key)
+            + varint_len(max_ts)
+            + varint_len(max_ts - min_ts)
+    }
+
     /// Encode block meta to a buffer.
     /// You may add extra fields to the buffer,
     /// in order to help keep track of `first_key` when decoding from the same buffer in the future.
     pub fn encode_block_meta(block_meta: &[BlockMeta], max_ts: u64, buf: &mut Vec<u8>) {
-        let original_len = buf.len();
-        buf.put_u32(block_meta.len() as u32);
-
-        // TODO(xingyu): improve this by acquiring buffer pool with a calculated size;
-        for meta_data in block_meta.iter() {
-            // don't use raw_len here since we would retrieve key based on key_len
-            // the ts field is in next u64 field
-            let first_key_len = meta_data.first_key.key_len();
-            let last_key_len = meta_data.last_key.key_len();
-            buf.put_u32(meta_data.offset as u32);
+        Self::encode_block_meta_in_chunks(block_meta, max_ts, BLOCK_META_CHUNK_LEN, buf);
+    }
 
-            buf.put_u16(first_key_len as u16);
-            buf.put(meta_data.first_key.key_ref());
-            buf.put_u64(meta_data.first_key.ts());
+    /// Same as `encode_block_meta`, with chunks of `chunk_len` blocks. Returns the offset of the
+    /// chunk index relative to the start of the meta section.
+    pub(crate) fn encode_block_meta_in_chunks(
+        block_meta: &[BlockMeta],
+        max_ts: u64,
+        chunk_len: usize,
+        buf: &mut Vec<u8>,
+    ) -> usize {
+        let original_len = buf.len();
+        buf.put_u32(block_meta.len() as u32 | BLOCK_META_FLAGS);
 
-            buf.put_u16(last_key_len as u16);
-            buf.put(meta_data.last_key.key_ref());
-            buf.put_u64(meta_data.last_key.ts());
+        let mut chunk_index = Vec::new();
+        for chunk in block_meta.chunks(chunk_len) {
+            let chunk_start = buf.len();
+            let mut prev_offset = 0;
+            let mut prev_first_key: &[u8] = &[];
+            for meta_data in chunk.iter() {
+                put_varint(buf, (meta_data.offset - prev_offset) as u64);
+                put_prefix_compressed_key(buf, prev_first_key, meta_data.first_key.as_key_slice());
+                put_prefix_compressed_key(
+                    buf,
+                    meta_data.first_key.key_ref(),
+                    meta_data.last_key.as_key_slice(),
+                );
+                put_varint(buf, meta_data.max_ts);
+                put_varint(buf, meta_data.max_ts - meta_data.min_ts);
+                prev_offset = meta_data.offset;
+                prev_first_key = meta_data.first_key.key_ref();
+            }
+            chunk_index.put_u32((chunk_start - original_len) as u32);
+            chunk_index.put_u32(crc32fast::hash(&buf[chunk_start..]));
+            put_prefix_compressed_key(&mut chunk_index, &[], chunk[0].first_key.as_key_slice());
         }
+        let chunk_index_offset = buf.len() - original_len;
+        buf.put(&chunk_index[..]);
+        buf.put_u32(chunk_index_offset as u32);
+        buf.put_u32(crc32fast::hash(&chunk_index));
 
         // add max_ts at the end
         buf.put_u64(max_ts);
 
-        // WARN: we shouldn't include the first u32 since it's for number of block_meta
-        let checksum = crc32fast::hash(&buf[original_len + SIZEOF_U32..]);
+        let checksum = crc32fast::hash(&buf[original_len..]);
         buf.put_u32(checksum);
+        chunk_index_offset
     }
 
     /// Decode block meta from a buffer.
-    pub fn decode_block_meta(mut buf: &[u8]) -> Result<(Vec<BlockMeta>, u64)> {
-        let mut meta_data_blocks = Vec::new();
+    pub fn decode_block_meta(buf: &[u8]) -> Result<(Vec<BlockMeta>, u64)> {
+        if buf.len() < SIZEOF_U32 + SIZEOF_U64 + SIZEOF_U32 {
+            bail!("block meta is too short: {} bytes", buf.len());
+        }
+        let num_block_meta = (&buf[..SIZEOF_U32]).get_u32();
+        if num_block_meta & BLOCK_META_DELTA_FLAG == 0 {
+            return Self::decode_legacy_block_meta(buf);
+        }
+        let has_ts_range = num_block_meta & BLOCK_META_TS_RANGE_FLAG != 0;
+        let chunked = num_block_meta & BLOCK_META_CHUNKED_FLAG != 0;
+        let num_block_meta = (num_block_meta & !BLOCK_META_FLAGS) as usize;
+        let (raw_block_meta, mut checksum) = buf.split_at(buf.len() - SIZEOF_U32);
+        if checksum.get_u32() != crc32fast::hash(raw_block_meta) {
+            bail!("checksum doesn't match!");
+        }
+
+        // the number of blocks isn't trusted before they are decoded
+        let mut meta_data_blocks = Vec::with_capacity(num_block_meta.min(buf.len()));
+        let max_ts = (&raw_block_meta[raw_block_meta.len() - SIZEOF_U64..]).get_u64();
+        if !chunked {
+            let mut buf = &raw_block_meta[SIZEOF_U32..raw_block_meta.len() - SIZEOF_U64];
+            Self::decode_chunk(
+                &mut buf,
+                0,
+                num_block_meta,
+                has_ts_range,
+                &mut meta_data_blocks,
+            )?;
+            if buf.has_remaining() {
+                bail!("{} unexpected bytes after the block meta", buf.remaining());
+            }
+            return Ok((meta_data_blocks, max_ts));
+        }
+
+        if buf.len() < SIZEOF_U32 + CHUNKED_BLOCK_META_TAIL_SIZE {
+            bail!("block meta is too short: {} bytes", buf.len());
+        }
+        let tail = buf.len() - CHUNKED_BLOCK_META_TAIL_SIZE;
+        let chunk_index_offset = (&buf[tail..]).get_u32() as usize;
+        if !(SIZEOF_U32..=tail).contains(&chunk_index_offset) {
+            bail!(
+                "block meta chunk index at {} is out of range",
+                chunk_index_offset
+            );
+        }
+        // the chunk index is only needed to decode chunks lazily
+        let mut buf = &buf[SIZEOF_U32..chunk_index_offset];
+        for first_idx in (0..num_block_meta).step_by(BLOCK_META_CHUNK_LEN) {
+            let num = BLOCK_META_CHUNK_LEN.min(num_block_meta - first_idx);
+            Self::decode_chunk(
+                &mut buf,
+                first_idx,
+                num,
+                has_ts_range,
+                &mut meta_data_blocks,
+            )?;
+        }
+        if buf.has_remaining() {
+            bail!("{} unexpected bytes after the block meta", buf.remaining());
+        }
+        Ok((meta_data_blocks, max_ts))
+    }
 
+    /// Decode the metadata of `num` blocks, starting with the one at `first_idx`, that doesn't
+    /// refer to the blocks before it.
+    fn decode_chunk(
+        buf: &mut &[u8],
+        first_idx: usize,
+        num: usize,
+        has_ts_range: bool,
+        meta_data_blocks: &mut Vec<BlockMeta>,
+    ) -> Result<()> {
+        let chunk_start = meta_data_blocks.len();
+        let mut offset = 0;
+        for idx in first_idx..first_idx + num {
+            let prev_first_key = meta_data_blocks[chunk_start..]
+                .last()
+                .map_or(&[][..], |x: &BlockMeta| x.first_key.key_ref());
+            let Some(offset_delta) = get_varint(buf) else {
+                bail!("block meta {} is malformed", idx);
+            };
+            let Some(first_key) = get_prefix_compressed_key(buf, prev_first_key) else {
+                bail!("block meta {} is malformed", idx);
+            };
+            let Some(last_key) = get_prefix_compressed_key(buf, first_key.key_ref()) else {
+                bail!("block meta {} is malformed", idx);
+            };
+            let ts_range = if has_ts_range {
+                get_ts_range(buf)
+            } else {
+                Some(UNKNOWN_TS_RANGE)
+            };
+            let Some((min_ts, max_ts)) = ts_range else {
+                bail!("block meta {} is malformed", idx);
+            };
+            offset += offset_delta as usize;
+            meta_data_blocks.push(BlockMeta {
+                offset,
+                first_key,
+                last_key,
+                min_ts,
+                max_ts,
+            });
+        }
+        Ok(())
+    }
+
+    /// Decode a meta section written with fixed-size offsets and key lengths.
+    fn decode_legacy_block_meta(buf: &[u8]) -> Result<(Vec<BlockMeta>, u64)> {
+        let mut meta_data_blocks = Vec::new();
+        // the checksum doesn't cover the number of meta blocks
+        let (raw_block_meta, mut checksum) = buf.split_at(buf.len() - SIZEOF_U32);
+        if checksum.get_u32() != crc32fast::hash(&raw_block_meta[SIZEOF_U32..]) {
+            bail!("checksum doesn't match!");
+        }
+        let mut buf = raw_block_meta;
         let num_block_meta = buf.get_u32();
-        let raw_block_meta = &buf[..buf.remaining() - SIZEOF_U32];
 
-        for _ in 0..num_block_meta {
+        let get_key = |buf: &mut &[u8]| {
+            if buf.remaining() < SIZEOF_U16 {
+                return None;
+            }
+            let key_len = buf.get_u16() as usize;
+            if buf.remaining() < key_len + SIZEOF_U64 {
+                return None;
+            }
+            let key = buf.copy_to_bytes(key_len);
+            Some(KeyBytes::from_bytes_with_ts(key, buf.get_u64()))
+        };
+        for idx in 0..num_block_meta {
+            if buf.remaining() < SIZEOF_U32 {
+                bail!("block meta {} is malformed", idx);
+            }
             let offset = buf.get_u32() as usize;
-            let first_key_len = buf.get_u16() as usize;
-            let first_key = buf.copy_to_bytes(first_key_len);
-            let first_key_ts = buf.get_u64();
-
-            let last_key_len = buf.get_u16() as usize;
-            let last_key = buf.copy_to_bytes(last_key_len);
-            let last_key_ts = buf.get_u64();
+            let (Some(first_key), Some(last_key)) = (get_key(&mut buf), get_key(&mut buf)) else {
+                bail!("block meta {} is malformed", idx);
+            };
 
             meta_data_blocks.push(BlockMeta {
                 offset: offset,
-                first_key: KeyBytes::from_bytes_with_ts(first_key, first_key_ts),
-                last_key: KeyBytes::from_bytes_with_ts(last_key, last_key_ts),
+                first_key,
+                last_key,
+                min_ts: UNKNOWN_TS_RANGE.0,
+                max_ts: UNKNOWN_TS_RANGE.1,
             });
         }
 
+        if buf.remaining() != SIZEOF_U64 {
+            bail!("{} unexpected bytes after the block meta", buf.remaining());
+        }
         let max_ts = buf.get_u64();
-        let checksum = buf.get_u32();
-        if checksum != crc32fast::hash(raw_block_meta) {
-            bail!("checksum doesn't match!");
+        Ok((meta_data_blocks, max_ts))
+    }
+}
+
+/// When the checksums of data blocks are verified as they are read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumVerification {
+    /// Never, a corrupted block is only caught if it fails to decode.
+    Never,
+    /// When a block is read from the disk. Cached blocks are trusted, except for those cached by a
+    /// read without verification, which are read and verified again.
+    #[default]
+    OnFill,
+    /// As `OnFill`, and cached blocks are hashed again on every read to catch corruption in memory.
+    Always,
+}
+
+/// Identifies an SST, written at the very end of the file.
+const SST_MAGIC: u64 = 0x6d69_6e69_2d6c_736d; // "mini-lsm"
+/// The newest SST layout this build writes and reads. Version 2 added the prefix bloom filter to
+/// the bloom section, see `decode_bloom_section`, version 3 the properties section between the
+/// bloom section and the footer, see `TableProperties`, version 4 the partitioned index fields of
+/// the footer, version 5 the compression dictionary, see `CompressionDict`, version 6 the user
+/// properties, see `SsTableBuilder::add_property`, version 7 encryption, see
+/// `EncryptionProvider`, version 8 the per-block bloom filters, see `BlockFilterIndex`, and
+/// version 9 the bloom section checksum.
+pub const SST_FORMAT_VERSION: u16 = 9;
+pub(crate) const SST_FOOTER_SIZE: usize = SIZEOF_U32 * 10 + SIZEOF_U16 * 2 + SIZEOF_U64;
+/// Size of the footer of version 4, each version after it adds a u32.
+const V4_SST_FOOTER_SIZE: usize = SIZEOF_U32 * 5 + SIZEOF_U16 * 2 + SIZEOF_U64;
+/// Size of the footer before version 4.
+pub(crate) const LEGACY_SST_FOOTER_SIZE: usize = SIZEOF_U32 * 3 + SIZEOF_U16 + SIZEOF_U64;
+/// The meta section is split into index partitions of `index_partition_len` blocks, and the SST is
+/// always opened with only the top-level index in memory, see `LazyBlockMeta`.
+pub(crate) const FOOTER_PARTITIONED_INDEX: u16 = 1;
+/// The data blocks and the meta, bloom and compression dictionary sections are encrypted with the
+/// key of `encryption_key_id`.
+pub(crate) const FOOTER_ENCRYPTED: u16 = 2;
+
+// -------------------------------------------------------------------------------------------------
+// | meta offset (u32) | bloom offset (u32) | meta checksum (u32) | index offset (u32) |
+// | index partition len (u32) | compression dict offset (u32) | user properties offset (u32) |
+// | encryption key id (u32) | block filter offset (u32) | bloom checksum (u32) | flags (u16) |
+// | version (u16) | magic (u64) |
+// -------------------------------------------------------------------------------------------------
+// Footers before version 9 don't have the bloom checksum, those before version 8 the block filter
+// offset either, those before version 7 the encryption key id either, those before version 6 the
+// user properties offset either, those before version 5 the compression dict offset either, and
+// those before version 4 the index offset, the index partition len and the flags either.
+/// The fixed-size footer of an SST, locating the meta and bloom sections.
+pub struct Footer {
+    pub version: u16,
+    pub block_meta_offset: u32,
+    pub bloom_offset: u32,
+    /// crc32 of the whole meta section
+    pub meta_checksum: u32,
+    /// Offset of the top-level index, i.e. the chunk index of the meta section, 0 without
+    /// `FOOTER_PARTITIONED_INDEX`.
+    pub index_offset: u32,
+    /// Number of blocks in each index partition, 0 without `FOOTER_PARTITIONED_INDEX`.
+    pub index_partition_len: u32,
+    /// Offset of the compression dictionary section, between the bloom and properties sections,
+    /// 0 if the blocks are compressed without one.
+    pub compression_dict_offset: u32,
+    /// Offset of the user properties section, right after the bloom section, 0 if there are none.
+    pub user_properties_offset: u32,
+    /// `EncryptionProvider::key_id` of the key the SST is encrypted with, 0 without
+    /// `FOOTER_ENCRYPTED`.
+    pub encryption_key_id: u32,
+    /// Offset of the per-block bloom filters, right after the bloom section, 0 if the SST has a
+    /// single bloom filter or none.
+    pub block_filter_offset: u32,
+    /// crc32 of the whole bloom section as stored, 0 before version 9.
+    pub bloom_checksum: u32,
+    pub flags: u16,
+}
+
+impl Footer {
+    /// Size of a footer of the given format version.
+    pub(crate) fn size(version: u16) -> usize {
+        match version {
+            0..=3 => LEGACY_SST_FOOTER_SIZE,
+            4..SST_FORMAT_VERSION => V4_SST_FOOTER_SIZE + SIZEOF_U32 * (version - 4) as usize,
+            _ => SST_FOOTER_SIZE,
         }
+    }
 
-        Ok((meta_data_blocks, max_ts))
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u32(self.block_meta_offset);
+        buf.put_u32(self.bloom_offset);
+        buf.put_u32(self.meta_checksum);
+        if self.version >= 4 {
+            buf.put_u32(self.index_offset);
+            buf.put_u32(self.index_partition_len);
+            if self.version >= 5 {
+                buf.put_u32(self.compression_dict_offset);
+            }
+            if self.version >= 6 {
+                buf.put_u32(self.user_properties_offset);
+            }
+            if self.version >= 7 {
+                buf.put_u32(self.encryption_key_id);
+            }
+            if self.version >= 8 {
+                buf.put_u32(self.block_filter_offset);
+            }
+            if self.version >= 9 {
+                buf.put_u32(self.bloom_checksum);
+            }
+            buf.put_u16(self.flags);
+        }
+        buf.put_u16(self.version);
+        buf.put_u64(SST_MAGIC);
+    }
+
+    pub fn has_partitioned_index(&self) -> bool {
+        self.flags & FOOTER_PARTITIONED_INDEX != 0
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FOOTER_ENCRYPTED != 0
+    }
+
+    /// Read and check the footer at the end of `file`.
+    pub fn read(file: &FileObject) -> Result<Self> {
+        let file_len = file.size();
+        if file_len < LEGACY_SST_FOOTER_SIZE as u64 {
+            bail!("file of {} bytes is too short to be an SST", file_len);
+        }
+        // the version and the magic are at the end of every footer
+        let tail_len = (SIZEOF_U16 + SIZEOF_U64) as u64;
+        let version = file
+            .read(file_len - tail_len, SIZEOF_U16 as u64)?
+            .as_slice()
+            .get_u16();
+        let footer_size = Self::size(version) as u64;
+        if file_len < footer_size {
+            bail!("file of {} bytes is too short to be an SST", file_len);
+        }
+        let raw_footer = file.read(file_len - footer_size, footer_size)?;
+        Self::decode(&raw_footer, file_len)
+    }
+
+    /// Decode the footer at the end of a file of `file_len` bytes, checking that the sections it
+    /// points to are inside the file.
+    fn decode(mut buf: &[u8], file_len: u64) -> Result<Self> {
+        let mut tail = &buf[buf.len() - SIZEOF_U16 - SIZEOF_U64..];
+        let version = tail.get_u16();
+        let magic = tail.get_u64();
+        if magic != SST_MAGIC {
+            bail!(
+                "bad SST magic {:#018x}, the file is not an SST or is in the legacy format without a footer",
+                magic
+            );
+        }
+        if version > SST_FORMAT_VERSION {
+            bail!(
+                "unsupported SST format version {}, the newest supported one is {}",
+                version,
+                SST_FORMAT_VERSION
+            );
+        }
+        assert_eq!(buf.len(), Self::size(version));
+        let mut footer = Self {
+            block_meta_offset: buf.get_u32(),
+            bloom_offset: buf.get_u32(),
+            meta_checksum: buf.get_u32(),
+            index_offset: 0,
+            index_partition_len: 0,
+            compression_dict_offset: 0,
+            user_properties_offset: 0,
+            encryption_key_id: 0,
+            block_filter_offset: 0,
+            bloom_checksum: 0,
+            flags: 0,
+            version,
+        };
+        if version >= 4 {
+            footer.index_offset = buf.get_u32();
+            footer.index_partition_len = buf.get_u32();
+            if version >= 5 {
+                footer.compression_dict_offset = buf.get_u32();
+            }
+            if version >= 6 {
+                footer.user_properties_offset = buf.get_u32();
+            }
+            if version >= 7 {
+                footer.encryption_key_id = buf.get_u32();
+            }
+            if version >= 8 {
+                footer.block_filter_offset = buf.get_u32();
+            }
+            if version >= 9 {
+                footer.bloom_checksum = buf.get_u32();
+            }
+            footer.flags = buf.get_u16();
+        }
+        if footer.has_partitioned_index() && footer.is_encrypted() {
+            bail!("SST footer has a partitioned index, which can't be encrypted");
+        }
+        if footer.has_partitioned_index()
+            && (footer.index_partition_len == 0
+                || !(footer.block_meta_offset..footer.bloom_offset).contains(&footer.index_offset))
+        {
+            bail!(
+                "SST footer has a partitioned index at {} with partitions of {} blocks",
+                footer.index_offset,
+                footer.index_partition_len
+            );
+        }
+        let sections_end = file_len - Self::size(version) as u64;
+        // the optional sections are after the bloom section, 0 if absent
+        let optional_in_range = |offset: u32| {
+            offset == 0 || (footer.bloom_offset as u64..=sections_end).contains(&(offset as u64))
+        };
+        if footer.block_meta_offset > footer.bloom_offset
+            || footer.bloom_offset as u64 > sections_end
+            || !optional_in_range(footer.compression_dict_offset)
+            || !optional_in_range(footer.user_properties_offset)
+            || !optional_in_range(footer.block_filter_offset)
+        {
+            bail!(
+                "SST footer points to meta at {}, bloom at {}, compression dict at {}, user properties at {} and block filters at {} in a file of {} bytes",
+                footer.block_meta_offset,
+                footer.bloom_offset,
+                footer.compression_dict_offset,
+                footer.user_properties_offset,
+                footer.block_filter_offset,
+                file_len
+            );
+        }
+        Ok(footer)
+    }
+}
+
+// -----------------------------------------------------------------
+// | key bloom len (u32) | key bloom filter | prefix bloom filter  |
+// -----------------------------------------------------------------
+// Either filter may be empty. Before version 2, the whole section is the key bloom filter.
+/// Decodes the bloom section of an SST of the given format version.
+fn decode_bloom_section(version: u16, buf: &[u8]) -> Result<(Option<Bloom>, Option<PrefixBloom>)> {
+    let (raw_bloom, raw_prefix_bloom) = if version < 2 {
+        (buf, &[][..])
+    } else {
+        if buf.len() < SIZEOF_U32 {
+            bail!("bloom section is too short: {} bytes", buf.len());
+        }
+        let bloom_len = (&buf[..SIZEOF_U32]).get_u32() as usize;
+        if bloom_len > buf.len() - SIZEOF_U32 {
+            bail!(
+                "bloom filter of {} bytes in a bloom section of {} bytes",
+                bloom_len,
+                buf.len()
+            );
+        }
+        buf[SIZEOF_U32..].split_at(bloom_len)
+    };
+    // SSTs built without a bloom filter have an empty one
+    let bloom = if raw_bloom.is_empty() {
+        None
+    } else {
+        Some(Bloom::decode(raw_bloom)?)
+    };
+    let prefix_bloom = if raw_prefix_bloom.is_empty() {
+        None
+    } else {
+        Some(PrefixBloom::decode(raw_prefix_bloom)?)
+    };
+    Ok((bloom, prefix_bloom))
+}
+
+/// Where the reads of a `FileObject` go.
+enum FileHandle {
+    /// Kept open as long as the `FileObject`.
+    Open(File),
+    /// Opened on demand through a `FileCache`, which may close it between reads.
+    Cached(CachedFile),
+}
+
+struct CachedFile {
+    sst_id: usize,
+    path: PathBuf,
+    cache: Arc<FileCache>,
+    /// Set by `FileObject::pin`, so that the file can be read after it's removed.
+    pinned: OnceLock<Arc<File>>,
+}
+
+impl CachedFile {
+    fn open(&self) -> Result<Arc<File>> {
+        if let Some(file) = self.pinned.get() {
+            return Ok(file.clone());
+        }
+        // the file may have been pinned and removed since
+        self.cache
+            .try_get_with(self.sst_id, &self.path)
+            .or_else(|e| self.pinned.get().cloned().ok_or(e))
+    }
+}
+
+impl Drop for CachedFile {
+    fn drop(&mut self) {
+        self.cache.invalidate(self.sst_id);
     }
 }
 
-/// A file object.
-pub struct FileObject(Option<File>, u64);
+/// A file object, optionally memory-mapped.
+pub struct FileObject(Option<FileHandle>, u64, Option<Bytes>);
 
 impl FileObject {
+    /// Runs `f` on the open file, reopening it first if it was closed by its `FileCache`.
+    fn with_file<T>(&self, f: impl FnOnce(&File) -> std::io::Result<T>) -> Result<T> {
+        match self.0.as_ref().unwrap() {
+            FileHandle::Open(file) => Ok(f(file)?),
+            FileHandle::Cached(cached) => Ok(f(cached.open()?.as_ref())?),
+        }
+    }
+
+    /// Maps the whole file into memory if `mmap` is set, so that `read_bytes` slices the mapping
+    /// instead of reading from the file. The mapping is unmapped when the last `Bytes` referring
+    /// to it is dropped, which may be after the file is removed.
+    pub fn with_mmap(mut self, mmap: bool) -> Result<Self> {
+        if mmap && self.2.is_none() {
+            // SAFETY: SST files are immutable once created and are only removed, never modified
+            let mapping = self.with_file(|file| unsafe { memmap2::Mmap::map(file) })?;
+            self.2 = Some(Bytes::from_owner(mapping));
+        }
+        Ok(self)
+    }
+
+    /// Whether the file is memory-mapped.
+    pub fn is_mmap(&self) -> bool {
+        self.2.is_some()
+    }
+
+    /// Same as `read`, but without a copy if the file is memory-mapped.
+    pub fn read_bytes(&self, offset: u64, len: u64) -> Result<Bytes> {
+        if let Some(mapping) = &self.2 {
+            let end = self.check_range(offset, len)?;
+            return Ok(mapping.slice(offset as usize..end as usize));
+        }
+        Ok(Bytes::from(self.read(offset, len)?))
+    }
+
+    /// Returns the end of a read of `len` bytes at `offset`, or an error if it goes past the end of
+    /// the file, before anything is allocated for it.
+    fn check_range(&self, offset: u64, len: u64) -> Result<u64> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.1 => Ok(end),
+            _ => bail!(
+                "read of {} bytes at {} is beyond the end of the file of {} bytes",
+                len,
+                offset,
+                self.1
+            ),
+        }
+    }
+
     pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if self.is_mmap() {
+            return Ok(self.read_bytes(offset, len)?.to_vec());
+        }
+        self.check_range(offset, len)?;
         use std::os::unix::fs::FileExt;
         let mut data = vec![0; len as usize];
-        self.0
-            .as_ref()
-            .unwrap()
-            .read_exact_at(&mut data[..], offset)?;
+        self.with_file(|file| file.read_exact_at(&mut data[..], offset))?;
         Ok(data)
     }
 
@@ -141,20 +753,105 @@ impl FileObject {
         self.1
     }
 
+    /// Tells the kernel that the file is about to be read from start to end, so that it reads
+    /// ahead aggressively. A no-op for memory-mapped files and on platforms other than Linux.
+    pub fn advise_sequential(&self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.0.is_some() && !self.is_mmap() {
+            use std::os::fd::AsRawFd;
+            self.with_file(|file| {
+                for advice in [libc::POSIX_FADV_SEQUENTIAL, libc::POSIX_FADV_WILLNEED] {
+                    // SAFETY: the file descriptor stays open while `file` is borrowed
+                    let ret = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+                    if ret != 0 {
+                        return Err(std::io::Error::from_raw_os_error(ret));
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
     /// Create a new file object (day 2) and write the file to the disk (day 4).
     pub fn create(path: &Path, data: Vec<u8>) -> Result<Self> {
-        std::fs::write(path, &data)?;
-        File::open(path)?.sync_all()?;
+        Self::create_with_direct_io(path, data, false)
+    }
+
+    /// Same as `create`, but the file is written with `O_DIRECT` if `direct_io` is set, so that it
+    /// doesn't push other data out of the page cache. Reads still go through the page cache.
+    pub fn create_with_direct_io(path: &Path, data: Vec<u8>, direct_io: bool) -> Result<Self> {
+        if direct_io {
+            direct_io::write_file(path, &data)?;
+        } else {
+            std::fs::write(path, &data)?;
+            File::open(path)?.sync_all()?;
+        }
         Ok(FileObject(
-            Some(File::options().read(true).write(false).open(path)?),
+            Some(FileHandle::Open(
+                File::options().read(true).write(false).open(path)?,
+            )),
             data.len() as u64,
+            None,
         ))
     }
 
     pub fn open(path: &Path) -> Result<Self> {
         let file = File::options().read(true).write(false).open(path)?;
         let size = file.metadata()?.len();
-        Ok(FileObject(Some(file), size))
+        Ok(FileObject(Some(FileHandle::Open(file)), size, None))
+    }
+
+    /// Same as `open`, but the file of SST `sst_id` is opened through `file_cache` if there is
+    /// one, so that it's only open while the cache keeps it.
+    pub fn open_with_file_cache(
+        sst_id: usize,
+        path: &Path,
+        file_cache: Option<Arc<FileCache>>,
+    ) -> Result<Self> {
+        let Some(cache) = file_cache else {
+            return Self::open(path);
+        };
+        let size = cache.try_get_with(sst_id, path)?.metadata()?.len();
+        Ok(FileObject(
+            Some(FileHandle::Cached(CachedFile {
+                sst_id,
+                path: path.to_path_buf(),
+                cache,
+                pinned: OnceLock::new(),
+            })),
+            size,
+            None,
+        ))
+    }
+
+    /// Closes the file and reopens it through `file_cache` from then on, see
+    /// `open_with_file_cache`. A memory mapping stays as it is.
+    pub(crate) fn with_file_cache(
+        mut self,
+        sst_id: usize,
+        path: &Path,
+        file_cache: Option<Arc<FileCache>>,
+    ) -> Self {
+        if let Some(cache) = file_cache {
+            self.0 = Some(FileHandle::Cached(CachedFile {
+                sst_id,
+                path: path.to_path_buf(),
+                cache,
+                pinned: OnceLock::new(),
+            }));
+        }
+        self
+    }
+
+    /// Keeps a file opened through a `FileCache` open until this is dropped, so that it can still
+    /// be read after it's removed.
+    pub fn pin(&self) -> Result<()> {
+        if let (Some(FileHandle::Cached(cached)), false) = (&self.0, self.is_mmap()) {
+            let file = cached.open()?;
+            let _ = cached.pinned.set(file);
+        }
+        Ok(())
     }
 }
 
@@ -162,8 +859,10 @@ impl FileObject {
 pub struct SsTable {
     /// The actual storage unit of SsTable, the format is as above.
     pub(crate) file: FileObject,
-    /// The meta blocks that hold info for data blocks.
+    /// The meta blocks that hold info for data blocks, empty if they are loaded lazily.
     pub(crate) block_meta: Vec<BlockMeta>,
+    /// The meta blocks of SSTs with a large meta section, decoded on demand.
+    pub(crate) lazy_block_meta: Option<LazyBlockMeta>,
     /// The offset that indicates the start point of meta blocks in `file`.
     pub(crate) block_meta_offset: usize,
     id: usize,
@@ -171,8 +870,32 @@ pub struct SsTable {
     first_key: KeyBytes,
     last_key: KeyBytes,
     pub(crate) bloom: Option<Bloom>,
+    /// The bloom filter over key prefixes, for SSTs built with a prefix extractor.
+    pub(crate) prefix_bloom: Option<PrefixBloom>,
+    /// Locates the bloom filter of each block, for SSTs built with one per block instead of
+    /// `bloom`.
+    pub(crate) block_filters: Option<BlockFilterIndex>,
     /// The maximum timestamp stored in this SST, implemented in week 3.
     max_ts: u64,
+    /// `None` for SSTs written before the properties section.
+    properties: Option<TableProperties>,
+    /// The minimum timestamp stored in this SST, 0 if unknown.
+    min_ts: u64,
+    /// The dictionary the blocks are compressed with, if any.
+    compression_dict: Option<CompressionDict>,
+    /// Application metadata stamped on the SST by its builder.
+    user_properties: HashMap<String, Bytes>,
+    /// Decrypts the blocks of an encrypted SST.
+    encryption: Option<Arc<dyn EncryptionProvider>>,
+    /// Set for an SST created by `open_lazily`, which has none of the above but its key range
+    /// and size.
+    lazy: Option<Box<LazyTable>>,
+}
+
+/// Opens an SST on its first read, see `SsTable::open_lazily`.
+struct LazyTable {
+    open: Box<dyn Fn() -> Result<SsTable> + Send + Sync>,
+    table: OnceLock<SsTable>,
 }
 
 impl SsTable {
@@ -183,38 +906,300 @@ impl SsTable {
 
     /// Open SSTable from a file.
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
-        // read file and decode block_meta data
+        Self::open_with_encryption(id, block_cache, file, None)
+    }
+
+    /// Same as `open`, decrypting the SST with `encryption` if it is encrypted. An SST that isn't
+    /// is opened as is, and one encrypted with another key fails to open.
+    pub fn open_with_encryption(
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        file: FileObject,
+        encryption: Option<Arc<dyn EncryptionProvider>>,
+    ) -> Result<Self> {
         let file_len = file.size();
-        let raw_bloom_offset = file.read(file_len - SIZEOF_U32 as u64, SIZEOF_U32 as u64)?;
-        let bloom_offset = (&raw_bloom_offset[..]).get_u32();
-        let raw_bloom = file.read(
-            bloom_offset as u64,
-            file_len - SIZEOF_U32 as u64 - bloom_offset as u64,
+        let footer = Footer::read(&file).context("failed to read the SST footer")?;
+        let encryption = Self::encryption_for(
+            footer.is_encrypted().then_some(footer.encryption_key_id),
+            encryption,
         )?;
-        let bloom = Bloom::decode(&raw_bloom)?;
-
-        let raw_meta_offset =
-            file.read(bloom_offset as u64 - SIZEOF_U32 as u64, SIZEOF_U32 as u64)?;
-        let meta_offset = (&raw_meta_offset[..]).get_u32();
-        // here is the len, it's not the end.
-        let raw_meta = file.read(
-            meta_offset as u64,
-            bloom_offset as u64 - SIZEOF_U32 as u64 - meta_offset as u64,
-        )?;
-        let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta[..])?;
+        let decrypt = |section: u64, raw: Vec<u8>| match &encryption {
+            Some(encryption) => encryption.decrypt(section, &raw),
+            None => Ok(raw),
+        };
+        let meta_offset = footer.block_meta_offset as u64;
+        let bloom_offset = footer.bloom_offset as u64;
+        let (block_meta, lazy_block_meta, max_ts) =
+            Self::read_block_meta(&file, &footer, encryption.as_deref())
+                .context("failed to read the SST block meta")?;
+        let (first_key, last_key) = match &lazy_block_meta {
+            // min_ts would need every chunk to be decoded, it's left unknown
+            Some(lazy_block_meta) => (
+                lazy_block_meta.first_key().clone(),
+                // already decoded by `read_block_meta`
+                lazy_block_meta
+                    .get(&file, lazy_block_meta.num_blocks() - 1)?
+                    .last_key
+                    .clone(),
+            ),
+            None => (
+                block_meta.first().unwrap().first_key.clone(),
+                block_meta.last().unwrap().last_key.clone(),
+            ),
+        };
+
+        let mut bloom_end = file_len - Footer::size(footer.version) as u64;
+        let properties = if footer.version >= 3 {
+            if bloom_end - bloom_offset < SST_PROPERTIES_SIZE as u64 {
+                bail!("SST is too short for its properties section");
+            }
+            bloom_end -= SST_PROPERTIES_SIZE as u64;
+            let raw_properties = file.read(bloom_end, SST_PROPERTIES_SIZE as u64)?;
+            Some(
+                TableProperties::decode(&raw_properties)
+                    .context("failed to read the SST properties")?,
+            )
+        } else {
+            None
+        };
+        let compression_dict = if footer.compression_dict_offset != 0 {
+            let dict_offset = footer.compression_dict_offset as u64;
+            if dict_offset > bloom_end {
+                bail!(
+                    "SST compression dict at {} overlaps its properties",
+                    dict_offset
+                );
+            }
+            let compression_dict = file
+                .read(dict_offset, bloom_end - dict_offset)
+                .and_then(|raw| decrypt(ENCRYPTION_COMPRESSION_DICT_SECTION, raw))
+                .and_then(|raw_dict| CompressionDict::decode(&raw_dict))
+                .context("failed to read the SST compression dictionary")?;
+            bloom_end = dict_offset;
+            Some(compression_dict)
+        } else {
+            None
+        };
+        let user_properties = if footer.user_properties_offset != 0 {
+            let user_properties_offset = footer.user_properties_offset as u64;
+            if user_properties_offset > bloom_end {
+                bail!(
+                    "SST user properties at {} overlap its compression dict or properties",
+                    user_properties_offset
+                );
+            }
+            let user_properties = file
+                .read(user_properties_offset, bloom_end - user_properties_offset)
+                .and_then(|raw| properties::decode_user_properties(&raw))
+                .context("failed to read the SST user properties")?;
+            bloom_end = user_properties_offset;
+            user_properties
+        } else {
+            HashMap::new()
+        };
+        let block_filters = if footer.block_filter_offset != 0 {
+            let block_filter_offset = footer.block_filter_offset as u64;
+            if block_filter_offset > bloom_end {
+                bail!(
+                    "SST block filters at {} overlap the sections after them",
+                    block_filter_offset
+                );
+            }
+            let num_blocks = lazy_block_meta
+                .as_ref()
+                .map_or(block_meta.len(), |x| x.num_blocks());
+            let block_filters =
+                BlockFilterIndex::open(&file, block_filter_offset, bloom_end, num_blocks)
+                    .context("failed to read the SST block filters")?;
+            bloom_end = block_filter_offset;
+            Some(block_filters)
+        } else {
+            None
+        };
+        let (bloom, prefix_bloom) = file
+            .read(bloom_offset, bloom_end - bloom_offset)
+            .and_then(|raw| {
+                // a broken filter would rule out keys that are there, unlike a broken block
+                if footer.version >= 9 && crc32fast::hash(&raw) != footer.bloom_checksum {
+                    bail!("SST bloom checksum mismatch");
+                }
+                decrypt(ENCRYPTION_BLOOM_SECTION, raw)
+            })
+            .and_then(|raw_bloom| decode_bloom_section(footer.version, &raw_bloom))
+            .context("failed to read the SST bloom section")?;
+        // blocks without a timestamp range count as 0
+        let min_ts = block_meta.iter().map(|meta| meta.min_ts).min().unwrap_or(0);
         Ok(SsTable {
             id: id,
             file: file,
             block_meta_offset: meta_offset as usize,
-            first_key: block_meta.first().unwrap().first_key.clone(),
-            last_key: block_meta.last().unwrap().last_key.clone(),
+            first_key,
+            last_key,
             block_meta: block_meta,
+            lazy_block_meta,
             block_cache: block_cache,
-            bloom: Some(bloom),
+            bloom,
+            prefix_bloom,
+            block_filters,
             max_ts: max_ts,
+            min_ts,
+            properties,
+            compression_dict,
+            user_properties,
+            encryption,
+            lazy: None,
         })
     }
 
+    /// The provider to decrypt an SST encrypted with key `key_id` with, `None` if it isn't
+    /// encrypted. Fails if `encryption` doesn't have the key.
+    pub fn encryption_for(
+        key_id: Option<u32>,
+        encryption: Option<Arc<dyn EncryptionProvider>>,
+    ) -> Result<Option<Arc<dyn EncryptionProvider>>> {
+        Ok(match (key_id, encryption) {
+            (None, _) => None,
+            (Some(key_id), Some(encryption)) if encryption.key_id() == key_id => Some(encryption),
+            (Some(key_id), Some(encryption)) => bail!(
+                "SST is encrypted with key {}, but the encryption provider has key {}",
+                key_id,
+                encryption.key_id()
+            ),
+            (Some(key_id), None) => bail!(
+                "SST is encrypted with key {}, but no encryption provider is configured",
+                key_id
+            ),
+        })
+    }
+
+    /// An SST that reads its key range, size and largest timestamp from the arguments, and calls
+    /// `open` to open the SST for everything else, the first time it's needed. `encryption` is
+    /// the provider it's encrypted with, see `encryption_for`. An SST that fails to open is read
+    /// as if it had no filters or properties, and the reads of its blocks fail.
+    pub fn open_lazily(
+        id: usize,
+        first_key: KeyBytes,
+        last_key: KeyBytes,
+        file_size: u64,
+        max_ts: u64,
+        encryption: Option<Arc<dyn EncryptionProvider>>,
+        open: impl Fn() -> Result<SsTable> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            max_ts,
+            encryption,
+            lazy: Some(Box::new(LazyTable {
+                open: Box::new(open),
+                table: OnceLock::new(),
+            })),
+            ..Self::create_meta_only(id, file_size, first_key, last_key)
+        }
+    }
+
+    /// The SST opened for one created by `open_lazily`, opened by the first call, or this one.
+    pub fn opened(&self) -> Result<&SsTable> {
+        let Some(lazy) = &self.lazy else {
+            return Ok(self);
+        };
+        if let Some(table) = lazy.table.get() {
+            return Ok(table);
+        }
+        let table = (lazy.open)().with_context(|| format!("failed to open SST {}", self.id))?;
+        Ok(lazy.table.get_or_init(|| table))
+    }
+
+    /// The SST opened for one created by `open_lazily`, `None` for any other.
+    fn lazily_opened(&self) -> Result<Option<&SsTable>> {
+        match &self.lazy {
+            Some(_) => self.opened().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// `opened`, or this SST if it fails to open, for the accessors that can't fail.
+    fn opened_or_self(&self) -> &SsTable {
+        self.opened().unwrap_or(self)
+    }
+
+    /// The key the SST is encrypted with, `None` if it isn't encrypted.
+    pub fn encryption_key_id(&self) -> Option<u32> {
+        self.encryption
+            .as_ref()
+            .map(|encryption| encryption.key_id())
+    }
+
+    /// Whether the SST was created by `open_lazily` and hasn't been opened yet.
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+            .as_ref()
+            .is_some_and(|lazy| lazy.table.get().is_none())
+    }
+
+    /// Reads and decodes the meta section, or only its chunk index if it's large, and checks that
+    /// the blocks are in order before it.
+    fn read_block_meta(
+        file: &FileObject,
+        footer: &Footer,
+        encryption: Option<&dyn EncryptionProvider>,
+    ) -> Result<(Vec<BlockMeta>, Option<LazyBlockMeta>, u64)> {
+        let meta_offset = footer.block_meta_offset as u64;
+        let bloom_offset = footer.bloom_offset as u64;
+        // an encrypted meta section can only be decrypted as a whole
+        let lazy_block_meta = if encryption.is_some() {
+            None
+        } else if footer.has_partitioned_index() {
+            let lazy_block_meta = LazyBlockMeta::open(
+                file,
+                meta_offset,
+                bloom_offset,
+                footer.index_partition_len as usize,
+            )?;
+            match lazy_block_meta {
+                Some(lazy_block_meta)
+                    if lazy_block_meta.0.index_offset() == footer.index_offset as u64 =>
+                {
+                    Some(lazy_block_meta)
+                }
+                _ => bail!(
+                    "SST partitioned index at {} doesn't match its meta section",
+                    footer.index_offset
+                ),
+            }
+        } else if bloom_offset - meta_offset >= LAZY_BLOCK_META_SIZE {
+            LazyBlockMeta::open(file, meta_offset, bloom_offset, BLOCK_META_CHUNK_LEN)?
+        } else {
+            None
+        };
+        match lazy_block_meta {
+            Some((lazy_block_meta, max_ts)) => {
+                // decodes the last chunk, so that a broken one fails here rather than on a read
+                lazy_block_meta.get(file, lazy_block_meta.num_blocks() - 1)?;
+                Ok((vec![], Some(lazy_block_meta), max_ts))
+            }
+            None => {
+                let raw_meta = file.read(meta_offset, bloom_offset - meta_offset)?;
+                if crc32fast::hash(&raw_meta) != footer.meta_checksum {
+                    bail!("SST meta checksum mismatch");
+                }
+                let raw_meta = match encryption {
+                    Some(encryption) => encryption.decrypt(ENCRYPTION_META_SECTION, &raw_meta)?,
+                    None => raw_meta,
+                };
+                let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta[..])?;
+                let Some(last) = block_meta.last() else {
+                    bail!("SST has no data blocks");
+                };
+                // blocks are never empty
+                if block_meta.windows(2).any(|w| w[0].offset >= w[1].offset)
+                    || last.offset as u64 >= meta_offset
+                {
+                    bail!("SST block offsets are out of order or past the meta section");
+                }
+                Ok((block_meta, None, max_ts))
+            }
+        }
+    }
+
     /// Create a mock SST with only first key + last key metadata
     pub fn create_meta_only(
         id: usize,
@@ -223,60 +1208,282 @@ impl SsTable {
         last_key: KeyBytes,
     ) -> Self {
         Self {
-            file: FileObject(None, file_size),
+            file: FileObject(None, file_size, None),
             block_meta: vec![],
+            lazy_block_meta: None,
             block_meta_offset: 0,
             id,
             block_cache: None,
             first_key,
             last_key,
             bloom: None,
+            prefix_bloom: None,
+            block_filters: None,
             max_ts: 0,
+            min_ts: 0,
+            properties: None,
+            compression_dict: None,
+            user_properties: HashMap::new(),
+            encryption: None,
+            lazy: None,
         }
     }
 
     /// Read a block from the disk.
     pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
-        let offset = self.block_meta[block_idx].offset;
-        let offset_end = self
-            .block_meta
-            .get(block_idx + 1)
-            .map_or(self.block_meta_offset, |x| x.offset);
-        let raw_block = self
+        if let Some(table) = self.lazily_opened()? {
+            return table.read_block(block_idx);
+        }
+        self.read_block_from_disk(block_idx, true)
+    }
+
+    /// Where the block ends in the file, including its checksum.
+    fn block_end(&self, block_idx: usize) -> Result<usize> {
+        let end = if block_idx + 1 < self.num_of_blocks() {
+            self.block_meta(block_idx + 1)?.offset
+        } else {
+            self.block_meta_offset
+        };
+        // the block meta of a lazily loaded section is only checked here
+        let offset = self.block_meta(block_idx)?.offset;
+        if end < offset {
+            bail!(
+                "block {} at {} ends before it starts, at {}",
+                block_idx,
+                offset,
+                end
+            );
+        }
+        Ok(end)
+    }
+
+    fn read_block_from_disk(&self, block_idx: usize, verify_checksum: bool) -> Result<Arc<Block>> {
+        let offset = self.block_meta(block_idx)?.offset;
+        let offset_end = self.block_end(block_idx)?;
+        // read the block together with its checksum, and decode it without another copy
+        let raw = self
             .file
-            .read(offset as u64, (offset_end - offset - SIZEOF_U32) as u64)?;
-        let raw_checksum = self
+            .read_bytes(offset as u64, (offset_end - offset) as u64)?;
+        self.decode_block(block_idx, raw, verify_checksum)
+    }
+
+    /// Reads the blocks from `start_idx` on that fit in `max_bytes` with a single read, at least
+    /// one. Returns them along with the index of the block after them, see `decode_block_in`.
+    pub(crate) fn read_raw_blocks(
+        &self,
+        start_idx: usize,
+        max_bytes: usize,
+    ) -> Result<(Bytes, usize)> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.read_raw_blocks(start_idx, max_bytes);
+        }
+        let offset = self.block_meta(start_idx)?.offset;
+        let mut end_idx = start_idx + 1;
+        let mut offset_end = self.block_end(start_idx)?;
+        while end_idx < self.num_of_blocks() {
+            let next_end = self.block_end(end_idx)?;
+            if next_end - offset > max_bytes {
+                break;
+            }
+            offset_end = next_end;
+            end_idx += 1;
+        }
+        let raw = self
             .file
-            .read((offset_end - SIZEOF_U32) as u64, SIZEOF_U32 as u64)?;
-        let checksum = (&raw_checksum[..]).get_u32();
+            .read_bytes(offset as u64, (offset_end - offset) as u64)?;
+        Ok((raw, end_idx))
+    }
+
+    /// Decodes block `block_idx` out of the blocks read by `read_raw_blocks` from `start_idx`.
+    pub(crate) fn decode_block_in(
+        &self,
+        raw_blocks: &Bytes,
+        start_idx: usize,
+        block_idx: usize,
+        verify_checksum: bool,
+    ) -> Result<Arc<Block>> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.decode_block_in(raw_blocks, start_idx, block_idx, verify_checksum);
+        }
+        let base = self.block_meta(start_idx)?.offset;
+        let offset = self.block_meta(block_idx)?.offset - base;
+        let offset_end = self.block_end(block_idx)? - base;
+        if offset_end > raw_blocks.len() {
+            bail!(
+                "block {} is not within the {} bytes read from block {}",
+                block_idx,
+                raw_blocks.len(),
+                start_idx
+            );
+        }
+        self.decode_block(
+            block_idx,
+            raw_blocks.slice(offset..offset_end),
+            verify_checksum,
+        )
+    }
+
+    /// Decodes a block read from the disk along with its checksum.
+    fn decode_block(
+        &self,
+        block_idx: usize,
+        raw: Bytes,
+        verify_checksum: bool,
+    ) -> Result<Arc<Block>> {
+        if raw.len() < SIZEOF_U32 {
+            bail!("block {} is too short: {} bytes", block_idx, raw.len());
+        }
+        let raw_block = raw.slice(..raw.len() - SIZEOF_U32);
+        let checksum = (&raw[raw.len() - SIZEOF_U32..]).get_u32();
 
-        if crc32fast::hash(&raw_block) != checksum {
+        if verify_checksum && crc32fast::hash(&raw_block) != checksum {
             bail!("checksum doesn't match!");
         }
+        let raw_block = match &self.encryption {
+            Some(encryption) => encryption.decrypt(block_idx as u64, &raw_block)?.into(),
+            None => raw_block,
+        };
 
-        let block = Block::decode(&raw_block[..]);
+        // the cache keeps the decompressed block
+        let block = Block::decode_bytes(compression::decompress_block(
+            raw_block,
+            self.compression_dict.as_ref(),
+        )?)?;
         Ok(Arc::new(block))
     }
 
+    /// Reads a block from the disk to be cached, verified unless `verify_checksums` is `Never`.
+    fn load_block(
+        &self,
+        block_idx: usize,
+        verify_checksums: ChecksumVerification,
+    ) -> Result<CachedBlock> {
+        let verified = verify_checksums != ChecksumVerification::Never;
+        let block = self.read_block_from_disk(block_idx, verified)?;
+        Ok(CachedBlock {
+            content_checksum: verified.then(|| block.content_checksum()),
+            block,
+        })
+    }
+
     /// Read a block from disk, with block cache. (Day 4)
     pub fn read_block_cached(&self, block_idx: usize) -> Result<Arc<Block>> {
+        self.read_block_with(block_idx, true, ChecksumVerification::default())
+    }
+
+    /// Read a block from the block cache if it is there, otherwise from the disk without adding
+    /// it to the cache. Used by scans that shouldn't evict the working set, like compaction.
+    pub fn read_block_cached_no_fill(&self, block_idx: usize) -> Result<Arc<Block>> {
+        self.read_block_with(block_idx, false, ChecksumVerification::default())
+    }
+
+    /// Read a block through the block cache, adding it on a miss if `fill_cache` is set, and
+    /// verify its checksum as `verify_checksums` says.
+    pub(crate) fn read_block_with(
+        &self,
+        block_idx: usize,
+        fill_cache: bool,
+        verify_checksums: ChecksumVerification,
+    ) -> Result<Arc<Block>> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.read_block_with(block_idx, fill_cache, verify_checksums);
+        }
+        let verify_checksum = verify_checksums != ChecksumVerification::Never;
         // need to handle if block_cache was None
-        if let Some(block_cache) = &self.block_cache {
-            let cache_key = (self.id, block_idx);
-            let block = block_cache
-                .clone()
-                .try_get_with(cache_key, || self.read_block(block_idx));
-            assert!(block.is_ok());
-            Ok(block.unwrap())
+        let Some(block_cache) = &self.block_cache else {
+            return self.read_block_from_disk(block_idx, verify_checksum);
+        };
+        let key = (self.id, block_idx);
+        let cached = if fill_cache {
+            block_cache.try_get_with(key, || self.load_block(block_idx, verify_checksums))?
         } else {
-            self.read_block(block_idx)
+            match block_cache.get(&key) {
+                Some(cached) => cached,
+                None => return self.read_block_from_disk(block_idx, verify_checksum),
+            }
+        };
+        match cached.content_checksum {
+            Some(content_checksum) => {
+                if verify_checksums == ChecksumVerification::Always
+                    && cached.block.content_checksum() != content_checksum
+                {
+                    bail!("cached block {} of SST {} is corrupted", block_idx, self.id);
+                }
+                Ok(cached.block)
+            }
+            None if verify_checksum => {
+                // cached by a read that didn't verify it, read it again to do so
+                let cached = self.load_block(block_idx, verify_checksums)?;
+                block_cache.insert(key, cached.clone());
+                Ok(cached.block)
+            }
+            None => Ok(cached.block),
         }
     }
 
+    /// Loads the data blocks that may contain keys in the user key range into the block cache, and
+    /// returns how many there are. The block meta and the bloom filter are always in memory since
+    /// `open`, so this is a no-op without a block cache.
+    pub fn prewarm(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<usize> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.prewarm(lower, upper);
+        }
+        if self.block_cache.is_none() || self.num_of_blocks() == 0 {
+            return Ok(0);
+        }
+        let start = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                self.find_block_idx(KeySlice::from_slice(key, TS_RANGE_BEGIN))?
+            }
+            Bound::Unbounded => 0,
+        };
+        let mut num_blocks = 0;
+        for block_idx in start..self.num_of_blocks() {
+            let first_key = self.block_meta(block_idx)?.first_key.key_ref();
+            let past_upper = match upper {
+                Bound::Included(key) => first_key > key,
+                Bound::Excluded(key) => first_key >= key,
+                Bound::Unbounded => false,
+            };
+            if past_upper {
+                break;
+            }
+            self.read_block_cached(block_idx)?;
+            num_blocks += 1;
+        }
+        Ok(num_blocks)
+    }
+
+    /// Keeps the file open until the SST is dropped, see `FileObject::pin`. Called before the file
+    /// is removed, for the reads still going on.
+    pub(crate) fn pin_file(&self) -> Result<()> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.pin_file();
+        }
+        self.file.pin()
+    }
+
+    /// Loads the first data block into the block cache, for SSTs opened with `prewarm_on_open`.
+    pub(crate) fn prewarm_first_block(&self) -> Result<()> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.prewarm_first_block();
+        }
+        if self.block_cache.is_some() && self.num_of_blocks() > 0 {
+            self.read_block_cached(0)?;
+        }
+        Ok(())
+    }
+
     /// Find the block that may contain `key`.
     /// Note: You may want to make use of the `first_key` stored in `BlockMeta`.
     /// You may also assume the key-value pairs stored in each consecutive block are sorted.
-    pub fn find_block_idx(&self, key: KeySlice) -> usize {
+    pub fn find_block_idx(&self, key: KeySlice) -> Result<usize> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.find_block_idx(key);
+        }
+        if let Some(lazy_block_meta) = &self.lazy_block_meta {
+            return lazy_block_meta.find_block_idx(&self.file, key);
+        }
         // binary search on block_meta
         let mut left = 0 as usize;
         let mut right = self.block_meta.len() - 1;
@@ -293,21 +1500,43 @@ impl SsTable {
         }
         if self.block_meta[left].first_key.as_key_slice() > key {
             // use saturating_sub to prevent "attempt to subtract with overflow"
-            return left.saturating_sub(1);
+            return Ok(left.saturating_sub(1));
         }
         if self.block_meta[right].first_key.as_key_slice() > key {
-            return right.saturating_sub(1);
+            return Ok(right.saturating_sub(1));
         }
-        return right;
+        return Ok(right);
 
         // self.block_meta
         //     .partition_point(|meta| meta.first_key.as_key_slice() <= key)
         //     .saturating_sub(1)
     }
 
+    /// The meta of a data block, which may need to be read from the disk first.
+    pub fn block_meta(&self, block_idx: usize) -> Result<&BlockMeta> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.block_meta(block_idx);
+        }
+        match &self.lazy_block_meta {
+            Some(lazy_block_meta) => lazy_block_meta.get(&self.file, block_idx),
+            None => match self.block_meta.get(block_idx) {
+                Some(meta) => Ok(meta),
+                None => bail!(
+                    "block {} is out of range of {} blocks",
+                    block_idx,
+                    self.block_meta.len()
+                ),
+            },
+        }
+    }
+
     /// Get number of data blocks.
     pub fn num_of_blocks(&self) -> usize {
-        self.block_meta.len()
+        let table = self.opened_or_self();
+        table
+            .lazy_block_meta
+            .as_ref()
+            .map_or(table.block_meta.len(), |x| x.num_blocks())
     }
 
     pub fn first_key(&self) -> &KeyBytes {
@@ -322,6 +1551,46 @@ impl SsTable {
         self.file.1
     }
 
+    /// Roughly how many bytes of data blocks come before `key`, to a block: where the block that
+    /// may contain it starts, or where the data blocks end if it's after the last key.
+    pub fn approximate_offset_of(&self, key: KeySlice) -> Result<u64> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.approximate_offset_of(key);
+        }
+        let block_idx = self.find_block_idx(key)?;
+        let meta = self.block_meta(block_idx)?;
+        if meta.last_key.as_key_slice() >= key {
+            return Ok(meta.offset as u64);
+        }
+        // the key is between this block and the next one
+        if block_idx + 1 < self.num_of_blocks() {
+            Ok(self.block_meta(block_idx + 1)?.offset as u64)
+        } else {
+            Ok(self.block_meta_offset as u64)
+        }
+    }
+
+    /// Roughly how many bytes of data blocks hold keys in the range, see `approximate_offset_of`.
+    /// A range within a single block may be 0.
+    pub fn approximate_size_of_range(
+        &self,
+        lower: Bound<KeySlice>,
+        upper: Bound<KeySlice>,
+    ) -> Result<u64> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.approximate_size_of_range(lower, upper);
+        }
+        let lower = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => self.approximate_offset_of(key)?,
+            Bound::Unbounded => 0,
+        };
+        let upper = match upper {
+            Bound::Included(key) | Bound::Excluded(key) => self.approximate_offset_of(key)?,
+            Bound::Unbounded => self.block_meta_offset as u64,
+        };
+        Ok(upper.saturating_sub(lower).min(self.table_size()))
+    }
+
     pub fn sst_id(&self) -> usize {
         self.id
     }
@@ -329,4 +1598,115 @@ impl SsTable {
     pub fn max_ts(&self) -> u64 {
         self.max_ts
     }
+
+    /// The minimum timestamp stored in this SST, taken from the block meta. It's 0 for SSTs
+    /// written before blocks had a timestamp range and for SSTs whose block meta is loaded lazily.
+    pub fn min_ts(&self) -> u64 {
+        self.opened_or_self().min_ts
+    }
+
+    /// Whether a snapshot read at `read_ts` may see any version in this SST.
+    pub fn has_versions_visible_at(&self, read_ts: u64) -> bool {
+        self.min_ts() <= read_ts
+    }
+
+    /// Reads every block to check its checksum, and that the keys are sorted and match the block
+    /// meta. The footer and the meta section are already checked by `open`, except for the chunks
+    /// of a lazily loaded meta section that are checked as they are read here.
+    pub fn verify(&self) -> Result<()> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.verify();
+        }
+        let mut prev_key = KeyVec::new();
+        for block_idx in 0..self.num_of_blocks() {
+            let meta = self.block_meta(block_idx)?;
+            let block = self
+                .read_block(block_idx)
+                .with_context(|| format!("failed to read block {}", block_idx))?;
+            let mut iter = BlockIterator::create_and_seek_to_first(block);
+            if !iter.is_valid() || iter.key() != meta.first_key.as_key_slice() {
+                bail!("block {} doesn't start with its first key", block_idx);
+            }
+            while iter.is_valid() {
+                if !prev_key.is_empty() && iter.key() <= prev_key.as_key_slice() {
+                    bail!(
+                        "key {:?} in block {} is not after {:?}",
+                        iter.key().key_ref(),
+                        block_idx,
+                        prev_key.key_ref()
+                    );
+                }
+                prev_key.set_from_slice(iter.key());
+                iter.next();
+            }
+            if prev_key.as_key_slice() != meta.last_key.as_key_slice() {
+                bail!("block {} doesn't end with its last key", block_idx);
+            }
+        }
+        Ok(())
+    }
+
+    /// The bloom filter of this SST, if it was built with one.
+    pub fn bloom(&self) -> Option<&Bloom> {
+        self.opened_or_self().bloom.as_ref()
+    }
+
+    /// Whether `key` may be in this SST according to its bloom filter, always true without one.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bloom()
+            .is_none_or(|bloom| bloom.may_contain(farmhash::fingerprint32(key)))
+    }
+
+    /// Whether `key` may be in this SST according to the bloom filter of the block it would be
+    /// in, always true for SSTs without per-block bloom filters. The filter is read through the
+    /// block cache.
+    pub fn block_may_contain(&self, key: &[u8]) -> Result<bool> {
+        if let Some(table) = self.lazily_opened()? {
+            return table.block_may_contain(key);
+        }
+        let Some(block_filters) = &self.block_filters else {
+            return Ok(true);
+        };
+        let mut block_idx = self.find_block_idx(KeySlice::from_slice(key, TS_RANGE_BEGIN))?;
+        // the newest version of the key may start the next block
+        if self.block_meta(block_idx)?.last_key.key_ref() < key
+            && block_idx + 1 < self.num_of_blocks()
+        {
+            block_idx += 1;
+        }
+        let filter = match &self.block_cache {
+            Some(block_cache) => block_cache
+                .try_get_block_filter_with((self.id, block_idx), || {
+                    block_filters.read(&self.file, block_idx)
+                })?,
+            None => Arc::new(block_filters.read(&self.file, block_idx)?),
+        };
+        Ok(filter.may_contain(farmhash::fingerprint32(key)))
+    }
+
+    /// The prefix bloom filter of this SST, if it was built with a prefix extractor.
+    pub fn prefix_bloom(&self) -> Option<&PrefixBloom> {
+        self.opened_or_self().prefix_bloom.as_ref()
+    }
+
+    /// The entry statistics of the SST, `None` if it was written before they were recorded.
+    pub fn properties(&self) -> Option<&TableProperties> {
+        self.opened_or_self().properties.as_ref()
+    }
+
+    pub fn compression_dict(&self) -> Option<&CompressionDict> {
+        self.opened_or_self().compression_dict.as_ref()
+    }
+
+    /// The properties added with `SsTableBuilder::add_property`, empty if there are none.
+    pub fn user_properties(&self) -> &HashMap<String, Bytes> {
+        &self.opened_or_self().user_properties
+    }
+
+    /// Whether this SST may have keys in the range according to its prefix bloom filter, always
+    /// true without one or if the bounds don't share a prefix.
+    pub fn may_contain_prefix(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+        self.prefix_bloom()
+            .is_none_or(|prefix_bloom| prefix_bloom.may_contain_range(lower, upper))
+    }
 }
@@ -0,0 +1,120 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut};
+
+use super::FileObject;
+use super::bloom::Bloom;
+use crate::block::SIZEOF_U32;
+
+// -----------------------------------------------------------------------------------------------
+// | filter #0 | ... | filter #N-1 | filter offset (u32) x (N + 1) | num filters (u32) | checksum |
+// -----------------------------------------------------------------------------------------------
+// Each filter is a `Bloom` over the keys of the data block with the same index, with its own
+// checksum. The offsets are relative to the start of the section, the last one is where the
+// filters end, and the checksum covers the offsets and the number of filters.
+/// Writes the per-block bloom filters, `filters` being the encoded filters one after another and
+/// `offsets` where each starts in it.
+pub(crate) fn encode_block_filters(filters: &[u8], offsets: &[u32], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(filters);
+    let index_start = buf.len();
+    for offset in offsets {
+        buf.put_u32(*offset);
+    }
+    buf.put_u32(filters.len() as u32);
+    buf.put_u32(offsets.len() as u32);
+    let checksum = crc32fast::hash(&buf[index_start..]);
+    buf.put_u32(checksum);
+}
+
+/// Locates the bloom filter of each data block of an SST built with
+/// `SsTableBuilder::with_bloom_per_block`. Only the offsets are kept in memory, the filters are read
+/// on demand through the block cache, see `SsTable::block_may_contain`.
+pub(crate) struct BlockFilterIndex {
+    /// Offset of the section in the file.
+    offset: u64,
+    /// Where the filter of each block starts in the section, and where the last one ends.
+    offsets: Vec<u32>,
+}
+
+impl BlockFilterIndex {
+    pub(crate) fn new(offset: u64, offsets: Vec<u32>) -> Self {
+        Self { offset, offsets }
+    }
+
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Reads the offsets at the end of the section in `offset..end`, which should have a filter
+    /// for each of the `num_blocks` blocks.
+    pub(crate) fn open(
+        file: &FileObject,
+        offset: u64,
+        end: u64,
+        num_blocks: usize,
+    ) -> Result<Self> {
+        let tail_len = (SIZEOF_U32 * 2) as u64;
+        if end - offset < tail_len {
+            bail!("block filter section of {} bytes", end - offset);
+        }
+        let num_filters = file
+            .read(end - tail_len, SIZEOF_U32 as u64)?
+            .as_slice()
+            .get_u32();
+        if num_filters as usize != num_blocks {
+            bail!("{} block filters for {} blocks", num_filters, num_blocks);
+        }
+        // the offsets and the number of filters, followed by the checksum
+        let index_len = (num_filters as u64 + 2) * SIZEOF_U32 as u64;
+        if end - offset < index_len + SIZEOF_U32 as u64 {
+            bail!(
+                "block filter section of {} bytes is too short for {} filters",
+                end - offset,
+                num_filters
+            );
+        }
+        let index_start = end - SIZEOF_U32 as u64 - index_len;
+        let raw_index = file.read(index_start, index_len + SIZEOF_U32 as u64)?;
+        let (mut raw_offsets, mut checksum) = raw_index.split_at(index_len as usize);
+        if crc32fast::hash(raw_offsets) != checksum.get_u32() {
+            bail!("block filter index checksum mismatch");
+        }
+        let offsets = (0..=num_filters)
+            .map(|_| raw_offsets.get_u32())
+            .collect::<Vec<_>>();
+        if offsets.windows(2).any(|w| w[0] > w[1])
+            || offsets.last().copied() != Some((index_start - offset) as u32)
+        {
+            bail!("block filter offsets are out of order");
+        }
+        Ok(Self { offset, offsets })
+    }
+
+    /// Reads and checks the filter of block `block_idx`.
+    pub(crate) fn read(&self, file: &FileObject, block_idx: usize) -> Result<Bloom> {
+        let (Some(&start), Some(&end)) =
+            (self.offsets.get(block_idx), self.offsets.get(block_idx + 1))
+        else {
+            bail!(
+                "block {} is out of range of {} block filters",
+                block_idx,
+                self.offsets.len() - 1
+            );
+        };
+        let raw = file.read(self.offset + start as u64, (end - start) as u64)?;
+        Bloom::decode(&raw)
+    }
+}
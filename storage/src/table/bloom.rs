@@ -63,6 +63,9 @@ impl<T: AsMut<[u8]>> BitSliceMut for T {
 impl Bloom {
     /// Decode a bloom filter
     pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() <= SIZEOF_U32 {
+            bail!("bloom filter is too short: {} bytes", buf.len());
+        }
         // exclude checksum (u32) length from total buf length;
         let original_len = buf.len() - SIZEOF_U32;
         let checksum = (&buf[original_len..]).get_u32();
@@ -125,6 +128,21 @@ impl Bloom {
         }
     }
 
+    /// Size of the encoded filter over `num_keys` keys built by `build_from_key_hashes`.
+    pub(crate) fn encoded_size(num_keys: usize, bits_per_key: usize) -> usize {
+        (num_keys * bits_per_key).max(64).div_ceil(8) + 1 + SIZEOF_U32
+    }
+
+    /// Number of bits in the filter.
+    pub fn num_bits(&self) -> usize {
+        self.filter.bit_len()
+    }
+
+    /// Number of probes for each key.
+    pub fn num_hashes(&self) -> u8 {
+        self.k
+    }
+
     /// Check if a bloom filter may contain some data
     pub fn may_contain(&self, h: u32) -> bool {
         if self.k > 30 {
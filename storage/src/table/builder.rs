@@ -12,21 +12,45 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::sync::Arc;
 
-use anyhow::Result;
-use bytes::BufMut;
+use anyhow::{Result, bail};
+use bytes::{BufMut, Bytes};
 use crc32fast;
 
-use super::{BlockMeta, SsTable};
+use super::{
+    BLOCK_META_CHUNK_LEN, BlockMeta, ENCRYPTION_BLOOM_SECTION, ENCRYPTION_COMPRESSION_DICT_SECTION,
+    ENCRYPTION_META_SECTION, EncryptionProvider, FOOTER_ENCRYPTED, FOOTER_PARTITIONED_INDEX,
+    Footer, SST_FOOTER_SIZE, SST_FORMAT_VERSION, SST_PROPERTIES_SIZE, SsTable,
+    prefix_compressed_key_len,
+};
 use crate::{
-    block::BlockBuilder,
+    block::{BlockAddResult, BlockBuilder, SIZEOF_U32, SIZEOF_U64, varint::varint_len},
     key::{KeySlice, KeyVec},
-    lsm_storage::BlockCache,
-    table::{FileObject, bloom::Bloom},
+    lsm_storage::{BlockCache, DEFAULT_BLOOM_FALSE_POSITIVE_RATE},
+    table::{
+        FileCache, FileObject, PrefixBloom, PrefixExtractor, TableProperties,
+        block_filter::{BlockFilterIndex, encode_block_filters},
+        bloom::Bloom,
+        compression::{
+            COMPRESSION_NONE, CompressionDict, CompressionOptions, compress_block,
+            compress_block_with_dict,
+        },
+        properties::encode_user_properties,
+    },
 };
 
+/// Values sampled to train the compression dictionary, as a multiple of its size.
+const COMPRESSION_DICT_SAMPLE_RATIO: usize = 100;
+/// SSTs with less data than this are compressed without a dictionary, which wouldn't pay for its
+/// own size.
+pub(crate) const COMPRESSION_DICT_MIN_DATA_SIZE: usize = 64 << 10;
+/// The number of blocks, the chunk index offset and checksum, max_ts and the checksum of the
+/// meta section.
+const BLOCK_META_TAIL_SIZE: usize = SIZEOF_U32 * 4 + SIZEOF_U64;
+
 /// Builds an SSTable from key-value pairs.
 pub struct SsTableBuilder {
     builder: BlockBuilder,
@@ -34,11 +58,68 @@ pub struct SsTableBuilder {
     last_key: KeyVec,
     data: Vec<u8>,
     pub(crate) meta: Vec<BlockMeta>,
-    block_size: usize,
-    // use it for bloom filter
+    // size of `meta` encoded in a single chunk, see `estimated_size`
+    meta_size: usize,
+    // use it for bloom filter, only the keys of the current block with per-block filters
     key_hashes: Vec<u32>,
     // record max ts
     max_ts: u64,
+    // the timestamp range of the current block
+    block_min_ts: u64,
+    block_max_ts: u64,
+    // hard cap on the size of a single entry, `None` means no limit
+    max_entry_size: Option<usize>,
+    compression: CompressionOptions,
+    // the maximum size of the zstd dictionary trained over the values, 0 doesn't train one
+    compression_dict_size: usize,
+    // values sampled for the dictionary, one after another
+    dict_samples: Vec<u8>,
+    dict_sample_sizes: Vec<usize>,
+    // `None` doesn't build a bloom filter
+    bloom_false_positive_rate: Option<f64>,
+    // build a bloom filter for each block instead of one for the SST
+    bloom_per_block: bool,
+    // the per-block bloom filters one after another, and where each starts
+    block_filters: Vec<u8>,
+    block_filter_offsets: Vec<u32>,
+    // reused to encode each block before it is compressed into `data`
+    block_buf: Vec<u8>,
+    // memory-map the file once it is built
+    mmap: bool,
+    // write the file with direct I/O
+    direct_io: bool,
+    // `None` writes a single-level index
+    index_partition_len: Option<usize>,
+    // `None` doesn't build a prefix bloom filter
+    prefix_extractor: Option<PrefixExtractor>,
+    // hashes of the distinct key prefixes, for the prefix bloom filter
+    prefix_hashes: Vec<u32>,
+    // panic instead of returning an error on out-of-order keys
+    paranoid_checks: bool,
+    // entry statistics, written to the properties section
+    properties: TableProperties,
+    // application metadata, written to the user properties section if there is any
+    user_properties: BTreeMap<String, Bytes>,
+    // `None` writes the SST in the clear
+    encryption: Option<Arc<dyn EncryptionProvider>>,
+    // `None` keeps the built SST open
+    file_cache: Option<Arc<FileCache>>,
+}
+
+/// Encrypts what was appended to `buf` from `start` as the block or section `block_idx`, if the
+/// SST is encrypted.
+fn encrypt_tail(
+    encryption: Option<&dyn EncryptionProvider>,
+    block_idx: u64,
+    buf: &mut Vec<u8>,
+    start: usize,
+) -> Result<()> {
+    if let Some(encryption) = encryption {
+        let ciphertext = encryption.encrypt(block_idx, &buf[start..])?;
+        buf.truncate(start);
+        buf.extend_from_slice(&ciphertext);
+    }
+    Ok(())
 }
 
 impl SsTableBuilder {
@@ -50,74 +131,423 @@ impl SsTableBuilder {
             last_key: KeyVec::new(),
             data: Vec::new(),
             meta: Vec::new(),
-            block_size: block_size,
+            meta_size: 0,
             key_hashes: Vec::new(),
             max_ts: 0,
+            block_min_ts: u64::MAX,
+            block_max_ts: 0,
+            max_entry_size: None,
+            compression: CompressionOptions::None,
+            compression_dict_size: 0,
+            dict_samples: Vec::new(),
+            dict_sample_sizes: Vec::new(),
+            bloom_false_positive_rate: Some(DEFAULT_BLOOM_FALSE_POSITIVE_RATE),
+            bloom_per_block: false,
+            block_filters: Vec::new(),
+            block_filter_offsets: Vec::new(),
+            block_buf: Vec::new(),
+            mmap: false,
+            direct_io: false,
+            index_partition_len: None,
+            prefix_extractor: None,
+            prefix_hashes: Vec::new(),
+            paranoid_checks: false,
+            properties: TableProperties::default(),
+            user_properties: BTreeMap::new(),
+            encryption: None,
+            file_cache: None,
+        }
+    }
+
+    /// Rejects entries whose key and value together exceed `max_entry_size` bytes.
+    pub fn with_max_entry_size(mut self, max_entry_size: Option<usize>) -> Self {
+        self.max_entry_size = max_entry_size;
+        self
+    }
+
+    /// Compresses each data block with `compression` before writing it.
+    pub fn with_compression(mut self, compression: CompressionOptions) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Trains a zstd dictionary of up to `compression_dict_size` bytes over values sampled from
+    /// the SST, and compresses every block with it. Small values that compress poorly on their own
+    /// share a lot with each other. Only applies to `CompressionOptions::Zstd`, and the blocks are
+    /// kept uncompressed until `build`, so `estimated_size` is the uncompressed size.
+    pub fn with_compression_dict_size(mut self, compression_dict_size: usize) -> Self {
+        self.compression_dict_size = compression_dict_size;
+        self
+    }
+
+    fn trains_compression_dict(&self) -> bool {
+        matches!(self.compression, CompressionOptions::Zstd { .. })
+            && self.compression_dict_size > 0
+    }
+
+    fn builds_block_filters(&self) -> bool {
+        self.bloom_per_block
+            && self.bloom_false_positive_rate.is_some()
+            && self.encryption.is_none()
+    }
+
+    /// Sizes the bloom filter for this false positive rate, `None` writes the SST without one.
+    pub fn with_bloom_false_positive_rate(mut self, false_positive_rate: Option<f64>) -> Self {
+        if let Some(rate) = false_positive_rate {
+            assert!(
+                rate > 0.0 && rate < 1.0,
+                "bloom false positive rate should be in (0, 1), got {}",
+                rate
+            );
+        }
+        self.bloom_false_positive_rate = false_positive_rate;
+        self
+    }
+
+    /// Reads the built SST through a memory mapping, see `FileObject::with_mmap`.
+    pub fn with_mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Writes the built SST bypassing the page cache, see `FileObject::create_with_direct_io`.
+    pub fn with_direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// Splits the index into partitions of the metadata of `index_partition_len` blocks, under a
+    /// top-level index of their first keys, so that a lookup only decodes one partition.
+    pub fn with_index_partition_len(mut self, index_partition_len: Option<usize>) -> Self {
+        if let Some(len) = index_partition_len {
+            assert!(len > 0, "index partitions should have at least one block");
         }
+        self.index_partition_len = index_partition_len;
+        self
+    }
+
+    /// Builds a small bloom filter for each data block instead of one for the whole SST, so that
+    /// only the filters of the blocks being read take memory, see `SsTable::block_may_contain`.
+    pub fn with_bloom_per_block(mut self, bloom_per_block: bool) -> Self {
+        self.bloom_per_block = bloom_per_block;
+        self
+    }
+
+    /// Encrypts the SST with `encryption`, see `EncryptionProvider`. The meta section of an
+    /// encrypted SST is never partitioned, see `with_index_partition_len`, and it has a single
+    /// bloom filter, see `with_bloom_per_block`.
+    pub fn with_encryption(mut self, encryption: Option<Arc<dyn EncryptionProvider>>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Closes the built SST once it's written and reopens it through `file_cache` when read, see
+    /// `FileObject::open_with_file_cache`.
+    pub fn with_file_cache(mut self, file_cache: Option<Arc<FileCache>>) -> Self {
+        self.file_cache = file_cache;
+        self
+    }
+
+    /// Builds a bloom filter over the key prefixes extracted by `prefix_extractor` as well, sized
+    /// for the false positive rate of the key bloom filter.
+    pub fn with_prefix_extractor(mut self, prefix_extractor: Option<PrefixExtractor>) -> Self {
+        self.prefix_extractor = prefix_extractor;
+        self
+    }
+
+    /// Panics on a key that isn't greater than the previous one, instead of returning an error, so
+    /// that the broken caller is caught right where it goes wrong.
+    pub fn with_paranoid_checks(mut self, paranoid_checks: bool) -> Self {
+        self.paranoid_checks = paranoid_checks;
+        self
+    }
+
+    /// Stamps the SST with a property of the application, read back with
+    /// `SsTable::user_properties`. Replaces the value of a key added before.
+    pub fn add_property(&mut self, key: &str, value: &[u8]) {
+        self.user_properties
+            .insert(key.to_string(), Bytes::copy_from_slice(value));
     }
 
     /// Adds a key-value pair to SSTable.
     ///
     /// Note: You should split a new block when the current block is full.(`std::mem::replace` may
     /// be helpful here)
-    pub fn add(&mut self, key: KeySlice, value: &[u8]) {
-        if self.first_key.is_empty() {
-            self.first_key.set_from_slice(key);
+    ///
+    /// An entry larger than `block_size` is written into a dedicated block of its own. An entry
+    /// larger than `max_entry_size`, or a key that isn't greater than the previous one in the SST,
+    /// is rejected with an error. Versions of the same key are ordered by decreasing timestamp.
+    pub fn add(&mut self, key: KeySlice, value: &[u8]) -> Result<()> {
+        // the previous key is in the current block, or is the last key of the previous one
+        let prev_key = if self.builder.is_empty() {
+            self.meta.last().map(|meta| meta.last_key.as_key_slice())
+        } else {
+            Some(self.last_key.as_key_slice())
+        };
+        if let Some(prev_key) = prev_key
+            && key <= prev_key
+        {
+            let msg = format!(
+                "key \"{}\"@{} is added after \"{}\"@{}",
+                key.key_ref().escape_ascii(),
+                key.ts(),
+                prev_key.key_ref().escape_ascii(),
+                prev_key.ts()
+            );
+            if self.paranoid_checks {
+                panic!("{}", msg);
+            }
+            bail!(msg);
         }
 
-        if key.ts() > self.max_ts {
-            self.max_ts = key.ts();
+        let entry_size = key.raw_len() + value.len();
+        if let Some(max_entry_size) = self.max_entry_size
+            && entry_size > max_entry_size
+        {
+            bail!(
+                "entry of {} bytes exceeds max_entry_size {}",
+                entry_size,
+                max_entry_size
+            );
         }
 
-        if self.builder.add(key, value) {
-            self.key_hashes.push(farmhash::fingerprint32(key.key_ref()));
-            self.last_key.set_from_slice(key);
-            return;
+        match self.builder.add(key, value) {
+            BlockAddResult::Added => {}
+            // an oversized entry starts a new block, and the next entry will find that block full
+            BlockAddResult::BlockFull | BlockAddResult::EntryTooLarge => {
+                self.finish_block()?;
+                // this is first entry in the new block!
+                assert_eq!(self.builder.add(key, value), BlockAddResult::Added);
+            }
+            BlockAddResult::OutOfOrder => unreachable!("the key order is checked above"),
         }
 
-        self.finish_block();
-
-        // this is first entry in the new block!
-        assert!(self.builder.add(key, value));
-        self.key_hashes.push(farmhash::fingerprint32(key.key_ref()));
+        if key.ts() > self.max_ts {
+            self.max_ts = key.ts();
+        }
+        self.properties.num_entries += 1;
+        if value.is_empty() {
+            self.properties.num_tombstones += 1;
+        }
+        self.properties.raw_key_size += key.key_len() as u64;
+        self.properties.raw_value_size += value.len() as u64;
+        if self.trains_compression_dict()
+            && !value.is_empty()
+            && self.dict_samples.len() < self.compression_dict_size * COMPRESSION_DICT_SAMPLE_RATIO
+        {
+            self.dict_samples.extend_from_slice(value);
+            self.dict_sample_sizes.push(value.len());
+        }
+        self.record_key(key);
+        Ok(())
+    }
 
-        self.first_key.set_from_slice(key);
+    // track the key and timestamp range of the current block and the key hash for the bloom filter
+    fn record_key(&mut self, key: KeySlice) {
+        if self.first_key.is_empty() {
+            self.first_key.set_from_slice(key);
+        }
         self.last_key.set_from_slice(key);
+        self.block_min_ts = self.block_min_ts.min(key.ts());
+        self.block_max_ts = self.block_max_ts.max(key.ts());
+        self.key_hashes.push(farmhash::fingerprint32(key.key_ref()));
+        // keys are sorted, so the keys with the same prefix are next to each other
+        if let Some(prefix) = self
+            .prefix_extractor
+            .and_then(|extractor| extractor.extract(key.key_ref()))
+        {
+            let prefix_hash = farmhash::fingerprint32(prefix);
+            if self.prefix_hashes.last() != Some(&prefix_hash) {
+                self.prefix_hashes.push(prefix_hash);
+            }
+        }
     }
+
     // finish the current block and use another new build
     //
-    // -------------------------------------------------------------------------------------------
-    // |         Block Section         |          Meta Section         |          Extra          |
-    // -------------------------------------------------------------------------------------------
-    // | data block | ... | data block |            metadata           | meta block offset (u32) |
-    // -------------------------------------------------------------------------------------------
+    // -------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------
+    // |         Block Section         |  Meta Section  |  Bloom Section  |  Block Filter Section  | User Properties Section |     Dict Section      |  Properties Section  |    Footer    |
+    // -------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------
+    // | data block | ... | data block |    metadata    |  bloom filter   | see `BlockFilterIndex` |    see `add_property`   | see `CompressionDict` |   TableProperties    | see `Footer` |
+    // -------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------
     //     |
     //     |
-    //     |--> | data block #1 | <-> | entry #1 | checksum #1 |
-    fn finish_block(&mut self) {
-        let old_builder = std::mem::replace(&mut self.builder, BlockBuilder::new(self.block_size));
-        let encoded = old_builder.build().encode();
+    //     |--> | data block #1 | <-> | compression type | (compressed) block | checksum #1 |
+    //
+    // The compression type and the block are encrypted together in an encrypted SST, and the
+    // checksum covers the ciphertext.
+    fn finish_block(&mut self) -> Result<()> {
+        if self.builder.is_empty() {
+            return Ok(());
+        }
         // update the meta data
+        let block_start = self.data.len();
+        self.meta_size += self.block_meta_len(block_start);
         self.meta.push(BlockMeta {
-            offset: self.data.len(),
-            first_key: self.first_key.clone().into_key_bytes(),
-            last_key: self.last_key.clone().into_key_bytes(),
+            offset: block_start,
+            first_key: std::mem::take(&mut self.first_key).into_key_bytes(),
+            last_key: std::mem::take(&mut self.last_key).into_key_bytes(),
+            min_ts: std::mem::replace(&mut self.block_min_ts, u64::MAX),
+            max_ts: std::mem::take(&mut self.block_max_ts),
         });
-        // calculate the checksum for this block and will be added as put_u32
-        let checksum = crc32fast::hash(&encoded);
-
-        // update the data
-        self.data.put(encoded);
+        // update the data, an uncompressed block is encoded right into it, and so is a block to be
+        // compressed with the dictionary at the end
+        if self.compression == CompressionOptions::None || self.trains_compression_dict() {
+            self.data.put_u8(COMPRESSION_NONE);
+            self.builder.encode_into(&mut self.data);
+        } else {
+            self.block_buf.clear();
+            self.builder.encode_into(&mut self.block_buf);
+            compress_block(self.compression, &self.block_buf, &mut self.data)?;
+        }
+        // reuse the block builder and its buffers for the next block
+        self.builder.reset();
+        if let Some(rate) = self.bloom_false_positive_rate
+            && self.builds_block_filters()
+        {
+            let bits_per_key = Bloom::bloom_bits_per_key(self.key_hashes.len(), rate);
+            self.block_filter_offsets
+                .push(self.block_filters.len() as u32);
+            Bloom::build_from_key_hashes(&self.key_hashes, bits_per_key)
+                .encode(&mut self.block_filters);
+            self.key_hashes.clear();
+        }
+        // a block to be compressed with the dictionary is encrypted after that
+        if !self.trains_compression_dict() {
+            encrypt_tail(
+                self.encryption.as_deref(),
+                (self.meta.len() - 1) as u64,
+                &mut self.data,
+                block_start,
+            )?;
+        }
 
+        // calculate the checksum over the stored (compressed, encrypted) bytes and will be added
+        // as put_u32
+        let checksum = crc32fast::hash(&self.data[block_start..]);
         self.data.put_u32(checksum);
+        Ok(())
     }
 
-    /// Get the estimated size of the SSTable.
-    ///
-    /// Since the data blocks contain much more data than meta blocks, just return the size of data
-    /// blocks here.
+    /// Trains the compression dictionary over the sampled values, and compresses the uncompressed
+    /// blocks in `data` with it, or without one for a small SST or if zstd can't make one.
+    fn compress_with_dict(&mut self) -> Result<Option<CompressionDict>> {
+        let CompressionOptions::Zstd { level } = self.compression else {
+            unreachable!("only zstd trains a compression dictionary");
+        };
+        let dict = if self.data.len() >= COMPRESSION_DICT_MIN_DATA_SIZE {
+            CompressionDict::train(
+                &self.dict_samples,
+                &self.dict_sample_sizes,
+                self.compression_dict_size,
+            )
+        } else {
+            None
+        };
+        let mut compressor = dict
+            .as_ref()
+            .map(|dict| zstd::bulk::Compressor::with_dictionary(level, dict.raw()))
+            .transpose()?;
+        let raw = std::mem::take(&mut self.data);
+        for idx in 0..self.meta.len() {
+            let start = self.meta[idx].offset;
+            let end = self.meta.get(idx + 1).map_or(raw.len(), |meta| meta.offset);
+            // skip the compression type and the checksum of the uncompressed block
+            let block = &raw[start + 1..end - SIZEOF_U32];
+            let block_start = self.data.len();
+            self.meta[idx].offset = block_start;
+            match compressor.as_mut() {
+                Some(compressor) => compress_block_with_dict(compressor, block, &mut self.data)?,
+                None => compress_block(self.compression, block, &mut self.data)?,
+            }
+            encrypt_tail(
+                self.encryption.as_deref(),
+                idx as u64,
+                &mut self.data,
+                block_start,
+            )?;
+            let checksum = crc32fast::hash(&self.data[block_start..]);
+            self.data.put_u32(checksum);
+        }
+        Ok(dict)
+    }
+
+    /// Check if no key-value pair has been added.
+    pub fn is_empty(&self) -> bool {
+        self.meta.is_empty() && self.builder.is_empty()
+    }
+
+    /// Size of the meta of the current block if it starts at `offset`, along with its entry in
+    /// the chunk index if it starts a chunk, see `BlockMeta::encode_block_meta_in_chunks`.
+    fn block_meta_len(&self, offset: usize) -> usize {
+        let chunk_len = self.index_partition_len.unwrap_or(BLOCK_META_CHUNK_LEN);
+        let first_key = self.first_key.as_key_slice();
+        let meta_len = |prev_offset, prev_first_key| {
+            BlockMeta::encoded_len(
+                prev_offset,
+                prev_first_key,
+                offset,
+                first_key,
+                self.last_key.as_key_slice(),
+                self.block_min_ts,
+                self.block_max_ts,
+            )
+        };
+        match self.meta.last() {
+            Some(prev) if !self.meta.len().is_multiple_of(chunk_len) => {
+                meta_len(prev.offset, prev.first_key.key_ref())
+            }
+            // the chunk offset and checksum, and the first key
+            _ => meta_len(0, &[]) + SIZEOF_U32 * 2 + prefix_compressed_key_len(&[], first_key),
+        }
+    }
+
+    /// Get the estimated size of the SSTable if it were built now: the finished blocks, the block
+    /// being built, the meta section, the bloom filters and the sections after them. Blocks
+    /// compressed with a dictionary count with their uncompressed size, see
+    /// `with_compression_dict_size`.
     pub fn estimated_size(&self) -> usize {
-        self.data.len()
+        let mut size = self.data.len() + self.meta_size + BLOCK_META_TAIL_SIZE;
+        if !self.builder.is_empty() {
+            // the compression type and the checksum
+            size += 1 + self.builder.estimated_size() + SIZEOF_U32;
+            size += self.block_meta_len(self.data.len());
+        }
+        // the key bloom filter length, then the filters
+        size += SIZEOF_U32;
+        if let Some(rate) = self.bloom_false_positive_rate {
+            let bits_per_key = Bloom::bloom_bits_per_key(1, rate);
+            if self.builds_block_filters() {
+                // the filters of the finished blocks, their offsets and the index tail
+                size +=
+                    self.block_filters.len() + (self.block_filter_offsets.len() + 3) * SIZEOF_U32;
+                if !self.builder.is_empty() {
+                    size += Bloom::encoded_size(self.key_hashes.len(), bits_per_key) + SIZEOF_U32;
+                }
+            } else {
+                size += Bloom::encoded_size(self.key_hashes.len(), bits_per_key);
+            }
+        }
+        if self.prefix_extractor.is_some() {
+            let rate = self
+                .bloom_false_positive_rate
+                .unwrap_or(DEFAULT_BLOOM_FALSE_POSITIVE_RATE);
+            let bits_per_key = Bloom::bloom_bits_per_key(1, rate);
+            // the extractor and the filter
+            size += 1 + SIZEOF_U32 + Bloom::encoded_size(self.prefix_hashes.len(), bits_per_key);
+        }
+        if !self.user_properties.is_empty() {
+            size += SIZEOF_U32 * 2;
+            for (key, value) in &self.user_properties {
+                size += varint_len(key.len() as u64) + key.len();
+                size += varint_len(value.len() as u64) + value.len();
+            }
+        }
+        if self.trains_compression_dict() {
+            // at most, along with its checksum
+            size += self.compression_dict_size + SIZEOF_U32;
+        }
+        size + SST_PROPERTIES_SIZE + SST_FOOTER_SIZE
     }
 
     /// Builds the SSTable and writes it to the given path. Use the `FileObject` structure to manipulate the disk objects.
@@ -129,24 +559,128 @@ impl SsTableBuilder {
     ) -> Result<SsTable> {
         // call finish_block to ensure everything is there and first_key and last_key
         // are also updated accordingly.
-        self.finish_block();
+        self.finish_block()?;
+        let compression_dict = if self.trains_compression_dict() {
+            self.compress_with_dict()?
+        } else {
+            None
+        };
+
+        let builds_block_filters = self.builds_block_filters();
 
         // we need to construct first_key and last_key from block_meta
         let mut buf = self.data;
+        let encryption = self.encryption.as_deref();
+        // an encrypted meta section is decrypted as a whole, so it isn't partitioned
+        let index_partition_len = self.index_partition_len.filter(|_| encryption.is_none());
         // the meta section is after the block section
         let block_meta_offset = buf.len();
-        BlockMeta::encode_block_meta(&self.meta, self.max_ts, &mut buf);
-        buf.put_u32(block_meta_offset as u32);
+        let index_offset = match index_partition_len {
+            Some(len) => {
+                block_meta_offset
+                    + BlockMeta::encode_block_meta_in_chunks(&self.meta, self.max_ts, len, &mut buf)
+            }
+            None => {
+                BlockMeta::encode_block_meta(&self.meta, self.max_ts, &mut buf);
+                0
+            }
+        };
+        encrypt_tail(
+            encryption,
+            ENCRYPTION_META_SECTION,
+            &mut buf,
+            block_meta_offset,
+        )?;
+
+        // add the bloom filters right after block_meta, see `table::decode_bloom_section`
+        let bloom_offset = buf.len();
+        let bloom = self
+            .bloom_false_positive_rate
+            .filter(|_| !builds_block_filters)
+            .map(|rate| {
+                let bits_per_key = Bloom::bloom_bits_per_key(self.key_hashes.len(), rate);
+                Bloom::build_from_key_hashes(&self.key_hashes, bits_per_key)
+            });
+        let mut raw_bloom = Vec::new();
+        if let Some(bloom) = bloom.as_ref() {
+            bloom.encode(&mut raw_bloom);
+        }
+        buf.put_u32(raw_bloom.len() as u32);
+        buf.extend(raw_bloom);
+        let prefix_bloom = self.prefix_extractor.map(|extractor| {
+            let rate = self
+                .bloom_false_positive_rate
+                .unwrap_or(DEFAULT_BLOOM_FALSE_POSITIVE_RATE);
+            let bits_per_key = Bloom::bloom_bits_per_key(self.prefix_hashes.len().max(1), rate);
+            PrefixBloom {
+                extractor,
+                bloom: Bloom::build_from_key_hashes(&self.prefix_hashes, bits_per_key),
+            }
+        });
+        if let Some(prefix_bloom) = prefix_bloom.as_ref() {
+            prefix_bloom.encode(&mut buf);
+        }
+        encrypt_tail(encryption, ENCRYPTION_BLOOM_SECTION, &mut buf, bloom_offset)?;
+        let bloom_checksum = crc32fast::hash(&buf[bloom_offset..]);
+        // the block filters, the user properties and the compression dictionary, if any, are
+        // between the bloom and properties sections
+        let block_filters = builds_block_filters.then(|| {
+            let offset = buf.len();
+            let mut offsets = self.block_filter_offsets;
+            encode_block_filters(&self.block_filters, &offsets, &mut buf);
+            offsets.push(self.block_filters.len() as u32);
+            BlockFilterIndex::new(offset as u64, offsets)
+        });
+        let user_properties_offset = if self.user_properties.is_empty() {
+            0
+        } else {
+            let offset = buf.len();
+            encode_user_properties(&self.user_properties, &mut buf);
+            offset
+        };
+        let compression_dict_offset = match compression_dict.as_ref() {
+            Some(dict) => {
+                let offset = buf.len();
+                dict.encode(&mut buf);
+                encrypt_tail(
+                    encryption,
+                    ENCRYPTION_COMPRESSION_DICT_SECTION,
+                    &mut buf,
+                    offset,
+                )?;
+                offset
+            }
+            None => 0,
+        };
+        self.properties.encode(&mut buf);
 
-        // add bloom filter right after block_meta
-        let bloom_filter_offset = buf.len();
-        let bits_per_key = Bloom::bloom_bits_per_key(self.key_hashes.len(), 0.01);
-        let bloom = Bloom::build_from_key_hashes(&self.key_hashes, bits_per_key);
-        bloom.encode(&mut buf);
-        buf.put_u32(bloom_filter_offset as u32);
+        Footer {
+            version: SST_FORMAT_VERSION,
+            block_meta_offset: block_meta_offset as u32,
+            bloom_offset: bloom_offset as u32,
+            meta_checksum: crc32fast::hash(&buf[block_meta_offset..bloom_offset]),
+            index_offset: index_offset as u32,
+            index_partition_len: index_partition_len.unwrap_or(0) as u32,
+            compression_dict_offset: compression_dict_offset as u32,
+            user_properties_offset: user_properties_offset as u32,
+            encryption_key_id: encryption.map_or(0, |encryption| encryption.key_id()),
+            bloom_checksum,
+            block_filter_offset: block_filters
+                .as_ref()
+                .map_or(0, |block_filters| block_filters.offset() as u32),
+            flags: match (index_partition_len, encryption) {
+                (Some(_), _) => FOOTER_PARTITIONED_INDEX,
+                (None, Some(_)) => FOOTER_ENCRYPTED,
+                (None, None) => 0,
+            },
+        }
+        .encode(&mut buf);
 
+        let min_ts = self.meta.iter().map(|meta| meta.min_ts).min().unwrap_or(0);
         Ok(SsTable {
-            file: FileObject::create(path.as_ref(), buf)?,
+            file: FileObject::create_with_direct_io(path.as_ref(), buf, self.direct_io)?
+                .with_mmap(self.mmap)?
+                .with_file_cache(id, path.as_ref(), self.file_cache),
             block_meta_offset: block_meta_offset,
             id: id,
             block_cache: block_cache,
@@ -154,8 +688,17 @@ impl SsTableBuilder {
             first_key: self.meta.first().unwrap().first_key.clone(),
             last_key: self.meta.last().unwrap().last_key.clone(),
             block_meta: self.meta,
-            bloom: Some(bloom),
+            lazy_block_meta: None,
+            bloom,
+            prefix_bloom,
+            block_filters,
             max_ts: self.max_ts,
+            min_ts,
+            properties: Some(self.properties),
+            compression_dict,
+            user_properties: self.user_properties.into_iter().collect(),
+            encryption: self.encryption,
+            lazy: None,
         })
     }
 
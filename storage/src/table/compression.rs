@@ -0,0 +1,159 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::Read;
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut, Bytes};
+use zstd::dict::DecoderDictionary;
+
+use crate::block::SIZEOF_U32;
+
+/// The block is stored as is.
+pub(crate) const COMPRESSION_NONE: u8 = 0;
+pub(crate) const COMPRESSION_LZ4: u8 = 1;
+pub(crate) const COMPRESSION_ZSTD: u8 = 2;
+/// Zstd with the dictionary of the SST, see `CompressionDict`.
+pub(crate) const COMPRESSION_ZSTD_DICT: u8 = 3;
+
+/// How the data blocks of an SST are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionOptions {
+    None,
+    Lz4,
+    /// Zstd with the given compression level, 0 means the zstd default.
+    Zstd {
+        level: i32,
+    },
+}
+
+// -------------------------------------------------
+// |  compression type (u8)  |  (compressed) block  |
+// -------------------------------------------------
+/// Compress an encoded block and append it to `buf` with its compression type. Stores the block
+/// raw when compression doesn't make it smaller.
+pub(crate) fn compress_block(
+    options: CompressionOptions,
+    block: &[u8],
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    let compressed = match options {
+        CompressionOptions::None => None,
+        CompressionOptions::Lz4 => Some((
+            COMPRESSION_LZ4,
+            lz4_flex::block::compress_prepend_size(block),
+        )),
+        CompressionOptions::Zstd { level } => {
+            Some((COMPRESSION_ZSTD, zstd::bulk::compress(block, level)?))
+        }
+    };
+    put_block(compressed, block, buf);
+    Ok(())
+}
+
+/// Same as `compress_block`, with a compressor set up with the dictionary of the SST.
+pub(crate) fn compress_block_with_dict(
+    compressor: &mut zstd::bulk::Compressor,
+    block: &[u8],
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    let compressed = compressor.compress(block)?;
+    put_block(Some((COMPRESSION_ZSTD_DICT, compressed)), block, buf);
+    Ok(())
+}
+
+fn put_block(compressed: Option<(u8, Vec<u8>)>, block: &[u8], buf: &mut Vec<u8>) {
+    match compressed {
+        Some((compression, compressed)) if compressed.len() < block.len() => {
+            buf.put_u8(compression);
+            buf.put(&compressed[..]);
+        }
+        _ => {
+            buf.put_u8(COMPRESSION_NONE);
+            buf.put(block);
+        }
+    }
+}
+
+/// Reverse of `compress_block`. An uncompressed block is returned as a slice of `data`. Blocks
+/// compressed with a dictionary need the one of their SST.
+pub(crate) fn decompress_block(data: Bytes, dict: Option<&CompressionDict>) -> Result<Bytes> {
+    let Some(&compression) = data.first() else {
+        bail!("block is missing its compression type");
+    };
+    let payload = &data[1..];
+    match compression {
+        COMPRESSION_NONE => Ok(data.slice(1..)),
+        COMPRESSION_LZ4 => Ok(lz4_flex::block::decompress_size_prepended(payload)?.into()),
+        COMPRESSION_ZSTD => Ok(zstd::stream::decode_all(payload)?.into()),
+        COMPRESSION_ZSTD_DICT => {
+            let Some(dict) = dict else {
+                bail!("block is compressed with a dictionary, but the SST has none");
+            };
+            let mut decoder =
+                zstd::stream::Decoder::with_prepared_dictionary(payload, &dict.decoder)?;
+            let mut block = Vec::new();
+            decoder.read_to_end(&mut block)?;
+            Ok(block.into())
+        }
+        _ => bail!("unsupported block compression type {}", compression),
+    }
+}
+
+// ------------------------------------
+// | dictionary | checksum (u32) |
+// ------------------------------------
+/// A zstd dictionary trained over values sampled while building an SST, stored in the SST and
+/// prepared once for decompressing all of its blocks.
+pub struct CompressionDict {
+    raw: Bytes,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl CompressionDict {
+    /// Trains a dictionary of up to `max_size` bytes over the samples stored one after another in
+    /// `samples`, `None` if zstd can't make one out of them.
+    pub(crate) fn train(samples: &[u8], sample_sizes: &[usize], max_size: usize) -> Option<Self> {
+        let raw = zstd::dict::from_continuous(samples, sample_sizes, max_size).ok()?;
+        Some(Self::new(raw.into()))
+    }
+
+    fn new(raw: Bytes) -> Self {
+        Self {
+            decoder: DecoderDictionary::copy(&raw),
+            raw,
+        }
+    }
+
+    /// The dictionary as stored in the SST, without its checksum.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put(&self.raw[..]);
+        buf.put_u32(crc32fast::hash(&self.raw));
+    }
+
+    pub(crate) fn decode(raw: &[u8]) -> Result<Self> {
+        if raw.len() <= SIZEOF_U32 {
+            bail!("compression dictionary section of {} bytes", raw.len());
+        }
+        let (dict, mut checksum) = raw.split_at(raw.len() - SIZEOF_U32);
+        if checksum.get_u32() != crc32fast::hash(dict) {
+            bail!("compression dictionary checksum mismatch");
+        }
+        Ok(Self::new(Bytes::copy_from_slice(dict)))
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+
+/// The memory address, file offset and length of every write to a file opened with `O_DIRECT`
+/// are a multiple of this, the logical block size of most devices.
+pub(crate) const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Bytes buffered before they are written out.
+const DIRECT_IO_BUFFER_SIZE: usize = 256 * DIRECT_IO_ALIGNMENT;
+
+/// Buffers the data written to it so that `inner` only sees aligned writes, see
+/// `DIRECT_IO_ALIGNMENT`. The last write is padded with zeros, which the caller truncates.
+pub(crate) struct AlignedWriter<W: Write> {
+    inner: W,
+    // over-allocated by `DIRECT_IO_ALIGNMENT` so that an aligned buffer fits in it from `start`
+    buf: Vec<u8>,
+    start: usize,
+    capacity: usize,
+    // bytes buffered from `start`
+    len: usize,
+    written: u64,
+}
+
+impl<W: Write> AlignedWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self::with_capacity(inner, DIRECT_IO_BUFFER_SIZE)
+    }
+
+    /// Buffers `capacity` bytes, rounded up to the alignment.
+    pub(crate) fn with_capacity(inner: W, capacity: usize) -> Self {
+        let capacity = capacity.max(1).next_multiple_of(DIRECT_IO_ALIGNMENT);
+        let buf = vec![0; capacity + DIRECT_IO_ALIGNMENT];
+        let start = buf.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+        Self {
+            inner,
+            buf,
+            start,
+            capacity,
+            len: 0,
+            written: 0,
+        }
+    }
+
+    pub(crate) fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let len = data.len().min(self.capacity - self.len);
+            let pos = self.start + self.len;
+            self.buf[pos..pos + len].copy_from_slice(&data[..len]);
+            self.len += len;
+            data = &data[len..];
+            if self.len == self.capacity {
+                self.flush_buffer()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_buffer(&mut self) -> io::Result<()> {
+        self.inner
+            .write_all(&self.buf[self.start..self.start + self.len])?;
+        self.written += self.len as u64;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Writes out what is buffered, padded with zeros to the alignment, and returns `inner` along
+    /// with the number of bytes written to it.
+    pub(crate) fn finish(mut self) -> io::Result<(W, u64)> {
+        if self.len > 0 {
+            let padded_len = self.len.next_multiple_of(DIRECT_IO_ALIGNMENT);
+            self.buf[self.start + self.len..self.start + padded_len].fill(0);
+            self.len = padded_len;
+            self.flush_buffer()?;
+        }
+        Ok((self.inner, self.written))
+    }
+}
+
+/// Writes `data` to a new file bypassing the page cache, or through it if the platform or the
+/// file system doesn't support direct I/O.
+pub(crate) fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    match write_file_direct(path, data) {
+        Err(e) if matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::Unsupported) => {
+            eprintln!(
+                "direct I/O is not supported for {}, falling back to buffered I/O: {}",
+                path.display(),
+                e
+            );
+            std::fs::write(path, data)?;
+            File::open(path)?.sync_all()
+        }
+        result => result,
+    }
+}
+
+fn write_file_direct(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut writer = AlignedWriter::new(open_direct(path)?);
+    writer.write(data)?;
+    let (file, _) = writer.finish()?;
+    // drop the padding of the last write
+    file.set_len(data.len() as u64)?;
+    file.sync_all()
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_path: &Path) -> io::Result<File> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "O_DIRECT is only available on Linux",
+    ))
+}
@@ -0,0 +1,41 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Debug;
+
+use anyhow::Result;
+
+/// The index passed to `EncryptionProvider` for the meta section, which isn't a data block.
+pub const ENCRYPTION_META_SECTION: u64 = u64::MAX;
+/// The index passed to `EncryptionProvider` for the bloom section.
+pub const ENCRYPTION_BLOOM_SECTION: u64 = u64::MAX - 1;
+/// The index passed to `EncryptionProvider` for the compression dictionary section.
+pub const ENCRYPTION_COMPRESSION_DICT_SECTION: u64 = u64::MAX - 2;
+
+/// Encrypts the SSTs built with it at rest: every data block after it is compressed, and the meta,
+/// bloom and compression dictionary sections, which have keys and values in them. The footer,
+/// the properties and the user properties are stored in the clear.
+///
+/// `block_idx` is the index of the data block, or one of the `ENCRYPTION_*_SECTION` constants,
+/// so that an implementation can bind each ciphertext to where it is stored. The same key may
+/// encrypt any number of SSTs, so nonces should not be derived from `block_idx` alone.
+pub trait EncryptionProvider: Send + Sync + Debug {
+    /// Identifies the key, stored in the footer of the SSTs encrypted with it. Only a provider
+    /// with the same key id can open them.
+    fn key_id(&self) -> u32;
+
+    fn encrypt(&self, block_idx: u64, plaintext: &[u8]) -> Result<Vec<u8>>;
+
+    fn decrypt(&self, block_idx: u64, ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
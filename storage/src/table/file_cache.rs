@@ -0,0 +1,67 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use moka::sync::ConcurrentCacheExt;
+
+/// Keeps at most `max_open_files` SST files open, keyed by SST id, closing the least recently
+/// used ones. A file evicted while it's being read is closed once the read is done.
+pub struct FileCache {
+    cache: moka::sync::Cache<usize, Arc<File>>,
+}
+
+impl FileCache {
+    pub fn new(max_open_files: usize) -> Self {
+        assert!(max_open_files > 0, "at least one file should be kept open");
+        Self {
+            cache: moka::sync::Cache::new(max_open_files as u64),
+        }
+    }
+
+    /// Returns the open file of the SST, or opens it at `path`.
+    pub fn try_get_with(&self, sst_id: usize, path: &Path) -> Result<Arc<File>> {
+        let mut opened = false;
+        let file = self
+            .cache
+            .try_get_with(sst_id, || {
+                opened = true;
+                File::options()
+                    .read(true)
+                    .write(false)
+                    .open(path)
+                    .map(Arc::new)
+            })
+            .map_err(|e| anyhow!("{}", e))?;
+        if opened {
+            // close the evicted files now rather than on a later access
+            self.cache.sync();
+        }
+        Ok(file)
+    }
+
+    /// Closes the file of the SST, if it's open.
+    pub fn invalidate(&self, sst_id: usize) {
+        self.cache.invalidate(&sst_id);
+    }
+
+    /// Number of files kept open.
+    pub fn open_files(&self) -> u64 {
+        self.cache.sync();
+        self.cache.entry_count()
+    }
+}
@@ -15,67 +15,300 @@
 use std::sync::Arc;
 
 use anyhow::Result;
+use bytes::Bytes;
 
-use super::SsTable;
-use crate::{block::BlockIterator, iterators::StorageIterator, key::KeySlice};
+use super::{ChecksumVerification, SsTable};
+use crate::{
+    block::{Block, BlockIterator, SeekResult},
+    iterators::StorageIterator,
+    key::{KeySlice, TS_RANGE_BEGIN},
+};
 
 /// An iterator over the contents of an SSTable.
 pub struct SsTableIterator {
     table: Arc<SsTable>,
-    blk_iter: BlockIterator,
+    /// `None` once the iterator moved past the last block it reads.
+    blk_iter: Option<BlockIterator>,
     blk_idx: usize,
+    /// Whether blocks read from the disk are added to the block cache
+    fill_cache: bool,
+    /// Blocks with only versions newer than this are skipped without being read
+    read_ts: u64,
+    verify_checksums: ChecksumVerification,
+    /// Reads several blocks at a time instead of one, bypassing the block cache
+    readahead: Option<Readahead>,
+}
+
+/// The raw blocks last read ahead by an `SsTableIterator`.
+struct Readahead {
+    /// Bytes read at a time
+    size: usize,
+    /// Blocks `start..end` of the table, along with their checksums
+    blocks: Bytes,
+    start: usize,
+    end: usize,
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of iterators created by the current thread, so that tests can check which SSTs a
+    /// read skipped.
+    pub(crate) static NUM_CREATED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
 }
 
 impl SsTableIterator {
+    pub(crate) fn new(
+        table: Arc<SsTable>,
+        fill_cache: bool,
+        read_ts: u64,
+        verify_checksums: ChecksumVerification,
+    ) -> Self {
+        #[cfg(test)]
+        NUM_CREATED.with(|x| x.set(x.get() + 1));
+        Self {
+            table,
+            blk_iter: None,
+            blk_idx: 0,
+            fill_cache,
+            read_ts,
+            verify_checksums,
+            readahead: None,
+        }
+    }
+
+    /// Reads the blocks not yet read ahead `readahead_size` bytes at a time, and never from the
+    /// block cache. 0 reads one block at a time, as usual.
+    pub(crate) fn with_readahead(mut self, readahead_size: usize) -> Self {
+        self.readahead = (readahead_size > 0).then(|| Readahead {
+            size: readahead_size,
+            blocks: Bytes::new(),
+            start: 0,
+            end: 0,
+        });
+        self
+    }
+
     /// Create a new iterator and seek to the first key-value pair in the first data block.
     pub fn create_and_seek_to_first(table: Arc<SsTable>) -> Result<Self> {
-        let block = table.read_block_cached(0)?;
-        Ok(Self {
-            table: table,
-            blk_iter: BlockIterator::create_and_seek_to_first(block),
-            blk_idx: 0,
-        })
+        let mut iter = Self::new(table, true, TS_RANGE_BEGIN, ChecksumVerification::default());
+        iter.seek_to_first()?;
+        Ok(iter)
+    }
+
+    /// Same as `create_and_seek_to_first`, but blocks that are not cached yet are read without
+    /// being added to the block cache, so that a compaction doesn't evict the working set. The
+    /// blocks are read `readahead_size` bytes at a time if it isn't 0, see `with_readahead`.
+    pub fn create_for_compaction(table: Arc<SsTable>, readahead_size: usize) -> Result<Self> {
+        table.opened()?.file.advise_sequential()?;
+        let mut iter = Self::new(
+            table,
+            false,
+            TS_RANGE_BEGIN,
+            ChecksumVerification::default(),
+        )
+        .with_readahead(readahead_size);
+        iter.seek_to_first()?;
+        Ok(iter)
     }
 
     /// Seek to the first key-value pair in the first data block.
     pub fn seek_to_first(&mut self) -> Result<()> {
-        let block = self.table.read_block_cached(0)?;
-        self.blk_iter = BlockIterator::create_and_seek_to_first(block);
-        self.blk_idx = 0;
-        Ok(())
+        self.seek_to_block(0)
+    }
+
+    /// Create a new iterator and seek to the last key-value pair in the last data block.
+    pub fn create_and_seek_to_last(table: Arc<SsTable>) -> Result<Self> {
+        let mut iter = Self::new(table, true, TS_RANGE_BEGIN, ChecksumVerification::default());
+        iter.seek_to_last()?;
+        Ok(iter)
+    }
+
+    /// Create a new iterator and seek to the last key-value pair which <= `key`.
+    pub fn create_and_seek_for_prev(table: Arc<SsTable>, key: KeySlice) -> Result<Self> {
+        let mut iter = Self::new(table, true, TS_RANGE_BEGIN, ChecksumVerification::default());
+        iter.seek_for_prev(key)?;
+        Ok(iter)
     }
 
     /// Create a new iterator and seek to the first key-value pair which >= `key`.
     pub fn create_and_seek_to_key(table: Arc<SsTable>, key: KeySlice) -> Result<Self> {
-        let block = table.read_block_cached(0)?;
-        let mut iter = Self {
-            table: table,
-            blk_iter: BlockIterator::create_and_seek_to_first(block),
-            blk_idx: 0,
-        };
+        Self::create_and_seek_to_key_with_ts(
+            table,
+            key,
+            TS_RANGE_BEGIN,
+            ChecksumVerification::default(),
+        )
+    }
+
+    /// Same as `create_and_seek_to_key`, for a snapshot read at `read_ts`. Blocks whose versions
+    /// are all newer than `read_ts` are skipped, here and as the iterator moves forward.
+    pub fn create_and_seek_to_key_with_ts(
+        table: Arc<SsTable>,
+        key: KeySlice,
+        read_ts: u64,
+        verify_checksums: ChecksumVerification,
+    ) -> Result<Self> {
+        let mut iter = Self::new(table, true, read_ts, verify_checksums);
         iter.seek_to_key(key)?;
         Ok(iter)
     }
 
+    /// Same as `create_and_seek_to_key`, and tells whether the iterator landed on `key`, so that
+    /// the caller doesn't need to compare the keys again.
+    pub fn create_and_seek_to_key_ret(
+        table: Arc<SsTable>,
+        key: KeySlice,
+        verify_checksums: ChecksumVerification,
+    ) -> Result<(Self, SeekResult)> {
+        let iter =
+            Self::create_and_seek_to_key_with_ts(table, key, TS_RANGE_BEGIN, verify_checksums)?;
+        let result = iter
+            .blk_iter
+            .as_ref()
+            .map_or(SeekResult::NotFound, |x| x.seek_result(key));
+        Ok((iter, result))
+    }
+
     /// Seek to the first key-value pair which >= `key`.
     /// Note: You probably want to review the handout for detailed explanation when implementing
     /// this function.
     pub fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
-        let mut blk_idx = self.table.find_block_idx(key);
-        let mut blk_iter =
-            BlockIterator::create_and_seek_to_key(self.table.read_block_cached(blk_idx)?, key);
-        if !blk_iter.is_valid() {
+        // only reads the block that may contain `key`, and the next one if all keys in it are
+        // smaller.
+        let blk_idx = self.table.find_block_idx(key)?;
+        if self.is_pruned(blk_idx)? {
+            // the following blocks only have greater keys
+            return self.seek_to_block(blk_idx + 1);
+        }
+        let blk_iter = BlockIterator::create_and_seek_to_key(self.read_block(blk_idx)?, key);
+        if blk_iter.is_valid() {
+            self.blk_idx = blk_idx;
+            self.blk_iter = Some(blk_iter);
+            Ok(())
+        } else {
             // try the next one iter;
-            blk_idx += 1;
-            if blk_idx < self.table.num_of_blocks() {
-                blk_iter =
-                    BlockIterator::create_and_seek_to_first(self.table.read_block_cached(blk_idx)?);
+            self.seek_to_block(blk_idx + 1)
+        }
+    }
+
+    /// Seek to the last key-value pair in the last data block.
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        self.seek_to_block_before(self.num_of_blocks()?)
+    }
+
+    /// Seek to the last key-value pair which <= `key`. The iterator becomes invalid if `key` is
+    /// before the first key of the table.
+    pub fn seek_for_prev(&mut self, key: KeySlice) -> Result<()> {
+        // the following blocks start with greater keys, so the entry is either in the block that
+        // may contain `key` or is the last one of a block before it.
+        let blk_idx = self.table.find_block_idx(key)?;
+        if self.is_pruned(blk_idx)? {
+            return self.seek_to_block_before(blk_idx);
+        }
+        let mut blk_iter = BlockIterator::create_and_seek_to_key(self.read_block(blk_idx)?, key);
+        // lands on the last entry if all keys in the block are smaller
+        if !blk_iter.is_valid() || blk_iter.key() > key {
+            blk_iter.prev();
+        }
+        if blk_iter.is_valid() {
+            self.blk_idx = blk_idx;
+            self.blk_iter = Some(blk_iter);
+            Ok(())
+        } else {
+            self.seek_to_block_before(blk_idx)
+        }
+    }
+
+    /// Move to the previous key-value pair, loading the previous block when the current one is
+    /// exhausted. The iterator becomes invalid when moving before the first key, and needs to be
+    /// re-positioned by one of the seek functions after that.
+    pub fn prev(&mut self) -> Result<()> {
+        let Some(blk_iter) = self.blk_iter.as_mut() else {
+            return Ok(());
+        };
+        blk_iter.prev();
+        if !blk_iter.is_valid() {
+            self.seek_to_block_before(self.blk_idx)?;
+        }
+        Ok(())
+    }
+
+    /// Seek to the last key-value pair of the last block before `end` that isn't pruned.
+    fn seek_to_block_before(&mut self, end: usize) -> Result<()> {
+        let end = end.min(self.num_of_blocks()?);
+        let mut last_visible = None;
+        for blk_idx in (0..end).rev() {
+            if !self.is_pruned(blk_idx)? {
+                last_visible = Some(blk_idx);
+                break;
             }
         }
+        match last_visible {
+            Some(blk_idx) => {
+                self.blk_idx = blk_idx;
+                self.blk_iter = Some(BlockIterator::create_and_seek_to_last(
+                    self.read_block(blk_idx)?,
+                ));
+            }
+            None => {
+                self.blk_idx = 0;
+                self.blk_iter = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Seek to the first key-value pair of the first block from `blk_idx` that isn't pruned.
+    fn seek_to_block(&mut self, mut blk_idx: usize) -> Result<()> {
+        while self.is_pruned(blk_idx)? {
+            blk_idx += 1;
+        }
         self.blk_idx = blk_idx;
-        self.blk_iter = blk_iter;
+        self.blk_iter = if blk_idx < self.num_of_blocks()? {
+            Some(BlockIterator::create_and_seek_to_first(
+                self.read_block(blk_idx)?,
+            ))
+        } else {
+            None
+        };
         Ok(())
     }
+
+    /// Whether all versions in the block are newer than `read_ts`, which never holds for SSTs
+    /// written before blocks recorded their timestamps.
+    fn is_pruned(&self, blk_idx: usize) -> Result<bool> {
+        if blk_idx >= self.num_of_blocks()? {
+            return Ok(false);
+        }
+        Ok(self.table.block_meta(blk_idx)?.min_ts > self.read_ts)
+    }
+
+    /// The blocks of the table, which fails if it's opened lazily and fails to open, rather than
+    /// reading as empty.
+    fn num_of_blocks(&self) -> Result<usize> {
+        Ok(self.table.opened()?.num_of_blocks())
+    }
+
+    fn read_block(&mut self, block_idx: usize) -> Result<Arc<Block>> {
+        let verify_checksum = self.verify_checksums != ChecksumVerification::Never;
+        let Some(readahead) = self.readahead.as_mut() else {
+            return self
+                .table
+                .read_block_with(block_idx, self.fill_cache, self.verify_checksums);
+        };
+        if !(readahead.start..readahead.end).contains(&block_idx) {
+            let (blocks, end) = self.table.read_raw_blocks(block_idx, readahead.size)?;
+            readahead.blocks = blocks;
+            readahead.start = block_idx;
+            readahead.end = end;
+        }
+        self.table.decode_block_in(
+            &readahead.blocks,
+            readahead.start,
+            block_idx,
+            verify_checksum,
+        )
+    }
 }
 
 impl StorageIterator for SsTableIterator {
@@ -83,28 +316,29 @@ impl StorageIterator for SsTableIterator {
 
     /// Return the `key` that's held by the underlying block iterator.
     fn key(&self) -> KeySlice {
-        self.blk_iter.key()
+        self.blk_iter.as_ref().unwrap().key()
     }
 
     /// Return the `value` that's held by the underlying block iterator.
     fn value(&self) -> &[u8] {
-        self.blk_iter.value()
+        self.blk_iter.as_ref().unwrap().value()
     }
 
     /// Return whether the current block iterator is valid or not.
     fn is_valid(&self) -> bool {
-        self.blk_iter.is_valid()
+        self.blk_iter.as_ref().is_some_and(|x| x.is_valid())
     }
 
     /// Move to the next `key` in the block.
     /// Note: You may want to check if the current block iterator is valid after the move.
     fn next(&mut self) -> Result<()> {
-        self.blk_iter.next();
+        let Some(blk_iter) = self.blk_iter.as_mut() else {
+            return Ok(());
+        };
+        blk_iter.next();
         // move to the next block iter if the current one is no longer valid.
-        if !self.blk_iter.is_valid() && self.blk_idx + 1 < self.table.num_of_blocks() {
-            self.blk_idx += 1;
-            let block = self.table.read_block_cached(self.blk_idx)?;
-            self.blk_iter = BlockIterator::create_and_seek_to_first(block);
+        if !blk_iter.is_valid() {
+            self.seek_to_block(self.blk_idx + 1)?;
         }
         Ok(())
     }
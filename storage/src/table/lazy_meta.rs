@@ -0,0 +1,227 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::OnceLock;
+
+use anyhow::{Result, bail};
+use bytes::Buf;
+
+use super::{
+    BLOCK_META_CHUNKED_FLAG, BLOCK_META_DELTA_FLAG, BLOCK_META_FLAGS, BLOCK_META_TS_RANGE_FLAG,
+    BlockMeta, CHUNKED_BLOCK_META_TAIL_SIZE, FileObject, get_prefix_compressed_key,
+};
+use crate::block::SIZEOF_U32;
+use crate::key::{KeyBytes, KeySlice};
+
+/// The metadata of `chunk_len` consecutive blocks, decoded on first use.
+struct BlockMetaChunk {
+    /// Offset of the chunk in the file.
+    offset: u64,
+    len: u64,
+    checksum: u32,
+    first_key: KeyBytes,
+    block_meta: OnceLock<Vec<BlockMeta>>,
+}
+
+/// The block meta of an SST whose meta section is too large to be decoded when it's opened, or
+/// that was built with a partitioned index. Only the chunk index is read by `open`, and each chunk
+/// is read and decoded the first time one of its blocks is looked up, then kept in memory.
+pub(crate) struct LazyBlockMeta {
+    num_blocks: usize,
+    has_ts_range: bool,
+    /// Number of blocks in each chunk but the last one.
+    chunk_len: usize,
+    /// Offset of the chunk index in the file.
+    index_offset: u64,
+    chunks: Vec<BlockMetaChunk>,
+}
+
+impl LazyBlockMeta {
+    /// Reads the chunk index of the meta section at `meta_offset..meta_end` of `file`, along with
+    /// max_ts. Returns `None` for sections written without a chunk index, which can only be decoded
+    /// as a whole.
+    pub(crate) fn open(
+        file: &FileObject,
+        meta_offset: u64,
+        meta_end: u64,
+        chunk_len: usize,
+    ) -> Result<Option<(Self, u64)>> {
+        if meta_end - meta_offset < (SIZEOF_U32 + CHUNKED_BLOCK_META_TAIL_SIZE) as u64 {
+            return Ok(None);
+        }
+        let num_blocks = file
+            .read(meta_offset, SIZEOF_U32 as u64)?
+            .as_slice()
+            .get_u32();
+        if num_blocks & BLOCK_META_DELTA_FLAG == 0 || num_blocks & BLOCK_META_CHUNKED_FLAG == 0 {
+            return Ok(None);
+        }
+        let has_ts_range = num_blocks & BLOCK_META_TS_RANGE_FLAG != 0;
+        let num_blocks = (num_blocks & !BLOCK_META_FLAGS) as usize;
+
+        let tail_offset = meta_end - CHUNKED_BLOCK_META_TAIL_SIZE as u64;
+        let mut tail = &file.read(tail_offset, CHUNKED_BLOCK_META_TAIL_SIZE as u64)?[..];
+        let chunk_index_offset = tail.get_u32() as u64;
+        let chunk_index_checksum = tail.get_u32();
+        let max_ts = tail.get_u64();
+        if chunk_index_offset < SIZEOF_U32 as u64 || meta_offset + chunk_index_offset > tail_offset
+        {
+            bail!(
+                "block meta chunk index at {} is out of range",
+                chunk_index_offset
+            );
+        }
+        let raw_chunk_index = file.read(
+            meta_offset + chunk_index_offset,
+            tail_offset - meta_offset - chunk_index_offset,
+        )?;
+        if crc32fast::hash(&raw_chunk_index) != chunk_index_checksum {
+            bail!("block meta chunk index checksum mismatch");
+        }
+
+        let num_chunks = num_blocks.div_ceil(chunk_len);
+        let mut buf = &raw_chunk_index[..];
+        // each entry takes at least two u32s, the number of blocks isn't trusted before that
+        let mut chunks: Vec<BlockMetaChunk> =
+            Vec::with_capacity(num_chunks.min(raw_chunk_index.len() / (SIZEOF_U32 * 2)));
+        for chunk_idx in 0..num_chunks {
+            if buf.remaining() < SIZEOF_U32 * 2 {
+                bail!("block meta chunk index entry {} is malformed", chunk_idx);
+            }
+            let offset = buf.get_u32() as u64;
+            let checksum = buf.get_u32();
+            let Some(first_key) = get_prefix_compressed_key(&mut buf, &[]) else {
+                bail!("block meta chunk index entry {} is malformed", chunk_idx);
+            };
+            // chunks are never empty
+            let min_offset = chunks
+                .last()
+                .map_or(SIZEOF_U32 as u64, |x| x.offset - meta_offset + 1);
+            if offset < min_offset || offset >= chunk_index_offset {
+                bail!(
+                    "block meta chunk {} at {} is out of range",
+                    chunk_idx,
+                    offset
+                );
+            }
+            // each chunk ends where the next one, or the chunk index, starts
+            if let Some(prev) = chunks.last_mut() {
+                prev.len = meta_offset + offset - prev.offset;
+            }
+            chunks.push(BlockMetaChunk {
+                offset: meta_offset + offset,
+                len: 0,
+                checksum,
+                first_key,
+                block_meta: OnceLock::new(),
+            });
+        }
+        if buf.has_remaining() {
+            bail!("{} unexpected bytes after the chunk index", buf.remaining());
+        }
+        let Some(last) = chunks.last_mut() else {
+            bail!("SST has no data blocks");
+        };
+        last.len = meta_offset + chunk_index_offset - last.offset;
+
+        Ok(Some((
+            Self {
+                num_blocks,
+                has_ts_range,
+                chunk_len,
+                index_offset: meta_offset + chunk_index_offset,
+                chunks,
+            },
+            max_ts,
+        )))
+    }
+
+    pub(crate) fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    pub(crate) fn index_offset(&self) -> u64 {
+        self.index_offset
+    }
+
+    /// The first key of the first block, known without decoding any chunk.
+    pub(crate) fn first_key(&self) -> &KeyBytes {
+        &self.chunks[0].first_key
+    }
+
+    /// The metadata of the blocks in the chunk, read from `file` the first time.
+    fn chunk(&self, file: &FileObject, chunk_idx: usize) -> Result<&[BlockMeta]> {
+        let chunk = &self.chunks[chunk_idx];
+        if let Some(block_meta) = chunk.block_meta.get() {
+            return Ok(block_meta);
+        }
+        let raw_chunk = file.read(chunk.offset, chunk.len)?;
+        if crc32fast::hash(&raw_chunk) != chunk.checksum {
+            bail!("block meta chunk {} checksum mismatch", chunk_idx);
+        }
+        let first_idx = chunk_idx * self.chunk_len;
+        let num = self.chunk_len.min(self.num_blocks - first_idx);
+        let mut block_meta = Vec::with_capacity(num);
+        let mut buf = &raw_chunk[..];
+        BlockMeta::decode_chunk(&mut buf, first_idx, num, self.has_ts_range, &mut block_meta)?;
+        if buf.has_remaining() {
+            bail!(
+                "{} unexpected bytes after block meta chunk {}",
+                buf.remaining(),
+                chunk_idx
+            );
+        }
+        // another reader may have decoded it in the meantime, both are the same
+        Ok(chunk.block_meta.get_or_init(|| block_meta))
+    }
+
+    pub(crate) fn get(&self, file: &FileObject, block_idx: usize) -> Result<&BlockMeta> {
+        if block_idx >= self.num_blocks {
+            bail!(
+                "block {} is out of range of {} blocks",
+                block_idx,
+                self.num_blocks
+            );
+        }
+        let chunk = self.chunk(file, block_idx / self.chunk_len)?;
+        Ok(&chunk[block_idx % self.chunk_len])
+    }
+
+    /// Same as `SsTable::find_block_idx`, only decodes the chunk that has the block.
+    pub(crate) fn find_block_idx(&self, file: &FileObject, key: KeySlice) -> Result<usize> {
+        let chunk_idx = self
+            .chunks
+            .partition_point(|chunk| chunk.first_key.as_key_slice() <= key)
+            .saturating_sub(1);
+        let idx_in_chunk = self
+            .chunk(file, chunk_idx)?
+            .partition_point(|meta| meta.first_key.as_key_slice() <= key)
+            .saturating_sub(1);
+        Ok(chunk_idx * self.chunk_len + idx_in_chunk)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Number of chunks decoded so far.
+    #[cfg(test)]
+    pub(crate) fn num_decoded_chunks(&self) -> usize {
+        self.chunks
+            .iter()
+            .filter(|chunk| chunk.block_meta.get().is_some())
+            .count()
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ops::Bound;
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut};
+
+use super::bloom::Bloom;
+use crate::block::SIZEOF_U32;
+
+const PREFIX_EXTRACTOR_FIXED_LENGTH: u8 = 1;
+const PREFIX_EXTRACTOR_DELIMITER: u8 = 2;
+
+/// Extracts the prefix of the user keys that the prefix bloom filter of an SST is built over, so
+/// that a scan within a single prefix can skip SSTs without any key with that prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixExtractor {
+    /// The first bytes of the key. Shorter keys have no prefix.
+    FixedLength(usize),
+    /// The key up to and including the first occurrence of the delimiter. Keys without the
+    /// delimiter have no prefix.
+    Delimiter(u8),
+}
+
+impl PrefixExtractor {
+    pub fn extract<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        match *self {
+            PrefixExtractor::FixedLength(len) => key.get(..len),
+            PrefixExtractor::Delimiter(delimiter) => key
+                .iter()
+                .position(|x| *x == delimiter)
+                .map(|pos| &key[..=pos]),
+        }
+    }
+
+    /// The prefix of every key in the range, if both bounds have the same one.
+    pub fn extract_range<'a>(
+        &self,
+        lower: Bound<&'a [u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Option<&'a [u8]> {
+        let (Bound::Included(lower) | Bound::Excluded(lower)) = lower else {
+            return None;
+        };
+        let (Bound::Included(upper) | Bound::Excluded(upper)) = upper else {
+            return None;
+        };
+        let prefix = self.extract(lower)?;
+        (self.extract(upper)? == prefix).then_some(prefix)
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            PrefixExtractor::FixedLength(len) => {
+                buf.put_u8(PREFIX_EXTRACTOR_FIXED_LENGTH);
+                buf.put_u32(len as u32);
+            }
+            PrefixExtractor::Delimiter(delimiter) => {
+                buf.put_u8(PREFIX_EXTRACTOR_DELIMITER);
+                buf.put_u32(delimiter as u32);
+            }
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self> {
+        if buf.remaining() < 1 + SIZEOF_U32 {
+            bail!("prefix extractor is too short: {} bytes", buf.remaining());
+        }
+        let kind = buf.get_u8();
+        let param = buf.get_u32();
+        match kind {
+            PREFIX_EXTRACTOR_FIXED_LENGTH => Ok(PrefixExtractor::FixedLength(param as usize)),
+            PREFIX_EXTRACTOR_DELIMITER if param <= u8::MAX as u32 => {
+                Ok(PrefixExtractor::Delimiter(param as u8))
+            }
+            _ => bail!("unknown prefix extractor {} ({})", kind, param),
+        }
+    }
+}
+
+/// A bloom filter over the key prefixes of an SST, along with the extractor it was built with so
+/// that it stays usable if the extractor in the options changes.
+pub struct PrefixBloom {
+    pub extractor: PrefixExtractor,
+    pub bloom: Bloom,
+}
+
+impl PrefixBloom {
+    /// Whether the SST may have keys in the range, always true if the range spans several
+    /// prefixes.
+    pub fn may_contain_range(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+        self.extractor
+            .extract_range(lower, upper)
+            .is_none_or(|prefix| self.bloom.may_contain(farmhash::fingerprint32(prefix)))
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        self.extractor.encode(buf);
+        self.bloom.encode(buf);
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        let extractor = PrefixExtractor::decode(&mut buf)?;
+        Ok(Self {
+            extractor,
+            bloom: Bloom::decode(buf)?,
+        })
+    }
+}
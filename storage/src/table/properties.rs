@@ -0,0 +1,124 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::block::varint::{get_varint, put_varint};
+use crate::block::{SIZEOF_U32, SIZEOF_U64};
+
+// ---------------------------------------------------------------------------------------------------------
+// | num entries (u64) | num tombstones (u64) | raw key size (u64) | raw value size (u64) | checksum (u32) |
+// ---------------------------------------------------------------------------------------------------------
+/// Size of the properties section, right before the footer since format version 3.
+pub(crate) const SST_PROPERTIES_SIZE: usize = SIZEOF_U64 * 4 + SIZEOF_U32;
+
+/// Statistics of the entries in an SST, collected by the builder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    /// Number of entries, counting every version of a key.
+    pub num_entries: u64,
+    /// Number of entries with an empty value, i.e. deletions.
+    pub num_tombstones: u64,
+    /// Total size of the user keys, without timestamps.
+    pub raw_key_size: u64,
+    pub raw_value_size: u64,
+}
+
+impl TableProperties {
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.put_u64(self.num_entries);
+        buf.put_u64(self.num_tombstones);
+        buf.put_u64(self.raw_key_size);
+        buf.put_u64(self.raw_value_size);
+        let checksum = crc32fast::hash(&buf[start..]);
+        buf.put_u32(checksum);
+    }
+
+    pub(crate) fn decode(raw: &[u8]) -> Result<Self> {
+        if raw.len() != SST_PROPERTIES_SIZE {
+            bail!("properties section of {} bytes", raw.len());
+        }
+        let checksum = (&raw[SST_PROPERTIES_SIZE - SIZEOF_U32..]).get_u32();
+        if crc32fast::hash(&raw[..SST_PROPERTIES_SIZE - SIZEOF_U32]) != checksum {
+            bail!("SST properties checksum mismatch");
+        }
+        let mut buf = raw;
+        Ok(Self {
+            num_entries: buf.get_u64(),
+            num_tombstones: buf.get_u64(),
+            raw_key_size: buf.get_u64(),
+            raw_value_size: buf.get_u64(),
+        })
+    }
+}
+
+/// Merges the user properties of the SSTs a compaction reads into the user properties of each SST
+/// it writes, see `SsTableBuilder::add_property`.
+pub type UserPropertiesMerger = fn(&[&HashMap<String, Bytes>]) -> HashMap<String, Bytes>;
+
+// ------------------------------------------------------------------------------------------------
+// | num properties (u32) | key len (varint) | key | value len (varint) | value | ... | checksum (u32) |
+// ------------------------------------------------------------------------------------------------
+/// Encodes the user properties of an SST, sorted by key.
+pub(crate) fn encode_user_properties(properties: &BTreeMap<String, Bytes>, buf: &mut Vec<u8>) {
+    let start = buf.len();
+    buf.put_u32(properties.len() as u32);
+    for (key, value) in properties {
+        put_varint(buf, key.len() as u64);
+        buf.put(key.as_bytes());
+        put_varint(buf, value.len() as u64);
+        buf.put(&value[..]);
+    }
+    let checksum = crc32fast::hash(&buf[start..]);
+    buf.put_u32(checksum);
+}
+
+pub(crate) fn decode_user_properties(raw: &[u8]) -> Result<HashMap<String, Bytes>> {
+    if raw.len() < SIZEOF_U32 * 2 {
+        bail!("user properties section of {} bytes", raw.len());
+    }
+    let (mut buf, mut checksum) = raw.split_at(raw.len() - SIZEOF_U32);
+    if crc32fast::hash(buf) != checksum.get_u32() {
+        bail!("SST user properties checksum mismatch");
+    }
+    let num_properties = buf.get_u32();
+    let get_bytes = |buf: &mut &[u8]| {
+        let len = get_varint(buf)? as usize;
+        if buf.remaining() < len {
+            return None;
+        }
+        Some(buf.copy_to_bytes(len))
+    };
+    let mut properties = HashMap::new();
+    for idx in 0..num_properties {
+        let (Some(key), Some(value)) = (get_bytes(&mut buf), get_bytes(&mut buf)) else {
+            bail!("user property {} is malformed", idx);
+        };
+        let Ok(key) = String::from_utf8(key.to_vec()) else {
+            bail!("user property {} has a key that isn't UTF-8", idx);
+        };
+        properties.insert(key, value);
+    }
+    if buf.has_remaining() {
+        bail!(
+            "{} unexpected bytes after the user properties",
+            buf.remaining()
+        );
+    }
+    Ok(properties)
+}
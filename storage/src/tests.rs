@@ -1,7 +1,14 @@
 //! DO NOT MODIFY -- Mini-LSM tests modules
 //! This file will be automatically rewritten by the copy-test command.
 
+mod block;
+mod compaction;
 mod harness;
+mod manifest;
+mod mem_table;
+mod storage;
+mod table;
+mod wal;
 mod week1_day1;
 mod week1_day2;
 mod week1_day3;
@@ -0,0 +1,830 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use crate::{
+    block::{
+        BLOCK_FORMAT_LEGACY, BLOCK_FORMAT_RESTART, BLOCK_FORMAT_VARINT, Block, BlockAddResult,
+        BlockBuilder, BlockIterator, SeekResult, varint::put_varint,
+    },
+    key::{KeySlice, KeyVec},
+};
+
+fn overlapping_key_of(idx: usize) -> KeyVec {
+    KeyVec::for_testing_from_vec_no_ts(format!("user_profile_{:06}", idx).into_bytes())
+}
+
+#[test]
+fn test_block_prefix_compression_round_trip() {
+    let mut builder = BlockBuilder::new(4096);
+    let mut num_keys = 0;
+    let mut uncompressed_size = 0;
+    while builder.add(overlapping_key_of(num_keys).as_key_slice(), b"v") == BlockAddResult::Added {
+        let key = overlapping_key_of(num_keys);
+        // key_len (u16) | key | ts (u64) | value_len (u16) | value | offset (u16)
+        uncompressed_size += 2 + key.key_len() + 8 + 2 + 1 + 2;
+        num_keys += 1;
+    }
+    uncompressed_size += 2;
+    let encoded = builder.build().encode();
+    assert!(
+        encoded.len() * 10 < uncompressed_size * 8,
+        "encoded block ({} bytes) should be meaningfully smaller than {} bytes",
+        encoded.len(),
+        uncompressed_size
+    );
+    assert!(encoded.len() <= 4096);
+
+    let mut iter =
+        BlockIterator::create_and_seek_to_first(Arc::new(Block::decode(&encoded).unwrap()));
+    for idx in 0..num_keys {
+        assert!(iter.is_valid());
+        assert_eq!(
+            iter.key().for_testing_key_ref(),
+            overlapping_key_of(idx).for_testing_key_ref()
+        );
+        assert_eq!(iter.value(), b"v");
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_prefix_compression_honors_block_size() {
+    for block_size in [128, 256, 1024, 4096] {
+        let mut builder = BlockBuilder::new(block_size);
+        let mut idx = 0;
+        while builder.add(
+            KeySlice::for_testing_from_slice_no_ts(overlapping_key_of(idx).key_ref()),
+            b"value",
+        ) == BlockAddResult::Added
+        {
+            idx += 1;
+        }
+        assert!(idx > 1);
+        let encoded = builder.build().encode();
+        assert!(
+            encoded.len() <= block_size,
+            "block of {} bytes exceeds block_size {}",
+            encoded.len(),
+            block_size
+        );
+    }
+}
+
+/// Builds a 16KB block with keys `key_00000`, `key_00002`, ... so that odd indexes fall between
+/// two keys.
+fn generate_large_block() -> (Arc<Block>, usize) {
+    let mut builder = BlockBuilder::new(16384);
+    let mut num_keys = 0;
+    while builder.add(
+        KeySlice::for_testing_from_slice_no_ts(format!("key_{:05}", num_keys * 2).as_bytes()),
+        b"value",
+    ) == BlockAddResult::Added
+    {
+        num_keys += 1;
+    }
+    assert!(num_keys > 256);
+    (Arc::new(builder.build()), num_keys)
+}
+
+#[test]
+fn test_block_seek_to_key_binary_search() {
+    let (block, num_keys) = generate_large_block();
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    for idx in (0..num_keys).rev() {
+        // exact match
+        let key = format!("key_{:05}", idx * 2);
+        iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(key.as_bytes()));
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), key.as_bytes());
+
+        // between two keys lands on the next one
+        let between = format!("key_{:05}", idx * 2 + 1);
+        iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(between.as_bytes()));
+        if idx + 1 == num_keys {
+            assert!(!iter.is_valid());
+        } else {
+            assert!(iter.is_valid());
+            assert_eq!(
+                iter.key().for_testing_key_ref(),
+                format!("key_{:05}", idx * 2 + 2).as_bytes()
+            );
+        }
+    }
+}
+
+#[test]
+fn test_block_seek_to_key_out_of_range() {
+    let (block, _) = generate_large_block();
+    // before the first key
+    let mut iter = BlockIterator::create_and_seek_to_key(
+        block.clone(),
+        KeySlice::for_testing_from_slice_no_ts(b"a"),
+    );
+    assert!(iter.is_valid());
+    assert_eq!(iter.key().for_testing_key_ref(), b"key_00000");
+
+    // after the last key
+    iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(b"key_99999"));
+    assert!(!iter.is_valid());
+
+    // the iterator can still be repositioned afterwards
+    iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(b"key_00003"));
+    assert!(iter.is_valid());
+    assert_eq!(iter.key().for_testing_key_ref(), b"key_00004");
+    iter.next();
+    assert_eq!(iter.key().for_testing_key_ref(), b"key_00006");
+}
+
+#[test]
+fn test_block_seek_to_key_ret() {
+    let (block, num_keys) = generate_large_block();
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    let mut seek = |key: &str| {
+        let result = iter.seek_to_key_ret(KeySlice::for_testing_from_slice_no_ts(key.as_bytes()));
+        let key = iter
+            .is_valid()
+            .then(|| String::from_utf8(iter.key().key_ref().to_vec()).unwrap());
+        (result, key)
+    };
+    let first = "key_00000".to_string();
+    let last = format!("key_{:05}", (num_keys - 1) * 2);
+    // before the first key
+    assert_eq!(seek("a"), (SeekResult::After, Some(first.clone())));
+    assert_eq!(seek(&first), (SeekResult::Exact, Some(first)));
+    // between two entries
+    assert_eq!(
+        seek("key_00003"),
+        (SeekResult::After, Some("key_00004".to_string()))
+    );
+    assert_eq!(seek(&last), (SeekResult::Exact, Some(last.clone())));
+    // past the end
+    assert_eq!(seek(&format!("{}0", last)), (SeekResult::NotFound, None));
+    assert_eq!(seek("z"), (SeekResult::NotFound, None));
+}
+
+#[test]
+fn test_block_decode_rejects_malformed_input() {
+    assert!(Block::decode(&[]).is_err());
+    assert!(Block::decode(&[0]).is_err());
+    // no entries
+    assert!(Block::decode(&[0, 0]).is_err());
+    // more offsets than bytes
+    assert!(Block::decode(&[0, 0, 0, 100]).is_err());
+
+    let (block, _) = generate_large_block();
+    let encoded = block.encode();
+    // truncated at every position
+    for len in 0..encoded.len() {
+        assert!(Block::decode(&encoded[..len]).is_err());
+    }
+}
+
+#[test]
+fn test_block_decode_fuzz_never_panics() {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+    let (block, _) = generate_large_block();
+    let encoded = block.encode();
+    for _ in 0..2000 {
+        // random garbage
+        let len = rng.gen_range(0..256);
+        let garbage: Vec<u8> = (0..len).map(|_| rng.r#gen()).collect();
+        if let Ok(block) = Block::decode(&garbage) {
+            let mut iter = BlockIterator::create_and_seek_to_first(Arc::new(block));
+            while iter.is_valid() {
+                iter.next();
+            }
+        }
+
+        // valid block with a few flipped bytes
+        let mut corrupted = encoded.to_vec();
+        for _ in 0..rng.gen_range(1..4) {
+            let pos = rng.gen_range(0..corrupted.len());
+            corrupted[pos] = rng.r#gen();
+        }
+        if let Ok(block) = Block::decode(&corrupted) {
+            let mut iter = BlockIterator::create_and_seek_to_key(
+                Arc::new(block),
+                KeySlice::for_testing_from_slice_no_ts(b"key_00100"),
+            );
+            while iter.is_valid() {
+                iter.next();
+            }
+        }
+    }
+}
+
+/// Encodes entries in the layout of an earlier block format: fixed u16 lengths and a plain
+/// `num_of_elements` trailer for `BLOCK_FORMAT_LEGACY`, varint lengths for `BLOCK_FORMAT_VARINT`,
+/// plus restart points every `restart_interval` entries for `BLOCK_FORMAT_RESTART`.
+fn encode_old_block(format: u8, entries: &[(&[u8], &[u8])], restart_interval: usize) -> Vec<u8> {
+    use bytes::BufMut;
+
+    let put_len = |data: &mut Vec<u8>, len: usize| {
+        if format == BLOCK_FORMAT_LEGACY {
+            data.put_u16(len as u16);
+        } else {
+            put_varint(data, len as u64);
+        }
+    };
+    let mut data = Vec::new();
+    let mut offsets = Vec::new();
+    let mut restarts = Vec::new();
+    let mut restart_key: &[u8] = &[];
+    for (idx, (key, value)) in entries.iter().enumerate() {
+        let is_restart = format != BLOCK_FORMAT_RESTART && idx == 0
+            || format == BLOCK_FORMAT_RESTART && idx % restart_interval == 0;
+        if is_restart {
+            restarts.push(idx as u16);
+            restart_key = key;
+        }
+        let overlap = if is_restart {
+            0
+        } else {
+            key.iter()
+                .zip(restart_key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        offsets.push(data.len() as u16);
+        put_len(&mut data, overlap);
+        put_len(&mut data, key.len() - overlap);
+        data.put(&key[overlap..]);
+        data.put_u64(0);
+        put_len(&mut data, value.len());
+        data.put(*value);
+    }
+    for offset in &offsets {
+        data.put_u16(*offset);
+    }
+    if format == BLOCK_FORMAT_LEGACY {
+        data.put_u16(offsets.len() as u16);
+        return data;
+    }
+    if format == BLOCK_FORMAT_RESTART {
+        for restart in &restarts {
+            data.put_u16(*restart);
+        }
+        data.put_u16(restarts.len() as u16);
+    }
+    data.put_u8(format);
+    data.put_u16(offsets.len() as u16 | 0x8000);
+    data
+}
+
+#[test]
+fn test_block_decode_legacy_format() {
+    let entries: Vec<(&[u8], &[u8])> = vec![
+        (b"key_001", b"value_1"),
+        (b"key_002", b""),
+        (b"key_010", b"value_10"),
+    ];
+    let block = Block::decode(&encode_old_block(BLOCK_FORMAT_LEGACY, &entries, 1)).unwrap();
+    let mut iter = BlockIterator::create_and_seek_to_first(Arc::new(block));
+    for (key, value) in &entries {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), *key);
+        assert_eq!(iter.value(), *value);
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_varint_lengths() {
+    use crate::block::varint::{get_varint, varint_len};
+
+    for value in [
+        0,
+        1,
+        127,
+        128,
+        16383,
+        16384,
+        65535,
+        65536,
+        u32::MAX as u64,
+        u64::MAX,
+    ] {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, value);
+        assert_eq!(buf.len(), varint_len(value));
+        let mut rbuf = &buf[..];
+        assert_eq!(get_varint(&mut rbuf), Some(value));
+        assert!(rbuf.is_empty());
+        // truncated varints are rejected
+        assert_eq!(get_varint(&mut &buf[..buf.len() - 1]), None);
+    }
+
+    let mut builder = BlockBuilder::new(4096);
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_no_ts(b"key_1"), b"v"),
+        BlockAddResult::Added
+    );
+    let block = builder.build();
+    // 1 byte for each length field instead of 2
+    assert_eq!(block.data.len(), 1 + 1 + 5 + 8 + 1 + 1);
+}
+
+#[test]
+fn test_block_value_larger_than_u16() {
+    let large_value = vec![b'x'; 100_000];
+    let large_key = vec![b'k'; 70_000];
+    let mut builder = BlockBuilder::new(4096);
+    assert_eq!(
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&large_key),
+            &large_value
+        ),
+        BlockAddResult::Added
+    );
+    // the block is already full
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_no_ts(b"l"), b"v"),
+        BlockAddResult::BlockFull
+    );
+    let encoded = builder.build().encode();
+    let block = Arc::new(Block::decode(&encoded).unwrap());
+    let iter = BlockIterator::create_and_seek_to_key(
+        block,
+        KeySlice::for_testing_from_slice_no_ts(&large_key),
+    );
+    assert!(iter.is_valid());
+    assert_eq!(iter.key().for_testing_key_ref(), &large_key[..]);
+    assert_eq!(iter.value(), &large_value[..]);
+}
+
+fn large_block_key(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx * 2).into_bytes()
+}
+
+#[test]
+fn test_block_seek_to_last_and_prev() {
+    let (block, num_keys) = generate_large_block();
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    iter.seek_to_last();
+    for idx in (0..num_keys).rev() {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), large_block_key(idx));
+        assert_eq!(iter.value(), b"value");
+        iter.prev();
+    }
+    assert!(!iter.is_valid());
+    // staying before the first entry
+    iter.prev();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_interleaved_next_and_prev() {
+    let (block, num_keys) = generate_large_block();
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    let mut expected = 0;
+    // two steps forward, one step back
+    for step in 0..num_keys * 3 {
+        if step % 3 == 2 {
+            iter.prev();
+            expected -= 1;
+        } else {
+            iter.next();
+            expected += 1;
+        }
+        if expected >= num_keys {
+            assert!(!iter.is_valid());
+            break;
+        }
+        assert_eq!(iter.key().for_testing_key_ref(), large_block_key(expected));
+    }
+
+    // stepping back from past the end lands on the last entry
+    iter.seek_to_first();
+    while iter.is_valid() {
+        iter.next();
+    }
+    iter.prev();
+    assert_eq!(
+        iter.key().for_testing_key_ref(),
+        large_block_key(num_keys - 1)
+    );
+    iter.next();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_seek_to_key_then_prev() {
+    let (block, num_keys) = generate_large_block();
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+
+    // position at an upper bound that falls between two keys and walk backwards
+    iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(b"key_00101"));
+    assert_eq!(iter.key().for_testing_key_ref(), b"key_00102");
+    iter.prev();
+    assert_eq!(iter.key().for_testing_key_ref(), b"key_00100");
+    iter.prev();
+    assert_eq!(iter.key().for_testing_key_ref(), b"key_00098");
+    iter.next();
+    assert_eq!(iter.key().for_testing_key_ref(), b"key_00100");
+
+    // an upper bound past the last key walks back from the last entry
+    iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(b"key_99999"));
+    assert!(!iter.is_valid());
+    iter.prev();
+    assert_eq!(
+        iter.key().for_testing_key_ref(),
+        large_block_key(num_keys - 1)
+    );
+
+    // an upper bound before the first key has nothing before it
+    iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(b"a"));
+    assert_eq!(iter.key().for_testing_key_ref(), large_block_key(0));
+    iter.prev();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_decode_bytes_does_not_copy() {
+    let mut builder = BlockBuilder::new(4096);
+    for idx in 0..10 {
+        assert_eq!(
+            builder.add(
+                KeySlice::for_testing_from_slice_no_ts(format!("key_{:03}", idx).as_bytes()),
+                format!("value_{:03}", idx).as_bytes(),
+            ),
+            BlockAddResult::Added
+        );
+    }
+    let encoded = builder.build().encode();
+    let block = Arc::new(Block::decode_bytes(encoded.clone()).unwrap());
+    // the entries are a prefix of the encoded buffer
+    assert_eq!(block.data.as_ptr(), encoded.as_ptr());
+
+    let data_range = block.data.as_ptr_range();
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    while iter.is_valid() {
+        assert!(data_range.contains(&iter.value().as_ptr()));
+        let value = iter.value_bytes();
+        assert_eq!(value.as_ptr(), iter.value().as_ptr());
+        assert_eq!(&value[..], iter.value());
+        iter.next();
+    }
+}
+
+#[test]
+fn test_block_add_rejection_reasons() {
+    let mut builder = BlockBuilder::new(64);
+    // an empty block takes anything, even an oversized entry
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_with_ts(b"b", 2), b"v"),
+        BlockAddResult::Added
+    );
+    // keys must be strictly increasing, and a larger ts sorts first for the same key
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_with_ts(b"a", 1), b"v"),
+        BlockAddResult::OutOfOrder
+    );
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_with_ts(b"b", 2), b"v"),
+        BlockAddResult::OutOfOrder
+    );
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_with_ts(b"b", 3), b"v"),
+        BlockAddResult::OutOfOrder
+    );
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_with_ts(b"b", 1), b"v"),
+        BlockAddResult::Added
+    );
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_no_ts(b"c"), &[b'c'; 100]),
+        BlockAddResult::EntryTooLarge
+    );
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_no_ts(b"c"), &[b'c'; 30]),
+        BlockAddResult::BlockFull
+    );
+
+    // rejected entries leave the block untouched
+    let block = Arc::new(builder.build());
+    assert_eq!(block.offsets.len(), 2);
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    assert_eq!(
+        iter.key(),
+        KeySlice::for_testing_from_slice_with_ts(b"b", 2)
+    );
+    iter.next();
+    assert_eq!(
+        iter.key(),
+        KeySlice::for_testing_from_slice_with_ts(b"b", 1)
+    );
+    iter.next();
+    assert!(!iter.is_valid());
+}
+
+fn build_block_with_restart_interval(block_size: usize, restart_interval: usize) -> (Block, usize) {
+    let mut builder = BlockBuilder::new(block_size).with_restart_interval(restart_interval);
+    let mut num_keys = 0;
+    while builder.add(overlapping_key_of(num_keys).as_key_slice(), b"value")
+        == BlockAddResult::Added
+    {
+        num_keys += 1;
+    }
+    (builder.build(), num_keys)
+}
+
+fn check_block_contents(block: Arc<Block>, num_keys: usize) {
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    for idx in 0..num_keys {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), overlapping_key_of(idx).as_key_slice());
+        assert_eq!(iter.value(), b"value");
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+    for idx in (0..num_keys).rev() {
+        iter.seek_to_key(overlapping_key_of(idx).as_key_slice());
+        assert_eq!(iter.key(), overlapping_key_of(idx).as_key_slice());
+    }
+    iter.seek_to_key(overlapping_key_of(num_keys).as_key_slice());
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_restart_interval() {
+    for restart_interval in [1, 2, 3, 16, usize::MAX] {
+        for block_size in [256, 1024, 4096] {
+            let (block, num_keys) = build_block_with_restart_interval(block_size, restart_interval);
+            let expected_restarts = num_keys.div_ceil(restart_interval.min(num_keys));
+            assert_eq!(block.restarts.len(), expected_restarts);
+            let encoded = block.encode();
+            assert!(encoded.len() <= block_size);
+
+            let decoded = Block::decode(&encoded).unwrap();
+            assert_eq!(decoded.restarts, block.restarts);
+            assert_eq!(decoded.offsets, block.offsets);
+            assert_eq!(decoded.data, block.data);
+            check_block_contents(Arc::new(decoded), num_keys);
+        }
+    }
+}
+
+#[test]
+fn test_block_restart_interval_one_stores_full_keys() {
+    let (block, num_keys) = build_block_with_restart_interval(4096, 1);
+    assert_eq!(block.restarts, (0..num_keys as u16).collect::<Vec<_>>());
+    // varint lengths take 1 byte each, no key shares anything with a previous one
+    let full_size = (0..num_keys)
+        .map(|idx| 1 + 1 + overlapping_key_of(idx).key_len() + 8 + 1 + 5)
+        .sum::<usize>();
+    assert_eq!(block.data.len(), full_size);
+
+    let (compressed, compressed_keys) = build_block_with_restart_interval(4096, 16);
+    assert!(compressed_keys > num_keys);
+    assert!(compressed.restarts.len() < compressed_keys);
+}
+
+#[test]
+fn test_block_restart_interval_covering_whole_block() {
+    let (block, num_keys) = build_block_with_restart_interval(4096, usize::MAX);
+    assert_eq!(block.restarts, vec![0]);
+    check_block_contents(Arc::new(block), num_keys);
+}
+
+#[test]
+fn test_block_decode_rejects_bad_restarts() {
+    let (block, _) = build_block_with_restart_interval(4096, 4);
+    let mut bad = Block::decode(&block.encode()).unwrap();
+    bad.restarts[0] = 1;
+    assert!(Block::decode(&bad.encode()).is_err());
+
+    let mut bad = Block::decode(&block.encode()).unwrap();
+    bad.restarts.swap(1, 2);
+    assert!(Block::decode(&bad.encode()).is_err());
+
+    // a restart point on an entry that is prefix-compressed
+    let mut bad = Block::decode(&block.encode()).unwrap();
+    bad.restarts[1] += 1;
+    assert!(Block::decode(&bad.encode()).is_err());
+}
+
+fn decode_error(data: &[u8]) -> String {
+    match Block::decode(data) {
+        Ok(_) => panic!("block decoded successfully"),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[test]
+fn test_block_decode_unknown_future_format() {
+    let (block, _) = build_block_with_restart_interval(4096, 16);
+    let mut encoded = block.encode().to_vec();
+    let format_pos = encoded.len() - 3;
+    assert_eq!(encoded[format_pos], block.format);
+    encoded[format_pos] = 7;
+    assert!(decode_error(&encoded).contains("unsupported block format 7"));
+
+    // the rest of the block isn't looked at, even if it is garbage
+    let garbage = [0xab, 0xcd, 7, 0x80, 0x00];
+    assert!(decode_error(&garbage).contains("unsupported block format 7"));
+    assert!(decode_error(&[255, 0xff, 0xff]).contains("unsupported block format 255"));
+}
+
+#[test]
+fn test_block_decode_all_known_formats() {
+    let keys = (0..100).map(overlapping_key_of).collect::<Vec<_>>();
+    let entries = keys
+        .iter()
+        .map(|key| (key.key_ref(), b"value".as_slice()))
+        .collect::<Vec<_>>();
+    for format in [
+        BLOCK_FORMAT_LEGACY,
+        BLOCK_FORMAT_VARINT,
+        BLOCK_FORMAT_RESTART,
+    ] {
+        let decoded = Block::decode(&encode_old_block(format, &entries, 16)).unwrap();
+        assert_eq!(decoded.format, format);
+        if format == BLOCK_FORMAT_RESTART {
+            assert_eq!(decoded.restarts.len(), 7);
+        } else {
+            assert_eq!(decoded.restarts, vec![0]);
+        }
+        check_block_contents(Arc::new(decoded), entries.len());
+    }
+}
+
+#[test]
+fn test_block_multiple_timestamps_per_key() {
+    let mut builder = BlockBuilder::new(4096).with_restart_interval(3);
+    let mut expected = Vec::new();
+    for key in [b"a".as_slice(), b"ab", b"b"] {
+        // newer versions come first
+        for ts in (1..=5).rev() {
+            let value = format!("{}@{}", String::from_utf8_lossy(key), ts);
+            assert_eq!(
+                builder.add(KeySlice::from_slice(key, ts), value.as_bytes()),
+                BlockAddResult::Added
+            );
+            expected.push((key.to_vec(), ts, value));
+        }
+    }
+    let block = Arc::new(Block::decode(&builder.build().encode()).unwrap());
+
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    for (key, ts, value) in &expected {
+        assert_eq!(iter.key().key_ref(), &key[..]);
+        assert_eq!(iter.key().ts(), *ts);
+        assert_eq!(iter.value(), value.as_bytes());
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+
+    // seeking with a ts lands on the newest version that is not newer than it
+    iter.seek_to_key(KeySlice::from_slice(b"ab", 3));
+    assert_eq!(iter.key(), KeySlice::from_slice(b"ab", 3));
+    iter.seek_to_key(KeySlice::from_slice(b"ab", 100));
+    assert_eq!(iter.key(), KeySlice::from_slice(b"ab", 5));
+    // older than every version of the key moves on to the next key
+    iter.seek_to_key(KeySlice::from_slice(b"ab", 0));
+    assert_eq!(iter.key(), KeySlice::from_slice(b"b", 5));
+    iter.prev();
+    assert_eq!(iter.key(), KeySlice::from_slice(b"ab", 1));
+}
+
+#[test]
+fn test_block_tombstones_omit_value_length() {
+    // a delete-only workload
+    let mut builder = BlockBuilder::new(4096);
+    let mut num_keys = 0;
+    while builder.add(overlapping_key_of(num_keys).as_key_slice(), b"") == BlockAddResult::Added {
+        num_keys += 1;
+    }
+    let block = builder.build();
+    let keys = (0..num_keys).map(overlapping_key_of).collect::<Vec<_>>();
+    let entries = keys
+        .iter()
+        .map(|key| (key.key_ref(), b"".as_slice()))
+        .collect::<Vec<_>>();
+    let before = encode_old_block(BLOCK_FORMAT_RESTART, &entries, 16).len();
+    let after = block.encode().len();
+    // one byte of value length saved per tombstone
+    assert_eq!(before - after, num_keys);
+
+    let block = Arc::new(Block::decode(&block.encode()).unwrap());
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    for key in &keys {
+        assert_eq!(iter.key(), key.as_key_slice());
+        assert!(iter.value().is_empty());
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_mixed_tombstones_and_values() {
+    let mut builder = BlockBuilder::new(4096).with_restart_interval(4);
+    // long keys need a multi-byte rest key length once the tombstone bit is added
+    let key_of = |idx: usize| format!("{}_{:03}", "k".repeat(70), idx).into_bytes();
+    let value_of = |idx: usize| {
+        if idx.is_multiple_of(3) {
+            Vec::new()
+        } else {
+            format!("value_{}", idx).into_bytes()
+        }
+    };
+    for idx in 0..30 {
+        assert_eq!(
+            builder.add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                &value_of(idx)
+            ),
+            BlockAddResult::Added
+        );
+    }
+    let block = Arc::new(Block::decode(&builder.build().encode()).unwrap());
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    for idx in 0..30 {
+        assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+    for idx in (0..30).rev() {
+        iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(&key_of(idx)));
+        assert_eq!(iter.value(), value_of(idx));
+    }
+}
+
+/// Fills the builder until the block is full, returns the number of entries added.
+fn fill_block(builder: &mut BlockBuilder, keys: &[KeyVec]) -> usize {
+    let mut num_keys = 0;
+    for key in keys {
+        if builder.add(key.as_key_slice(), b"value") != BlockAddResult::Added {
+            break;
+        }
+        num_keys += 1;
+    }
+    num_keys
+}
+
+#[test]
+fn test_block_builder_reset_reuses_buffers() {
+    let keys: Vec<_> = (0..1000).map(overlapping_key_of).collect();
+    let mut builder = BlockBuilder::new(4096);
+    let mut buf = Vec::new();
+    let mut first_block = Vec::new();
+    fill_block(&mut builder, &keys);
+    builder.encode_into(&mut first_block);
+    builder.reset();
+    // the first block grew the buffers, later ones of the same size reuse them
+    let buffers = builder.buffers_for_test();
+    for _ in 0..100 {
+        buf.clear();
+        fill_block(&mut builder, &keys);
+        builder.encode_into(&mut buf);
+        builder.reset();
+        assert_eq!(buf, first_block);
+        assert_eq!(builder.buffers_for_test(), buffers);
+    }
+}
+
+#[test]
+fn test_block_builder_reset() {
+    let keys: Vec<_> = (0..1000).map(overlapping_key_of).collect();
+    let mut builder = BlockBuilder::new(4096);
+    let num_keys = fill_block(&mut builder, &keys);
+    builder.reset();
+    assert!(builder.is_empty());
+    // keys smaller than the ones before the reset are accepted again
+    let reused_keys = fill_block(&mut builder, &keys[..num_keys]);
+    assert_eq!(num_keys, reused_keys);
+    let mut buf = Vec::new();
+    builder.encode_into(&mut buf);
+    assert_eq!(&buf[..], &builder.build().encode()[..]);
+}
+
+#[test]
+fn test_block_encode_into_appends() {
+    let (block, _) = generate_large_block();
+    let encoded = block.encode();
+    let mut buf = b"prefix".to_vec();
+    block.encode_into(&mut buf);
+    assert_eq!(&buf[..6], b"prefix");
+    assert_eq!(&buf[6..], &encoded[..]);
+    // and it decodes to the same block
+    let decoded = Block::decode(&buf[6..]).unwrap();
+    assert_eq!(&decoded.encode()[..], &encoded[..]);
+}
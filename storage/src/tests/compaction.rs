@@ -0,0 +1,512 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::{
+    compact::{
+        CompactionController, CompactionOptions, CompactionTask, LeveledCompactionController,
+        LeveledCompactionOptions, LeveledCompactionTask, SimpleLeveledCompactionController,
+        SimpleLeveledCompactionOptions, SimpleLeveledCompactionTask, TieredCompactionController,
+        TieredCompactionOptions, TieredCompactionTask,
+    },
+    key::KeyBytes,
+    lsm_storage::LsmStorageState,
+    mem_table::MemTable,
+    table::SsTable,
+};
+
+/// The id, size in MB, and first and last key of an SST.
+type Sst = (usize, u64, usize, usize);
+
+fn key_of(idx: usize) -> KeyBytes {
+    KeyBytes::for_testing_from_bytes_no_ts(Bytes::from(format!("key_{:05}", idx)))
+}
+
+fn controller(max_levels: usize) -> LeveledCompactionController {
+    LeveledCompactionController::new(LeveledCompactionOptions {
+        level_size_multiplier: 10,
+        level0_file_num_compaction_trigger: 2,
+        max_levels,
+        base_level_size_mb: 10,
+    })
+}
+
+/// A state of SSTs with no data, only the given sizes and key ranges.
+fn state_of(l0: &[Sst], levels: &[&[Sst]]) -> LsmStorageState {
+    let mut sstables = HashMap::new();
+    let mut add = |ssts: &[Sst]| {
+        for &(sst_id, size_mb, first, last) in ssts {
+            let sst = SsTable::create_meta_only(sst_id, size_mb << 20, key_of(first), key_of(last));
+            sstables.insert(sst_id, Arc::new(sst));
+        }
+        ssts.iter().map(|sst| sst.0).collect::<Vec<_>>()
+    };
+    let l0_sstables = add(l0);
+    let levels = levels
+        .iter()
+        .enumerate()
+        .map(|(level, ssts)| (level + 1, add(ssts)))
+        .collect();
+    LsmStorageState {
+        memtable: Arc::new(MemTable::create(0)),
+        imm_memtables: Vec::new(),
+        l0_sstables,
+        levels,
+        sstables,
+    }
+}
+
+fn task_of(task: &LeveledCompactionTask) -> (Option<usize>, Vec<usize>, usize, Vec<usize>, bool) {
+    (
+        task.upper_level,
+        task.upper_level_sst_ids.clone(),
+        task.lower_level,
+        task.lower_level_sst_ids.clone(),
+        task.is_lower_level_bottom_level,
+    )
+}
+
+#[test]
+fn test_leveled_l0_to_bottom_level_while_it_fits_in_base_level() {
+    let controller = controller(4);
+    // all of the data fits in the 10MB of the base level, so the levels above the bottom one are
+    // skipped
+    let state = state_of(
+        &[(10, 1, 0, 50)],
+        &[&[], &[], &[], &[(1, 2, 0, 9), (2, 2, 60, 99)]],
+    );
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    let state = state_of(
+        &[(11, 1, 40, 70), (10, 1, 0, 50)],
+        &[
+            &[],
+            &[],
+            &[],
+            &[(1, 2, 0, 9), (2, 2, 60, 99), (3, 2, 100, 120)],
+        ],
+    );
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task_of(&task), (None, vec![11, 10], 4, vec![1, 2], true));
+}
+
+#[test]
+fn test_leveled_base_level_follows_bottom_level_size() {
+    let controller = controller(4);
+    // the targets work back from the 1000MB bottom level: 100MB for L3 and 10MB for L2, which
+    // is the base level, as L1 would be under 10MB
+    let state = state_of(
+        &[(11, 1, 0, 99), (10, 1, 0, 99)],
+        &[
+            &[],
+            &[(1, 5, 0, 49), (2, 5, 50, 99)],
+            &[(3, 90, 0, 99)],
+            &[(4, 1000, 0, 99)],
+        ],
+    );
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task_of(&task), (None, vec![11, 10], 2, vec![1, 2], false));
+}
+
+#[test]
+fn test_leveled_picks_level_furthest_over_target() {
+    let controller = controller(4);
+    // L2 is at 3x its 10MB target and L3 at 1.5x its 100MB one
+    let state = state_of(
+        &[(20, 1, 0, 99)],
+        &[
+            &[],
+            &[(12, 10, 0, 29), (11, 10, 30, 59), (13, 10, 60, 99)],
+            &[(5, 50, 0, 25), (6, 50, 26, 40), (7, 50, 41, 99)],
+            &[(1, 1000, 0, 99)],
+        ],
+    );
+    let task = controller.generate_compaction_task(&state).unwrap();
+    // the oldest SST in L2, with the ones in L3 it overlaps
+    assert_eq!(task_of(&task), (Some(2), vec![11], 3, vec![6, 7], false));
+
+    // once L2 is under its target, L3
+    let state = state_of(
+        &[],
+        &[
+            &[],
+            &[(12, 5, 0, 29)],
+            &[(5, 50, 0, 25), (6, 50, 26, 40), (7, 50, 41, 99)],
+            &[(1, 500, 0, 49), (2, 500, 50, 99)],
+        ],
+    );
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(task_of(&task), (Some(3), vec![5], 4, vec![1], true));
+
+    // and nothing once every level is under its target
+    let state = state_of(
+        &[],
+        &[
+            &[],
+            &[(12, 5, 0, 29)],
+            &[(5, 50, 0, 25)],
+            &[(1, 1000, 0, 99)],
+        ],
+    );
+    assert!(controller.generate_compaction_task(&state).is_none());
+}
+
+#[test]
+fn test_leveled_apply_compaction_result_keeps_level_sorted() {
+    let controller = controller(2);
+    let mut state = state_of(
+        &[(10, 1, 20, 60)],
+        &[&[(1, 1, 0, 9), (2, 1, 30, 40), (3, 1, 70, 80)], &[]],
+    );
+    let task = LeveledCompactionTask {
+        upper_level: None,
+        upper_level_sst_ids: vec![10],
+        lower_level: 1,
+        lower_level_sst_ids: vec![2],
+        is_lower_level_bottom_level: false,
+    };
+    // flushed while the compaction ran, and its output, in no particular order
+    let new_ssts = state_of(&[(11, 1, 0, 99), (21, 1, 41, 60), (20, 1, 20, 40)], &[]);
+    state.l0_sstables.insert(0, 11);
+    state.sstables.extend(new_ssts.sstables);
+
+    let (new_state, removed) = controller.apply_compaction_result(&state, &task, &[21, 20], false);
+    assert_eq!(removed, vec![10, 2]);
+    assert_eq!(new_state.l0_sstables, vec![11]);
+    assert_eq!(new_state.levels[0].1, vec![1, 20, 21, 3]);
+
+    // recovery has no SST to sort by
+    let (new_state, _) = controller.apply_compaction_result(&state, &task, &[21, 20], true);
+    assert_eq!(new_state.levels[0].1, vec![1, 3, 21, 20]);
+}
+
+#[test]
+#[should_panic(expected = "after it in level 1")]
+fn test_leveled_apply_compaction_result_checks_overlap() {
+    let controller = controller(2);
+    let mut state = state_of(&[(10, 1, 20, 60)], &[&[(1, 1, 0, 9), (2, 1, 30, 40)], &[]]);
+    let task = LeveledCompactionTask {
+        upper_level: None,
+        upper_level_sst_ids: vec![10],
+        lower_level: 1,
+        lower_level_sst_ids: vec![],
+        is_lower_level_bottom_level: false,
+    };
+    // SST 2 should have been compacted along with it
+    state
+        .sstables
+        .extend(state_of(&[(20, 1, 20, 60)], &[]).sstables);
+    controller.apply_compaction_result(&state, &task, &[20], false);
+}
+
+fn simple_controller() -> SimpleLeveledCompactionController {
+    SimpleLeveledCompactionController::new(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+    })
+}
+
+fn simple_task_of(
+    task: &SimpleLeveledCompactionTask,
+) -> (Option<usize>, Vec<usize>, usize, Vec<usize>, bool) {
+    (
+        task.upper_level,
+        task.upper_level_sst_ids.clone(),
+        task.lower_level,
+        task.lower_level_sst_ids.clone(),
+        task.is_lower_level_bottom_level,
+    )
+}
+
+#[test]
+fn test_simple_leveled_generate_compaction_task() {
+    let controller = simple_controller();
+    // L0 goes to L1 as a whole once it has 2 SSTs
+    let state = state_of(
+        &[(11, 1, 0, 9), (10, 1, 0, 9)],
+        &[&[(1, 1, 0, 9)], &[], &[]],
+    );
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(
+        simple_task_of(&task),
+        (None, vec![11, 10], 1, vec![1], false)
+    );
+
+    // L2 has fewer than twice the SSTs of L1
+    let state = state_of(
+        &[(10, 1, 0, 9)],
+        &[
+            &[(1, 1, 0, 9), (2, 1, 10, 19)],
+            &[(3, 1, 0, 9), (4, 1, 10, 19), (5, 1, 20, 29)],
+            &[],
+        ],
+    );
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(
+        simple_task_of(&task),
+        (Some(1), vec![1, 2], 2, vec![3, 4, 5], false)
+    );
+
+    // an empty level has nothing to compact to the one below
+    let state = state_of(&[], &[&[], &[(3, 1, 0, 9)], &[(4, 1, 0, 9)]]);
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(simple_task_of(&task), (Some(2), vec![3], 3, vec![4], true));
+
+    let state = state_of(
+        &[(10, 1, 0, 9)],
+        &[
+            &[(1, 1, 0, 9)],
+            &[(2, 1, 0, 9), (3, 1, 10, 19)],
+            &[(4, 1, 0, 4), (5, 1, 5, 9), (6, 1, 10, 14), (7, 1, 15, 19)],
+        ],
+    );
+    assert!(controller.generate_compaction_task(&state).is_none());
+}
+
+#[test]
+fn test_simple_leveled_apply_compaction_result() {
+    let controller = simple_controller();
+    let state = state_of(
+        &[(12, 1, 0, 9), (11, 1, 0, 9), (10, 1, 0, 9)],
+        &[&[(1, 1, 0, 9), (2, 1, 10, 19)], &[(3, 1, 0, 19)], &[]],
+    );
+    // SST 12 was flushed while L0 was compacted, so it stays
+    let task = SimpleLeveledCompactionTask {
+        upper_level: None,
+        upper_level_sst_ids: vec![11, 10],
+        lower_level: 1,
+        lower_level_sst_ids: vec![1, 2],
+        is_lower_level_bottom_level: false,
+    };
+    let (new_state, removed) = controller.apply_compaction_result(&state, &task, &[20, 21]);
+    assert_eq!(removed, vec![11, 10, 1, 2]);
+    assert_eq!(new_state.l0_sstables, vec![12]);
+    assert_eq!(new_state.levels[0].1, vec![20, 21]);
+    assert_eq!(new_state.levels[1].1, vec![3]);
+
+    // the upper level is emptied, and the lower one replaced
+    let task = SimpleLeveledCompactionTask {
+        upper_level: Some(1),
+        upper_level_sst_ids: vec![1, 2],
+        lower_level: 2,
+        lower_level_sst_ids: vec![3],
+        is_lower_level_bottom_level: false,
+    };
+    let (new_state, removed) = controller.apply_compaction_result(&state, &task, &[22]);
+    assert_eq!(removed, vec![1, 2, 3]);
+    assert_eq!(new_state.l0_sstables, vec![12, 11, 10]);
+    assert!(new_state.levels[0].1.is_empty());
+    assert_eq!(new_state.levels[1].1, vec![22]);
+}
+
+#[test]
+fn test_force_full_compaction_apply_result() {
+    let controller =
+        CompactionController::new(&CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        }));
+    let state = state_of(
+        &[(12, 1, 0, 9), (11, 1, 0, 9)],
+        &[
+            &[(1, 1, 0, 9)],
+            &[(2, 1, 0, 9)],
+            &[(3, 1, 0, 9), (4, 1, 10, 19)],
+        ],
+    );
+    // SST 12 was flushed while everything else was compacted, so it stays
+    let task = CompactionTask::ForceFullCompaction {
+        l0_sstables: vec![11],
+        l1_sstables: vec![1, 2, 3, 4],
+    };
+    let (new_state, removed) = controller.apply_compaction_result(&state, &task, &[20, 21], true);
+    assert_eq!(removed, vec![11, 1, 2, 3, 4]);
+    assert_eq!(new_state.l0_sstables, vec![12]);
+    let levels = new_state.levels.iter().map(|(_, sst_ids)| sst_ids.clone());
+    assert_eq!(
+        levels.collect::<Vec<_>>(),
+        vec![vec![], vec![], vec![20, 21]]
+    );
+
+    // the tiers are merged into the last one, after the tier flushed since
+    let controller =
+        CompactionController::new(&CompactionOptions::Tiered(TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: None,
+        }));
+    let state = state_of(&[], &[&[(12, 1, 0, 9)], &[(1, 1, 0, 9)], &[(2, 1, 0, 9)]]);
+    let task = CompactionTask::ForceFullCompaction {
+        l0_sstables: vec![],
+        l1_sstables: vec![1, 2],
+    };
+    let (new_state, removed) = controller.apply_compaction_result(&state, &task, &[20], true);
+    assert_eq!(removed, vec![1, 2]);
+    assert_eq!(new_state.levels, vec![(1, vec![12]), (20, vec![20])]);
+}
+
+fn tiered_controller(max_merge_width: Option<usize>) -> TieredCompactionController {
+    TieredCompactionController::new(TieredCompactionOptions {
+        num_tiers: 3,
+        max_size_amplification_percent: 100,
+        size_ratio: 1,
+        min_merge_width: 2,
+        max_merge_width,
+    })
+}
+
+fn tiers_of(task: &TieredCompactionTask) -> (Vec<usize>, bool) {
+    let tier_ids = task.tiers.iter().map(|(tier_id, _)| *tier_id).collect();
+    (tier_ids, task.bottom_tier_included)
+}
+
+#[test]
+fn test_tiered_space_amplification_by_size() {
+    let controller = tiered_controller(None);
+    // as many SSTs above the bottom tier as in it, but a tenth of its size
+    let state = state_of(
+        &[],
+        &[
+            &[(1, 1, 0, 9)],
+            &[(2, 1, 0, 9)],
+            &[(3, 10, 0, 9), (4, 10, 10, 19)],
+        ],
+    );
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    // fewer SSTs above the bottom tier than in it, but more than twice its size
+    let state = state_of(
+        &[],
+        &[
+            &[(1, 10, 0, 9)],
+            &[(2, 10, 0, 9)],
+            &[(3, 1, 0, 9), (4, 1, 10, 19), (5, 1, 20, 29)],
+        ],
+    );
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(tiers_of(&task), (vec![1, 2, 3], true));
+}
+
+#[test]
+fn test_tiered_size_ratio_by_size() {
+    let controller = tiered_controller(None);
+    let bottom_tier = (10..20)
+        .map(|sst_id| (sst_id, 10, sst_id * 10, sst_id * 10 + 9))
+        .collect::<Vec<_>>();
+    // tier 1 has more SSTs than tier 2, but is smaller
+    let state = state_of(
+        &[],
+        &[
+            &[(1, 1, 0, 9), (2, 1, 10, 19)],
+            &[(3, 4, 0, 19)],
+            &bottom_tier,
+        ],
+    );
+    assert!(controller.generate_compaction_task(&state).is_none());
+
+    // tier 1 has fewer SSTs than tier 2, but is larger
+    let state = state_of(
+        &[],
+        &[
+            &[(1, 4, 0, 19)],
+            &[(2, 1, 0, 9), (3, 1, 10, 19)],
+            &bottom_tier,
+        ],
+    );
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(tiers_of(&task), (vec![1, 2], false));
+}
+
+#[test]
+fn test_tiered_no_task_at_num_tiers() {
+    let controller = tiered_controller(None);
+    // each tier is larger than the ones above it, and the bottom one than all of them
+    let state = state_of(&[], &[&[(1, 1, 0, 9)], &[(2, 2, 0, 9)], &[(3, 4, 0, 9)]]);
+    assert!(controller.generate_compaction_task(&state).is_none());
+}
+
+#[test]
+fn test_tiered_reduces_sorted_runs_below_num_tiers() {
+    let state = state_of(
+        &[],
+        &[
+            &[(1, 1, 0, 9)],
+            &[(2, 2, 0, 9)],
+            &[(3, 4, 0, 9)],
+            &[(4, 8, 0, 9)],
+            &[(5, 16, 0, 9)],
+        ],
+    );
+    // merging the first 4 tiers leaves 2
+    let controller = tiered_controller(None);
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(tiers_of(&task), (vec![1, 2, 3, 4], false));
+    let (new_state, _) = controller.apply_compaction_result(&state, &task, &[6]);
+    assert_eq!(new_state.levels, vec![(6, vec![6]), (5, vec![5])]);
+
+    // up to max_merge_width of them at once
+    let controller = tiered_controller(Some(2));
+    let task = controller.generate_compaction_task(&state).unwrap();
+    assert_eq!(tiers_of(&task), (vec![1, 2], false));
+}
+
+/// The sizes of the tiers with one SST each, the min merge width, and the tiers merged.
+type TieredCase = (Vec<u64>, usize, Option<(Vec<usize>, bool)>);
+
+#[test]
+fn test_tiered_size_ratio_min_merge_width() {
+    let cases: Vec<TieredCase> = vec![
+        (vec![1, 1, 4, 100], 2, None),
+        (vec![2, 1, 1, 100], 2, Some((vec![1, 2], false))),
+        (vec![2, 1, 1, 100], 3, Some((vec![1, 2, 3], false))),
+        (vec![2, 1, 1, 100], 4, None),
+        (vec![1, 2, 1, 100], 2, Some((vec![1, 2, 3], false))),
+        (vec![1, 2, 1, 100], 3, Some((vec![1, 2, 3], false))),
+        (vec![3, 1, 1, 2, 100], 4, Some((vec![1, 2, 3, 4], false))),
+        (vec![3, 1, 1, 2, 100], 5, None),
+        (vec![4, 1], 2, Some((vec![1, 2], true))),
+        (vec![4, 1], 3, None),
+    ];
+    for (sizes, min_merge_width, expected) in cases {
+        let controller = TieredCompactionController::new(TieredCompactionOptions {
+            // enough tiers to compact, but not to reduce the sorted runs
+            num_tiers: sizes.len(),
+            max_size_amplification_percent: 1000,
+            size_ratio: 1,
+            min_merge_width,
+            max_merge_width: None,
+        });
+        let tiers = (1..)
+            .zip(&sizes)
+            .map(|(sst_id, &size_mb)| vec![(sst_id, size_mb, 0, 9)])
+            .collect::<Vec<_>>();
+        let tiers = tiers.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        let task = controller.generate_compaction_task(&state_of(&[], &tiers));
+        assert_eq!(
+            task.as_ref().map(tiers_of),
+            expected,
+            "{:?} with min_merge_width {}",
+            sizes,
+            min_merge_width
+        );
+    }
+}
@@ -19,7 +19,9 @@ use std::{
     time::Duration,
 };
 
-use anyhow::{Result, bail};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{Result, anyhow, bail};
 use bytes::Bytes;
 
 use crate::{
@@ -30,7 +32,7 @@ use crate::{
     iterators::{StorageIterator, merge_iterator::MergeIterator},
     key::{KeySlice, TS_ENABLED},
     lsm_storage::{BlockCache, LsmStorageInner, LsmStorageState, MiniLsm},
-    table::{SsTable, SsTableBuilder, SsTableIterator},
+    table::{EncryptionProvider, SsTable, SsTableBuilder, SsTableIterator},
 };
 
 #[derive(Clone)]
@@ -206,7 +208,9 @@ pub fn generate_sst(
 ) -> SsTable {
     let mut builder = SsTableBuilder::new(128);
     for (key, value) in data {
-        builder.add(KeySlice::for_testing_from_slice_no_ts(&key[..]), &value[..]);
+        builder
+            .add(KeySlice::for_testing_from_slice_no_ts(&key[..]), &value[..])
+            .unwrap();
     }
     builder.build(id, block_cache, path.as_ref()).unwrap()
 }
@@ -219,10 +223,12 @@ pub fn generate_sst_with_ts(
 ) -> SsTable {
     let mut builder = SsTableBuilder::new(128);
     for ((key, ts), value) in data {
-        builder.add(
-            KeySlice::for_testing_from_slice_with_ts(&key[..], ts),
-            &value[..],
-        );
+        builder
+            .add(
+                KeySlice::for_testing_from_slice_with_ts(&key[..], ts),
+                &value[..],
+            )
+            .unwrap();
     }
     builder.build(id, block_cache, path.as_ref()).unwrap()
 }
@@ -305,11 +311,11 @@ pub fn check_compaction_ratio(storage: Arc<MiniLsm>) {
     let l0_sst_num = state.l0_sstables.len();
     for (_, files) in &state.levels {
         let size = match &compaction_options {
-            CompactionOptions::Leveled(_) => files
+            CompactionOptions::Leveled(_) | CompactionOptions::Tiered(_) => files
                 .iter()
                 .map(|x| state.sstables.get(x).as_ref().unwrap().table_size())
                 .sum::<u64>(),
-            CompactionOptions::Simple(_) | CompactionOptions::Tiered(_) => files.len() as u64,
+            CompactionOptions::Simple(_) => files.len() as u64,
             _ => unreachable!(),
         };
         level_size.push(size);
@@ -460,3 +466,62 @@ pub fn construct_merge_iterator_over_storage(
     }
     MergeIterator::create(iters)
 }
+
+const AES_GCM_NONCE_SIZE: usize = 12;
+
+/// Encrypts with AES-256-GCM under a random nonce, stored before the ciphertext. The block index
+/// is authenticated along with it.
+#[derive(Debug)]
+pub struct AesGcmProvider {
+    key_id: u32,
+    key: [u8; 32],
+}
+
+impl AesGcmProvider {
+    pub fn new(key_id: u32, key: [u8; 32]) -> Arc<Self> {
+        Arc::new(Self { key_id, key })
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.key))
+    }
+}
+
+impl EncryptionProvider for AesGcmProvider {
+    fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    fn encrypt(&self, block_idx: u64, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce: [u8; AES_GCM_NONCE_SIZE] = rand::random();
+        let aad = block_idx.to_be_bytes();
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|e| anyhow!("failed to encrypt block {}: {}", block_idx, e))?;
+        Ok([&nonce[..], &ciphertext].concat())
+    }
+
+    fn decrypt(&self, block_idx: u64, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if ciphertext.len() < AES_GCM_NONCE_SIZE {
+            bail!("ciphertext of block {} is too short", block_idx);
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(AES_GCM_NONCE_SIZE);
+        let aad = block_idx.to_be_bytes();
+        self.cipher()
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|e| anyhow!("failed to decrypt block {}: {}", block_idx, e))
+    }
+}
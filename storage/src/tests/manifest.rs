@@ -0,0 +1,797 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::BufMut;
+use tempfile::tempdir;
+
+use super::harness::construct_merge_iterator_over_storage;
+use crate::{
+    compact::{
+        CompactionOptions, CompactionTask, LeveledCompactionOptions, LeveledCompactionTask,
+        SimpleLeveledCompactionOptions, SimpleLeveledCompactionTask, TieredCompactionOptions,
+        TieredCompactionTask,
+    },
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, ManifestReplay, MiniLsm},
+    manifest::{
+        Manifest, ManifestFormat, ManifestRecord, ManifestSnapshot, ManifestSyncMode,
+        PersistedOptions, SstMeta,
+    },
+};
+
+fn sst_meta(sst_id: usize) -> SstMeta {
+    SstMeta {
+        sst_id,
+        first_key: format!("key_{}", sst_id).into_bytes(),
+        first_ts: sst_id as u64,
+        last_key: vec![],
+        last_ts: u64::MAX,
+        size: 4096,
+        max_ts: 1 << 40,
+        encryption_key_id: sst_id.is_multiple_of(2).then_some(7),
+    }
+}
+
+fn records() -> Vec<ManifestRecord> {
+    vec![
+        ManifestRecord::NewMemtable(0),
+        ManifestRecord::Flush(0),
+        ManifestRecord::NewMemtable(300),
+        ManifestRecord::Compaction(
+            CompactionTask::Leveled(LeveledCompactionTask {
+                upper_level: None,
+                upper_level_sst_ids: vec![1, 2],
+                lower_level: 3,
+                lower_level_sst_ids: vec![],
+                is_lower_level_bottom_level: true,
+            }),
+            vec![4, 5],
+        ),
+        ManifestRecord::Compaction(
+            CompactionTask::Simple(SimpleLeveledCompactionTask {
+                upper_level: Some(0),
+                upper_level_sst_ids: vec![6],
+                lower_level: 1,
+                lower_level_sst_ids: vec![7, 1 << 40],
+                is_lower_level_bottom_level: false,
+            }),
+            vec![],
+        ),
+        ManifestRecord::Compaction(
+            CompactionTask::Tiered(TieredCompactionTask {
+                tiers: vec![(8, vec![8, 9]), (10, vec![])],
+                bottom_tier_included: true,
+            }),
+            vec![11],
+        ),
+        ManifestRecord::Compaction(
+            CompactionTask::ForceFullCompaction {
+                l0_sstables: vec![12],
+                l1_sstables: vec![13, 14],
+            },
+            vec![15],
+        ),
+        ManifestRecord::Ingest(2, vec![16, 17]),
+        ManifestRecord::CompactionIntent(
+            CompactionTask::ForceFullCompaction {
+                l0_sstables: vec![16],
+                l1_sstables: vec![],
+            },
+            vec![24, 25, 26],
+        ),
+        ManifestRecord::FlushMerged(vec![18, 19], 20),
+        ManifestRecord::Snapshot(ManifestSnapshot {
+            memtables: vec![21],
+            flushed: vec![18, 19],
+            l0_sstables: vec![20],
+            levels: vec![(1, vec![4, 5]), (2, vec![])],
+            max_id: 21,
+            pending_delete: vec![6, 7],
+            options: Some(PersistedOptions {
+                compaction_options: CompactionOptions::Leveled(LeveledCompactionOptions {
+                    level_size_multiplier: 10,
+                    level0_file_num_compaction_trigger: 2,
+                    max_levels: 4,
+                    base_level_size_mb: 128,
+                }),
+                block_size: 4096,
+                target_sst_size: 2 << 20,
+                enable_wal: true,
+                serializable: false,
+            }),
+            sst_metas: vec![sst_meta(20), sst_meta(4)],
+            watermark: Some(1 << 40),
+            reserved: vec![22, 23],
+        }),
+        ManifestRecord::Watermark(0),
+        ManifestRecord::DeleteSsts(vec![6, 7]),
+        ManifestRecord::SstMetas(vec![sst_meta(11), sst_meta(1 << 40)]),
+        ManifestRecord::Watermark(u64::MAX),
+        ManifestRecord::Options(PersistedOptions {
+            compaction_options: CompactionOptions::Tiered(TieredCompactionOptions {
+                num_tiers: 3,
+                max_size_amplification_percent: 200,
+                size_ratio: 1,
+                min_merge_width: 2,
+                max_merge_width: None,
+            }),
+            block_size: 1 << 16,
+            target_sst_size: 1 << 20,
+            enable_wal: false,
+            serializable: true,
+        }),
+        ManifestRecord::Options(PersistedOptions {
+            compaction_options: CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                size_ratio_percent: 200,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+            }),
+            block_size: 4096,
+            target_sst_size: 2 << 20,
+            enable_wal: true,
+            serializable: false,
+        }),
+    ]
+}
+
+#[test]
+fn test_manifest_formats_round_trip() {
+    for format in [ManifestFormat::Json, ManifestFormat::Binary] {
+        let dir = tempdir().unwrap();
+        let manifest = Manifest::create(&dir, format).unwrap();
+        for record in records() {
+            manifest.add_record_when_init(record).unwrap();
+        }
+        drop(manifest);
+
+        let (manifest, recovered) = Manifest::recover(&dir).unwrap();
+        assert_eq!(manifest.format(), format);
+        assert_eq!(format!("{:?}", recovered), format!("{:?}", records()));
+    }
+}
+
+/// Appends a record framed like those of a newer version, its checksum chaining that of the last
+/// record in the manifest.
+fn append_future_record(path: &Path, record_type: u8, version: u8, payload: &[u8]) {
+    let manifest = std::fs::read(path).unwrap();
+    let chain = u32::from_be_bytes(manifest[manifest.len() - 4..].try_into().unwrap());
+    let mut encoded = vec![record_type, version];
+    encoded.extend_from_slice(payload);
+    let mut hasher = crc32fast::Hasher::new_with_initial(chain);
+    hasher.update(&encoded);
+    let mut buf = Vec::new();
+    buf.put_u32(encoded.len() as u32);
+    buf.extend_from_slice(&encoded);
+    buf.put_u32(hasher.finalize());
+    let mut file = OpenOptions::new().append(true).open(path).unwrap();
+    file.write_all(&buf).unwrap();
+}
+
+#[test]
+fn test_manifest_unknown_records() {
+    for format in [ManifestFormat::Json, ManifestFormat::Binary] {
+        let dir = tempdir().unwrap();
+        let manifest = Manifest::create(&dir, format).unwrap();
+        manifest
+            .add_record_when_init(ManifestRecord::NewMemtable(0))
+            .unwrap();
+        drop(manifest);
+        let path = Manifest::current_path(&dir).unwrap();
+
+        // an optional record of an unknown type, and one of a known type but a newer version
+        append_future_record(&path, 0x80 | 0x40, 1, b"future");
+        append_future_record(&path, 0x80 | 7, 2, b"future");
+        let (manifest, recovered) = Manifest::recover(&dir).unwrap();
+        manifest
+            .add_record_when_init(ManifestRecord::NewMemtable(1))
+            .unwrap();
+        drop(manifest);
+        let (_, recovered_again) = Manifest::recover(&dir).unwrap();
+        assert_eq!(format!("{:?}", recovered), "[NewMemtable(0)]");
+        assert_eq!(
+            format!("{:?}", recovered_again),
+            "[NewMemtable(0), NewMemtable(1)]"
+        );
+
+        // the same, but required
+        let data = std::fs::read(&path).unwrap();
+        for (record_type, version) in [(0x40, 1), (1, 2)] {
+            std::fs::write(&path, &data).unwrap();
+            append_future_record(&path, record_type, version, b"future");
+            let Err(err) = Manifest::recover(&dir) else {
+                panic!("recovered a required record of an unknown type");
+            };
+            assert!(
+                format!("{:#}", err).contains(&format!(
+                    "record of type {} version {} is required",
+                    record_type, version
+                )),
+                "{:#}",
+                err
+            );
+        }
+
+        std::fs::write(&path, [0xff]).unwrap();
+        let Err(err) = Manifest::recover(&dir) else {
+            panic!("recovered a manifest in an unknown format");
+        };
+        assert!(
+            format!("{:#}", err).contains("unknown manifest format"),
+            "{:#}",
+            err
+        );
+    }
+}
+
+fn write_and_flush(storage: &Arc<LsmStorageInner>, range: std::ops::Range<usize>) {
+    for idx in range {
+        storage
+            .put(format!("key_{:05}", idx).as_bytes(), b"value")
+            .unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    storage.force_flush_next_imm_memtable().unwrap();
+}
+
+fn check_keys(storage: &Arc<LsmStorageInner>, num_keys: usize) {
+    for idx in 0..num_keys {
+        assert_eq!(
+            storage
+                .get(format!("key_{:05}", idx).as_bytes())
+                .unwrap()
+                .as_deref(),
+            Some(&b"value"[..])
+        );
+    }
+}
+
+#[test]
+fn test_json_manifest_recovers() {
+    let dir = tempdir().unwrap();
+    let json_options = LsmStorageOptions {
+        manifest_format: ManifestFormat::Json,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, json_options).unwrap());
+    write_and_flush(&storage, 0..10);
+    drop(storage);
+    let manifest_path = Manifest::current_path(&dir).unwrap();
+    assert_eq!(std::fs::read(&manifest_path).unwrap()[0], 5);
+
+    // an existing manifest keeps its format
+    let options = LsmStorageOptions::default_for_week1_test();
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    check_keys(&storage, 10);
+    write_and_flush(&storage, 10..20);
+    drop(storage);
+    assert_eq!(std::fs::read(&manifest_path).unwrap()[0], 5);
+
+    // one written before `CURRENT` and the types of records existed, without a format byte or
+    // with that of JSON, which are appended to the same way
+    for (header, num_keys) in [(&[][..], 30), (&[1][..], 40)] {
+        let (_, records) = Manifest::recover(&dir).unwrap();
+        let mut manifest = header.to_vec();
+        for record in records {
+            let encoded = serde_json::to_vec(&record).unwrap();
+            manifest.put_u32(encoded.len() as u32);
+            manifest.extend_from_slice(&encoded);
+            manifest.put_u32(crc32fast::hash(&encoded));
+        }
+        std::fs::remove_file(Manifest::current_path(&dir).unwrap()).unwrap();
+        std::fs::remove_file(dir.path().join("CURRENT")).unwrap();
+        std::fs::write(Manifest::path_of(&dir, 0), &manifest).unwrap();
+        for _ in 0..2 {
+            let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+            check_keys(&storage, num_keys - 10);
+            drop(storage);
+        }
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        write_and_flush(&storage, num_keys - 10..num_keys);
+        drop(storage);
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        check_keys(&storage, num_keys);
+        assert_eq!(
+            Manifest::current_path(&dir).unwrap(),
+            Manifest::path_of(&dir, 0)
+        );
+        assert_eq!(
+            std::fs::read(Manifest::path_of(&dir, 0)).unwrap()[..1],
+            manifest[..1]
+        );
+    }
+}
+
+#[test]
+fn test_manifest_dangling_current() {
+    let dir = tempdir().unwrap();
+    let manifest = Manifest::create(&dir, ManifestFormat::Binary).unwrap();
+    for record in records() {
+        manifest.add_record_when_init(record).unwrap();
+    }
+    drop(manifest);
+
+    // a newer manifest cut short in its first record, and `CURRENT` naming one that's gone
+    std::fs::write(Manifest::path_of(&dir, 3), [2, 0, 0]).unwrap();
+    std::fs::write(dir.path().join("CURRENT"), "MANIFEST-000002\n").unwrap();
+    let (manifest, recovered) = Manifest::recover(&dir).unwrap();
+    assert_eq!(format!("{:?}", recovered), format!("{:?}", records()));
+    assert_eq!(
+        Manifest::current_path(&dir).unwrap(),
+        Manifest::path_of(&dir, 1)
+    );
+    assert!(!Manifest::path_of(&dir, 3).exists());
+    manifest
+        .add_record_when_init(ManifestRecord::NewMemtable(1))
+        .unwrap();
+    drop(manifest);
+    let (_, recovered) = Manifest::recover(&dir).unwrap();
+    assert_eq!(recovered.len(), records().len() + 1);
+}
+
+#[test]
+fn test_manifest_create_crash() {
+    // the manifest is created, and the crash comes before `CURRENT` names it
+    let dir = tempdir().unwrap();
+    drop(Manifest::create(&dir, ManifestFormat::Binary).unwrap());
+    std::fs::remove_file(dir.path().join("CURRENT")).unwrap();
+    assert!(Manifest::exists(&dir).unwrap());
+    let (manifest, recovered) = Manifest::recover(&dir).unwrap();
+    assert!(recovered.is_empty());
+    assert_eq!(manifest.format(), ManifestFormat::Binary);
+    assert_eq!(
+        Manifest::current_path(&dir).unwrap(),
+        Manifest::path_of(&dir, 1)
+    );
+}
+
+#[test]
+fn test_manifest_format_size_and_replay() {
+    let mut results = Vec::new();
+    for format in [ManifestFormat::Json, ManifestFormat::Binary] {
+        let dir = tempdir().unwrap();
+        let manifest = Manifest::create(&dir, format).unwrap();
+        for idx in 0..2000 {
+            let task = CompactionTask::Simple(SimpleLeveledCompactionTask {
+                upper_level: Some(1),
+                upper_level_sst_ids: (idx * 100..idx * 100 + 10).collect(),
+                lower_level: 2,
+                lower_level_sst_ids: (idx * 100 + 10..idx * 100 + 50).collect(),
+                is_lower_level_bottom_level: true,
+            });
+            let output = (idx * 100 + 50..idx * 100 + 90).collect();
+            manifest
+                .add_record_when_init(ManifestRecord::NewMemtable(idx))
+                .unwrap();
+            manifest
+                .add_record_when_init(ManifestRecord::Compaction(task, output))
+                .unwrap();
+        }
+        drop(manifest);
+
+        let size = std::fs::metadata(Manifest::current_path(&dir).unwrap())
+            .unwrap()
+            .len();
+        let start = Instant::now();
+        let (_, records) = Manifest::recover(&dir).unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(records.len(), 4000);
+        println!(
+            "{:?} manifest: {} bytes, replayed in {:?}",
+            format, size, elapsed
+        );
+        results.push(size);
+    }
+    assert!(results[1] * 2 < results[0], "{:?}", results);
+}
+
+#[test]
+fn test_manifest_batched_sync() {
+    let dir = tempdir().unwrap();
+    let manifest = Manifest::create(&dir, ManifestFormat::Binary)
+        .unwrap()
+        .with_sync_mode(ManifestSyncMode::Batched);
+    manifest
+        .add_record_when_init(ManifestRecord::NewMemtable(0))
+        .unwrap();
+    // dropped without writing the buffered record, like a crash
+    drop(manifest);
+    let (manifest, recovered) = Manifest::recover(&dir).unwrap();
+    assert!(recovered.is_empty());
+
+    let manifest = manifest.with_sync_mode(ManifestSyncMode::Batched);
+    manifest
+        .add_record_when_init(ManifestRecord::NewMemtable(0))
+        .unwrap();
+    manifest.sync().unwrap();
+    manifest
+        .add_record_when_init(ManifestRecord::NewMemtable(1))
+        .unwrap();
+    // written along with the record before it
+    manifest
+        .add_record_when_init(ManifestRecord::Flush(0))
+        .unwrap();
+    manifest
+        .add_record_when_init(ManifestRecord::DeleteSsts(vec![2]))
+        .unwrap();
+    drop(manifest);
+    let (_, recovered) = Manifest::recover(&dir).unwrap();
+    assert_eq!(
+        format!("{:?}", recovered),
+        "[NewMemtable(0), NewMemtable(1), Flush(0)]"
+    );
+}
+
+#[test]
+fn test_manifest_hole() {
+    for format in [ManifestFormat::Json, ManifestFormat::Binary] {
+        let dir = tempdir().unwrap();
+        let manifest = Manifest::create(&dir, format).unwrap();
+        for record in records() {
+            manifest.add_record_when_init(record).unwrap();
+        }
+        drop(manifest);
+        let path = Manifest::current_path(&dir).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let (manifest, recovered) = Manifest::recover_with_offsets(&dir).unwrap();
+        assert_eq!(manifest.validated_len(), data.len() as u64);
+        drop(manifest);
+        let offsets = recovered
+            .iter()
+            .map(|(offset, _)| *offset)
+            .collect::<Vec<_>>();
+
+        // a record zeroed by the filesystem, or whose length is, which reads as if the records
+        // after it were torn
+        let hole = offsets[3]..offsets[4];
+        let mut zeroed = data.clone();
+        zeroed[hole.clone()].fill(0);
+        let mut cut_short = data.clone();
+        cut_short[hole.start..hole.start + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        for (corrupted, expected) in [(zeroed, "checksum"), (cut_short, "hole")] {
+            std::fs::write(&path, &corrupted).unwrap();
+            let Err(err) = Manifest::recover(&dir) else {
+                panic!("recovered a manifest with a hole");
+            };
+            assert!(format!("{:#}", err).contains(expected), "{:#}", err);
+            // nothing is appended after the hole
+            assert_eq!(std::fs::read(&path).unwrap(), corrupted);
+        }
+
+        // the last record torn by a crash is dropped, and the records from then on follow the
+        // one before it
+        let last = *offsets.last().unwrap();
+        std::fs::write(&path, &data[..data.len() - 2]).unwrap();
+        let (manifest, recovered) = Manifest::recover(&dir).unwrap();
+        assert_eq!(recovered.len(), records().len() - 1);
+        assert_eq!(manifest.validated_len(), last as u64);
+        manifest
+            .add_record_when_init(ManifestRecord::NewMemtable(1))
+            .unwrap();
+        drop(manifest);
+        let (_, recovered) = Manifest::recover(&dir).unwrap();
+        assert_eq!(recovered.len(), records().len());
+        assert!(matches!(
+            recovered.last(),
+            Some(ManifestRecord::NewMemtable(1))
+        ));
+    }
+
+    // a manifest written before checksums were chained is appended to the same way
+    let dir = tempdir().unwrap();
+    let manifest = Manifest::create(&dir, ManifestFormat::Binary).unwrap();
+    for record in records() {
+        manifest.add_record_when_init(record).unwrap();
+    }
+    drop(manifest);
+    let path = Manifest::current_path(&dir).unwrap();
+    let mut data = std::fs::read(&path).unwrap();
+    data[0] = 4;
+    let mut start = 1;
+    while start < data.len() {
+        let record_len = u32::from_be_bytes(data[start..start + 4].try_into().unwrap()) as usize;
+        let end = start + 4 + record_len;
+        let checksum = crc32fast::hash(&data[start + 4..end]);
+        data[end..end + 4].copy_from_slice(&checksum.to_be_bytes());
+        start = end + 4;
+    }
+    std::fs::write(&path, &data).unwrap();
+    let (manifest, recovered) = Manifest::recover(&dir).unwrap();
+    assert_eq!(format!("{:?}", recovered), format!("{:?}", records()));
+    manifest
+        .add_record_when_init(ManifestRecord::NewMemtable(1))
+        .unwrap();
+    drop(manifest);
+    let (_, recovered) = Manifest::recover(&dir).unwrap();
+    assert_eq!(recovered.len(), records().len() + 1);
+    assert_eq!(std::fs::read(&path).unwrap()[0], 4);
+}
+
+#[test]
+fn test_manifest_streaming_recovery() {
+    let dir = tempdir().unwrap();
+    let manifest = Manifest::create(&dir, ManifestFormat::Binary).unwrap();
+    let num_records = 100_000;
+    for chunk in (0..num_records / 2).collect::<Vec<_>>().chunks(1000) {
+        let records = chunk
+            .iter()
+            .flat_map(|id| [ManifestRecord::NewMemtable(*id), ManifestRecord::Flush(*id)]);
+        manifest.add_records_when_init(records.collect()).unwrap();
+    }
+    drop(manifest);
+    let path = Manifest::current_path(&dir).unwrap();
+    let file_len = std::fs::metadata(&path).unwrap().len();
+
+    // the records are replayed one at a time as they're read
+    let mut records = Manifest::recover_iter(&dir).unwrap();
+    let mut count = 0;
+    let mut last_offset = 0;
+    let replay = ManifestReplay::try_replay(
+        &CompactionOptions::NoCompaction,
+        records.by_ref().map(|record| {
+            let (offset, record) = record?;
+            assert!(offset > last_offset);
+            last_offset = offset;
+            count += 1;
+            Ok(record)
+        }),
+    )
+    .unwrap();
+    assert_eq!(count, num_records);
+    assert!(replay.memtables.is_empty());
+    assert_eq!(replay.state.l0_sstables.len(), num_records / 2);
+    assert_eq!(replay.state.l0_sstables[0], num_records / 2 - 1);
+    assert_eq!(replay.max_id, num_records / 2 - 1);
+    let manifest = records.finish().unwrap();
+    assert_eq!(manifest.validated_len(), file_len);
+
+    // with the last record torn, and left unread by the caller
+    manifest
+        .add_record_when_init(ManifestRecord::NewMemtable(num_records))
+        .unwrap();
+    drop(manifest);
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(std::fs::metadata(&path).unwrap().len() - 1)
+        .unwrap();
+    let mut records = Manifest::recover_iter(&dir).unwrap();
+    assert!(matches!(
+        records.next(),
+        Some(Ok((1, ManifestRecord::NewMemtable(0))))
+    ));
+    let manifest = records.finish().unwrap();
+    assert_eq!(manifest.validated_len(), file_len);
+    manifest
+        .add_record_when_init(ManifestRecord::NewMemtable(num_records))
+        .unwrap();
+    drop(manifest);
+    let (_, recovered) = Manifest::recover(&dir).unwrap();
+    assert_eq!(recovered.len(), num_records + 1);
+    assert!(matches!(
+        recovered.last(),
+        Some(ManifestRecord::NewMemtable(id)) if *id == num_records
+    ));
+}
+
+#[test]
+fn test_manifest_replay() {
+    let dir = tempdir().unwrap();
+    let options = simple_leveled_options(3);
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    write_and_flush(&storage, 0..10);
+    write_and_flush(&storage, 10..20);
+    storage.trigger_compaction().unwrap();
+    write_and_flush(&storage, 20..30);
+    let state = storage.state.read().clone();
+    drop(storage);
+
+    let (_, records) = Manifest::recover_with_offsets(&dir).unwrap();
+    // the header, then each record after the one before
+    assert_eq!(records[0].0, 1);
+    assert!(records.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    let replay = ManifestReplay::replay(
+        &options.compaction_options,
+        records.into_iter().map(|(_, record)| record),
+    );
+    assert_eq!(replay.state.l0_sstables, state.l0_sstables);
+    assert_eq!(replay.state.levels, state.levels);
+    assert_eq!(replay.memtables.iter().last(), Some(&state.memtable.id()));
+    assert_eq!(replay.options, Some(PersistedOptions::new(&options)));
+    let mut sst_ids = replay.sst_ids().collect::<Vec<_>>();
+    sst_ids.sort();
+    let mut expected = state.sstables.keys().copied().collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(sst_ids, expected);
+}
+
+#[test]
+fn test_recovered_watermark() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        watermark_record_interval: Some(1000),
+        ..LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction)
+    };
+    let versions_of_a = |storage: &MiniLsm| {
+        let mut iter = construct_merge_iterator_over_storage(&storage.inner.state.read());
+        let mut versions = Vec::new();
+        while iter.is_valid() && iter.key().key_ref() == b"a" {
+            versions.push(iter.key().ts());
+            iter.next().unwrap();
+        }
+        versions
+    };
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    let snapshot = storage.new_txn().unwrap();
+    storage.put(b"a", b"2").unwrap();
+    storage.put(b"a", b"3").unwrap();
+    // recorded on close, at the snapshot still alive
+    storage.close().unwrap();
+    drop(snapshot);
+    drop(storage);
+
+    // the snapshot is gone, but compaction keeps the versions visible at the recorded watermark
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(storage.inner.mvcc().watermark(), 1);
+    storage.force_full_compaction().unwrap();
+    assert_eq!(versions_of_a(&storage), vec![3, 2, 1]);
+
+    // once it's advanced by the interval, compaction records it before dropping the versions
+    for idx in 0..1000 {
+        storage.put(format!("b{}", idx).as_bytes(), b"1").unwrap();
+    }
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    assert_eq!(versions_of_a(&storage), vec![3]);
+    let watermark = storage.inner.mvcc().latest_commit_ts();
+    assert_eq!(storage.inner.mvcc().watermark(), watermark);
+    drop(storage);
+    let (_, records) = Manifest::recover(&dir).unwrap();
+    let recorded = records.iter().rev().find_map(|record| match record {
+        ManifestRecord::Watermark(ts) => Some(*ts),
+        _ => None,
+    });
+    assert_eq!(recorded, Some(watermark));
+}
+
+fn simple_leveled_options(max_levels: usize) -> LsmStorageOptions {
+    LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels,
+        },
+    ))
+}
+
+fn recorded_options(dir: &tempfile::TempDir) -> Vec<PersistedOptions> {
+    let (_, records) = Manifest::recover(dir).unwrap();
+    records
+        .into_iter()
+        .filter_map(|record| match record {
+            ManifestRecord::Options(options) => Some(options),
+            ManifestRecord::Snapshot(snapshot) => snapshot.options,
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_reopen_with_incompatible_options() {
+    let dir = tempdir().unwrap();
+    let options = simple_leveled_options(3);
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    write_and_flush(&storage, 0..10);
+    drop(storage);
+    assert_eq!(
+        recorded_options(&dir),
+        vec![PersistedOptions::new(&options)]
+    );
+
+    let tiered = LsmStorageOptions::default_for_week2_test(CompactionOptions::Tiered(
+        TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: None,
+        },
+    ));
+    let no_compaction = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let enable_wal = LsmStorageOptions {
+        enable_wal: true,
+        ..options.clone()
+    };
+    let serializable = LsmStorageOptions {
+        serializable: true,
+        ..options.clone()
+    };
+    for (incompatible, error) in [
+        (tiered, "compaction options"),
+        (no_compaction, "compaction options"),
+        (simple_leveled_options(4), "compaction options"),
+        (enable_wal, "enable_wal"),
+        (serializable, "serializable"),
+    ] {
+        let Err(err) = LsmStorageInner::open(&dir, incompatible) else {
+            panic!("opened the DB with incompatible options");
+        };
+        assert!(format!("{:#}", err).contains(error), "{:#}", err);
+    }
+
+    // none of them touched the DB
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    check_keys(&storage, 10);
+    drop(storage);
+    assert_eq!(
+        recorded_options(&dir),
+        vec![PersistedOptions::new(&options)]
+    );
+}
+
+#[test]
+fn test_reopen_with_changed_options() {
+    let dir = tempdir().unwrap();
+    let options = simple_leveled_options(3);
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    write_and_flush(&storage, 0..10);
+    drop(storage);
+
+    // not recorded, so nothing changes
+    let larger_cache = LsmStorageOptions {
+        block_cache_capacity: options.block_cache_capacity * 2,
+        ..options.clone()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, larger_cache).unwrap());
+    check_keys(&storage, 10);
+    drop(storage);
+    assert_eq!(recorded_options(&dir).len(), 1);
+
+    // recorded once, and kept by a roll over
+    let changed = LsmStorageOptions {
+        block_size: options.block_size * 2,
+        target_sst_size: options.target_sst_size * 2,
+        compaction_options: CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+            size_ratio_percent: 100,
+            level0_file_num_compaction_trigger: 4,
+            max_levels: 3,
+        }),
+        ..options.clone()
+    };
+    for _ in 0..2 {
+        let storage = Arc::new(LsmStorageInner::open(&dir, changed.clone()).unwrap());
+        write_and_flush(&storage, 10..20);
+        check_keys(&storage, 20);
+        drop(storage);
+        let recorded = recorded_options(&dir);
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[1], PersistedOptions::new(&changed));
+    }
+    let rolled_over = LsmStorageOptions {
+        max_manifest_size: 1,
+        ..changed.clone()
+    };
+    drop(LsmStorageInner::open(&dir, rolled_over).unwrap());
+    assert_eq!(
+        recorded_options(&dir),
+        vec![PersistedOptions::new(&changed)]
+    );
+    assert!(LsmStorageInner::open(&dir, options).is_ok());
+}
@@ -0,0 +1,451 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ops::Bound;
+use std::time::Instant;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    iterators::StorageIterator,
+    key::{KeySlice, TS_RANGE_BEGIN, TS_RANGE_END},
+    mem_table::{MAX_KEY_VALUE_LEN, MEMTABLE_ENTRY_OVERHEAD, MemTable},
+};
+
+/// The versions the memtable holds, as (key, ts, value).
+fn versions(memtable: &MemTable) -> Vec<(Vec<u8>, u64, Bytes)> {
+    let mut iter = memtable.scan(Bound::Unbounded, Bound::Unbounded);
+    let mut versions = Vec::new();
+    while iter.is_valid() {
+        versions.push((
+            iter.key().key_ref().to_vec(),
+            iter.key().ts(),
+            Bytes::copy_from_slice(iter.value()),
+        ));
+        iter.next().unwrap();
+    }
+    versions
+}
+
+fn put_versions(memtable: &MemTable) {
+    // out of order, and interleaved with the versions of the keys around
+    for ts in [2, 1, 3] {
+        for key in [&b"a"[..], b"b", b"c"] {
+            let value = format!("{}@{}", String::from_utf8_lossy(key), ts);
+            memtable
+                .put(
+                    KeySlice::for_testing_from_slice_with_ts(key, ts),
+                    value.as_bytes(),
+                )
+                .unwrap();
+        }
+    }
+}
+
+fn expected_versions() -> Vec<(Vec<u8>, u64, Bytes)> {
+    let mut expected = Vec::new();
+    for key in ["a", "b", "c"] {
+        for ts in [3, 2, 1] {
+            expected.push((
+                key.as_bytes().to_vec(),
+                ts,
+                Bytes::from(format!("{}@{}", key, ts)),
+            ));
+        }
+    }
+    expected
+}
+
+#[test]
+fn test_memtable_versions_in_key_asc_ts_desc_order() {
+    memtable_versions_in_key_asc_ts_desc_order(MemTable::create(0));
+}
+
+#[test]
+fn test_memtable_versions_in_key_asc_ts_desc_order_sharded() {
+    memtable_versions_in_key_asc_ts_desc_order(MemTable::create(0).with_shards(4));
+}
+
+fn memtable_versions_in_key_asc_ts_desc_order(memtable: MemTable) {
+    put_versions(&memtable);
+    assert_eq!(versions(&memtable), expected_versions());
+
+    for ts in 1..=3 {
+        assert_eq!(
+            memtable.get(KeySlice::for_testing_from_slice_with_ts(b"b", ts)),
+            Some(Bytes::from(format!("b@{}", ts)))
+        );
+    }
+    assert_eq!(
+        memtable.get(KeySlice::for_testing_from_slice_with_ts(b"b", 4)),
+        None
+    );
+
+    // all the versions of one key, newest first
+    let mut iter = memtable.scan(
+        Bound::Included(KeySlice::for_testing_from_slice_with_ts(
+            b"b",
+            TS_RANGE_BEGIN,
+        )),
+        Bound::Included(KeySlice::for_testing_from_slice_with_ts(b"b", TS_RANGE_END)),
+    );
+    for ts in [3, 2, 1] {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().key_ref(), b"b");
+        assert_eq!(iter.key().ts(), ts);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    // the versions at or below ts 2, a bound on the ts as well as the key
+    let mut iter = memtable.scan(
+        Bound::Included(KeySlice::for_testing_from_slice_with_ts(b"b", 2)),
+        Bound::Excluded(KeySlice::for_testing_from_slice_with_ts(
+            b"c",
+            TS_RANGE_BEGIN,
+        )),
+    );
+    for ts in [2, 1] {
+        assert_eq!((iter.key().key_ref(), iter.key().ts()), (&b"b"[..], ts));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_memtable_versions_survive_recovery() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00001.wal");
+    let memtable = MemTable::create_with_wal(1, &path).unwrap();
+    put_versions(&memtable);
+    memtable.sync_wal().unwrap();
+    drop(memtable);
+
+    let memtable = MemTable::recover_from_wal(1, &path).unwrap();
+    assert_eq!(versions(&memtable), expected_versions());
+}
+
+/// Puts from many threads at once, returning the memtable once they're done.
+fn put_concurrently(memtable: MemTable) -> MemTable {
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for thread in 0..16 {
+            let memtable = &memtable;
+            scope.spawn(move || {
+                for idx in 0..2000 {
+                    let key = format!("key_{:02}_{:05}", thread, idx);
+                    memtable
+                        .put(
+                            KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), 1),
+                            b"value",
+                        )
+                        .unwrap();
+                }
+            });
+        }
+    });
+    println!(
+        "{} puts into {} shards in {:?}",
+        memtable.len(),
+        memtable.num_shards(),
+        start.elapsed()
+    );
+    memtable
+}
+
+#[test]
+fn test_sharded_memtable_concurrent_puts() {
+    let single = put_concurrently(MemTable::create(0));
+    let sharded = put_concurrently(MemTable::create(0).with_shards(16));
+    assert_eq!(sharded.len(), 16 * 2000);
+    assert_eq!(sharded.payload_size(), single.payload_size());
+    assert_eq!(sharded.approximate_size(), single.approximate_size());
+    // the shards are merged into one sorted scan
+    assert_eq!(versions(&sharded), versions(&single));
+    assert_eq!(
+        sharded.get(KeySlice::for_testing_from_slice_with_ts(b"key_07_01234", 1)),
+        Some(Bytes::from_static(b"value"))
+    );
+}
+
+#[test]
+fn test_memtable_put_batch() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00001.wal");
+    let memtable = MemTable::create_with_wal(1, &path).unwrap().with_shards(4);
+    let base_size = memtable.approximate_size();
+    let keys = (0..10)
+        .map(|idx| format!("key_{}", idx))
+        .collect::<Vec<_>>();
+    let batch = keys
+        .iter()
+        .map(|key| {
+            (
+                KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), 1),
+                &b"value"[..],
+            )
+        })
+        .collect::<Vec<_>>();
+    memtable.put_batch(&batch).unwrap();
+    let payload_size = 10 * (5 + 8 + 5);
+    assert_eq!(memtable.payload_size(), payload_size);
+    assert_eq!(
+        memtable.approximate_size(),
+        base_size + payload_size + 10 * MEMTABLE_ENTRY_OVERHEAD
+    );
+
+    // a key or value too large for the WAL fails the whole batch, before any of it is written
+    let large = vec![b'v'; MAX_KEY_VALUE_LEN + 1];
+    let key = KeySlice::for_testing_from_slice_with_ts(b"key_large", 2);
+    let other = KeySlice::for_testing_from_slice_with_ts(b"key_other", 2);
+    let large_key = KeySlice::for_testing_from_slice_with_ts(&large, 2);
+    for batch in [
+        vec![(other, &b"value"[..]), (key, &large[..])],
+        vec![(large_key, &b"value"[..]), (other, &b"value"[..])],
+    ] {
+        assert!(memtable.put_batch(&batch).is_err());
+        assert!(memtable.put_batch_without_wal(&batch).is_err());
+    }
+    assert_eq!(memtable.len(), 10);
+    assert_eq!(memtable.payload_size(), payload_size);
+    assert!(!memtable.has_unlogged_writes());
+    // the largest ones fit
+    let largest = &large[..MAX_KEY_VALUE_LEN];
+    memtable.put_batch(&[(key, largest)]).unwrap();
+    memtable.sync_wal().unwrap();
+    drop(memtable);
+
+    let memtable = MemTable::recover_from_wal(1, &path).unwrap();
+    assert_eq!(memtable.len(), 11);
+    assert_eq!(memtable.get(other), None);
+    assert_eq!(memtable.get(key).as_deref(), Some(largest));
+}
+
+#[test]
+fn test_memtable_key_filter() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00001.wal");
+    let memtable = MemTable::create_with_wal(1, &path)
+        .unwrap()
+        .with_key_filter(100);
+    let key_of = |idx: usize| format!("key_{:05}", idx);
+    for idx in (0..200).step_by(2) {
+        memtable
+            .put(
+                KeySlice::for_testing_from_slice_with_ts(key_of(idx).as_bytes(), 1),
+                b"value",
+            )
+            .unwrap();
+    }
+    let num_ruled_out = |memtable: &MemTable| {
+        (1..200)
+            .step_by(2)
+            .filter(|idx| !memtable.may_contain(key_of(*idx).as_bytes()))
+            .count()
+    };
+    // no false negatives, and about 1% false positives
+    assert!(
+        (0..200)
+            .step_by(2)
+            .all(|idx| memtable.may_contain(key_of(idx).as_bytes()))
+    );
+    assert!(num_ruled_out(&memtable) > 90);
+    memtable.sync_wal().unwrap();
+
+    // the filter of a recovered memtable is built from the keys in its WAL
+    let recovered = MemTable::recover_from_wal(1, &path).unwrap();
+    assert!(
+        (0..200)
+            .step_by(2)
+            .all(|idx| recovered.may_contain(key_of(idx).as_bytes()))
+    );
+    assert!(num_ruled_out(&recovered) > 90);
+
+    // without a filter, every key may be there
+    memtable.drop_key_filter();
+    assert_eq!(num_ruled_out(&memtable), 0);
+    assert_eq!(num_ruled_out(&MemTable::create(2)), 0);
+}
+
+#[test]
+fn test_memtable_entry_and_tombstone_counts() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00001.wal");
+    let memtable = MemTable::create_with_wal(1, &path).unwrap();
+    for ts in 1..=10 {
+        let key = KeySlice::for_testing_from_slice_with_ts(b"key", ts);
+        // every third version is a delete
+        let value: &[u8] = if ts % 3 == 0 { b"" } else { b"value" };
+        memtable.put(key, value).unwrap();
+    }
+    memtable
+        .put_batch(&[
+            (KeySlice::for_testing_from_slice_with_ts(b"a", 1), b""),
+            (KeySlice::for_testing_from_slice_with_ts(b"b", 1), b"value"),
+        ])
+        .unwrap();
+    assert_eq!(memtable.len(), 12);
+    assert_eq!(memtable.tombstone_count(), 4);
+    memtable.sync_wal().unwrap();
+
+    let recovered = MemTable::recover_from_wal(1, &path).unwrap();
+    assert_eq!(recovered.len(), 12);
+    assert_eq!(recovered.tombstone_count(), 4);
+    let empty = MemTable::create(2).with_shards(4);
+    assert_eq!((empty.len(), empty.tombstone_count()), (0, 0));
+}
+
+#[test]
+fn test_memtable_get() {
+    memtable_get(MemTable::create(0));
+}
+
+#[test]
+fn test_memtable_get_sharded() {
+    memtable_get(MemTable::create(0).with_shards(4));
+}
+
+fn memtable_get(memtable: MemTable) {
+    memtable.for_testing_put_slice(b"key1", b"value1").unwrap();
+    memtable.for_testing_put_slice(b"key2", b"value2").unwrap();
+    memtable.for_testing_put_slice(b"key3", b"value3").unwrap();
+    assert_eq!(
+        &memtable.for_testing_get_slice(b"key1").unwrap()[..],
+        b"value1"
+    );
+    assert_eq!(
+        &memtable.for_testing_get_slice(b"key2").unwrap()[..],
+        b"value2"
+    );
+    assert_eq!(
+        &memtable.for_testing_get_slice(b"key3").unwrap()[..],
+        b"value3"
+    );
+}
+
+#[test]
+fn test_memtable_overwrite() {
+    memtable_overwrite(MemTable::create(0));
+}
+
+#[test]
+fn test_memtable_overwrite_sharded() {
+    memtable_overwrite(MemTable::create(0).with_shards(4));
+}
+
+fn memtable_overwrite(memtable: MemTable) {
+    memtable.for_testing_put_slice(b"key1", b"value1").unwrap();
+    memtable.for_testing_put_slice(b"key2", b"value2").unwrap();
+    memtable.for_testing_put_slice(b"key3", b"value3").unwrap();
+    memtable.for_testing_put_slice(b"key1", b"value11").unwrap();
+    memtable.for_testing_put_slice(b"key2", b"value22").unwrap();
+    memtable.for_testing_put_slice(b"key3", b"value33").unwrap();
+    assert_eq!(
+        &memtable.for_testing_get_slice(b"key1").unwrap()[..],
+        b"value11"
+    );
+    assert_eq!(
+        &memtable.for_testing_get_slice(b"key2").unwrap()[..],
+        b"value22"
+    );
+    assert_eq!(
+        &memtable.for_testing_get_slice(b"key3").unwrap()[..],
+        b"value33"
+    );
+}
+
+#[test]
+fn test_memtable_iter() {
+    memtable_iter(MemTable::create(0));
+}
+
+#[test]
+fn test_memtable_iter_sharded() {
+    memtable_iter(MemTable::create(0).with_shards(4));
+}
+
+fn memtable_iter(memtable: MemTable) {
+    memtable.for_testing_put_slice(b"key1", b"value1").unwrap();
+    memtable.for_testing_put_slice(b"key2", b"value2").unwrap();
+    memtable.for_testing_put_slice(b"key3", b"value3").unwrap();
+
+    {
+        let mut iter = memtable.for_testing_scan_slice(Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(iter.key().for_testing_key_ref(), b"key1");
+        assert_eq!(iter.value(), b"value1");
+        assert!(iter.is_valid());
+        iter.next().unwrap();
+        assert_eq!(iter.key().for_testing_key_ref(), b"key2");
+        assert_eq!(iter.value(), b"value2");
+        assert!(iter.is_valid());
+        iter.next().unwrap();
+        assert_eq!(iter.key().for_testing_key_ref(), b"key3");
+        assert_eq!(iter.value(), b"value3");
+        assert!(iter.is_valid());
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+    }
+
+    {
+        let mut iter =
+            memtable.for_testing_scan_slice(Bound::Included(b"key1"), Bound::Included(b"key2"));
+        assert_eq!(iter.key().for_testing_key_ref(), b"key1");
+        assert_eq!(iter.value(), b"value1");
+        assert!(iter.is_valid());
+        iter.next().unwrap();
+        assert_eq!(iter.key().for_testing_key_ref(), b"key2");
+        assert_eq!(iter.value(), b"value2");
+        assert!(iter.is_valid());
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+    }
+
+    {
+        let mut iter =
+            memtable.for_testing_scan_slice(Bound::Excluded(b"key1"), Bound::Excluded(b"key3"));
+        assert_eq!(iter.key().for_testing_key_ref(), b"key2");
+        assert_eq!(iter.value(), b"value2");
+        assert!(iter.is_valid());
+        iter.next().unwrap();
+        assert!(!iter.is_valid());
+    }
+}
+
+#[test]
+fn test_empty_memtable_iter() {
+    empty_memtable_iter(MemTable::create(0));
+}
+
+#[test]
+fn test_empty_memtable_iter_sharded() {
+    empty_memtable_iter(MemTable::create(0).with_shards(4));
+}
+
+fn empty_memtable_iter(memtable: MemTable) {
+    {
+        let iter =
+            memtable.for_testing_scan_slice(Bound::Excluded(b"key1"), Bound::Excluded(b"key3"));
+        assert!(!iter.is_valid());
+    }
+    {
+        let iter =
+            memtable.for_testing_scan_slice(Bound::Included(b"key1"), Bound::Included(b"key2"));
+        assert!(!iter.is_valid());
+    }
+    {
+        let iter = memtable.for_testing_scan_slice(Bound::Unbounded, Bound::Unbounded);
+        assert!(!iter.is_valid());
+    }
+}
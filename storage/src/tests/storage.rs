@@ -0,0 +1,3564 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    ops::{Bound, Range, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use super::harness::{AesGcmProvider, check_lsm_iter_result_by_key, sync};
+use crate::{
+    compact::{
+        COMPACTION_FAIL_AFTER, CompactionOptions, LeveledCompactionOptions,
+        SimpleLeveledCompactionOptions,
+    },
+    iterators::{StorageIterator, concat_iterator::SstConcatIterator},
+    key::KeySlice,
+    lsm_storage::{
+        DatabaseLocked, Entry, LsmStorageInner, LsmStorageOptions, MemtableStats, MiniLsm,
+        ReadOptions, WriteBatchRecord, WriteError, WriteOptions, WriteStall, key_within,
+        range_overlap,
+    },
+    manifest::{Manifest, ManifestRecord, ManifestSyncMode, ROLL_OVER_FAIL_AFTER, SstMeta},
+    mem_table::{MAX_KEY_VALUE_LEN, MEMTABLE_ENTRY_OVERHEAD, MemTable, NUM_PROBED},
+    mvcc::txn::TxnIterator,
+    table::{
+        ChecksumVerification, Footer, PrefixExtractor, SsTableBuilder, SsTableIterator,
+        iterator::NUM_CREATED,
+    },
+    value_log::{ValueLog, ValuePointer},
+    wal::{Wal, WalMetricsSnapshot, WalSyncPolicy},
+};
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx).into_bytes()
+}
+
+/// Leaves the storage as a crash would, without closing or dropping it, which also releases the
+/// lock on the directory.
+fn crash(storage: Arc<LsmStorageInner>) {
+    storage.dir_lock.unlock().unwrap();
+    std::mem::forget(storage);
+}
+
+fn open_with_bloom(
+    dir: &tempfile::TempDir,
+    bloom_false_positive_rate: Option<f64>,
+) -> Arc<LsmStorageInner> {
+    let options = LsmStorageOptions {
+        bloom_false_positive_rate,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(dir, options).unwrap());
+    // only even keys, so that odd ones are within the key range of the SST but absent
+    for idx in (0..1000).step_by(2) {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    sync(&storage);
+    storage
+}
+
+/// Number of blocks requested from the block cache, whether they were cached or not.
+fn block_reads(storage: &LsmStorageInner) -> u64 {
+    let stats = storage.block_cache.stats();
+    stats.hits + stats.misses
+}
+
+#[test]
+fn test_get_missing_key_skips_sst_by_bloom_filter() {
+    let dir = tempdir().unwrap();
+    let storage = open_with_bloom(&dir, Some(0.01));
+    let sst = {
+        let state = storage.state.read();
+        assert_eq!(state.l0_sstables.len(), 1);
+        state.sstables[&state.l0_sstables[0]].clone()
+    };
+    let missing = (1..1000)
+        .step_by(2)
+        .map(key_of)
+        .filter(|key| !sst.may_contain(key))
+        .collect::<Vec<_>>();
+    // about 1% of them are false positives
+    assert!(missing.len() > 450, "{} keys ruled out", missing.len());
+
+    let reads = block_reads(&storage);
+    for key in missing.iter() {
+        assert_eq!(storage.get(key).unwrap(), None);
+    }
+    assert_eq!(block_reads(&storage), reads);
+
+    // a present key still reads its block
+    assert_eq!(
+        storage.get(&key_of(500)).unwrap().as_deref(),
+        Some(&b"value"[..])
+    );
+    assert!(block_reads(&storage) > reads);
+}
+
+#[test]
+fn test_get_missing_key_skips_block_by_its_bloom_filter() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        bloom_per_block: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    for idx in (0..1000).step_by(2) {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    sync(&storage);
+    let sst = {
+        let state = storage.state.read();
+        state.sstables[&state.l0_sstables[0]].clone()
+    };
+    assert!(sst.bloom.is_none());
+    // within the key range of the SST
+    let missing = (1..998)
+        .step_by(2)
+        .map(key_of)
+        .filter(|key| !sst.block_may_contain(key).unwrap())
+        .collect::<Vec<_>>();
+    assert!(missing.len() > 450, "{} keys ruled out", missing.len());
+
+    // only the filter of the block is read
+    let reads = block_reads(&storage);
+    for key in missing.iter() {
+        assert_eq!(storage.get(key).unwrap(), None);
+    }
+    assert_eq!(block_reads(&storage), reads + missing.len() as u64);
+
+    let reads = block_reads(&storage);
+    for idx in (0..1000).step_by(2) {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+    }
+    assert!(block_reads(&storage) >= reads + 1000);
+}
+
+#[test]
+fn test_get_without_bloom_filter() {
+    let dir = tempdir().unwrap();
+    let storage = open_with_bloom(&dir, None);
+    let sst = {
+        let state = storage.state.read();
+        state.sstables[&state.l0_sstables[0]].clone()
+    };
+    assert!(sst.bloom.is_none());
+    assert!(sst.may_contain(&key_of(1)));
+
+    let reads = block_reads(&storage);
+    assert_eq!(storage.get(&key_of(1)).unwrap(), None);
+    assert!(block_reads(&storage) > reads);
+    assert_eq!(
+        storage.get(&key_of(2)).unwrap().as_deref(),
+        Some(&b"value"[..])
+    );
+    drop(storage);
+
+    // the SST opens again without its bloom section
+    let storage = open_with_bloom(&dir, None);
+    assert_eq!(
+        storage.get(&key_of(2)).unwrap().as_deref(),
+        Some(&b"value"[..])
+    );
+}
+
+#[test]
+fn test_range_overlap_bounds() {
+    use Bound::{Excluded, Included, Unbounded};
+    let overlap = |lower, upper| range_overlap(lower, upper, b"c", b"f");
+    assert!(overlap(Unbounded, Unbounded));
+    assert!(overlap(Included(b"a"), Included(b"c")));
+    assert!(!overlap(Included(b"a"), Excluded(b"c")));
+    assert!(!overlap(Unbounded, Excluded(b"c")));
+    assert!(!overlap(Included(b"a"), Included(b"b")));
+    assert!(overlap(Included(b"f"), Unbounded));
+    assert!(!overlap(Excluded(b"f"), Unbounded));
+    assert!(!overlap(Included(b"g"), Included(b"z")));
+    assert!(overlap(Excluded(b"e"), Excluded(b"f")));
+    assert!(overlap(Excluded(b"a"), Excluded(b"z")));
+    // a range inside the table
+    assert!(overlap(Included(b"d"), Included(b"e")));
+
+    assert!(key_within(b"c", b"c", b"f"));
+    assert!(key_within(b"f", b"c", b"f"));
+    assert!(key_within(b"d", b"c", b"f"));
+    assert!(!key_within(b"b", b"c", b"f"));
+    assert!(!key_within(b"g", b"c", b"f"));
+}
+
+fn sst_iterators_created(f: impl FnOnce()) -> usize {
+    let before = NUM_CREATED.with(|x| x.get());
+    f();
+    NUM_CREATED.with(|x| x.get()) - before
+}
+
+fn scan_keys(storage: &Arc<LsmStorageInner>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> usize {
+    let mut iter = storage.scan(lower, upper).unwrap();
+    let mut num_keys = 0;
+    while iter.is_valid() {
+        num_keys += 1;
+        iter.next().unwrap();
+    }
+    num_keys
+}
+
+#[test]
+fn test_narrow_read_skips_ssts_outside_the_range() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        block_size: 256,
+        target_sst_size: 32 << 10,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    // 10 L0 SSTs with disjoint key ranges, with a gap between each of them, with values large
+    // enough that the memtable of each fits in `target_sst_size` but the SSTs compacted from them
+    // don't
+    let value = [b'v'; 400];
+    for sst_idx in 0..10 {
+        for idx in 0..50 {
+            storage.put(&key_of(sst_idx * 100 + idx), &value).unwrap();
+        }
+        sync(&storage);
+    }
+    assert_eq!(storage.state.read().l0_sstables.len(), 10);
+
+    let created = sst_iterators_created(|| {
+        let num_keys = scan_keys(
+            &storage,
+            Bound::Included(&key_of(210)),
+            Bound::Included(&key_of(220)),
+        );
+        assert_eq!(num_keys, 11);
+    });
+    assert_eq!(created, 1);
+    // the bounds touch two tables, but exclude both
+    let created = sst_iterators_created(|| {
+        let num_keys = scan_keys(
+            &storage,
+            Bound::Excluded(&key_of(249)),
+            Bound::Excluded(&key_of(300)),
+        );
+        assert_eq!(num_keys, 0);
+    });
+    assert_eq!(created, 0);
+    let created = sst_iterators_created(|| {
+        assert_eq!(storage.get(b"key_00210_absent").unwrap(), None);
+        assert_eq!(storage.get(b"zzz").unwrap(), None);
+    });
+    assert!(created <= 1);
+
+    // the same after compacting everything into many non-overlapping SSTs in one level
+    storage.force_full_compaction().unwrap();
+    let num_ssts = {
+        let state = storage.state.read();
+        assert!(state.l0_sstables.is_empty());
+        state.levels[0].1.len()
+    };
+    assert!(num_ssts >= 5, "{} SSTs", num_ssts);
+    let created = sst_iterators_created(|| {
+        let num_keys = scan_keys(
+            &storage,
+            Bound::Included(&key_of(210)),
+            Bound::Included(&key_of(220)),
+        );
+        assert_eq!(num_keys, 11);
+    });
+    assert!(created <= 2, "{} iterators", created);
+    let created = sst_iterators_created(|| {
+        assert_eq!(storage.get(b"zzz").unwrap(), None);
+    });
+    assert_eq!(created, 0);
+}
+
+#[test]
+fn test_concat_iterator_opens_tables_lazily() {
+    let dir = tempdir().unwrap();
+    // 10 SSTs covering key_{sst_idx}00 to key_{sst_idx}09
+    let sstables = (0..10)
+        .map(|sst_idx| {
+            let mut builder = SsTableBuilder::new(128);
+            for idx in 0..10 {
+                let key = key_of(sst_idx * 100 + idx);
+                builder
+                    .add(KeySlice::for_testing_from_slice_no_ts(&key), b"value")
+                    .unwrap();
+            }
+            let path = dir.path().join(format!("{}.sst", sst_idx));
+            Arc::new(builder.build_for_test(path).unwrap())
+        })
+        .collect::<Vec<_>>();
+
+    let created = sst_iterators_created(|| {
+        let iter = SstConcatIterator::create_and_seek_to_first(sstables.clone()).unwrap();
+        assert_eq!(iter.key().key_ref(), key_of(0));
+    });
+    assert_eq!(created, 1);
+    let created = sst_iterators_created(|| {
+        let key = key_of(505);
+        let iter = SstConcatIterator::create_and_seek_to_key(
+            sstables.clone(),
+            KeySlice::for_testing_from_slice_no_ts(&key),
+        )
+        .unwrap();
+        assert_eq!(iter.key().key_ref(), key);
+    });
+    assert_eq!(created, 1);
+    // a key in the gap after a table moves on to the next one
+    let created = sst_iterators_created(|| {
+        let key = key_of(550);
+        let iter = SstConcatIterator::create_and_seek_to_key(
+            sstables.clone(),
+            KeySlice::for_testing_from_slice_no_ts(&key),
+        )
+        .unwrap();
+        assert_eq!(iter.key().key_ref(), key_of(600));
+    });
+    assert_eq!(created, 2);
+    // tables are opened one at a time while iterating
+    let key = key_of(805);
+    let mut iter = SstConcatIterator::create_and_seek_to_key(
+        sstables.clone(),
+        KeySlice::for_testing_from_slice_no_ts(&key),
+    )
+    .unwrap();
+    let created = sst_iterators_created(|| {
+        for _ in 805..810 {
+            iter.next().unwrap();
+        }
+    });
+    assert_eq!(created, 1);
+    assert_eq!(iter.key().key_ref(), key_of(900));
+    let created = sst_iterators_created(|| {
+        while iter.is_valid() {
+            iter.next().unwrap();
+        }
+    });
+    assert_eq!(created, 0);
+}
+
+#[test]
+fn test_point_range_scan_opens_one_table() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        block_size: 256,
+        target_sst_size: 2048,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    // small batches, so that puts never freeze the memtable on their own
+    for batch in 0..50 {
+        for idx in 0..10 {
+            storage.put(&key_of(batch * 10 + idx), b"value").unwrap();
+        }
+        sync(&storage);
+    }
+    storage.force_full_compaction().unwrap();
+    let sst = {
+        let state = storage.state.read();
+        let ssts = &state.levels[0].1;
+        assert!(ssts.len() >= 5, "{} SSTs", ssts.len());
+        state.sstables[&ssts[ssts.len() / 2]].clone()
+    };
+    let key = sst.first_key().key_ref().to_vec();
+    let created = sst_iterators_created(|| {
+        let num_keys = scan_keys(&storage, Bound::Included(&key), Bound::Included(&key));
+        assert_eq!(num_keys, 1);
+    });
+    assert_eq!(created, 1);
+}
+
+/// Flushes the memtable, unless a put just froze it, and the memtables frozen by puts.
+fn flush_all(storage: &LsmStorageInner) {
+    if !storage.state.read().memtable.is_empty() {
+        sync(storage);
+    }
+    while !storage.state.read().imm_memtables.is_empty() {
+        storage.force_flush_next_imm_memtable().unwrap();
+    }
+}
+
+#[test]
+fn test_compaction_splits_output_at_key_boundaries() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        block_size: 256,
+        target_sst_size: 2048,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    // 5 versions of each key, each kept alive by a snapshot taken right after it was written
+    let mut txns = Vec::new();
+    for round in 0..5 {
+        for batch in 0..4 {
+            for idx in 0..50 {
+                let value = format!("value_{}", round);
+                storage
+                    .put(&key_of(batch * 50 + idx), value.as_bytes())
+                    .unwrap();
+            }
+            flush_all(&storage);
+        }
+        txns.push(storage.new_txn().unwrap());
+    }
+    storage.force_full_compaction().unwrap();
+
+    let ssts = {
+        let state = storage.state.read();
+        assert!(state.l0_sstables.is_empty());
+        state.levels[0]
+            .1
+            .iter()
+            .map(|id| state.sstables[id].clone())
+            .collect::<Vec<_>>()
+    };
+    assert!(ssts.len() >= 5, "{} SSTs", ssts.len());
+    // the level lists the SSTs in key order, and no user key spans two of them
+    for pair in ssts.windows(2) {
+        assert!(pair[0].last_key().key_ref() < pair[1].first_key().key_ref());
+    }
+    let mut num_entries = 0;
+    for sst in ssts.iter() {
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+        while iter.is_valid() {
+            num_entries += 1;
+            iter.next().unwrap();
+        }
+    }
+    assert_eq!(num_entries, 200 * 5);
+
+    for (round, txn) in txns.iter().enumerate() {
+        let value = format!("value_{}", round);
+        for idx in [0, 99, 199] {
+            assert_eq!(
+                txn.get(&key_of(idx)).unwrap().as_deref(),
+                Some(value.as_bytes())
+            );
+        }
+    }
+}
+
+#[test]
+fn test_mmap_scan_survives_compaction() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        mmap: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for batch in 0..4 {
+        for idx in 0..100 {
+            storage.put(&key_of(batch * 100 + idx), b"value").unwrap();
+        }
+        sync(&storage);
+    }
+    drop(storage);
+
+    // SSTs are mapped both when they are built and when they are opened again
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    {
+        let state = storage.state.read();
+        assert_eq!(state.l0_sstables.len(), 4);
+        assert!(
+            state
+                .sstables
+                .values()
+                .all(|sst| sst.opened().unwrap().file.is_mmap())
+        );
+    }
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for idx in 0..10 {
+        assert_eq!(iter.key(), key_of(idx));
+        iter.next().unwrap();
+    }
+    // the iterator keeps the old SSTs mapped after their files are removed
+    storage.force_full_compaction().unwrap();
+    {
+        let state = storage.state.read();
+        assert!(state.sstables.values().all(|sst| sst.file.is_mmap()));
+    }
+    let mut num_keys = 10;
+    while iter.is_valid() {
+        assert_eq!(iter.key(), key_of(num_keys));
+        assert_eq!(iter.value(), b"value");
+        num_keys += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(num_keys, 400);
+    assert_eq!(
+        storage.get(&key_of(250)).unwrap().as_deref(),
+        Some(&b"value"[..])
+    );
+}
+
+/// Writes an SST with `key_of(idx)` for each idx in `range` outside of the LSM tree.
+fn external_sst(dir: &tempfile::TempDir, name: &str, range: Range<usize>, value: &[u8]) -> PathBuf {
+    let mut builder = SsTableBuilder::new(256);
+    for idx in range {
+        builder
+            .add(KeySlice::for_testing_from_slice_no_ts(&key_of(idx)), value)
+            .unwrap();
+    }
+    let path = dir.path().join(name);
+    builder.build_for_test(&path).unwrap();
+    path
+}
+
+fn simple_leveled_options() -> LsmStorageOptions {
+    LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        },
+    ))
+}
+
+fn levels_of(storage: &LsmStorageInner) -> (Vec<usize>, Vec<Vec<usize>>) {
+    let state = storage.state.read();
+    (
+        state.l0_sstables.clone(),
+        state.levels.iter().map(|(_, ssts)| ssts.clone()).collect(),
+    )
+}
+
+#[test]
+fn test_ingest_external_sst_picks_lowest_level() {
+    let external = tempdir().unwrap();
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(&dir, simple_leveled_options()).unwrap());
+    let upper = external_sst(&external, "upper.sst", 200..300, b"v1");
+    let lower = external_sst(&external, "lower.sst", 0..100, b"v1");
+    // disjoint SSTs in an empty tree go to the bottom level, in key order
+    storage
+        .ingest_external_sst(&[upper.clone(), lower.clone()])
+        .unwrap();
+    let (l0, levels) = levels_of(&storage);
+    assert!(l0.is_empty());
+    assert!(levels[0].is_empty() && levels[1].is_empty());
+    assert_eq!(levels[2].len(), 2);
+    let bottom = levels[2].clone();
+    {
+        let state = storage.state.read();
+        assert_eq!(state.sstables[&bottom[0]].first_key().key_ref(), key_of(0));
+        assert_eq!(
+            state.sstables[&bottom[1]].first_key().key_ref(),
+            key_of(200)
+        );
+    }
+    // the files stay where they were
+    assert!(upper.exists() && lower.exists());
+
+    // goes between the two SSTs of the bottom level
+    let middle = external_sst(&external, "middle.sst", 100..200, b"v2");
+    storage.ingest_external_sst(&[middle]).unwrap();
+    let (_, levels) = levels_of(&storage);
+    assert_eq!(levels[2].len(), 3);
+    assert_eq!(levels[2][0], bottom[0]);
+    assert_eq!(levels[2][2], bottom[1]);
+
+    // overlaps with the bottom level, so it goes right above it
+    let overlapping = external_sst(&external, "overlapping.sst", 50..60, b"v3");
+    storage
+        .ingest_external_sst(std::slice::from_ref(&overlapping))
+        .unwrap();
+    let (l0, levels) = levels_of(&storage);
+    assert!(l0.is_empty());
+    assert_eq!(levels[1].len(), 1);
+    // and now the level below L1 is taken, so this one goes to L1
+    storage.ingest_external_sst(&[overlapping]).unwrap();
+    let (l0, levels) = levels_of(&storage);
+    assert!(l0.is_empty());
+    assert_eq!(levels[0].len(), 1);
+
+    for (idx, value) in [(0, "v1"), (150, "v2"), (55, "v3"), (299, "v1")] {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().as_deref(),
+            Some(value.as_bytes())
+        );
+    }
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 300);
+    // the ingested keys are older than the next write
+    storage.put(&key_of(0), b"new").unwrap();
+    assert_eq!(
+        storage.get(&key_of(0)).unwrap().as_deref(),
+        Some(&b"new"[..])
+    );
+    drop(storage);
+
+    // the ingestion is replayed from the manifest
+    let storage = Arc::new(LsmStorageInner::open(&dir, simple_leveled_options()).unwrap());
+    let (l0, recovered) = levels_of(&storage);
+    assert!(l0.is_empty());
+    assert_eq!(recovered, levels);
+    assert_eq!(
+        storage.get(&key_of(150)).unwrap().as_deref(),
+        Some(&b"v2"[..])
+    );
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 300);
+}
+
+#[test]
+fn test_ingest_overlapping_external_ssts_go_to_l0() {
+    let external = tempdir().unwrap();
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(&dir, simple_leveled_options()).unwrap());
+    let first = external_sst(&external, "first.sst", 0..100, b"v1");
+    let second = external_sst(&external, "second.sst", 50..150, b"v2");
+    storage.ingest_external_sst(&[first, second]).unwrap();
+    let (l0, levels) = levels_of(&storage);
+    assert_eq!(l0.len(), 2);
+    assert!(levels.iter().all(|ssts| ssts.is_empty()));
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 150);
+    drop(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, simple_leveled_options()).unwrap());
+    assert_eq!(levels_of(&storage).0, l0);
+}
+
+#[test]
+fn test_ingest_rejects_invalid_external_sst() {
+    let external = tempdir().unwrap();
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(&dir, simple_leveled_options()).unwrap());
+    let valid = external_sst(&external, "valid.sst", 0..100, b"value");
+    let corrupted = external_sst(&external, "corrupted.sst", 100..200, b"value");
+    let mut data = std::fs::read(&corrupted).unwrap();
+    data[10] ^= 0xff;
+    std::fs::write(&corrupted, &data).unwrap();
+    let not_an_sst = external.path().join("not_an_sst");
+    std::fs::write(&not_an_sst, vec![0; 100]).unwrap();
+
+    let num_files = || std::fs::read_dir(dir.path()).unwrap().count();
+    let files_before = num_files();
+    for path in [corrupted, not_an_sst] {
+        let err = storage
+            .ingest_external_sst(&[valid.clone(), path.clone()])
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains(&path.display().to_string()),
+            "{:#}",
+            err
+        );
+    }
+    // nothing is ingested, not even the valid SST
+    assert_eq!(num_files(), files_before);
+    let (l0, levels) = levels_of(&storage);
+    assert!(l0.is_empty());
+    assert!(levels.iter().all(|ssts| ssts.is_empty()));
+    assert_eq!(storage.get(&key_of(0)).unwrap(), None);
+}
+
+#[test]
+fn test_concurrent_ingests() {
+    let external = tempdir().unwrap();
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(&dir, simple_leveled_options()).unwrap());
+    let paths = (0..8)
+        .map(|idx| {
+            external_sst(
+                &external,
+                &format!("{}.sst", idx),
+                idx * 100..idx * 100 + 50,
+                b"value",
+            )
+        })
+        .collect::<Vec<_>>();
+    std::thread::scope(|scope| {
+        for pair in paths.chunks(2) {
+            let storage = &storage;
+            scope.spawn(move || storage.ingest_external_sst(pair).unwrap());
+        }
+    });
+
+    let sst_ids = {
+        let state = storage.state.read();
+        assert_eq!(state.sstables.len(), 8);
+        state.sstables.keys().copied().collect::<HashSet<_>>()
+    };
+    assert_eq!(sst_ids.len(), 8);
+    assert_eq!(
+        scan_keys(&storage, Bound::Unbounded, Bound::Unbounded),
+        8 * 50
+    );
+    let (_, levels) = levels_of(&storage);
+    drop(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, simple_leveled_options()).unwrap());
+    assert_eq!(levels_of(&storage).1, levels);
+}
+
+#[test]
+fn test_write_batch_is_read_as_a_whole() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        // the memtable is frozen every few batches, never in the middle of one
+        target_sst_size: 16 << 10,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    let keys = (0..20)
+        .map(|idx| Bytes::from(key_of(idx)))
+        .collect::<Vec<_>>();
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+                    let mut values = Vec::new();
+                    while iter.is_valid() {
+                        values.push(Bytes::copy_from_slice(iter.value()));
+                        iter.next().unwrap();
+                    }
+                    // either every key of the last batch or none of them
+                    assert!(values.is_empty() || values.len() == keys.len());
+                    assert!(values.windows(2).all(|pair| pair[0] == pair[1]));
+                }
+            });
+        }
+        for round in 0..200 {
+            let batch = if round % 10 == 9 {
+                keys.iter()
+                    .map(|key| WriteBatchRecord::Del(key.clone()))
+                    .collect::<Vec<_>>()
+            } else {
+                let value = Bytes::from(format!("value_{}", round));
+                keys.iter()
+                    .map(|key| WriteBatchRecord::Put(key.clone(), value.clone()))
+                    .collect::<Vec<_>>()
+            };
+            storage.write_batch(&batch).unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+    });
+    assert!(storage.state.read().imm_memtables.len() > 1);
+}
+
+#[test]
+fn test_write_batch_recovery_drops_torn_batch() {
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let batch_of = |prefix: &str| {
+        (0..10)
+            .map(|idx| {
+                WriteBatchRecord::Put(
+                    Bytes::from(format!("{}_{}", prefix, idx)),
+                    Bytes::from_static(b"value"),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let num_keys = |storage: &Arc<LsmStorageInner>, prefix: &str| {
+        (0..10)
+            .filter(|idx| {
+                let key = format!("{}_{}", prefix, idx);
+                storage.get(key.as_bytes()).unwrap().is_some()
+            })
+            .count()
+    };
+    // a crash in the middle of writing the last batch, which either leaves it short or leaves
+    // its checksum unwritten
+    let tear_off: [fn(&mut Vec<u8>); 2] = [
+        |wal| wal.truncate(wal.len() - 7),
+        |wal| *wal.last_mut().unwrap() ^= 0xff,
+    ];
+    for tear in tear_off {
+        let dir = tempdir().unwrap();
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        storage.write_batch(&batch_of("a")).unwrap();
+        storage.write_batch(&batch_of("b")).unwrap();
+        let wal_path = storage.path_of_wal(storage.state.read().memtable.id());
+        drop(storage);
+        let mut wal = std::fs::read(&wal_path).unwrap();
+        tear(&mut wal);
+        std::fs::write(&wal_path, &wal).unwrap();
+
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        assert_eq!(num_keys(&storage, "a"), 10);
+        assert_eq!(num_keys(&storage, "b"), 0);
+        storage.write_batch(&batch_of("c")).unwrap();
+        drop(storage);
+
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        assert_eq!(num_keys(&storage, "a"), 10);
+        assert_eq!(num_keys(&storage, "b"), 0);
+        assert_eq!(num_keys(&storage, "c"), 10);
+    }
+}
+
+#[test]
+fn test_memtable_freezes_at_its_memory_budget() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        target_sst_size: 1 << 20,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    let mut idx = 0;
+    while storage.state.read().imm_memtables.is_empty() {
+        storage.put(format!("{:08}", idx).as_bytes(), b"v").unwrap();
+        idx += 1;
+    }
+    let frozen = storage.state.read().imm_memtables[0].clone();
+    // an 8-byte key with its timestamp and a 1-byte value
+    let entry_size = 8 + 8 + 1 + MEMTABLE_ENTRY_OVERHEAD;
+    assert_eq!(frozen.approximate_size(), frozen.len() * entry_size);
+    assert!(frozen.approximate_size() >= 1 << 20);
+    assert!(frozen.approximate_size() < (1 << 20) + entry_size);
+    // the keys and values alone are a fraction of the memory they take
+    assert_eq!(frozen.payload_size(), frozen.len() * 17);
+    assert!(frozen.payload_size() * 8 < 1 << 20);
+}
+
+#[test]
+fn test_max_open_files() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        block_size: 256,
+        // every read goes to the files
+        block_cache_capacity: 0,
+        max_open_files: Some(4),
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for sst_idx in 0..30 {
+        for idx in 0..50 {
+            storage.put(&key_of(sst_idx * 50 + idx), b"value").unwrap();
+        }
+        sync(&storage);
+    }
+    let open_files = || storage.file_cache.as_ref().unwrap().open_files();
+    assert!(open_files() <= 4);
+    drop(storage);
+
+    // recovery doesn't keep every SST open either
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    let open_files = || storage.file_cache.as_ref().unwrap().open_files();
+    assert_eq!(storage.state.read().l0_sstables.len(), 30);
+    assert!(open_files() <= 4);
+
+    std::thread::scope(|scope| {
+        for thread_idx in 0..4 {
+            let storage = &storage;
+            scope.spawn(move || {
+                for idx in (thread_idx..1500).step_by(7) {
+                    assert_eq!(
+                        storage.get(&key_of(idx)).unwrap().as_deref(),
+                        Some(&b"value"[..])
+                    );
+                }
+                assert_eq!(scan_keys(storage, Bound::Unbounded, Bound::Unbounded), 1500);
+            });
+        }
+        // the SSTs being scanned are removed while the scans are going on
+        let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+        storage.force_full_compaction().unwrap();
+        let mut num_keys = 0;
+        while iter.is_valid() {
+            num_keys += 1;
+            iter.next().unwrap();
+        }
+        assert_eq!(num_keys, 1500);
+    });
+    assert!(open_files() <= 4);
+    assert_eq!(
+        scan_keys(&storage, Bound::Unbounded, Bound::Unbounded),
+        1500
+    );
+}
+
+#[test]
+fn test_prewarm_on_open() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        prewarm_on_open: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for batch in 0..3 {
+        for idx in 0..100 {
+            storage.put(&key_of(batch * 100 + idx), b"value").unwrap();
+        }
+        sync(&storage);
+    }
+    drop(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    let stats = storage.block_cache.stats();
+    assert_eq!(stats.entry_count, 3);
+    assert_eq!(stats.hits, 0);
+    // the first block of each SST is already cached
+    for batch in 0..3 {
+        assert_eq!(
+            storage.get(&key_of(batch * 100)).unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+    }
+    let after = storage.block_cache.stats();
+    assert_eq!(after.misses, stats.misses);
+    assert_eq!(after.hits, 3);
+
+    // compaction outputs are warmed as they are built
+    storage.force_full_compaction().unwrap();
+    let num_ssts = storage.state.read().sstables.len() as u64;
+    assert_eq!(storage.block_cache.stats().misses, after.misses + num_ssts);
+    let misses = storage.block_cache.stats().misses;
+    storage.get(&key_of(0)).unwrap();
+    assert_eq!(storage.block_cache.stats().misses, misses);
+}
+
+fn tenant_key(tenant: &str, idx: usize) -> Vec<u8> {
+    format!("{}/{:05}", tenant, idx).into_bytes()
+}
+
+#[test]
+fn test_prefix_scan_skips_ssts_without_the_prefix() {
+    for prefix_extractor in [Some(PrefixExtractor::Delimiter(b'/')), None] {
+        let dir = tempdir().unwrap();
+        let options = LsmStorageOptions {
+            prefix_extractor,
+            ..LsmStorageOptions::default_for_week1_test()
+        };
+        let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+        // the first SST has tenants a and c, so its key range covers tenant b
+        for tenant in ["a", "c", "b"] {
+            for idx in 0..100 {
+                storage.put(&tenant_key(tenant, idx), b"value").unwrap();
+            }
+            if tenant != "a" {
+                sync(&storage);
+            }
+        }
+        assert_eq!(storage.state.read().l0_sstables.len(), 2);
+
+        let prefix_range = |tenant: &str| {
+            let lower = format!("{}/", tenant).into_bytes();
+            let mut upper = lower.clone();
+            upper.push(0xff);
+            (lower, upper)
+        };
+        let (lower, upper) = prefix_range("b");
+        let created = sst_iterators_created(|| {
+            assert_eq!(
+                scan_keys(&storage, Bound::Included(&lower), Bound::Included(&upper)),
+                100
+            )
+        });
+        assert_eq!(created, if prefix_extractor.is_some() { 1 } else { 2 });
+
+        // no SST has keys of tenant bb, the first one is read without the prefix bloom filter
+        let (lower, upper) = prefix_range("bb");
+        let reads = block_reads(&storage);
+        let created = sst_iterators_created(|| {
+            assert_eq!(
+                scan_keys(&storage, Bound::Included(&lower), Bound::Excluded(&upper)),
+                0
+            )
+        });
+        if prefix_extractor.is_some() {
+            assert_eq!(created, 0);
+            assert_eq!(block_reads(&storage), reads);
+        } else {
+            assert_eq!(created, 1);
+            assert!(block_reads(&storage) > reads);
+        }
+
+        // a range over several tenants can't use the prefix bloom filter
+        let created = sst_iterators_created(|| {
+            assert_eq!(
+                scan_keys(&storage, Bound::Included(b"b/"), Bound::Included(b"c/")),
+                100
+            )
+        });
+        assert_eq!(created, 2);
+    }
+}
+
+#[test]
+fn test_sst_ts_range_prunes_snapshot_reads_and_restores_commit_ts() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week1_test();
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    // each round overwrites the same keys with 100 commits, in its own SST
+    for round in 0..3 {
+        for idx in 0..100 {
+            storage
+                .put(&key_of(idx), format!("value_{}", round).as_bytes())
+                .unwrap();
+        }
+        sync(&storage);
+    }
+    {
+        let state = storage.state.read();
+        for (round, sst_id) in state.l0_sstables.iter().rev().enumerate() {
+            let sst = &state.sstables[sst_id];
+            assert_eq!(sst.min_ts(), round as u64 * 100 + 1);
+            assert_eq!(sst.max_ts(), (round as u64 + 1) * 100);
+        }
+    }
+
+    // a snapshot at the end of the first round can only see the first SST
+    let created = sst_iterators_created(|| {
+        assert_eq!(
+            storage
+                .get_with_ts(&key_of(42), 100, &ReadOptions::default())
+                .unwrap()
+                .as_deref(),
+            Some(&b"value_0"[..])
+        );
+    });
+    assert_eq!(created, 1);
+    let created = sst_iterators_created(|| {
+        let mut iter = storage
+            .scan_with_ts(
+                Bound::Unbounded,
+                Bound::Unbounded,
+                150,
+                &ReadOptions::default(),
+            )
+            .unwrap();
+        let mut num_keys = 0;
+        while iter.is_valid() {
+            let expected = if num_keys < 50 { "value_1" } else { "value_0" };
+            assert_eq!(iter.value(), expected.as_bytes());
+            num_keys += 1;
+            iter.next().unwrap();
+        }
+        assert_eq!(num_keys, 100);
+    });
+    assert_eq!(created, 2);
+    // the older SSTs can't have a newer version than the one found in the latest SST
+    let created = sst_iterators_created(|| {
+        storage
+            .get_with_ts(&key_of(42), 300, &ReadOptions::default())
+            .unwrap();
+    });
+    assert_eq!(created, 1);
+
+    drop(storage);
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    assert_eq!(storage.mvcc().latest_commit_ts(), 300);
+    storage.put(&key_of(0), b"value_3").unwrap();
+    assert_eq!(storage.mvcc().latest_commit_ts(), 301);
+    assert_eq!(
+        storage.get(&key_of(0)).unwrap().as_deref(),
+        Some(&b"value_3"[..])
+    );
+    assert_eq!(
+        storage
+            .get_with_ts(&key_of(0), 300, &ReadOptions::default())
+            .unwrap()
+            .as_deref(),
+        Some(&b"value_2"[..])
+    );
+}
+
+#[test]
+fn test_level_stats() {
+    let dir = tempdir().unwrap();
+    let storage =
+        Arc::new(LsmStorageInner::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap());
+    for idx in 0..100 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    sync(&storage);
+    for idx in 0..25 {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+    sync(&storage);
+
+    let stats = storage.level_stats();
+    assert_eq!(stats.len(), 2);
+    let (l0, l1) = (stats[0], stats[1]);
+    assert_eq!((l0.level, l0.num_files), (0, 2));
+    let state = storage.state.read().clone();
+    let l0_size: u64 = state
+        .l0_sstables
+        .iter()
+        .map(|id| state.sstables[id].table_size())
+        .sum();
+    assert_eq!(l0.total_size, l0_size);
+    assert_eq!(l0.num_entries, Some(125));
+    assert_eq!(l0.num_tombstones, Some(25));
+    assert_eq!(l0.tombstone_ratio(), Some(0.2));
+    assert_eq!((l1.level, l1.num_files, l1.total_size), (1, 0, 0));
+    assert_eq!(l1.num_entries, Some(0));
+    assert_eq!(l1.tombstone_ratio(), None);
+
+    // deletions and the versions they hide are dropped by compacting into the last level
+    storage.force_full_compaction().unwrap();
+    let stats = storage.level_stats();
+    assert_eq!(stats[0].num_files, 0);
+    assert_eq!(stats[1].num_entries, Some(75));
+    assert_eq!(stats[1].tombstone_ratio(), Some(0.0));
+}
+
+#[test]
+fn test_verify_checksums_read_option() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week1_test();
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    storage.put(&key_of(0), b"value").unwrap();
+    sync(&storage);
+    let path = {
+        let state = storage.state.read();
+        storage.path_of_sst(state.l0_sstables[0])
+    };
+    // corrupt the value in place, the block still decodes fine
+    let mut data = std::fs::read(&path).unwrap();
+    let offset = data.windows(5).position(|w| w == b"value").unwrap();
+    data[offset] = b'V';
+    std::fs::write(&path, &data).unwrap();
+
+    let never = ReadOptions {
+        verify_checksums: Some(ChecksumVerification::Never),
+    };
+    let corrupted = Some(&b"Value"[..]);
+    assert_eq!(
+        storage
+            .get_with_options(&key_of(0), &never)
+            .unwrap()
+            .as_deref(),
+        corrupted
+    );
+    assert!(
+        storage
+            .scan_with_options(Bound::Unbounded, Bound::Unbounded, &never)
+            .is_ok()
+    );
+    // the block cached without verification is verified by the reads that ask for it
+    assert!(storage.get(&key_of(0)).is_err());
+    assert!(storage.scan(Bound::Unbounded, Bound::Unbounded).is_err());
+
+    drop(storage);
+    let options = LsmStorageOptions {
+        verify_checksums: ChecksumVerification::Never,
+        ..options
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    assert_eq!(storage.get(&key_of(0)).unwrap().as_deref(), corrupted);
+    let on_fill = ReadOptions {
+        verify_checksums: Some(ChecksumVerification::OnFill),
+    };
+    assert!(storage.get_with_options(&key_of(0), &on_fill).is_err());
+}
+
+#[test]
+fn test_compaction_readahead() {
+    let compact = |compaction_readahead_size: usize| {
+        let dir = tempdir().unwrap();
+        let options = LsmStorageOptions {
+            block_size: 256,
+            target_sst_size: 4096,
+            compaction_readahead_size,
+            ..LsmStorageOptions::default_for_week1_test()
+        };
+        let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+        for round in 0..3 {
+            for idx in (round..600).step_by(2) {
+                storage
+                    .put(&key_of(idx), format!("value_{}_{}", round, idx).as_bytes())
+                    .unwrap();
+            }
+            for idx in (0..600).step_by(7) {
+                storage.delete(&key_of(idx + round)).unwrap();
+            }
+            flush_all(&storage);
+        }
+        storage.force_full_compaction().unwrap();
+        assert!(storage.state.read().levels[0].1.len() > 1);
+
+        let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+        let mut entries = Vec::new();
+        while iter.is_valid() {
+            entries.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next().unwrap();
+        }
+        entries
+    };
+    let expected = compact(0);
+    assert!(!expected.is_empty());
+    for compaction_readahead_size in [1, 1000, 1 << 20] {
+        assert_eq!(compact(compaction_readahead_size), expected);
+    }
+}
+
+#[test]
+fn test_orphan_ssts_removed_on_recovery() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week1_test();
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for idx in 0..100 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    sync(&storage);
+    let live = storage.state.read().l0_sstables.clone();
+    drop(storage);
+
+    // the process crashed after building the SST, before the manifest recorded it
+    let write_orphan = |sst_id: usize| {
+        let mut builder = SsTableBuilder::new(4096);
+        builder
+            .add(KeySlice::from_slice(b"orphan", 1), b"value")
+            .unwrap();
+        builder
+            .build(
+                sst_id,
+                None,
+                LsmStorageInner::path_of_sst_static(&dir, sst_id),
+            )
+            .unwrap();
+    };
+    write_orphan(100);
+    std::fs::write(dir.path().join("00101.sst.bak"), b"not an SST").unwrap();
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    assert!(!LsmStorageInner::path_of_sst_static(&dir, 100).exists());
+    assert!(dir.path().join("00101.sst.bak").exists());
+    for sst_id in live.iter() {
+        assert!(storage.path_of_sst(*sst_id).exists());
+    }
+    assert_eq!(storage.get(b"orphan").unwrap(), None);
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 100);
+    drop(storage);
+
+    write_orphan(200);
+    let options = LsmStorageOptions {
+        trash_orphan_ssts: true,
+        ..options
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    assert!(!LsmStorageInner::path_of_sst_static(&dir, 200).exists());
+    assert!(dir.path().join("trash").join("00200.sst").exists());
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 100);
+}
+
+#[test]
+fn test_recovered_memtables_newest_first() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        paranoid_checks: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    // `key` is written in every memtable, `old` only in the first two and `last` in the last
+    for version in 1..=3 {
+        let value = format!("value_{}", version);
+        storage.put(b"key", value.as_bytes()).unwrap();
+        if version < 3 {
+            storage.put(b"old", value.as_bytes()).unwrap();
+        }
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+    }
+    storage.put(b"last", b"value_4").unwrap();
+    storage.sync().unwrap();
+    drop(storage);
+
+    for _ in 0..2 {
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        assert_eq!(storage.state.read().imm_memtables.len(), 4);
+        for (key, value) in [("key", "value_3"), ("old", "value_2"), ("last", "value_4")] {
+            assert_eq!(
+                storage.get(key.as_bytes()).unwrap().as_deref(),
+                Some(value.as_bytes())
+            );
+        }
+        assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 3);
+        storage.sync().unwrap();
+    }
+}
+
+#[test]
+fn test_ids_not_reused_after_crash() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        trash_orphan_ssts: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    storage.put(b"key", b"value").unwrap();
+    storage.sync().unwrap();
+
+    // the process crashed after creating the WAL of the next memtable and building an SST, before
+    // the manifest recorded either
+    let wal_id = storage.next_sst_id();
+    let sst_id = storage.next_sst_id();
+    drop(storage);
+    let wal_path = LsmStorageInner::path_of_wal_static(&dir, wal_id);
+    let wal = Wal::create(&wal_path, wal_id as u64, 0).unwrap();
+    wal.put(
+        KeySlice::for_testing_from_slice_with_ts(b"stray", 1),
+        b"value",
+    )
+    .unwrap();
+    wal.sync().unwrap();
+    drop(wal);
+    let wal_len = std::fs::metadata(&wal_path).unwrap().len();
+    let mut builder = SsTableBuilder::new(4096);
+    builder
+        .add(KeySlice::from_slice(b"orphan", 1), b"value")
+        .unwrap();
+    builder
+        .build(
+            sst_id,
+            None,
+            LsmStorageInner::path_of_sst_static(&dir, sst_id),
+        )
+        .unwrap();
+
+    // the orphan SST is moved to the trash and the stray WAL is kept, both keeping their ids
+    for _ in 0..2 {
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        assert!(storage.state.read().memtable.id() > sst_id);
+        assert_eq!(storage.get(b"key").unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(storage.get(b"stray").unwrap(), None);
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+        storage.force_flush_next_imm_memtable().unwrap();
+        storage.put(b"key", b"value").unwrap();
+        storage.sync().unwrap();
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), wal_len);
+        assert!(
+            dir.path()
+                .join("trash")
+                .join(format!("{:05}.sst", sst_id))
+                .exists()
+        );
+    }
+}
+
+/// Freezes and flushes memtables of 10 keys each, the last two left unflushed.
+fn write_memtables(storage: &Arc<LsmStorageInner>, num_memtables: usize) {
+    for memtable in 0..num_memtables {
+        for idx in 0..10 {
+            storage.put(&key_of(memtable * 10 + idx), b"value").unwrap();
+        }
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+        if memtable + 2 < num_memtables {
+            storage.force_flush_next_imm_memtable().unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_manifest_roll_over() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        max_manifest_size: 512,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    write_memtables(&storage, 100);
+    let l0_sstables = storage.state.read().l0_sstables.clone();
+    assert_eq!(l0_sstables.len(), 98);
+    // the snapshot of 98 SSTs and their key ranges and the records since, instead of ~300 records
+    let manifest_len = std::fs::metadata(Manifest::current_path(&dir).unwrap())
+        .unwrap()
+        .len();
+    assert!(manifest_len < 5120, "{} bytes", manifest_len);
+    drop(storage);
+
+    let (_, records) = Manifest::recover(&dir).unwrap();
+    assert!(matches!(records[0], ManifestRecord::Snapshot(_)));
+    for _ in 0..2 {
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        assert_eq!(storage.state.read().l0_sstables, l0_sstables);
+        assert_eq!(storage.state.read().imm_memtables.len(), 2);
+        assert_eq!(
+            scan_keys(&storage, Bound::Unbounded, Bound::Unbounded),
+            1000
+        );
+    }
+}
+
+#[test]
+fn test_manifest_roll_over_crash() {
+    let manifest_files = |dir: &Path| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("MANIFEST"))
+            .collect::<Vec<_>>()
+    };
+    for step in 1..=5 {
+        let dir = tempdir().unwrap();
+        let options = LsmStorageOptions {
+            enable_wal: true,
+            ..LsmStorageOptions::default_for_week1_test()
+        };
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        write_memtables(&storage, 10);
+        let l0_sstables = storage.state.read().l0_sstables.clone();
+        drop(storage);
+
+        // recovery rolls the manifest over, and crashes after the step
+        let options = LsmStorageOptions {
+            max_manifest_size: 1,
+            ..options
+        };
+        ROLL_OVER_FAIL_AFTER.with(|x| x.set(Some(step)));
+        let Err(err) = LsmStorageInner::open(&dir, options.clone()) else {
+            panic!(
+                "rolled the manifest over without failing after step {}",
+                step
+            );
+        };
+        ROLL_OVER_FAIL_AFTER.with(|x| x.set(None));
+        assert!(format!("{:#}", err).contains(&format!("step {}", step)));
+        assert_eq!(dir.path().join("CURRENT.tmp").exists(), step == 2);
+        // the new manifest is written first, and the old one removed last
+        assert_eq!(
+            manifest_files(dir.path()).len(),
+            if step < 5 { 2 } else { 1 }
+        );
+
+        for _ in 0..2 {
+            let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+            assert!(!dir.path().join("CURRENT.tmp").exists());
+            let current = Manifest::current_path(&dir).unwrap();
+            assert_eq!(
+                manifest_files(dir.path()),
+                vec![current.file_name().unwrap().to_str().unwrap()]
+            );
+            assert_eq!(storage.state.read().l0_sstables, l0_sstables);
+            assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 100);
+        }
+    }
+}
+
+#[test]
+fn test_manifest_torn_last_record() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    write_memtables(&storage, 5);
+    let l0_sstables = storage.state.read().l0_sstables.clone();
+    drop(storage);
+    let manifest_path = Manifest::current_path(&dir).unwrap();
+    let manifest = std::fs::read(&manifest_path).unwrap();
+    // the last record is that of the memtable created by the last freeze, which the crash loses
+    let record_end = |start: usize| {
+        let record_len = u32::from_be_bytes(manifest[start..start + 4].try_into().unwrap());
+        start + 4 + record_len as usize + 4
+    };
+    // the records follow the format byte
+    let mut last_start = 1;
+    while record_end(last_start) < manifest.len() {
+        last_start = record_end(last_start);
+    }
+
+    for cut in last_start..manifest.len() {
+        std::fs::write(&manifest_path, &manifest[..cut]).unwrap();
+        for _ in 0..2 {
+            let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+            assert_eq!(storage.state.read().l0_sstables, l0_sstables);
+            assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 50);
+        }
+    }
+
+    // a whole record that fails its checksum is torn only if it's the last one
+    let mut corrupted = manifest.clone();
+    *corrupted.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&manifest_path, &corrupted).unwrap();
+    LsmStorageInner::open(&dir, options.clone()).unwrap();
+    let mut corrupted = manifest.clone();
+    corrupted[last_start - 1] ^= 0xff;
+    std::fs::write(&manifest_path, &corrupted).unwrap();
+    let Err(err) = LsmStorageInner::open(&dir, options) else {
+        panic!("opened a manifest corrupted in the middle");
+    };
+    assert!(format!("{:#}", err).contains("checksum"), "{:#}", err);
+}
+
+/// The ids of the SST files in `dir`, sorted.
+fn sst_files(dir: &Path) -> Vec<usize> {
+    let mut sst_ids = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "sst"))
+                .map(|path| {
+                    let stem = path.file_stem().unwrap().to_str().unwrap();
+                    stem.parse::<usize>().unwrap()
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    sst_ids.sort();
+    sst_ids
+}
+
+/// The ids of the SSTs in the state of the storage, sorted.
+fn live_ssts(storage: &LsmStorageInner) -> Vec<usize> {
+    let mut live = storage
+        .state
+        .read()
+        .sstables
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    live.sort();
+    live
+}
+
+#[test]
+fn test_compaction_crash_before_deleting_inputs() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        trash_orphan_ssts: true,
+        ..simple_leveled_options()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for memtable in 0..2 {
+        for idx in 0..10 {
+            storage.put(&key_of(memtable * 10 + idx), b"value").unwrap();
+        }
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+        storage.force_flush_next_imm_memtable().unwrap();
+    }
+    let inputs = storage
+        .state
+        .read()
+        .l0_sstables
+        .iter()
+        .map(|sst_id| {
+            let path = storage.path_of_sst(*sst_id);
+            let data = std::fs::read(&path).unwrap();
+            (path, data)
+        })
+        .collect::<Vec<_>>();
+    storage.trigger_compaction().unwrap();
+    let levels = levels_of(&storage);
+    assert!(levels.0.is_empty());
+    drop(storage);
+
+    // the last record is the one that the inputs are unlinked
+    let manifest_path = Manifest::current_path(&dir).unwrap();
+    let manifest = std::fs::read(&manifest_path).unwrap();
+    let record_end = |start: usize| {
+        let record_len = u32::from_be_bytes(manifest[start..start + 4].try_into().unwrap());
+        start + 4 + record_len as usize + 4
+    };
+    let mut last_start = 1;
+    while record_end(last_start) < manifest.len() {
+        last_start = record_end(last_start);
+    }
+    let (_, records) = Manifest::recover(&dir).unwrap();
+    assert!(
+        matches!(records.last(), Some(ManifestRecord::DeleteSsts(sst_ids)) if sst_ids.len() == 2)
+    );
+
+    // a crash after the compaction is recorded, with none, some or all of its inputs unlinked
+    for num_unlinked in 0..=inputs.len() {
+        std::fs::write(&manifest_path, &manifest[..last_start]).unwrap();
+        for (path, data) in &inputs[num_unlinked..] {
+            std::fs::write(path, data).unwrap();
+        }
+        for _ in 0..2 {
+            let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+            assert_eq!(levels_of(&storage), levels);
+            assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 20);
+            // the inputs are removed, not kept as orphans
+            assert_eq!(sst_files(dir.path()), live_ssts(&storage));
+            assert!(sst_files(&dir.path().join("trash")).is_empty());
+        }
+    }
+}
+
+#[test]
+fn test_compaction_crash_after_intent() {
+    for step in 1..=3 {
+        let dir = tempdir().unwrap();
+        let options = LsmStorageOptions {
+            trash_orphan_ssts: true,
+            block_size: 256,
+            target_sst_size: 2048,
+            ..simple_leveled_options()
+        };
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        for memtable in 0..2 {
+            for idx in 0..50 {
+                storage
+                    .put(&key_of(idx * 2 + memtable), &[b'v'; 64])
+                    .unwrap();
+            }
+            flush_all(&storage);
+        }
+        let before = levels_of(&storage);
+
+        // the compaction crashes right after recording its intent, building its first SST, or
+        // recording its result
+        COMPACTION_FAIL_AFTER.with(|x| x.set(Some(step)));
+        let err = storage.trigger_compaction().unwrap_err();
+        COMPACTION_FAIL_AFTER.with(|x| x.set(None));
+        assert!(format!("{:#}", err).contains(&format!("step {}", step)));
+        let after = levels_of(&storage);
+        crash(storage);
+
+        let (_, records) = Manifest::recover(&dir).unwrap();
+        let reserved = records
+            .iter()
+            .filter_map(|record| match record {
+                ManifestRecord::CompactionIntent(_, output) => Some(output.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        let built = reserved
+            .iter()
+            .filter(|sst_id| LsmStorageInner::path_of_sst_static(&dir, **sst_id).exists())
+            .count();
+        match step {
+            1 => assert_eq!(built, 0),
+            2 => assert_eq!(built, 1),
+            _ => assert!(built > 1, "{} SSTs built", built),
+        }
+
+        for _ in 0..2 {
+            let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+            let expected = if step < 3 { &before } else { &after };
+            assert_eq!(&levels_of(&storage), expected);
+            assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 100);
+            // the output of a compaction that didn't finish is removed rather than moved to the
+            // trash, and so are the inputs of one that did
+            assert_eq!(sst_files(dir.path()), live_ssts(&storage));
+            assert!(sst_files(&dir.path().join("trash")).is_empty());
+            // and the reserved ids aren't reused
+            assert!(storage.next_sst_id() > *reserved.iter().max().unwrap());
+        }
+
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        storage.trigger_compaction().unwrap();
+        assert!(levels_of(&storage).0.is_empty());
+        assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 100);
+        assert_eq!(sst_files(dir.path()), live_ssts(&storage));
+    }
+}
+
+/// Writes 40 rounds of 1000 keys spread over 20000, flushing and compacting until there's nothing
+/// left to compact after each, and gives the latest value of each key.
+fn write_and_compact(storage: &Arc<LsmStorageInner>) -> BTreeMap<Vec<u8>, String> {
+    let mut expected = BTreeMap::new();
+    for round in 0..40 {
+        for idx in 0..1000 {
+            let key = key_of((round * 7919 + idx * 13) % 20000);
+            let value = format!("{:0100}", round);
+            storage.put(&key, value.as_bytes()).unwrap();
+            expected.insert(key, value);
+        }
+        flush_all(storage);
+        while storage
+            .compaction_controller
+            .generate_compaction_task(&storage.state.read())
+            .is_some()
+        {
+            storage.trigger_compaction().unwrap();
+        }
+    }
+    expected
+}
+
+/// Checks that the SSTs of each level are sorted and don't overlap, and that the storage has
+/// `expected`.
+fn check_compacted(storage: &Arc<LsmStorageInner>, expected: &BTreeMap<Vec<u8>, String>) {
+    let state = storage.state.read();
+    for (_, sst_ids) in &state.levels {
+        for pair in sst_ids.windows(2) {
+            let (this_sst, next_sst) = (&state.sstables[&pair[0]], &state.sstables[&pair[1]]);
+            assert!(this_sst.last_key().key_ref() < next_sst.first_key().key_ref());
+        }
+    }
+    drop(state);
+    for (key, value) in expected.iter().step_by(97) {
+        assert_eq!(storage.get(key).unwrap().unwrap(), value.as_bytes());
+    }
+    assert_eq!(
+        scan_keys(storage, Bound::Unbounded, Bound::Unbounded),
+        expected.len()
+    );
+}
+
+#[test]
+fn test_leveled_compaction() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        target_sst_size: 64 << 10,
+        ..LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(
+            LeveledCompactionOptions {
+                level_size_multiplier: 2,
+                level0_file_num_compaction_trigger: 2,
+                max_levels: 3,
+                base_level_size_mb: 1,
+            },
+        ))
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    let expected = write_and_compact(&storage);
+    let levels = levels_of(&storage);
+    // compacted from L1 on
+    assert!(
+        levels
+            .1
+            .iter()
+            .filter(|sst_ids| !sst_ids.is_empty())
+            .count()
+            > 1
+    );
+    check_compacted(&storage, &expected);
+    drop(storage);
+
+    // the levels are sorted again once the SSTs are opened
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    assert_eq!(levels_of(&storage), levels);
+    check_compacted(&storage, &expected);
+}
+
+#[test]
+fn test_simple_leveled_compaction() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        target_sst_size: 64 << 10,
+        ..simple_leveled_options()
+    };
+    let check_ratio = |(l0_sstables, levels): &(Vec<usize>, Vec<Vec<usize>>)| {
+        assert!(l0_sstables.len() < 2);
+        // each level has at least twice the SSTs of the one above it, unless both are empty
+        for pair in levels.windows(2) {
+            if !pair[0].is_empty() || !pair[1].is_empty() {
+                assert!(pair[1].len() >= 2 * pair[0].len(), "{:?}", levels);
+            }
+        }
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    let expected = write_and_compact(&storage);
+    let levels = levels_of(&storage);
+    check_ratio(&levels);
+    assert!(!levels.1[2].is_empty());
+    check_compacted(&storage, &expected);
+    drop(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    assert_eq!(levels_of(&storage), levels);
+    check_compacted(&storage, &expected);
+}
+
+#[test]
+fn test_force_full_compaction_drops_deletes() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        target_sst_size: 64 << 10,
+        ..simple_leveled_options()
+    };
+    let files_size = || {
+        sst_files(dir.path())
+            .iter()
+            .map(|sst_id| {
+                let path = LsmStorageInner::path_of_sst_static(&dir, *sst_id);
+                std::fs::metadata(path).unwrap().len()
+            })
+            .sum::<u64>()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    let value = [b'v'; 100];
+    for idx in 0..20000 {
+        storage.put(&key_of(idx), &value).unwrap();
+    }
+    flush_all(&storage);
+    for idx in (0..20000).filter(|idx| idx % 100 != 0) {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+    flush_all(&storage);
+    drop(storage);
+    let (files_before, size_before) = (sst_files(dir.path()).len(), files_size());
+
+    // the compaction thread runs alongside
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.force_full_compaction().unwrap();
+    let levels = levels_of(&storage.inner);
+    assert!(levels.0.is_empty());
+    assert!(levels.1[..2].iter().all(|sst_ids| sst_ids.is_empty()));
+    assert_eq!(levels.1[2].len(), 1);
+    assert_eq!(sst_files(dir.path()), levels.1[2]);
+    assert!(files_before > 10);
+    assert!(
+        files_size() * 10 < size_before,
+        "{} of {}",
+        files_size(),
+        size_before
+    );
+    let check = |storage: &MiniLsm| {
+        for idx in 0..20000 {
+            let expected = (idx % 100 == 0).then_some(&value[..]);
+            assert_eq!(storage.get(&key_of(idx)).unwrap().as_deref(), expected);
+        }
+    };
+    check(&storage);
+    storage.close().unwrap();
+    drop(storage);
+
+    // the result is recorded in the manifest
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(levels_of(&storage.inner), levels);
+    check(&storage);
+}
+
+#[test]
+fn test_batched_manifest_crash() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        wal_sync_policy: WalSyncPolicy::Always,
+        manifest_sync_mode: ManifestSyncMode::Batched,
+        ..simple_leveled_options()
+    };
+    let open = || Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    let new_memtables = || {
+        let (_, records) = Manifest::recover(&dir).unwrap();
+        records
+            .into_iter()
+            .filter_map(|record| match record {
+                ManifestRecord::NewMemtable(memtable_id) => Some(memtable_id),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let storage = open();
+    for idx in 0..10 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    let frozen = storage.state.read().memtable.id();
+    // a crash before anything syncs the manifest loses the record of the new memtable, which
+    // nothing was written to
+    crash(storage);
+    assert!(!new_memtables().contains(&frozen));
+    let storage = open();
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 10);
+
+    // a synced write syncs the record of its memtable first
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    let frozen = storage.state.read().memtable.id();
+    storage.put(&key_of(10), b"value").unwrap();
+    crash(storage);
+    assert!(new_memtables().contains(&frozen));
+    let storage = open();
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 11);
+
+    // a crash right after a flush, which is durable before its WALs are removed
+    for idx in 11..20 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    while !storage.state.read().imm_memtables.is_empty() {
+        storage.force_flush_next_imm_memtable().unwrap();
+    }
+    let flushed = levels_of(&storage);
+    assert!(flushed.0.len() >= 2);
+    crash(storage);
+    let storage = open();
+    assert_eq!(levels_of(&storage), flushed);
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 20);
+
+    // a crash right after a compaction, which is durable before its inputs are unlinked; the
+    // record that they're unlinked is lost
+    storage.trigger_compaction().unwrap();
+    let compacted = levels_of(&storage);
+    assert!(compacted.0.is_empty());
+    crash(storage);
+    let (_, records) = Manifest::recover(&dir).unwrap();
+    assert!(matches!(
+        records[records.len() - 2..],
+        [ManifestRecord::Compaction(..), ManifestRecord::SstMetas(_)]
+    ));
+    let storage = open();
+    assert_eq!(levels_of(&storage), compacted);
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 20);
+    for sst_id in flushed.0 {
+        assert!(!storage.path_of_sst(sst_id).exists());
+    }
+}
+
+#[test]
+fn test_recovery_with_and_without_sst_metas() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        max_manifest_size: 256,
+        ..simple_leveled_options()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    write_memtables(&storage, 10);
+    storage.trigger_compaction().unwrap();
+    while !storage.state.read().imm_memtables.is_empty() {
+        storage.force_flush_next_imm_memtable().unwrap();
+    }
+    assert!(!levels_of(&storage).0.is_empty() && !levels_of(&storage).1[0].is_empty());
+    let sst_metas_of = |storage: &LsmStorageInner| {
+        let state = storage.state.read();
+        let mut sst_metas: Vec<_> = state
+            .sstables
+            .values()
+            .map(|sst| SstMeta::new(sst))
+            .collect();
+        sst_metas.sort_by_key(|sst_meta| sst_meta.sst_id);
+        sst_metas
+    };
+    let num_lazy = |storage: &LsmStorageInner| {
+        let state = storage.state.read();
+        state.sstables.values().filter(|sst| sst.is_lazy()).count()
+    };
+    let levels = levels_of(&storage);
+    let sst_metas = sst_metas_of(&storage);
+    drop(storage);
+
+    // the SSTs are opened once they're read
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    assert_eq!(levels_of(&storage), levels);
+    assert_eq!(sst_metas_of(&storage), sst_metas);
+    assert_eq!(num_lazy(&storage), sst_metas.len());
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 100);
+    assert_eq!(num_lazy(&storage), 0);
+    // opening rolled the manifest over, and the SST flushed since is recorded after the snapshot
+    for idx in 100..110 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    storage.force_flush_next_imm_memtable().unwrap();
+    let levels = levels_of(&storage);
+    let sst_metas = sst_metas_of(&storage);
+    drop(storage);
+
+    // a manifest written before the SST metas were recorded
+    let (manifest, records) = Manifest::recover(&dir).unwrap();
+    let format = manifest.format();
+    drop(manifest);
+    assert!(records.iter().any(|record| matches!(
+        record,
+        ManifestRecord::Snapshot(snapshot) if !snapshot.sst_metas.is_empty()
+    )));
+    assert!(matches!(records.last(), Some(ManifestRecord::SstMetas(_))));
+    std::fs::remove_file(Manifest::current_path(&dir).unwrap()).unwrap();
+    std::fs::remove_file(dir.path().join("CURRENT")).unwrap();
+    let manifest = Manifest::create(&dir, format).unwrap();
+    for record in records {
+        match record {
+            ManifestRecord::SstMetas(_) => continue,
+            ManifestRecord::Snapshot(mut snapshot) => {
+                snapshot.sst_metas.clear();
+                manifest
+                    .add_record_when_init(ManifestRecord::Snapshot(snapshot))
+                    .unwrap();
+            }
+            record => manifest.add_record_when_init(record).unwrap(),
+        }
+    }
+    drop(manifest);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    assert_eq!(levels_of(&storage), levels);
+    assert_eq!(sst_metas_of(&storage), sst_metas);
+    assert_eq!(num_lazy(&storage), 0);
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 110);
+}
+
+#[test]
+fn test_open_locks_the_directory() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    let manifest_path = Manifest::current_path(&dir).unwrap();
+    let manifest = std::fs::read(&manifest_path).unwrap();
+
+    // a second open fails right away, before touching the manifest
+    let second_open = {
+        let path = dir.path().to_path_buf();
+        let options = options.clone();
+        std::thread::spawn(move || LsmStorageInner::open(&path, options).err())
+    };
+    let err = second_open
+        .join()
+        .unwrap()
+        .expect("opened a directory that's open");
+    assert_eq!(
+        err.downcast_ref::<DatabaseLocked>(),
+        Some(&DatabaseLocked {
+            path: dir.path().to_path_buf(),
+            pid: Some(std::process::id()),
+        })
+    );
+    assert_eq!(std::fs::read(&manifest_path).unwrap(), manifest);
+
+    // closing or dropping the storage releases the lock
+    storage.close().unwrap();
+    let reopened = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(
+        reopened.get(b"key").unwrap(),
+        Some(Bytes::from_static(b"value"))
+    );
+    drop(storage);
+    drop(reopened);
+    LsmStorageInner::open(&dir, options).unwrap();
+}
+
+#[test]
+fn test_recovery_reports_broken_sst() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week1_test();
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for idx in 0..100 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    sync(&storage);
+    let sst_id = storage.state.read().l0_sstables[0];
+    drop(storage);
+
+    let path = LsmStorageInner::path_of_sst_static(&dir, sst_id);
+    let data = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+    let Err(err) = LsmStorageInner::open(&dir, options) else {
+        panic!("opened with a truncated SST");
+    };
+    let expected = format!("failed to open SST {} at {}", sst_id, path.display());
+    assert_eq!(err.to_string(), expected);
+    assert!(format!("{:#}", err).contains("footer"), "{:#}", err);
+}
+
+#[test]
+fn test_compaction_output_close_to_target_sst_size() {
+    let external = tempdir().unwrap();
+    let path = external.path().join("input.sst");
+    let mut builder = SsTableBuilder::new(4096);
+    for idx in 0..20000 {
+        builder
+            .add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                format!("value_{:010}", idx).as_bytes(),
+            )
+            .unwrap();
+    }
+    builder.build_for_test(&path).unwrap();
+
+    for target_sst_size in [4 << 10, 16 << 10, 64 << 10] {
+        let dir = tempdir().unwrap();
+        let options = LsmStorageOptions {
+            block_size: 1024,
+            target_sst_size,
+            ..LsmStorageOptions::default_for_week1_test()
+        };
+        let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+        storage
+            .ingest_external_sst(std::slice::from_ref(&path))
+            .unwrap();
+        storage.force_full_compaction().unwrap();
+        let state = storage.state.read();
+        let sst_ids = &state.levels[0].1;
+        assert!(sst_ids.len() > 2);
+        // all but the last SST are cut once their estimated size reaches the target, so the
+        // last entry added may take them just over it
+        for sst_id in &sst_ids[..sst_ids.len() - 1] {
+            let size = state.sstables[sst_id].table_size() as usize;
+            assert!(
+                size.abs_diff(target_sst_size) <= target_sst_size / 100,
+                "SST of {} bytes for a target of {}",
+                size,
+                target_sst_size
+            );
+        }
+    }
+}
+
+#[test]
+fn test_approximate_range_size() {
+    let dir = tempdir().unwrap();
+    let storage =
+        Arc::new(LsmStorageInner::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap());
+    assert_eq!(
+        storage
+            .approximate_range_size(Bound::Unbounded, Bound::Unbounded)
+            .unwrap(),
+        0
+    );
+    for idx in 0..2000 {
+        storage
+            .put(&key_of(idx), format!("value_{:010}", idx).as_bytes())
+            .unwrap();
+        if idx % 500 == 499 {
+            sync(&storage);
+        }
+    }
+    // the memtables aren't counted
+    storage.put(&key_of(2000), b"value").unwrap();
+
+    let size_of = |lower: Bound<&[u8]>, upper: Bound<&[u8]>| {
+        storage.approximate_range_size(lower, upper).unwrap()
+    };
+    let total = size_of(Bound::Unbounded, Bound::Unbounded);
+    assert!(total > 0);
+    let mut prev_size = 0;
+    for idx in (0..=2000).step_by(100) {
+        let key = key_of(idx);
+        let size = size_of(Bound::Unbounded, Bound::Excluded(&key));
+        assert!(size >= prev_size, "range up to key {} shrank", idx);
+        prev_size = size;
+    }
+    assert_eq!(prev_size, total);
+    let key = key_of(1000);
+    assert_eq!(size_of(Bound::Included(&key), Bound::Excluded(&key)), 0);
+    let last_key = key_of(2000);
+    assert_eq!(size_of(Bound::Included(&last_key), Bound::Unbounded), 0);
+    let half = size_of(Bound::Unbounded, Bound::Excluded(&key));
+    assert!(half > total / 3 && half < total * 2 / 3);
+}
+
+#[test]
+fn test_encryption_of_existing_database() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week1_test();
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for idx in 0..100 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    sync(&storage);
+    drop(storage);
+
+    // SSTs written before encryption was turned on are still readable
+    let encrypted_options = LsmStorageOptions {
+        encryption: Some(AesGcmProvider::new(1, [7; 32])),
+        ..options.clone()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, encrypted_options.clone()).unwrap());
+    for idx in 100..200 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    sync(&storage);
+    let is_encrypted = |storage: &LsmStorageInner| {
+        let state = storage.state.read();
+        let mut sst_ids = state.l0_sstables.clone();
+        sst_ids.extend(state.levels.iter().flat_map(|(_, files)| files));
+        sst_ids
+            .iter()
+            .map(|sst_id| {
+                Footer::read(&state.sstables[sst_id].opened().unwrap().file)
+                    .unwrap()
+                    .is_encrypted()
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(is_encrypted(&storage), vec![true, false]);
+    drop(storage);
+
+    let Err(err) = LsmStorageInner::open(&dir, options) else {
+        panic!("opened encrypted SSTs without the key");
+    };
+    assert!(
+        format!("{:#}", err).contains("no encryption provider"),
+        "{:#}",
+        err
+    );
+    let storage = Arc::new(LsmStorageInner::open(&dir, encrypted_options).unwrap());
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 200);
+    // compaction rewrites the old SSTs encrypted
+    storage.force_full_compaction().unwrap();
+    assert!(is_encrypted(&storage).into_iter().all(|e| e));
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 200);
+}
+
+#[test]
+fn test_wal_encryption_on_recovery() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        encryption: Some(AesGcmProvider::new(1, [7; 32])),
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for idx in 0..100 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    storage.sync().unwrap();
+    drop(storage);
+
+    for (encryption, message) in [
+        (None, "no encryption provider"),
+        (
+            Some(AesGcmProvider::new(2, [7; 32])),
+            "encrypted with key 1",
+        ),
+    ] {
+        let Err(err) = LsmStorageInner::open(
+            &dir,
+            LsmStorageOptions {
+                encryption: encryption.map(|e| e as _),
+                ..options.clone()
+            },
+        ) else {
+            panic!("recovered encrypted WALs without the key");
+        };
+        assert!(format!("{:#}", err).contains(message), "{:#}", err);
+    }
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 100);
+}
+
+/// Keeps the newest schema version of the inputs, and the number of SSTs they were merged from.
+fn merge_schema_versions(inputs: &[&HashMap<String, Bytes>]) -> HashMap<String, Bytes> {
+    let mut merged = HashMap::new();
+    if let Some(version) = inputs.iter().filter_map(|p| p.get("schema_version")).max() {
+        merged.insert("schema_version".to_string(), version.clone());
+    }
+    merged.insert(
+        "merged_from".to_string(),
+        Bytes::from(inputs.len().to_string()),
+    );
+    merged
+}
+
+#[test]
+fn test_compaction_merges_user_properties() {
+    let external = tempdir().unwrap();
+    let mut paths = Vec::new();
+    for (name, range, version) in [("a.sst", 0..100, b"2"), ("b.sst", 50..150, b"3")] {
+        let mut builder = SsTableBuilder::new(256);
+        for idx in range {
+            builder
+                .add(
+                    KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                    b"value",
+                )
+                .unwrap();
+        }
+        builder.add_property("schema_version", version);
+        builder.add_property("shard", b"7");
+        let path = external.path().join(name);
+        builder.build_for_test(&path).unwrap();
+        paths.push(path);
+    }
+
+    let compacted_properties = |merge_user_properties| {
+        let dir = tempdir().unwrap();
+        let options = LsmStorageOptions {
+            merge_user_properties,
+            block_size: 256,
+            target_sst_size: 1024,
+            ..LsmStorageOptions::default_for_week1_test()
+        };
+        let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+        storage.ingest_external_sst(&paths).unwrap();
+        {
+            let state = storage.state.read();
+            let ingested = state.l0_sstables.iter().chain(&state.levels[0].1);
+            for sst_id in ingested {
+                assert_eq!(state.sstables[sst_id].user_properties()["shard"], &b"7"[..]);
+            }
+        }
+        storage.force_full_compaction().unwrap();
+        assert_eq!(scan_keys(&storage, Bound::Unbounded, Bound::Unbounded), 150);
+        let state = storage.state.read();
+        assert!(state.l0_sstables.is_empty());
+        assert!(state.levels[0].1.len() > 1);
+        // every output SST gets the same properties
+        let properties = state.levels[0]
+            .1
+            .iter()
+            .map(|sst_id| state.sstables[sst_id].user_properties().clone())
+            .collect::<Vec<_>>();
+        assert!(properties.iter().all(|p| *p == properties[0]));
+        properties[0].clone()
+    };
+    // dropped by default
+    assert!(compacted_properties(None).is_empty());
+    let merged = compacted_properties(Some(merge_schema_versions));
+    assert_eq!(merged.len(), 2);
+    assert_eq!(merged["schema_version"], &b"3"[..]);
+    assert_eq!(merged["merged_from"], &b"2"[..]);
+}
+
+#[test]
+fn test_wal_sync_policy() {
+    let key_of = |prefix: &str, idx: usize| format!("{}_{:03}", prefix, idx).into_bytes();
+    // the keys written in order that survived, which must be a prefix of them
+    let num_kept = |storage: &Arc<LsmStorageInner>, prefix: &str| {
+        let kept = (0..100)
+            .map(|idx| storage.get(&key_of(prefix, idx)).unwrap().is_some())
+            .collect::<Vec<_>>();
+        let num_kept = kept.iter().take_while(|kept| **kept).count();
+        assert!(kept[num_kept..].iter().all(|kept| !kept));
+        num_kept
+    };
+    for policy in [
+        WalSyncPolicy::Always,
+        WalSyncPolicy::EveryMillis(10),
+        WalSyncPolicy::Manual,
+    ] {
+        let dir = tempdir().unwrap();
+        let options = LsmStorageOptions {
+            enable_wal: true,
+            wal_sync_policy: policy,
+            ..LsmStorageOptions::default_for_week1_test()
+        };
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let flush_thread = storage.spawn_flush_thread(rx).unwrap().unwrap();
+        for idx in 0..100 {
+            storage.put(&key_of("synced", idx), b"value").unwrap();
+        }
+        match policy {
+            WalSyncPolicy::Always => {}
+            WalSyncPolicy::EveryMillis(_) => std::thread::sleep(Duration::from_millis(200)),
+            WalSyncPolicy::Manual => storage.sync().unwrap(),
+        }
+        for idx in 0..100 {
+            storage.put(&key_of("unsynced", idx), b"value").unwrap();
+        }
+        tx.send(()).unwrap();
+        flush_thread.join().unwrap();
+        // a crash between the buffered writes and the next sync, which drops the buffer without
+        // writing it out
+        crash(storage);
+
+        let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+        assert_eq!(num_kept(&storage, "synced"), 100, "{:?}", policy);
+        let num_unsynced = num_kept(&storage, "unsynced");
+        match policy {
+            WalSyncPolicy::Always => assert_eq!(num_unsynced, 100),
+            WalSyncPolicy::EveryMillis(_) => {}
+            // they all fit in the write buffer
+            WalSyncPolicy::Manual => assert_eq!(num_unsynced, 0),
+        }
+    }
+}
+
+#[test]
+fn test_sync_makes_earlier_writes_durable() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        wal_sync_policy: WalSyncPolicy::Manual,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    let key_of = |prefix: &str, idx: usize| format!("{}_{:03}", prefix, idx).into_bytes();
+    for idx in 0..100 {
+        storage.put(&key_of("synced", idx), b"value").unwrap();
+        if idx % 40 == 0 {
+            storage
+                .force_freeze_memtable(&storage.state_lock.lock())
+                .unwrap();
+        }
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let storage = storage.clone();
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            let mut idx = 0;
+            while !stop.load(Ordering::Relaxed) {
+                storage.put(&key_of("racing", idx), b"value").unwrap();
+                idx += 1;
+            }
+        })
+    };
+    for _ in 0..10 {
+        storage.sync().unwrap();
+    }
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+    assert!(!*storage.dir_unsynced.lock());
+    // a crash before the next sync, which drops the racing writes still buffered
+    crash(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    for idx in 0..100 {
+        assert!(storage.get(&key_of("synced", idx)).unwrap().is_some());
+    }
+}
+
+#[test]
+fn test_memtable_stats() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        memtable_shards: 4,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    assert_eq!(storage.memtable_stats(), MemtableStats::default());
+    for idx in 0..100 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    for idx in 0..30 {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+    let expected = MemtableStats {
+        num_imm_memtables: 1,
+        num_entries: 130,
+        num_tombstones: 30,
+    };
+    assert_eq!(storage.memtable_stats(), expected);
+    storage.sync().unwrap();
+    drop(storage);
+
+    // rebuilt from the WALs, into immutable memtables
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    assert_eq!(
+        storage.memtable_stats(),
+        MemtableStats {
+            num_imm_memtables: 2,
+            ..expected
+        }
+    );
+    storage.force_flush_next_imm_memtable().unwrap();
+    assert_eq!(storage.memtable_stats().num_entries, 30);
+}
+
+#[test]
+fn test_flush_removes_wal() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for idx in 0..100 {
+        storage.put(&key_of(idx), b"flushed").unwrap();
+    }
+    let memtable_id = storage.state.read().memtable.id();
+    let wal_path = storage.path_of_wal(memtable_id);
+    storage.sync().unwrap();
+    let wal = std::fs::read(&wal_path).unwrap();
+    flush_all(&storage);
+    assert!(!wal_path.exists());
+    storage.put(&key_of(0), b"overwritten").unwrap();
+    storage.sync().unwrap();
+    drop(storage);
+
+    // a crash after the flush is recorded but before its WAL is removed
+    std::fs::write(&wal_path, &wal).unwrap();
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    assert!(!wal_path.exists());
+    let snapshot = storage.state.read().clone();
+    assert_eq!(snapshot.l0_sstables, vec![memtable_id]);
+    // only the WAL of the memtable written after the flush is replayed
+    assert_eq!(snapshot.imm_memtables.len(), 1);
+    assert_eq!(
+        storage.get(&key_of(0)).unwrap(),
+        Some(Bytes::from_static(b"overwritten"))
+    );
+    for idx in 1..100 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap(),
+            Some(Bytes::from_static(b"flushed"))
+        );
+    }
+}
+
+#[test]
+fn test_writes_without_wal() {
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let skip_wal = WriteOptions {
+        disable_wal: true,
+        ..Default::default()
+    };
+    // every other write skips the WAL, all in the same memtable
+    let write = |storage: &Arc<LsmStorageInner>, range: std::ops::Range<usize>| {
+        for idx in range {
+            if idx % 2 == 0 {
+                storage.put(&key_of(idx), b"logged").unwrap();
+            } else {
+                storage
+                    .put_with_options(&key_of(idx), b"unlogged", &skip_wal)
+                    .unwrap();
+            }
+        }
+    };
+    let num_kept = |storage: &Arc<LsmStorageInner>, range: std::ops::Range<usize>| {
+        range
+            .filter(|idx| storage.get(&key_of(*idx)).unwrap().is_some())
+            .count()
+    };
+
+    // a crash loses only the writes that skipped the WAL
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    write(&storage, 0..100);
+    let sync = WriteOptions {
+        sync: true,
+        ..Default::default()
+    };
+    storage.put_with_options(b"last", b"synced", &sync).unwrap();
+    crash(storage);
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    assert_eq!(num_kept(&storage, 0..100), 50);
+    assert!(
+        (0..100).step_by(2).all(|idx| {
+            storage.get(&key_of(idx)).unwrap() == Some(Bytes::from_static(b"logged"))
+        })
+    );
+    assert_eq!(
+        storage.get(b"last").unwrap(),
+        Some(Bytes::from_static(b"synced"))
+    );
+
+    // unless the memtables are flushed first, by closing or `force_flush`
+    for close in [true, false] {
+        let dir = tempdir().unwrap();
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        write(&storage.inner, 0..100);
+        storage
+            .inner
+            .force_freeze_memtable(&storage.inner.state_lock.lock())
+            .unwrap();
+        write(&storage.inner, 100..200);
+        if close {
+            storage.close().unwrap();
+        } else {
+            storage.force_flush().unwrap();
+            let snapshot = storage.inner.state.read().clone();
+            assert!(!snapshot.memtable.has_unlogged_writes());
+            assert!(
+                snapshot
+                    .imm_memtables
+                    .iter()
+                    .all(|memtable| !memtable.has_unlogged_writes())
+            );
+            storage.sync().unwrap();
+        }
+        drop(storage);
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        assert_eq!(num_kept(&storage, 0..200), 200);
+    }
+}
+
+#[test]
+fn test_group_commit() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        wal_sync_policy: WalSyncPolicy::Always,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    let (num_threads, num_writes) = (32, 50);
+    std::thread::scope(|s| {
+        for thread in 0..num_threads {
+            let storage = &storage;
+            s.spawn(move || {
+                for idx in 0..num_writes {
+                    let key = key_of(thread * num_writes + idx);
+                    storage.put(&key, b"value").unwrap();
+                    // synced and visible once `put` returns
+                    assert!(storage.get(&key).unwrap().is_some());
+                }
+            });
+        }
+    });
+    let num_syncs = storage
+        .state
+        .read()
+        .memtable
+        .wal
+        .as_ref()
+        .unwrap()
+        .num_syncs();
+    assert!(num_syncs < (num_threads * num_writes / 4) as u64);
+    assert_eq!(
+        storage.mvcc().latest_commit_ts(),
+        (num_threads * num_writes) as u64
+    );
+    // a crash right after the writes return
+    crash(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    for idx in 0..num_threads * num_writes {
+        assert!(storage.get(&key_of(idx)).unwrap().is_some());
+    }
+}
+
+/// Scans the storage or a transaction.
+type ScanFn<'a> = &'a dyn Fn(Bound<&[u8]>, Bound<&[u8]>) -> anyhow::Result<TxnIterator>;
+
+#[test]
+fn test_scan_bounds_on_keys() {
+    let dir = tempdir().unwrap();
+    let storage =
+        Arc::new(LsmStorageInner::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap());
+    let key_of = |idx: usize| format!("key_{}", idx).into_bytes();
+    let freeze = |storage: &Arc<LsmStorageInner>| {
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap()
+    };
+    // every key has a version in each of L1, L0, an immutable memtable and the memtable, some of
+    // them tombstones, so that the bounds land on keys whose newest version hides older ones
+    let layers: [&[(usize, Option<&str>)]; 4] = [
+        &[
+            (1, Some("l1")),
+            (2, Some("l1")),
+            (3, Some("l1")),
+            (4, Some("l1")),
+            (5, Some("l1")),
+            (6, Some("l1")),
+            (7, Some("l1")),
+            (8, Some("l1")),
+            (9, Some("l1")),
+        ],
+        &[(4, None), (6, Some("l0"))],
+        &[(2, Some("imm")), (3, None), (8, Some("imm"))],
+        &[(2, None), (5, None), (7, Some("mem")), (8, None)],
+    ];
+    let mut expected = BTreeMap::new();
+    for (layer, writes) in layers.iter().enumerate() {
+        for (idx, value) in writes.iter() {
+            match value {
+                Some(value) => {
+                    storage.put(&key_of(*idx), value.as_bytes()).unwrap();
+                    expected.insert(key_of(*idx), value.as_bytes().to_vec());
+                }
+                None => {
+                    storage.delete(&key_of(*idx)).unwrap();
+                    expected.remove(&key_of(*idx));
+                }
+            }
+        }
+        match layer {
+            0 => {
+                freeze(&storage);
+                storage.force_flush_next_imm_memtable().unwrap();
+                storage.force_full_compaction().unwrap();
+            }
+            1 => {
+                freeze(&storage);
+                storage.force_flush_next_imm_memtable().unwrap();
+            }
+            2 => freeze(&storage),
+            _ => {}
+        }
+    }
+    {
+        let snapshot = storage.state.read();
+        assert!(!snapshot.levels[0].1.is_empty());
+        assert_eq!(snapshot.l0_sstables.len(), 1);
+        assert_eq!(snapshot.imm_memtables.len(), 1);
+    }
+
+    // and a transaction writes over some of them, which its scans merge in
+    let txn = storage.new_txn().unwrap();
+    let mut txn_expected = expected.clone();
+    txn.delete(&key_of(6));
+    txn_expected.remove(&key_of(6));
+    for idx in [2, 4] {
+        txn.put(&key_of(idx), b"txn");
+        txn_expected.insert(key_of(idx), b"txn".to_vec());
+    }
+
+    let bound_of = |kind: usize, key: &[u8]| -> Bound<Vec<u8>> {
+        match kind {
+            0 => Bound::Included(key.to_vec()),
+            1 => Bound::Excluded(key.to_vec()),
+            _ => Bound::Unbounded,
+        }
+    };
+    let scans: [(ScanFn<'_>, _); 2] = [
+        (&|lower, upper| storage.scan(lower, upper), &expected),
+        (&|lower, upper| txn.scan(lower, upper), &txn_expected),
+    ];
+    for (scan, expected) in scans {
+        for lower_kind in 0..3 {
+            for upper_kind in 0..3 {
+                for lower in 1..=9 {
+                    for upper in lower..=9 {
+                        let (lower_key, upper_key) = (key_of(lower), key_of(upper));
+                        let lower_bound = bound_of(lower_kind, &lower_key);
+                        let upper_bound = bound_of(upper_kind, &upper_key);
+                        let expected_range = expected
+                            .iter()
+                            .filter(|(key, _)| {
+                                RangeBounds::<Vec<u8>>::contains(
+                                    &(lower_bound.as_ref(), upper_bound.as_ref()),
+                                    *key,
+                                )
+                            })
+                            .map(|(key, value)| (key.clone(), value.clone()))
+                            .collect::<Vec<_>>();
+                        let mut iter = scan(
+                            lower_bound.as_ref().map(|key| &key[..]),
+                            upper_bound.as_ref().map(|key| &key[..]),
+                        )
+                        .unwrap();
+                        let mut range = Vec::new();
+                        while iter.is_valid() {
+                            range.push((iter.key().to_vec(), iter.value().to_vec()));
+                            iter.next().unwrap();
+                        }
+                        assert_eq!(range, expected_range, "{:?} {:?}", lower_bound, upper_bound);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_flush_merges_imm_memtables() {
+    // a key put into several memtables with the same timestamp keeps the newest value
+    let dir = tempdir().unwrap();
+    let memtables = (0..3)
+        .map(|idx| {
+            let memtable = MemTable::create(idx);
+            memtable
+                .for_testing_put_slice(b"key", format!("value_{}", idx).as_bytes())
+                .unwrap();
+            memtable
+                .for_testing_put_slice(format!("key_{}", idx).as_bytes(), b"value")
+                .unwrap();
+            Arc::new(memtable)
+        })
+        .rev()
+        .collect::<Vec<_>>();
+    let mut builder = SsTableBuilder::new(4096);
+    MemTable::flush_merged(&memtables, &mut builder).unwrap();
+    let sst = Arc::new(builder.build_for_test(dir.path().join("1.sst")).unwrap());
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    let mut entries = Vec::new();
+    while iter.is_valid() {
+        entries.push((iter.key().key_ref().to_vec(), iter.value().to_vec()));
+        iter.next().unwrap();
+    }
+    assert_eq!(
+        entries,
+        vec![
+            (b"key".to_vec(), b"value_2".to_vec()),
+            (b"key_0".to_vec(), b"value".to_vec()),
+            (b"key_1".to_vec(), b"value".to_vec()),
+            (b"key_2".to_vec(), b"value".to_vec()),
+        ]
+    );
+
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        flush_merge_imm: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    let mut memtable_ids = Vec::new();
+    for idx in 0..3 {
+        storage
+            .put(b"key", format!("value_{}", idx).as_bytes())
+            .unwrap();
+        storage.put(&key_of(idx), b"value").unwrap();
+        memtable_ids.push(storage.state.read().memtable.id());
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+    }
+    storage.put(&key_of(3), b"value").unwrap();
+    storage.force_flush_next_imm_memtable().unwrap();
+    {
+        let snapshot = storage.state.read();
+        assert!(snapshot.imm_memtables.is_empty());
+        assert_eq!(snapshot.l0_sstables, vec![memtable_ids[2]]);
+    }
+    for memtable_id in memtable_ids.iter() {
+        assert!(!storage.path_of_wal(*memtable_id).exists());
+    }
+    let check = |storage: &Arc<LsmStorageInner>| {
+        assert_eq!(
+            storage.get(b"key").unwrap(),
+            Some(Bytes::from_static(b"value_2"))
+        );
+        for idx in 0..4 {
+            assert!(storage.get(&key_of(idx)).unwrap().is_some());
+        }
+    };
+    check(&storage);
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    {
+        let snapshot = storage.state.read();
+        assert_eq!(snapshot.l0_sstables, vec![memtable_ids[2]]);
+        // only the memtable written after the flush is recovered from its WAL
+        assert_eq!(snapshot.imm_memtables.len(), 1);
+    }
+    check(&storage);
+}
+
+fn large_value_options() -> LsmStorageOptions {
+    LsmStorageOptions {
+        enable_wal: true,
+        large_value_threshold: Some(1024),
+        max_value_size: usize::MAX,
+        ..LsmStorageOptions::default_for_week1_test()
+    }
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    // every third value is larger than the threshold, and larger than the WAL can hold
+    let len = if idx.is_multiple_of(3) { 100_000 + idx } else { 10 };
+    format!("{:05}", idx).repeat(len / 5 + 1).into_bytes()
+}
+
+#[test]
+fn test_large_values_round_trip() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(&dir, large_value_options()).unwrap());
+    for idx in 0..30 {
+        storage.put(&key_of(idx), &value_of(idx)).unwrap();
+        if idx == 10 {
+            storage
+                .force_freeze_memtable(&storage.state_lock.lock())
+                .unwrap();
+            storage.force_flush_next_imm_memtable().unwrap();
+        }
+    }
+    // stored as is, it would be read as a pointer into the value log
+    let pointer_like = ValuePointer {
+        file_id: 0,
+        offset: 0,
+        len: 10,
+        checksum: 0,
+    }
+    .encode();
+    storage.put(b"pointer_like", &pointer_like).unwrap();
+    assert!(dir.path().join("00000.vlog").exists());
+
+    let check = |storage: &Arc<LsmStorageInner>| {
+        for idx in 0..30 {
+            assert_eq!(
+                storage.get(&key_of(idx)).unwrap(),
+                Some(Bytes::from(value_of(idx)))
+            );
+        }
+        assert_eq!(
+            storage.get(b"pointer_like").unwrap(),
+            Some(pointer_like.clone())
+        );
+    };
+    check(&storage);
+    // the memtables hold pointers, not the values
+    assert!(storage.state.read().memtable.approximate_size() < 100_000);
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, large_value_options()).unwrap());
+    check(&storage);
+    storage.put(&key_of(30), &value_of(30)).unwrap();
+    assert_eq!(
+        storage.get(&key_of(30)).unwrap(),
+        Some(Bytes::from(value_of(30)))
+    );
+    storage.sync().unwrap();
+    drop(storage);
+
+    // a WAL referring past the end of the value log can't be recovered
+    let vlog = std::fs::OpenOptions::new()
+        .write(true)
+        .open(dir.path().join("00000.vlog"))
+        .unwrap();
+    vlog.set_len(vlog.metadata().unwrap().len() - 1).unwrap();
+    assert!(LsmStorageInner::open(&dir, large_value_options()).is_err());
+}
+
+#[test]
+fn test_value_log_append_after_failed_write() {
+    let dir = tempdir().unwrap();
+    let value_log = ValueLog::open(&dir).unwrap();
+    let first = value_log.append(&value_of(0)).unwrap();
+    // a write that failed halfway through, leaving part of its value in the file
+    let mut vlog = std::fs::OpenOptions::new()
+        .append(true)
+        .open(dir.path().join("00000.vlog"))
+        .unwrap();
+    vlog.write_all(&value_of(3)[..1000]).unwrap();
+    drop(vlog);
+
+    let second = value_log.append(&value_of(6)).unwrap();
+    assert_eq!(second.offset, first.len as u64 + 1000);
+    assert_eq!(value_log.read(&first).unwrap(), Bytes::from(value_of(0)));
+    assert_eq!(value_log.read(&second).unwrap(), Bytes::from(value_of(6)));
+    let third = value_log.append(&value_of(1)).unwrap();
+    assert_eq!(third.offset, second.offset + second.len as u64);
+    assert_eq!(value_log.read(&third).unwrap(), Bytes::from(value_of(1)));
+}
+
+#[test]
+fn test_scan_large_values() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(&dir, large_value_options()).unwrap());
+    for idx in 0..20 {
+        storage.put(&key_of(idx), &value_of(idx + 1)).unwrap();
+    }
+    flush_all(&storage);
+    // overwrite and delete some of the keys above the flushed versions
+    for idx in (0..20).step_by(2) {
+        storage.put(&key_of(idx), &value_of(idx)).unwrap();
+    }
+    for idx in (0..20).step_by(5) {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+
+    let mut iter = storage
+        .scan(Bound::Included(&key_of(3)), Bound::Excluded(&key_of(15)))
+        .unwrap();
+    for idx in 3..15 {
+        if idx % 5 == 0 {
+            continue;
+        }
+        let expected = if idx % 2 == 0 { idx } else { idx + 1 };
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key_of(idx));
+        assert_eq!(iter.value(), value_of(expected));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_flush_concurrently_with_writes() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        target_sst_size: 4096,
+        ..LsmStorageOptions::default_for_week1_day6_test()
+    };
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    // nothing to freeze or flush
+    storage.freeze_memtable().unwrap();
+    assert!(storage.inner.state.read().imm_memtables.is_empty());
+    assert_eq!(storage.flush().unwrap(), Vec::<usize>::new());
+
+    let done = AtomicBool::new(false);
+    let mut flushed = Vec::new();
+    std::thread::scope(|scope| {
+        let writers = (0..4)
+            .map(|thread| {
+                let storage = &storage;
+                scope.spawn(move || {
+                    for idx in 0..2000 {
+                        storage.put(&key_of(thread * 2000 + idx), b"value").unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        scope.spawn(|| {
+            while !done.load(Ordering::SeqCst) {
+                storage.freeze_memtable().unwrap();
+            }
+        });
+        while writers.iter().any(|writer| !writer.is_finished()) {
+            flushed.extend(storage.flush().unwrap());
+        }
+        done.store(true, Ordering::SeqCst);
+    });
+    flushed.extend(storage.flush().unwrap());
+
+    let snapshot = storage.inner.state.read().clone();
+    assert!(snapshot.memtable.is_empty());
+    assert!(snapshot.imm_memtables.is_empty());
+    for sst_id in flushed.iter() {
+        assert!(snapshot.l0_sstables.contains(sst_id));
+    }
+    let mut keys = Vec::new();
+    for sst_id in snapshot.l0_sstables.iter() {
+        let mut iter =
+            SsTableIterator::create_and_seek_to_first(snapshot.sstables[sst_id].clone()).unwrap();
+        while iter.is_valid() {
+            keys.push(iter.key().key_ref().to_vec());
+            iter.next().unwrap();
+        }
+    }
+    keys.sort();
+    assert_eq!(keys, (0..8000).map(key_of).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_write_stall() {
+    let options = LsmStorageOptions {
+        target_sst_size: 4096,
+        ..LsmStorageOptions::default_for_week1_day6_test()
+    };
+    let memory_usage = |storage: &MiniLsm| {
+        let snapshot = storage.inner.state.read().clone();
+        snapshot.memtable.approximate_size()
+            + snapshot
+                .imm_memtables
+                .iter()
+                .map(|memtable| memtable.approximate_size())
+                .sum::<usize>()
+    };
+
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.inner.pause_flush.store(true, Ordering::SeqCst);
+    let num_written = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        let writer = scope.spawn(|| {
+            for idx in 0..1000 {
+                storage.put(&key_of(idx), &[b'x'; 100]).unwrap();
+                num_written.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        // the writer stalls once the immutable memtables reach the limit
+        let mut last_written = usize::MAX;
+        while num_written.load(Ordering::SeqCst) != last_written {
+            last_written = num_written.load(Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        assert!(!writer.is_finished());
+        assert!(last_written < 1000);
+        assert_eq!(
+            storage.inner.state.read().imm_memtables.len(),
+            options.num_memtable_limit
+        );
+        assert!(memory_usage(&storage) < 4 * options.target_sst_size);
+
+        storage.inner.pause_flush.store(false, Ordering::SeqCst);
+        writer.join().unwrap();
+    });
+    assert!(storage.inner.state.read().imm_memtables.len() <= options.num_memtable_limit);
+    for idx in 0..1000 {
+        assert!(storage.get(&key_of(idx)).unwrap().is_some());
+    }
+    storage.close().unwrap();
+
+    // with a timeout, the stalled write fails instead
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions {
+            write_stall_timeout: Some(Duration::from_millis(100)),
+            ..options.clone()
+        },
+    )
+    .unwrap();
+    storage.inner.pause_flush.store(true, Ordering::SeqCst);
+    let error = (0..1000)
+        .find_map(|idx| storage.put(&key_of(idx), &[b'x'; 100]).err())
+        .unwrap();
+    assert!(error.downcast_ref::<WriteStall>().is_some());
+    storage.inner.pause_flush.store(false, Ordering::SeqCst);
+    storage.close().unwrap();
+}
+
+#[test]
+fn test_recycle_wal() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        recycle_wal: true,
+        wal_preallocate_size: 64 << 10,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let wal_files = || {
+        std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wal"))
+            .count()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+    for round in 0..5 {
+        for idx in 0..100 {
+            storage
+                .put(&key_of(idx), format!("value_{}", round).as_bytes())
+                .unwrap();
+        }
+        let flushed_id = storage.state.read().memtable.id();
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+        storage.force_flush_next_imm_memtable().unwrap();
+        // kept to be recycled by the next memtable
+        assert!(storage.path_of_wal(flushed_id).exists());
+        assert_eq!(wal_files(), 2);
+    }
+    let memtable_id = storage.state.read().memtable.id();
+    assert_eq!(
+        std::fs::metadata(storage.path_of_wal(memtable_id))
+            .unwrap()
+            .len(),
+        64 << 10
+    );
+    // the memtable writes over the records of the WALs it recycled
+    for idx in 0..50 {
+        storage.put(&key_of(idx), b"value_last").unwrap();
+    }
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    assert_eq!(storage.state.read().imm_memtables.len(), 1);
+    assert_eq!(storage.state.read().imm_memtables[0].len(), 50);
+    for idx in 0..100 {
+        let expected = if idx < 50 { "value_last" } else { "value_4" };
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap(),
+            Some(Bytes::from(expected))
+        );
+    }
+}
+
+#[test]
+fn test_commit_ts_restored_from_memtables_and_ssts() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    // the oldest versions in an SST, the newer ones only in the WALs of a frozen memtable and
+    // the current one
+    for round in 0..3 {
+        for idx in 0..10 {
+            storage
+                .put(&key_of(idx), format!("value_{}", round).as_bytes())
+                .unwrap();
+        }
+        match round {
+            0 => {
+                storage.flush().unwrap();
+            }
+            1 => storage.freeze_memtable().unwrap(),
+            _ => {}
+        }
+    }
+    let last_ts = storage.inner.mvcc().latest_commit_ts();
+    assert_eq!(last_ts, 30);
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.mvcc().latest_commit_ts(), last_ts);
+    let snapshot = storage.new_txn().unwrap();
+    for idx in (0..10).step_by(2) {
+        storage.put(&key_of(idx), b"value_reopened").unwrap();
+    }
+    assert_eq!(storage.inner.mvcc().latest_commit_ts(), last_ts + 5);
+
+    let expected = |idx: usize| {
+        if idx.is_multiple_of(2) {
+            Bytes::from_static(b"value_reopened")
+        } else {
+            Bytes::from_static(b"value_2")
+        }
+    };
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for idx in 0..10 {
+        assert_eq!(storage.get(&key_of(idx)).unwrap(), Some(expected(idx)));
+        assert_eq!(iter.key(), key_of(idx));
+        assert_eq!(iter.value(), expected(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    // a snapshot taken before still reads the versions written before reopening
+    for idx in 0..10 {
+        assert_eq!(
+            snapshot.get(&key_of(idx)).unwrap(),
+            Some(Bytes::from_static(b"value_2"))
+        );
+    }
+}
+
+#[test]
+fn test_get_entry_tells_deleted_from_missing() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, LsmStorageOptions::default_for_week1_day6_test()).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    storage.delete(&key_of(1)).unwrap();
+    storage.flush().unwrap();
+    storage.delete(&key_of(0)).unwrap();
+
+    // the deletion in the memtable is found without reading any block of the SST
+    let reads = block_reads(&storage.inner);
+    assert_eq!(
+        storage.get_entry(&key_of(0)).unwrap(),
+        Some(Entry::Tombstone)
+    );
+    assert_eq!(storage.get(&key_of(0)).unwrap(), None);
+    assert_eq!(block_reads(&storage.inner), reads);
+
+    assert_eq!(
+        storage.get_entry(&key_of(1)).unwrap(),
+        Some(Entry::Tombstone)
+    );
+    assert_eq!(
+        storage.get_entry(&key_of(2)).unwrap(),
+        Some(Entry::Value(Bytes::from_static(b"value")))
+    );
+    assert_eq!(storage.get_entry(&key_of(1000)).unwrap(), None);
+    assert!(block_reads(&storage.inner) > reads);
+}
+
+#[test]
+fn test_sharded_memtable() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        memtable_shards: 4,
+        ..LsmStorageOptions::default_for_week1_day6_test()
+    };
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(storage.inner.state.read().memtable.num_shards(), 4);
+    for idx in (0..200).rev() {
+        storage
+            .put(&key_of(idx), format!("value_{}", idx).as_bytes())
+            .unwrap();
+    }
+    for idx in (0..200).step_by(3) {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+    let expected = (0..200)
+        .filter(|idx| idx % 3 != 0)
+        .map(|idx| {
+            (
+                Bytes::from(key_of(idx)),
+                Bytes::from(format!("value_{}", idx)),
+            )
+        })
+        .collect::<Vec<_>>();
+    check_lsm_iter_result_by_key(
+        &mut storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        expected.clone(),
+    );
+
+    // the SST is sorted across the shards
+    storage.flush().unwrap();
+    assert_eq!(storage.inner.state.read().memtable.num_shards(), 4);
+    {
+        let state = storage.inner.state.read();
+        let sst = state.sstables[&state.l0_sstables[0]].clone();
+        sst.verify().unwrap();
+    }
+    check_lsm_iter_result_by_key(
+        &mut storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        expected.clone(),
+    );
+
+    storage.put(&key_of(0), b"value").unwrap();
+    storage.close().unwrap();
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(
+        storage.get(&key_of(0)).unwrap().as_deref(),
+        Some(&b"value"[..])
+    );
+    assert_eq!(storage.get(&key_of(3)).unwrap(), None);
+    assert_eq!(
+        storage.get(&key_of(4)).unwrap(),
+        Some(Bytes::from_static(b"value_4"))
+    );
+}
+
+#[test]
+fn test_write_batch_across_freeze_threshold() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        target_sst_size: 4096,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    for idx in 0..10 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    assert!(storage.state.read().memtable.approximate_size() < 4096);
+
+    // the memtable reaches the threshold halfway through the batch, and is frozen after all of it
+    let batch = (10..60)
+        .map(|idx| WriteBatchRecord::Put(key_of(idx), b"value".to_vec()))
+        .collect::<Vec<_>>();
+    storage.write_batch(&batch).unwrap();
+    {
+        let state = storage.state.read();
+        assert_eq!(state.imm_memtables.len(), 1);
+        assert_eq!(state.imm_memtables[0].len(), 60);
+        assert!(state.memtable.is_empty());
+    }
+
+    // a batch with a value too large for the WAL is rejected as a whole
+    let batch = [
+        WriteBatchRecord::Put(key_of(60), vec![b'v'; 10]),
+        WriteBatchRecord::Put(key_of(61), vec![b'v'; 1 << 16]),
+    ];
+    assert!(storage.write_batch(&batch).is_err());
+    assert!(storage.state.read().memtable.is_empty());
+    assert_eq!(storage.get(&key_of(60)).unwrap(), None);
+    storage.put(&key_of(60), b"value").unwrap();
+    assert_eq!(
+        storage.get(&key_of(60)).unwrap().as_deref(),
+        Some(&b"value"[..])
+    );
+}
+
+#[test]
+fn test_wal_metrics() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        wal_sync_policy: WalSyncPolicy::Always,
+        target_sst_size: 4096,
+        ..LsmStorageOptions::default_for_week1_day6_test()
+    };
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.wal_metrics(), WalMetricsSnapshot::default());
+    let first_memtable = storage.inner.state.read().memtable.id();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), b"value").unwrap();
+    }
+    // counted over the WALs of all the memtables
+    assert_ne!(storage.inner.state.read().memtable.id(), first_memtable);
+    let metrics = storage.wal_metrics();
+    assert_eq!(metrics.records_written, 100);
+    assert_eq!(metrics.syncs, 100);
+    assert!(metrics.bytes_written > 100 * (key_of(0).len() + 5) as u64);
+    assert!(metrics.max_sync_latency > Duration::ZERO);
+    assert!(metrics.max_sync_latency <= metrics.total_sync_latency);
+
+    storage.reset_metrics();
+    assert_eq!(storage.wal_metrics(), WalMetricsSnapshot::default());
+    storage
+        .write_batch(&[
+            WriteBatchRecord::Put(key_of(0), b"value".to_vec()),
+            WriteBatchRecord::Del(key_of(1)),
+        ])
+        .unwrap();
+    let metrics = storage.wal_metrics();
+    assert_eq!((metrics.records_written, metrics.syncs), (1, 1));
+    storage.close().unwrap();
+
+    // the writes in between the syncs share them
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        wal_sync_policy: WalSyncPolicy::EveryMillis(20),
+        ..LsmStorageOptions::default_for_week1_day6_test()
+    };
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), b"value").unwrap();
+        if idx % 25 == 0 {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+    std::thread::sleep(Duration::from_millis(50));
+    let metrics = storage.wal_metrics();
+    assert_eq!(metrics.records_written, 100);
+    assert!(metrics.syncs > 0);
+    assert!(metrics.syncs < 100, "{} syncs", metrics.syncs);
+}
+
+#[test]
+fn test_key_and_value_size_limits() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        enable_wal: true,
+        ..LsmStorageOptions::default_for_week1_day6_test()
+    };
+    assert_eq!(options.max_key_size, MAX_KEY_VALUE_LEN);
+    assert_eq!(options.max_value_size, MAX_KEY_VALUE_LEN);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let key_error = |size| WriteError::KeyTooLarge {
+        size,
+        limit: MAX_KEY_VALUE_LEN,
+    };
+    let value_error = |size| WriteError::ValueTooLarge {
+        size,
+        limit: MAX_KEY_VALUE_LEN,
+    };
+
+    let too_large = vec![b'k'; MAX_KEY_VALUE_LEN + 1];
+    let error = storage.put(&too_large, b"value").unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&key_error(MAX_KEY_VALUE_LEN + 1))
+    );
+    let error = storage.delete(&too_large).unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&key_error(MAX_KEY_VALUE_LEN + 1))
+    );
+    let error = storage.put(b"key", &too_large).unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&value_error(MAX_KEY_VALUE_LEN + 1))
+    );
+    // nothing of a batch with one entry over the limit is written
+    let error = storage
+        .write_batch(&[
+            WriteBatchRecord::Put(&b"key"[..], &b"value"[..]),
+            WriteBatchRecord::Put(&b"key"[..], &too_large[..]),
+        ])
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&value_error(MAX_KEY_VALUE_LEN + 1))
+    );
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"key", &too_large);
+    assert!(
+        txn.commit()
+            .unwrap_err()
+            .downcast_ref::<WriteError>()
+            .is_some()
+    );
+    assert!(storage.inner.state.read().memtable.is_empty());
+    assert_eq!(storage.wal_metrics().records_written, 0);
+    assert_eq!(storage.get(b"key").unwrap(), None);
+
+    // exactly at the limits
+    let largest = &too_large[..MAX_KEY_VALUE_LEN];
+    storage.put(largest, largest).unwrap();
+    storage.close().unwrap();
+    let storage = MiniLsm::open(&dir, storage.inner.options.as_ref().clone()).unwrap();
+    assert_eq!(storage.get(largest).unwrap().as_deref(), Some(largest));
+    storage.flush().unwrap();
+    assert_eq!(storage.get(largest).unwrap().as_deref(), Some(largest));
+}
+
+#[test]
+fn test_configured_key_and_value_size_limits() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions {
+        max_key_size: 16,
+        max_value_size: 100,
+        ..LsmStorageOptions::default_for_week1_test()
+    };
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    storage.put(&[b'k'; 16], &[b'v'; 100]).unwrap();
+    let error = storage.put(&[b'k'; 17], b"value").unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&WriteError::KeyTooLarge {
+            size: 17,
+            limit: 16
+        })
+    );
+    let error = storage.put(b"key", &[b'v'; 101]).unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&WriteError::ValueTooLarge {
+            size: 101,
+            limit: 100
+        })
+    );
+    assert_eq!(
+        error.to_string(),
+        "value of 101 bytes is larger than 100 bytes"
+    );
+    assert_eq!(
+        storage.get(&[b'k'; 16]).unwrap(),
+        Some(Bytes::from(vec![b'v'; 100]))
+    );
+    assert_eq!(storage.get(b"key").unwrap(), None);
+}
+
+#[test]
+fn test_get_skips_immutable_memtables_by_key_filter() {
+    let dir = tempdir().unwrap();
+    let storage =
+        Arc::new(LsmStorageInner::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap());
+    // ten immutable memtables, each with keys of its own
+    for memtable in 0..10 {
+        for idx in 0..100 {
+            storage
+                .put(&key_of(memtable * 1000 + idx), b"value")
+                .unwrap();
+        }
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+    }
+    assert_eq!(storage.state.read().imm_memtables.len(), 10);
+    let probed = |f: &dyn Fn()| {
+        NUM_PROBED.with(|x| x.set(0));
+        f();
+        NUM_PROBED.with(|x| x.get())
+    };
+
+    // an absent key is mostly only looked up in the current memtable
+    let num_probed = probed(&|| {
+        for idx in 0..100 {
+            assert_eq!(storage.get(&key_of(20000 + idx)).unwrap(), None);
+        }
+    });
+    assert!(num_probed < 120, "{} memtables probed", num_probed);
+
+    // a present one in the memtables that may have it
+    let num_probed = probed(&|| {
+        assert_eq!(
+            storage.get(&key_of(42)).unwrap().as_deref(),
+            Some(&b"value"[..])
+        );
+    });
+    assert!((2..=11).contains(&num_probed));
+
+    // the filters are dropped with the flushed memtables
+    let oldest = storage.state.read().imm_memtables.last().unwrap().clone();
+    let absent = (20000..20100)
+        .map(key_of)
+        .find(|key| !oldest.may_contain(key))
+        .unwrap();
+    storage.force_flush_next_imm_memtable().unwrap();
+    assert!(oldest.may_contain(&absent));
+    assert_eq!(
+        storage.get(&key_of(42)).unwrap().as_deref(),
+        Some(&b"value"[..])
+    );
+}
@@ -0,0 +1,2582 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashMap, ops::Bound, path::Path, sync::Arc};
+
+use bytes::{Buf, BufMut, Bytes};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tempfile::{TempDir, tempdir};
+
+use super::harness::{AesGcmProvider, MockIterator};
+
+use crate::{
+    block::{SIZEOF_U32, SeekResult},
+    iterators::StorageIterator,
+    key::{KeyBytes, KeySlice, TS_RANGE_BEGIN},
+    lsm_storage::BlockCache,
+    table::{
+        BlockMeta, ChecksumVerification, CompressionOptions, FileCache, FileObject, Footer,
+        LAZY_BLOCK_META_SIZE, LEGACY_SST_FOOTER_SIZE, PrefixExtractor, SST_FOOTER_SIZE,
+        SST_FORMAT_VERSION, SST_PROPERTIES_SIZE, SsTable, SsTableBuilder, SsTableIterator,
+        TableProperties,
+        direct_io::{AlignedWriter, DIRECT_IO_ALIGNMENT},
+    },
+};
+
+/// Runs the tests, each taking whether to memory-map SSTs, in both read modes: as `read::<test>`
+/// with SSTs read from their files, and as `mmap::<test>` with them mapped.
+macro_rules! mmap_tests {
+    ($($(#[$attr:meta])* $name:ident,)*) => {
+        mod read {
+            $(#[test] $(#[$attr])* fn $name() { super::$name(false) })*
+        }
+
+        mod mmap {
+            $(#[test] $(#[$attr])* fn $name() { super::$name(true) })*
+        }
+    };
+}
+
+fn new_builder(block_size: usize, mmap: bool) -> SsTableBuilder {
+    SsTableBuilder::new(block_size).with_mmap(mmap)
+}
+
+fn open_file(path: &Path, mmap: bool) -> FileObject {
+    FileObject::open(path).unwrap().with_mmap(mmap).unwrap()
+}
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:010}", idx).into_bytes()
+}
+
+/// Builds `1.sst` in a new directory with `builder`, adding `keys` with their `value`.
+fn build_sst(
+    mut builder: SsTableBuilder,
+    keys: impl IntoIterator<Item = usize>,
+    value: impl Fn(usize) -> Vec<u8>,
+    block_cache: Option<Arc<BlockCache>>,
+) -> (TempDir, SsTable) {
+    for idx in keys {
+        builder
+            .add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                &value(idx),
+            )
+            .unwrap();
+    }
+    let dir = tempdir().unwrap();
+    let sst = builder
+        .build(1, block_cache, dir.path().join("1.sst"))
+        .unwrap();
+    (dir, sst)
+}
+
+fn generate_sst(num_keys: usize, mmap: bool) -> (TempDir, SsTable) {
+    build_sst(new_builder(128, mmap), 0..num_keys, value_of, None)
+}
+
+/// Flips one byte of the file on disk and opens it again.
+fn corrupt_and_reopen(dir: &TempDir, offset: usize, mmap: bool) -> SsTable {
+    let path = dir.path().join("1.sst");
+    let mut data = std::fs::read(&path).unwrap();
+    data[offset] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+    SsTable::open(
+        1,
+        Some(Arc::new(BlockCache::new(16))),
+        open_file(&path, mmap),
+    )
+    .unwrap()
+}
+
+fn test_sst_block_checksum_detects_corruption(mmap: bool) {
+    let (dir, sst) = generate_sst(100, mmap);
+    assert!(sst.num_of_blocks() > 2);
+    // corrupt the first byte of the second data block
+    let offset = sst.block_meta[1].offset;
+    let sst = Arc::new(corrupt_and_reopen(&dir, offset, mmap));
+
+    assert!(sst.read_block(0).is_ok());
+    assert!(sst.read_block(1).is_err());
+    assert!(sst.read_block_cached(1).is_err());
+    assert!(sst.read_block(2).is_ok());
+
+    // iterating into the corrupted block fails instead of yielding garbage
+    assert!(
+        SsTableIterator::create_and_seek_to_key(
+            sst.clone(),
+            KeySlice::for_testing_from_slice_no_ts(sst.block_meta[1].first_key.key_ref()),
+        )
+        .is_err()
+    );
+}
+
+fn test_sst_block_checksum_detects_corrupted_checksum(mmap: bool) {
+    let (dir, sst) = generate_sst(100, mmap);
+    // the last 4 bytes before the next block are the checksum of block 0
+    let offset = sst.block_meta[1].offset - 1;
+    let sst = corrupt_and_reopen(&dir, offset, mmap);
+    assert!(sst.read_block(0).is_err());
+    assert!(sst.read_block(1).is_ok());
+}
+
+fn test_sst_verify_checksums_modes(mmap: bool) {
+    let (dir, sst) = generate_sst(100, mmap);
+    // corrupt a value in the second data block, which still decodes fine
+    let path = dir.path().join("1.sst");
+    let data = std::fs::read(&path).unwrap();
+    let start = sst.block_meta[1].offset;
+    let original = sst.read_block(1).unwrap().data.to_vec();
+    let offset = start
+        + data[start..]
+            .windows(6)
+            .position(|w| w == b"value_")
+            .unwrap();
+    let sst = corrupt_and_reopen(&dir, offset, mmap);
+
+    // never verified, so the corruption goes unnoticed and the block is cached as is
+    let block = sst
+        .read_block_with(1, true, ChecksumVerification::Never)
+        .unwrap();
+    assert_ne!(block.data, original);
+    assert!(
+        sst.read_block_with(1, true, ChecksumVerification::Never)
+            .is_ok()
+    );
+    // a read that verifies doesn't trust the unverified cache entry
+    assert!(
+        sst.read_block_with(1, true, ChecksumVerification::OnFill)
+            .is_err()
+    );
+    assert!(
+        sst.read_block_with(1, false, ChecksumVerification::Always)
+            .is_err()
+    );
+
+    // on a cache miss the block is verified when it's filled
+    let sst = SsTable::open(
+        1,
+        Some(Arc::new(BlockCache::new(16))),
+        open_file(&path, mmap),
+    )
+    .unwrap();
+    assert!(
+        sst.read_block_with(1, true, ChecksumVerification::OnFill)
+            .is_err()
+    );
+    assert!(
+        sst.read_block_with(1, false, ChecksumVerification::OnFill)
+            .is_err()
+    );
+    assert!(
+        sst.read_block_with(0, true, ChecksumVerification::Always)
+            .is_ok()
+    );
+}
+
+fn test_sst_value_larger_than_u16(mmap: bool) {
+    let mut builder = new_builder(4096, mmap);
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"a"), b"small")
+        .unwrap();
+    builder
+        .add(
+            KeySlice::for_testing_from_slice_no_ts(b"b"),
+            &vec![b'b'; 200_000],
+        )
+        .unwrap();
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"c"), b"small")
+        .unwrap();
+    let dir = tempdir().unwrap();
+    let sst = Arc::new(builder.build_for_test(dir.path().join("1.sst")).unwrap());
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    let mut values = Vec::new();
+    while iter.is_valid() {
+        values.push((
+            iter.key().for_testing_key_ref().to_vec(),
+            iter.value().len(),
+        ));
+        iter.next().unwrap();
+    }
+    assert_eq!(
+        values,
+        vec![
+            (b"a".to_vec(), 5),
+            (b"b".to_vec(), 200_000),
+            (b"c".to_vec(), 5)
+        ]
+    );
+}
+
+fn test_sst_oversized_entry_gets_dedicated_block(mmap: bool) {
+    let big_value = vec![b'x'; 1 << 20];
+    let mut builder = new_builder(4096, mmap);
+    for idx in 0..100 {
+        let value = if idx == 50 || idx == 51 {
+            big_value.clone()
+        } else {
+            value_of(idx)
+        };
+        builder
+            .add(KeySlice::for_testing_from_slice_no_ts(&key_of(idx)), &value)
+            .unwrap();
+    }
+    let dir = tempdir().unwrap();
+    let sst = Arc::new(builder.build_for_test(dir.path().join("1.sst")).unwrap());
+
+    // each oversized entry is the only entry of its block, and its neighbours are not merged in
+    let big_blocks = sst
+        .block_meta
+        .iter()
+        .enumerate()
+        .filter(|(_, meta)| meta.first_key.key_ref() >= key_of(50).as_slice())
+        .take(3)
+        .collect::<Vec<_>>();
+    assert_eq!(big_blocks[0].1.first_key.key_ref(), key_of(50));
+    assert_eq!(big_blocks[0].1.last_key.key_ref(), key_of(50));
+    assert_eq!(big_blocks[1].1.first_key.key_ref(), key_of(51));
+    assert_eq!(big_blocks[1].1.last_key.key_ref(), key_of(51));
+    assert_eq!(big_blocks[2].1.first_key.key_ref(), key_of(52));
+    let before = &sst.block_meta[big_blocks[0].0 - 1];
+    assert_eq!(before.last_key.key_ref(), key_of(49));
+    for (idx, _) in &big_blocks[..2] {
+        assert_eq!(sst.read_block(*idx).unwrap().offsets.len(), 1);
+    }
+    for meta in &sst.block_meta {
+        assert!(meta.first_key <= meta.last_key);
+    }
+
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    for idx in 0..100 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+        if idx == 50 || idx == 51 {
+            assert_eq!(iter.value(), &big_value[..]);
+        } else {
+            assert_eq!(iter.value(), value_of(idx));
+        }
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    let iter = SsTableIterator::create_and_seek_to_key(
+        sst,
+        KeySlice::for_testing_from_slice_no_ts(&key_of(51)),
+    )
+    .unwrap();
+    assert_eq!(iter.key().for_testing_key_ref(), key_of(51));
+    assert_eq!(iter.value().len(), 1 << 20);
+}
+
+fn test_sst_max_entry_size(mmap: bool) {
+    let mut builder = new_builder(4096, mmap).with_max_entry_size(Some(1024));
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"a"), &[b'a'; 1000])
+        .unwrap();
+    assert!(
+        builder
+            .add(
+                KeySlice::for_testing_from_slice_no_ts(b"b"),
+                &[b'b'; 1 << 20]
+            )
+            .is_err()
+    );
+    // the rejected entry leaves the builder untouched
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"c"), b"c")
+        .unwrap();
+    let dir = tempdir().unwrap();
+    let sst = Arc::new(builder.build_for_test(dir.path().join("1.sst")).unwrap());
+    assert_eq!(sst.num_of_blocks(), 1);
+    assert_eq!(sst.last_key().for_testing_key_ref(), b"c");
+}
+
+fn compressible_value_of(idx: usize) -> Vec<u8> {
+    value_of(idx).repeat(20)
+}
+
+fn test_sst_compression_round_trip(mmap: bool) {
+    let (_dir, raw_sst) = build_sst(
+        new_builder(4096, mmap).with_compression(CompressionOptions::None),
+        0..1000,
+        compressible_value_of,
+        Some(Arc::new(BlockCache::new(16))),
+    );
+    for compression in [
+        CompressionOptions::None,
+        CompressionOptions::Lz4,
+        CompressionOptions::Zstd { level: 0 },
+        CompressionOptions::Zstd { level: 19 },
+    ] {
+        let (_dir, sst) = build_sst(
+            new_builder(4096, mmap).with_compression(compression),
+            0..1000,
+            compressible_value_of,
+            Some(Arc::new(BlockCache::new(16))),
+        );
+        if compression != CompressionOptions::None {
+            assert!(sst.table_size() * 3 < raw_sst.table_size());
+        }
+        // the cache holds the decompressed block
+        assert_eq!(
+            sst.read_block_cached(0).unwrap().data,
+            raw_sst.read_block(0).unwrap().data
+        );
+        let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+        for idx in 0..1000 {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+            assert_eq!(iter.value(), compressible_value_of(idx));
+            iter.next().unwrap();
+        }
+        assert!(!iter.is_valid());
+    }
+}
+
+fn test_sst_incompressible_block_stored_raw(mmap: bool) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let values = (0..1000)
+        .map(|_| (0..1000).map(|_| rng.r#gen::<u8>()).collect::<Vec<u8>>())
+        .collect::<Vec<_>>();
+    let (dir, sst) = build_sst(
+        new_builder(4096, mmap).with_compression(CompressionOptions::Lz4),
+        0..1000,
+        |idx| values[idx].clone(),
+        Some(Arc::new(BlockCache::new(16))),
+    );
+    let data = std::fs::read(dir.path().join("1.sst")).unwrap();
+    for (idx, meta) in sst.block_meta.iter().enumerate() {
+        // random values grow under lz4, so every block falls back to no compression
+        assert_eq!(data[meta.offset], 0);
+        // type byte, the raw encoded block and its checksum
+        let block_end = sst
+            .block_meta
+            .get(idx + 1)
+            .map_or(sst.block_meta_offset, |meta| meta.offset);
+        let block = sst.read_block(idx).unwrap();
+        assert_eq!(block_end - meta.offset, 1 + block.encode().len() + 4);
+    }
+    let sst = Arc::new(sst);
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    for value in &values {
+        assert_eq!(iter.value(), &value[..]);
+        iter.next().unwrap();
+    }
+}
+
+fn test_sst_compressed_block_checksum(mmap: bool) {
+    let (dir, sst) = build_sst(
+        new_builder(4096, mmap).with_compression(CompressionOptions::Lz4),
+        0..1000,
+        compressible_value_of,
+        Some(Arc::new(BlockCache::new(16))),
+    );
+    let data = std::fs::read(dir.path().join("1.sst")).unwrap();
+    assert_eq!(data[sst.block_meta[1].offset], 1);
+    // corrupt the compressed payload, the checksum catches it before decompression
+    let sst = corrupt_and_reopen(&dir, sst.block_meta[1].offset + 10, mmap);
+    assert!(sst.read_block(0).is_ok());
+    let Err(err) = sst.read_block(1) else {
+        panic!("corrupted block was read successfully");
+    };
+    assert!(err.to_string().contains("checksum"));
+}
+
+fn test_sst_rejects_out_of_order_key_in_block(mmap: bool) {
+    let mut builder = new_builder(4096, mmap);
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"b"), b"b")
+        .unwrap();
+    assert!(
+        builder
+            .add(KeySlice::for_testing_from_slice_no_ts(b"a"), b"a")
+            .is_err()
+    );
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"c"), b"c")
+        .unwrap();
+    let dir = tempdir().unwrap();
+    let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    assert_eq!(sst.first_key().for_testing_key_ref(), b"b");
+    assert_eq!(sst.last_key().for_testing_key_ref(), b"c");
+}
+
+fn test_sst_block_with_unknown_format(mmap: bool) {
+    let (dir, sst) = generate_sst(100, mmap);
+    let path = dir.path().join("1.sst");
+    let mut data = std::fs::read(&path).unwrap();
+    // rewrite the format byte of block 1 and fix up its checksum, as a newer writer would
+    let begin = sst.block_meta[1].offset;
+    let end = sst.block_meta[2].offset;
+    data[end - 4 - 3] = 9;
+    let checksum = crc32fast::hash(&data[begin..end - 4]);
+    data[end - 4..end].copy_from_slice(&checksum.to_be_bytes());
+    std::fs::write(&path, &data).unwrap();
+
+    let sst = SsTable::open(1, None, open_file(&path, mmap)).unwrap();
+    assert!(sst.read_block(0).is_ok());
+    let Err(err) = sst.read_block(1) else {
+        panic!("block with an unknown format was read successfully");
+    };
+    assert!(err.to_string().contains("unsupported block format 9"));
+}
+
+fn test_sst_rejects_out_of_order_key_across_blocks(mmap: bool) {
+    let mut builder = new_builder(64, mmap);
+    // same key with decreasing timestamps, spread over several blocks
+    for ts in (1..=10).rev() {
+        builder
+            .add(KeySlice::from_slice(b"key", ts), &value_of(ts as usize))
+            .unwrap();
+    }
+    assert!(builder.meta.len() > 1);
+    for (key, ts) in [(&b"key"[..], 1), (b"key", 2), (b"ke", 5), (b"a", 100)] {
+        let Err(err) = builder.add(KeySlice::from_slice(key, ts), b"value") else {
+            panic!("out-of-order key {:?}@{} was added", key, ts);
+        };
+        let expected = format!(
+            "key \"{}\"@{} is added after \"key\"@1",
+            key.escape_ascii(),
+            ts
+        );
+        assert_eq!(err.to_string(), expected);
+    }
+    builder
+        .add(KeySlice::from_slice(b"key", 0), b"value")
+        .unwrap();
+    builder
+        .add(KeySlice::from_slice(b"key_1", 5), b"value")
+        .unwrap();
+}
+
+fn test_sst_build_catches_out_of_order_iterator(mmap: bool) {
+    // a broken iterator, e.g. from compaction, that goes back to an earlier key after a few blocks
+    let mut data: Vec<_> = (0..50)
+        .map(|idx| (Bytes::from(key_of(idx)), Bytes::from(value_of(idx))))
+        .collect();
+    data.swap(30, 31);
+    let mut iter = MockIterator::new(data);
+    let mut builder = new_builder(128, mmap);
+    let mut result = Ok(());
+    while iter.is_valid() {
+        result = builder.add(iter.key(), iter.value());
+        if result.is_err() {
+            break;
+        }
+        iter.next().unwrap();
+    }
+    let err = result.unwrap_err();
+    assert!(builder.meta.len() > 1);
+    assert!(err.to_string().contains("key_00030"));
+    assert!(err.to_string().contains("key_00031"));
+}
+
+#[should_panic(expected = "key \"a\"@0 is added after \"b\"@0")]
+fn test_sst_paranoid_checks_panic_on_out_of_order_key(mmap: bool) {
+    let mut builder = new_builder(4096, mmap).with_paranoid_checks(true);
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"b"), b"b")
+        .unwrap();
+    let _ = builder.add(KeySlice::for_testing_from_slice_no_ts(b"a"), b"a");
+}
+
+fn test_sst_versions_across_blocks(mmap: bool) {
+    let mut builder = new_builder(128, mmap);
+    for idx in 0..20 {
+        for ts in (1..=10).rev() {
+            builder
+                .add(
+                    KeySlice::from_slice(&key_of(idx), ts),
+                    &value_of(ts as usize),
+                )
+                .unwrap();
+        }
+    }
+    let dir = tempdir().unwrap();
+    let sst = Arc::new(builder.build_for_test(dir.path().join("1.sst")).unwrap());
+    assert_eq!(sst.max_ts(), 10);
+    assert_eq!(sst.min_ts(), 1);
+    assert_eq!(
+        sst.first_key().as_key_slice(),
+        KeySlice::from_slice(&key_of(0), 10)
+    );
+    assert_eq!(
+        sst.last_key().as_key_slice(),
+        KeySlice::from_slice(&key_of(19), 1)
+    );
+
+    // the block meta keeps the ts, and a user key can span several blocks
+    let mut spanning = 0;
+    for pair in sst.block_meta.windows(2) {
+        assert!(pair[0].last_key < pair[1].first_key);
+        if pair[0].last_key.key_ref() == pair[1].first_key.key_ref() {
+            spanning += 1;
+            assert!(pair[0].last_key.ts() > pair[1].first_key.ts());
+        }
+    }
+    assert!(spanning > 0);
+
+    for idx in 0..20 {
+        for ts in 1..=10 {
+            let iter = SsTableIterator::create_and_seek_to_key(
+                sst.clone(),
+                KeySlice::from_slice(&key_of(idx), ts),
+            )
+            .unwrap();
+            assert_eq!(iter.key(), KeySlice::from_slice(&key_of(idx), ts));
+            assert_eq!(iter.value(), value_of(ts as usize));
+        }
+    }
+
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    for idx in 0..20 {
+        for ts in (1..=10).rev() {
+            assert_eq!(iter.key(), KeySlice::from_slice(&key_of(idx), ts));
+            iter.next().unwrap();
+        }
+    }
+    assert!(!iter.is_valid());
+}
+
+fn test_sst_seek_to_key_ret(mmap: bool) {
+    let (_dir, sst) = generate_sst(100, mmap);
+    let sst = Arc::new(sst);
+    let seek = |key: &[u8]| {
+        let (iter, result) = SsTableIterator::create_and_seek_to_key_ret(
+            sst.clone(),
+            KeySlice::for_testing_from_slice_no_ts(key),
+            ChecksumVerification::OnFill,
+        )
+        .unwrap();
+        (
+            result,
+            iter.is_valid().then(|| iter.key().key_ref().to_vec()),
+        )
+    };
+    // before the first key
+    assert_eq!(seek(b"a"), (SeekResult::After, Some(key_of(0))));
+    for idx in 0..100 {
+        assert_eq!(seek(&key_of(idx)), (SeekResult::Exact, Some(key_of(idx))));
+        // between two entries, which may be in different blocks
+        let mut between = key_of(idx);
+        between.push(b'a');
+        let expected = if idx + 1 < 100 {
+            (SeekResult::After, Some(key_of(idx + 1)))
+        } else {
+            (SeekResult::NotFound, None)
+        };
+        assert_eq!(seek(&between), expected);
+    }
+    // past the end
+    assert_eq!(seek(b"z"), (SeekResult::NotFound, None));
+}
+
+fn test_sst_seek_to_key_ret_versions_across_blocks(mmap: bool) {
+    let mut builder = new_builder(128, mmap);
+    for idx in 0..20 {
+        for ts in (1..=10).rev() {
+            builder
+                .add(KeySlice::from_slice(&key_of(idx), ts * 2), b"value")
+                .unwrap();
+        }
+    }
+    let dir = tempdir().unwrap();
+    let sst = Arc::new(builder.build_for_test(dir.path().join("1.sst")).unwrap());
+    for idx in 0..20 {
+        // older versions are found even when they are at the beginning of the next block
+        for ts in (3..=21).step_by(2) {
+            let (iter, result) = SsTableIterator::create_and_seek_to_key_ret(
+                sst.clone(),
+                KeySlice::from_slice(&key_of(idx), ts),
+                ChecksumVerification::OnFill,
+            )
+            .unwrap();
+            assert_eq!(result, SeekResult::Exact);
+            assert_eq!(
+                iter.key(),
+                KeySlice::from_slice(&key_of(idx), (ts - 1).min(20))
+            );
+        }
+        // older than all versions
+        let (_, result) = SsTableIterator::create_and_seek_to_key_ret(
+            sst.clone(),
+            KeySlice::from_slice(&key_of(idx), 1),
+            ChecksumVerification::OnFill,
+        )
+        .unwrap();
+        let expected = if idx + 1 < 20 {
+            SeekResult::After
+        } else {
+            SeekResult::NotFound
+        };
+        assert_eq!(result, expected);
+    }
+}
+
+fn test_block_cache_repeated_point_read(mmap: bool) {
+    let block_cache = Arc::new(BlockCache::new(1 << 20));
+    let (dir, sst) = build_sst(
+        new_builder(128, mmap),
+        0..100,
+        value_of,
+        Some(block_cache.clone()),
+    );
+    let sst = Arc::new(sst);
+    let key = key_of(57);
+    let seek = || {
+        let iter = SsTableIterator::create_and_seek_to_key(
+            sst.clone(),
+            KeySlice::for_testing_from_slice_no_ts(&key),
+        )
+        .unwrap();
+        assert_eq!(iter.key().for_testing_key_ref(), key);
+        assert_eq!(iter.value(), value_of(57));
+    };
+    seek();
+    let stats = block_cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.entry_count), (0, 1, 1));
+
+    // later reads never go to the file, not even to block 0. A cached block of a memory-mapped
+    // SST still points into the mapping, so the file can't be truncated under it.
+    if !mmap {
+        std::fs::write(dir.path().join("1.sst"), b"").unwrap();
+    }
+    for _ in 0..10 {
+        seek();
+    }
+    let stats = block_cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.entry_count), (10, 1, 1));
+    assert!(stats.size > 0);
+}
+
+fn test_block_cache_compaction_does_not_fill(mmap: bool) {
+    let block_cache = Arc::new(BlockCache::new(1 << 20));
+    let (_dir, sst) = build_sst(
+        new_builder(128, mmap),
+        0..100,
+        value_of,
+        Some(block_cache.clone()),
+    );
+    let sst = Arc::new(sst);
+    SsTableIterator::create_and_seek_to_key(
+        sst.clone(),
+        KeySlice::for_testing_from_slice_no_ts(&key_of(0)),
+    )
+    .unwrap();
+
+    let mut iter = SsTableIterator::create_for_compaction(sst.clone(), 0).unwrap();
+    let mut num_keys = 0;
+    while iter.is_valid() {
+        num_keys += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(num_keys, 100);
+    let stats = block_cache.stats();
+    // only the block read by the point lookup is cached, and the scan used it
+    assert_eq!(stats.entry_count, 1);
+    assert_eq!(stats.hits, 1);
+    assert_eq!(stats.misses as usize, sst.num_of_blocks());
+}
+
+fn test_sst_iterator_readahead(mmap: bool) {
+    let block_cache = Arc::new(BlockCache::new(1 << 20));
+    let (_dir, sst) = build_sst(
+        new_builder(128, mmap),
+        0..100,
+        value_of,
+        Some(block_cache.clone()),
+    );
+    let sst = Arc::new(sst);
+    let collect = |mut iter: SsTableIterator| {
+        let mut entries = Vec::new();
+        while iter.is_valid() {
+            entries.push((iter.key().to_key_vec(), iter.value().to_vec()));
+            iter.next().unwrap();
+        }
+        entries
+    };
+    let expected = collect(SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap());
+    assert_eq!(expected.len(), 100);
+    let block_size = sst.block_meta[1].offset - sst.block_meta[0].offset;
+    // one block, a few blocks not aligned to block boundaries, and the whole file at a time
+    for readahead_size in [0, 1, 3 * block_size + 1, 1 << 20] {
+        let stats = block_cache.stats();
+        let iter = SsTableIterator::create_for_compaction(sst.clone(), readahead_size).unwrap();
+        assert_eq!(collect(iter), expected, "readahead {}", readahead_size);
+
+        let mut iter = SsTableIterator::new(
+            sst.clone(),
+            false,
+            TS_RANGE_BEGIN,
+            ChecksumVerification::OnFill,
+        )
+        .with_readahead(readahead_size);
+        iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(&key_of(42)))
+            .unwrap();
+        assert_eq!(collect(iter), expected[42..]);
+        // blocks read ahead don't go through the block cache
+        if readahead_size > 0 {
+            let new_stats = block_cache.stats();
+            assert_eq!(
+                (new_stats.hits, new_stats.misses),
+                (stats.hits, stats.misses)
+            );
+        }
+    }
+}
+
+fn test_block_cache_capacity_in_bytes(mmap: bool) {
+    let block_size = {
+        let cache = Some(Arc::new(BlockCache::new(0)));
+        let (_dir, sst) = build_sst(new_builder(128, mmap), 0..100, value_of, cache);
+        sst.read_block(0).unwrap().encode().len() as u64
+    };
+    let block_cache = Arc::new(BlockCache::new(block_size * 4));
+    let (_dir, sst) = build_sst(
+        new_builder(128, mmap),
+        0..100,
+        value_of,
+        Some(block_cache.clone()),
+    );
+    let sst = Arc::new(sst);
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    while iter.is_valid() {
+        iter.next().unwrap();
+    }
+    let stats = block_cache.stats();
+    assert_eq!(stats.misses as usize, sst.num_of_blocks());
+    assert!(stats.size <= block_size * 4);
+    assert!(stats.entry_count <= 4);
+    assert!(stats.evictions > 0);
+}
+
+/// Encodes the block meta the way it was written before offsets and keys were delta-encoded.
+fn encode_legacy_block_meta(block_meta: &[BlockMeta], max_ts: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.put_u32(block_meta.len() as u32);
+    for meta in block_meta {
+        buf.put_u32(meta.offset as u32);
+        for key in [&meta.first_key, &meta.last_key] {
+            buf.put_u16(key.key_len() as u16);
+            buf.put(key.key_ref());
+            buf.put_u64(key.ts());
+        }
+    }
+    buf.put_u64(max_ts);
+    let checksum = crc32fast::hash(&buf[4..]);
+    buf.put_u32(checksum);
+    buf
+}
+
+fn test_sst_block_meta_delta_encoding(mmap: bool) {
+    let num_keys = 10000;
+    // every entry gets a block of its own
+    let mut builder = new_builder(16, mmap);
+    for idx in 0..num_keys {
+        builder
+            .add(KeySlice::from_slice(&key_of(idx), idx as u64), b"v")
+            .unwrap();
+    }
+    // the last block is only finished by `build`
+    let expected_meta = builder.meta.clone();
+    assert_eq!(expected_meta.len(), num_keys - 1);
+    let dir = tempdir().unwrap();
+    let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    assert_eq!(sst.num_of_blocks(), num_keys);
+    assert!(sst.block_meta[..num_keys - 1] == expected_meta[..]);
+
+    let mut encoded = Vec::new();
+    BlockMeta::encode_block_meta(&sst.block_meta, sst.max_ts(), &mut encoded);
+    let legacy = encode_legacy_block_meta(&sst.block_meta, sst.max_ts());
+    assert!(
+        encoded.len() * 10 < legacy.len() * 6,
+        "{} bytes of meta, {} before delta encoding",
+        encoded.len(),
+        legacy.len()
+    );
+    // the meta section in the file is the encoded one
+    let file_size = sst.table_size() as usize;
+    let meta_in_file = sst
+        .file
+        .read(sst.block_meta_offset as u64, encoded.len() as u64)
+        .unwrap();
+    assert_eq!(meta_in_file, encoded);
+    assert!(sst.block_meta_offset + encoded.len() < file_size);
+
+    for idx in 0..num_keys {
+        let user_key = key_of(idx);
+        let key = KeySlice::from_slice(&user_key, idx as u64);
+        let blk_idx = sst.find_block_idx(key).unwrap();
+        assert_eq!(blk_idx, idx);
+        let block = sst.read_block(blk_idx).unwrap();
+        let iter = crate::block::BlockIterator::create_and_seek_to_first(block);
+        assert_eq!(iter.key(), key);
+    }
+}
+
+fn test_sst_block_meta_legacy_encoding(mmap: bool) {
+    let (_dir, sst) = generate_sst(100, mmap);
+    let legacy = encode_legacy_block_meta(&sst.block_meta, 42);
+    let (block_meta, max_ts) = BlockMeta::decode_block_meta(&legacy).unwrap();
+    assert_eq!(max_ts, 42);
+    assert_eq!(block_meta.len(), sst.block_meta.len());
+    for (decoded, expected) in block_meta.iter().zip(sst.block_meta.iter()) {
+        assert_eq!(decoded.offset, expected.offset);
+        assert_eq!(decoded.first_key, expected.first_key);
+        assert_eq!(decoded.last_key, expected.last_key);
+        // no block is ever skipped because of its timestamps
+        assert_eq!((decoded.min_ts, decoded.max_ts), (0, u64::MAX));
+    }
+}
+
+fn test_sst_block_meta_corruption(mmap: bool) {
+    let (_dir, sst) = generate_sst(100, mmap);
+    let mut encoded = Vec::new();
+    BlockMeta::encode_block_meta(&sst.block_meta, 0, &mut encoded);
+    // every byte is covered by the checksum, including the number of blocks
+    for idx in 0..encoded.len() {
+        let mut corrupted = encoded.clone();
+        corrupted[idx] ^= 0x01;
+        assert!(BlockMeta::decode_block_meta(&corrupted).is_err(), "{}", idx);
+    }
+    assert!(BlockMeta::decode_block_meta(&encoded[..8]).is_err());
+}
+
+fn test_sst_block_ts_range(mmap: bool) {
+    let mut builder = new_builder(128, mmap);
+    for idx in 0..100 {
+        for ts in [idx as u64 + 100, idx as u64 + 1] {
+            builder
+                .add(KeySlice::from_slice(&key_of(idx), ts), b"value")
+                .unwrap();
+        }
+    }
+    let dir = tempdir().unwrap();
+    let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    assert!(sst.num_of_blocks() > 1);
+    for (blk_idx, meta) in sst.block_meta.iter().enumerate() {
+        let mut iter =
+            crate::block::BlockIterator::create_and_seek_to_first(sst.read_block(blk_idx).unwrap());
+        let (mut min_ts, mut max_ts) = (u64::MAX, 0);
+        while iter.is_valid() {
+            min_ts = min_ts.min(iter.key().ts());
+            max_ts = max_ts.max(iter.key().ts());
+            iter.next();
+        }
+        assert_eq!((meta.min_ts, meta.max_ts), (min_ts, max_ts));
+    }
+}
+
+fn test_sst_prunes_blocks_newer_than_read_ts(mmap: bool) {
+    // keys are written in order, so later blocks only have newer versions
+    let block_cache = Arc::new(BlockCache::new(1 << 20));
+    let mut builder = new_builder(128, mmap);
+    for idx in 0..100 {
+        builder
+            .add(KeySlice::from_slice(&key_of(idx), idx as u64 + 1), b"value")
+            .unwrap();
+    }
+    let dir = tempdir().unwrap();
+    let sst = Arc::new(
+        builder
+            .build(1, Some(block_cache.clone()), dir.path().join("1.sst"))
+            .unwrap(),
+    );
+    let read_ts = 30;
+    let visible_blocks = sst
+        .block_meta
+        .iter()
+        .filter(|meta| meta.min_ts <= read_ts)
+        .count();
+    assert!(visible_blocks < sst.num_of_blocks());
+
+    let mut iter = SsTableIterator::create_and_seek_to_key_with_ts(
+        sst.clone(),
+        KeySlice::from_slice(&key_of(10), crate::key::TS_RANGE_BEGIN),
+        read_ts,
+        ChecksumVerification::OnFill,
+    )
+    .unwrap();
+    let mut visible = Vec::new();
+    while iter.is_valid() {
+        if iter.key().ts() <= read_ts {
+            visible.push(iter.key().key_ref().to_vec());
+        }
+        iter.next().unwrap();
+    }
+    assert_eq!(visible, (10..30).map(key_of).collect::<Vec<_>>());
+    // the blocks with only newer versions are never read
+    let stats = block_cache.stats();
+    assert!(stats.entry_count as usize <= visible_blocks);
+
+    // seeking into a pruned block skips to the next visible one, or the end of the SST
+    let iter = SsTableIterator::create_and_seek_to_key_with_ts(
+        sst.clone(),
+        KeySlice::from_slice(&key_of(90), crate::key::TS_RANGE_BEGIN),
+        read_ts,
+        ChecksumVerification::OnFill,
+    )
+    .unwrap();
+    assert!(!iter.is_valid());
+    let iter = SsTableIterator::create_and_seek_to_key_with_ts(
+        sst.clone(),
+        KeySlice::from_slice(&key_of(90), crate::key::TS_RANGE_BEGIN),
+        crate::key::TS_RANGE_BEGIN,
+        ChecksumVerification::OnFill,
+    )
+    .unwrap();
+    assert_eq!(iter.key().key_ref(), key_of(90));
+
+    // backwards, the pruned blocks at the end are skipped as well
+    let mut iter = SsTableIterator::create_and_seek_to_key_with_ts(
+        sst.clone(),
+        KeySlice::from_slice(&key_of(0), crate::key::TS_RANGE_BEGIN),
+        read_ts,
+        ChecksumVerification::OnFill,
+    )
+    .unwrap();
+    iter.seek_to_last().unwrap();
+    let last_visible = sst.block_meta[visible_blocks - 1].last_key.as_key_slice();
+    assert_eq!(iter.key(), last_visible);
+    iter.seek_for_prev(KeySlice::from_slice(
+        &key_of(90),
+        crate::key::TS_RANGE_BEGIN,
+    ))
+    .unwrap();
+    assert_eq!(iter.key(), last_visible);
+}
+
+/// Builds the data section of `generate_sst_with_compression` one `Block` at a time, the way
+/// `SsTableBuilder` did before it encoded blocks in place.
+fn data_section_built_block_by_block(
+    compression: CompressionOptions,
+    value: impl Fn(usize) -> Vec<u8>,
+) -> Vec<u8> {
+    let finish = |builder: crate::block::BlockBuilder, data: &mut Vec<u8>| {
+        let encoded = builder.build().encode();
+        let compressed = match compression {
+            CompressionOptions::None => None,
+            CompressionOptions::Lz4 => Some((1, lz4_flex::block::compress_prepend_size(&encoded))),
+            CompressionOptions::Zstd { level } => {
+                Some((2, zstd::bulk::compress(&encoded, level).unwrap()))
+            }
+        };
+        let block_start = data.len();
+        match compressed {
+            Some((compression, compressed)) if compressed.len() < encoded.len() => {
+                data.put_u8(compression);
+                data.put(&compressed[..]);
+            }
+            _ => {
+                data.put_u8(0);
+                data.put(&encoded[..]);
+            }
+        }
+        let checksum = crc32fast::hash(&data[block_start..]);
+        data.put_u32(checksum);
+    };
+    let mut data = Vec::new();
+    let mut builder = crate::block::BlockBuilder::new(4096);
+    for idx in 0..1000 {
+        let key = key_of(idx);
+        let key = KeySlice::for_testing_from_slice_no_ts(&key);
+        if builder.add(key, &value(idx)) != crate::block::BlockAddResult::Added {
+            finish(builder, &mut data);
+            builder = crate::block::BlockBuilder::new(4096);
+            assert_eq!(
+                builder.add(key, &value(idx)),
+                crate::block::BlockAddResult::Added
+            );
+        }
+    }
+    finish(builder, &mut data);
+    data
+}
+
+fn test_sst_blocks_encoded_in_place(mmap: bool) {
+    for compression in [
+        CompressionOptions::None,
+        CompressionOptions::Lz4,
+        CompressionOptions::Zstd { level: 0 },
+    ] {
+        for value in [value_of, compressible_value_of] {
+            let (_dir, sst) = build_sst(
+                new_builder(4096, mmap).with_compression(compression),
+                0..1000,
+                value,
+                Some(Arc::new(BlockCache::new(16))),
+            );
+            let data = sst.file.read(0, sst.block_meta_offset as u64).unwrap();
+            assert!(
+                data == data_section_built_block_by_block(compression, value),
+                "{:?}",
+                compression
+            );
+        }
+    }
+}
+
+fn json_value_of(idx: usize) -> Vec<u8> {
+    format!(
+        r#"{{"id":{},"name":"user_{}","email":"user_{}@example.com","active":{},"roles":["reader"]}}"#,
+        idx,
+        idx,
+        idx,
+        idx.is_multiple_of(2)
+    )
+    .into_bytes()
+}
+
+fn test_sst_compression_dict(mmap: bool) {
+    let (_dir, plain_sst) = build_sst(
+        new_builder(1024, mmap)
+            .with_compression(CompressionOptions::Zstd { level: 3 })
+            .with_compression_dict_size(0),
+        0..5000,
+        json_value_of,
+        None,
+    );
+    assert!(plain_sst.compression_dict().is_none());
+    let (dir, sst) = build_sst(
+        new_builder(1024, mmap)
+            .with_compression(CompressionOptions::Zstd { level: 3 })
+            .with_compression_dict_size(16 << 10),
+        0..5000,
+        json_value_of,
+        None,
+    );
+    let dict_len = sst.compression_dict().unwrap().raw().len();
+    assert!(dict_len > 0 && dict_len <= 16 << 10, "{}", dict_len);
+    // smaller even with the dictionary stored in it
+    assert!(
+        sst.table_size() < plain_sst.table_size(),
+        "{} >= {}",
+        sst.table_size(),
+        plain_sst.table_size()
+    );
+    let data = std::fs::read(dir.path().join("1.sst")).unwrap();
+    assert_eq!(data[sst.block_meta(0).unwrap().offset], 3);
+
+    // the dictionary is loaded once on open, and every block decompresses with it
+    let sst = SsTable::open_for_test(open_file(&dir.path().join("1.sst"), mmap)).unwrap();
+    assert_eq!(sst.compression_dict().unwrap().raw().len(), dict_len);
+    sst.verify().unwrap();
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+    for idx in 0..5000 {
+        assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+        assert_eq!(iter.value(), json_value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    // a corrupted dictionary fails to open
+    let footer = Footer::read(&open_file(&dir.path().join("1.sst"), mmap)).unwrap();
+    let err = reopen_error(
+        &dir,
+        |data| {
+            data[footer.compression_dict_offset as usize + 10] ^= 1;
+        },
+        mmap,
+    );
+    assert!(err.contains("compression dictionary checksum"), "{}", err);
+}
+
+fn test_sst_compression_dict_skipped_for_small_sst(mmap: bool) {
+    let (dir, sst) = build_sst(
+        new_builder(1024, mmap)
+            .with_compression(CompressionOptions::Zstd { level: 3 })
+            .with_compression_dict_size(16 << 10),
+        0..100,
+        json_value_of,
+        None,
+    );
+    assert!(sst.compression_dict().is_none());
+    let sst = SsTable::open_for_test(open_file(&dir.path().join("1.sst"), mmap)).unwrap();
+    assert!(sst.compression_dict().is_none());
+    assert_eq!(Footer::read(&sst.file).unwrap().compression_dict_offset, 0);
+    // the blocks are still compressed, without the dictionary
+    let data = std::fs::read(dir.path().join("1.sst")).unwrap();
+    assert_eq!(data[sst.block_meta(0).unwrap().offset], 2);
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+    for idx in 0..100 {
+        assert_eq!(iter.value(), json_value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+fn test_sst_bloom_false_positive_rate(mmap: bool) {
+    let false_positives = |rate| {
+        let (dir, _) = build_sst(
+            new_builder(128, mmap).with_bloom_false_positive_rate(Some(rate)),
+            0..1000,
+            value_of,
+            None,
+        );
+        let sst = SsTable::open_for_test(open_file(&dir.path().join("1.sst"), mmap)).unwrap();
+        for idx in 0..1000 {
+            assert!(sst.may_contain(&key_of(idx)));
+        }
+        let filter_len = sst.bloom.as_ref().unwrap().filter.len();
+        let false_positives = (1000..11000)
+            .filter(|idx| sst.may_contain(&key_of(*idx)))
+            .count();
+        (filter_len, false_positives)
+    };
+    let (small_filter, many_false_positives) = false_positives(0.1);
+    let (large_filter, few_false_positives) = false_positives(0.001);
+    assert!(small_filter < large_filter);
+    assert!(few_false_positives < many_false_positives);
+    assert!(many_false_positives < 2000, "{}", many_false_positives);
+    assert!(few_false_positives < 100, "{}", few_false_positives);
+}
+
+/// Builds an SST of the even keys, so that the odd ones are within its range but absent.
+fn test_sst_bloom_per_block(mmap: bool) {
+    let false_positives = |bloom_per_block| {
+        let (dir, sst) = build_sst(
+            new_builder(128, mmap).with_bloom_per_block(bloom_per_block),
+            (0..2000).step_by(2),
+            value_of,
+            None,
+        );
+        assert_eq!(sst.bloom.is_none(), bloom_per_block);
+        assert_eq!(sst.block_filters.is_some(), bloom_per_block);
+        let sst = SsTable::open_for_test(open_file(&dir.path().join("1.sst"), mmap)).unwrap();
+        assert_eq!(sst.bloom.is_none(), bloom_per_block);
+        // every key starts a block at some point, after a block ending with another key
+        for idx in (0..2000).step_by(2) {
+            assert!(sst.may_contain(&key_of(idx)));
+            assert!(sst.block_may_contain(&key_of(idx)).unwrap());
+        }
+        (1..2000)
+            .step_by(2)
+            .filter(|idx| {
+                sst.may_contain(&key_of(*idx)) && sst.block_may_contain(&key_of(*idx)).unwrap()
+            })
+            .count()
+    };
+    let whole_sst = false_positives(false);
+    let per_block = false_positives(true);
+    // both are sized for 1%, the filters of small blocks round up to more bits per key
+    assert!(whole_sst < 30, "{}", whole_sst);
+    assert!(per_block < 30, "{}", per_block);
+
+    // an encrypted SST keeps a single bloom filter
+    let mut builder = new_builder(128, mmap)
+        .with_bloom_per_block(true)
+        .with_encryption(Some(AesGcmProvider::new(1, [0; 32])));
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"key"), b"value")
+        .unwrap();
+    let dir = tempdir().unwrap();
+    let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    assert!(sst.bloom.is_some());
+    assert!(sst.block_filters.is_none());
+}
+
+fn test_sst_bloom_per_block_cached(mmap: bool) {
+    let (dir, sst) = build_sst(
+        new_builder(128, mmap).with_bloom_per_block(true),
+        (0..2000).step_by(2),
+        value_of,
+        None,
+    );
+    let num_blocks = sst.num_of_blocks();
+    let path = dir.path().join("1.sst");
+    let block_cache = Arc::new(BlockCache::new(1 << 20));
+    let sst = SsTable::open(1, Some(block_cache.clone()), open_file(&path, mmap)).unwrap();
+    for idx in 0..2000 {
+        sst.block_may_contain(&key_of(idx)).unwrap();
+    }
+    // each filter is read once, and only the filters are cached
+    let stats = block_cache.stats();
+    assert_eq!(stats.misses, num_blocks as u64);
+    assert_eq!(stats.hits, 2000 - num_blocks as u64);
+    assert_eq!(stats.entry_count, num_blocks as u64);
+    assert!(stats.size < sst.table_size() / 4, "{:?}", stats);
+
+    // cold filters are evicted from a small cache
+    let block_cache = Arc::new(BlockCache::new(256));
+    let sst = SsTable::open(1, Some(block_cache.clone()), open_file(&path, mmap)).unwrap();
+    for idx in 0..2000 {
+        sst.block_may_contain(&key_of(idx)).unwrap();
+    }
+    let stats = block_cache.stats();
+    assert!(stats.evictions > 0, "{:?}", stats);
+    assert!(stats.size <= 256, "{:?}", stats);
+
+    // a corrupted filter fails the lookup, and a corrupted index fails to open
+    let block_filter_offset = Footer::read(&sst.file).unwrap().block_filter_offset as usize;
+    let mut data = std::fs::read(&path).unwrap();
+    data[block_filter_offset] ^= 1;
+    std::fs::write(&path, &data).unwrap();
+    let sst = SsTable::open_for_test(open_file(&path, mmap)).unwrap();
+    let err = sst.block_may_contain(&key_of(0)).err().unwrap().to_string();
+    assert!(err.contains("checksum"), "{}", err);
+    let err = reopen_error(
+        &dir,
+        |data| {
+            let index_end = data.len() - SST_FOOTER_SIZE - SST_PROPERTIES_SIZE - 8;
+            data[index_end] ^= 1;
+        },
+        mmap,
+    );
+    assert!(err.contains("block filter"), "{}", err);
+}
+
+fn test_sst_estimated_size(mmap: bool) {
+    let builders = [
+        new_builder(128, mmap),
+        new_builder(4096, mmap),
+        new_builder(4096, mmap).with_bloom_false_positive_rate(None),
+        new_builder(4096, mmap).with_bloom_per_block(true),
+        new_builder(4096, mmap).with_prefix_extractor(Some(PrefixExtractor::FixedLength(6))),
+    ];
+    for (idx, mut builder) in builders.into_iter().enumerate() {
+        assert!(builder.estimated_size() > SST_FOOTER_SIZE + SST_PROPERTIES_SIZE);
+        for idx in 0..1000 {
+            builder
+                .add(
+                    KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                    &value_of(idx),
+                )
+                .unwrap();
+        }
+        builder.add_property("shard_id", b"42");
+        let estimated_size = builder.estimated_size();
+        let dir = tempdir().unwrap();
+        let size = builder
+            .build_for_test(dir.path().join("1.sst"))
+            .unwrap()
+            .table_size() as usize;
+        // the block being built, the meta and bloom sections all count
+        assert!(
+            size.abs_diff(estimated_size) <= estimated_size / 100,
+            "builder {}: estimated {} bytes, built {}",
+            idx,
+            estimated_size,
+            size
+        );
+    }
+}
+
+fn test_sst_file_cache(mmap: bool) {
+    let dir = tempdir().unwrap();
+    let file_cache = Arc::new(FileCache::new(1));
+    let ssts = (0..3)
+        .map(|sst_id| {
+            let mut builder = new_builder(128, mmap).with_file_cache(Some(file_cache.clone()));
+            for idx in 0..100 {
+                builder
+                    .add(
+                        KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                        &value_of(idx),
+                    )
+                    .unwrap();
+            }
+            let path = dir.path().join(format!("{}.sst", sst_id));
+            Arc::new(builder.build(sst_id, None, path).unwrap())
+        })
+        .collect::<Vec<_>>();
+    // the built SSTs are closed until they are read
+    assert_eq!(file_cache.open_files(), 0);
+
+    let mut iters = ssts
+        .iter()
+        .map(|sst| SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap())
+        .collect::<Vec<_>>();
+    for idx in 0..100 {
+        for iter in iters.iter_mut() {
+            assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+            assert_eq!(iter.value(), value_of(idx));
+            iter.next().unwrap();
+        }
+        assert!(file_cache.open_files() <= 1);
+    }
+
+    // a pinned file is still read after it's removed
+    ssts[0].pin_file().unwrap();
+    std::fs::remove_file(dir.path().join("0.sst")).unwrap();
+    for block_idx in 0..ssts[0].num_of_blocks() {
+        ssts[0].read_block(block_idx).unwrap();
+    }
+    drop(iters);
+    drop(ssts);
+    assert_eq!(file_cache.open_files(), 0);
+
+    let path = dir.path().join("1.sst");
+    let file = FileObject::open_with_file_cache(1, &path, Some(file_cache.clone())).unwrap();
+    let sst = SsTable::open(1, None, file.with_mmap(mmap).unwrap()).unwrap();
+    sst.verify().unwrap();
+}
+
+fn test_sst_approximate_offset_of(mmap: bool) {
+    let (_dir, sst) = generate_sst(1000, mmap);
+    let data_size = sst.block_meta_offset as u64;
+    let offset_of = |key: &[u8]| {
+        sst.approximate_offset_of(KeySlice::for_testing_from_slice_no_ts(key))
+            .unwrap()
+    };
+    assert_eq!(offset_of(b"key"), 0);
+    assert_eq!(offset_of(b"zzz"), data_size);
+
+    let mut prev_offset = 0;
+    for idx in 0..1000 {
+        let key = key_of(idx);
+        let offset = offset_of(&key);
+        assert!(
+            offset >= prev_offset,
+            "{:?} is before the key before it",
+            key
+        );
+        let block_idx = sst
+            .find_block_idx(KeySlice::for_testing_from_slice_no_ts(&key))
+            .unwrap();
+        assert_eq!(offset, sst.block_meta(block_idx).unwrap().offset as u64);
+        prev_offset = offset;
+    }
+
+    let size_of = |lower: Bound<&[u8]>, upper: Bound<&[u8]>| {
+        let key = |key| KeySlice::for_testing_from_slice_no_ts(key);
+        sst.approximate_size_of_range(lower.map(key), upper.map(key))
+            .unwrap()
+    };
+    assert_eq!(size_of(Bound::Unbounded, Bound::Unbounded), data_size);
+    assert_eq!(size_of(Bound::Unbounded, Bound::Excluded(b"key")), 0);
+    assert_eq!(size_of(Bound::Included(b"zzz"), Bound::Unbounded), 0);
+    // an empty range, and a range the wrong way round
+    let (key_a, key_b) = (key_of(100), key_of(900));
+    assert_eq!(size_of(Bound::Included(&key_a), Bound::Excluded(&key_a)), 0);
+    assert_eq!(size_of(Bound::Included(&key_b), Bound::Included(&key_a)), 0);
+    let size = size_of(Bound::Included(&key_a), Bound::Included(&key_b));
+    assert!(size > 0 && size < data_size);
+    assert!(size < size_of(Bound::Included(&key_a), Bound::Unbounded));
+}
+
+fn test_sst_without_bloom_filter(mmap: bool) {
+    let (dir, sst) = build_sst(
+        new_builder(128, mmap).with_bloom_false_positive_rate(None),
+        0..1000,
+        value_of,
+        None,
+    );
+    assert!(sst.bloom.is_none());
+    let (_, with_bloom) = build_sst(
+        new_builder(128, mmap).with_bloom_false_positive_rate(Some(0.01)),
+        0..1000,
+        value_of,
+        None,
+    );
+    assert!(sst.table_size() < with_bloom.table_size());
+
+    let sst = Arc::new(SsTable::open_for_test(open_file(&dir.path().join("1.sst"), mmap)).unwrap());
+    assert!(sst.bloom.is_none());
+    assert!(sst.may_contain(b"anything"));
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    for idx in 0..1000 {
+        assert_eq!(iter.key().key_ref(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+/// Rewrites the SST on disk with `f` and returns the error from opening it.
+fn reopen_error(dir: &TempDir, f: impl FnOnce(&mut Vec<u8>), mmap: bool) -> String {
+    let path = dir.path().join("1.sst");
+    let mut data = std::fs::read(&path).unwrap();
+    f(&mut data);
+    std::fs::write(&path, &data).unwrap();
+    match SsTable::open_for_test(open_file(&path, mmap)) {
+        Ok(_) => panic!("SST opened after being corrupted"),
+        // with the causes, after which structure failed
+        Err(e) => format!("{:#}", e),
+    }
+}
+
+fn test_sst_footer_round_trip(mmap: bool) {
+    let (dir, sst) = generate_sst(100, mmap);
+    let data = std::fs::read(dir.path().join("1.sst")).unwrap();
+    let mut footer = &data[data.len() - SST_FOOTER_SIZE..];
+    assert_eq!(footer.get_u32() as usize, sst.block_meta_offset);
+    let bloom_offset = footer.get_u32() as usize;
+    assert_eq!(
+        footer.get_u32(),
+        crc32fast::hash(&data[sst.block_meta_offset..bloom_offset])
+    );
+    // no partitioned index, compression dictionary, user properties, encryption or block filters
+    assert_eq!(
+        (
+            footer.get_u32(),
+            footer.get_u32(),
+            footer.get_u32(),
+            footer.get_u32(),
+            footer.get_u32(),
+            footer.get_u32(),
+        ),
+        (0, 0, 0, 0, 0, 0)
+    );
+    let bloom_end = data.len() - SST_FOOTER_SIZE - SST_PROPERTIES_SIZE;
+    assert_eq!(
+        footer.get_u32(),
+        crc32fast::hash(&data[bloom_offset..bloom_end])
+    );
+    assert_eq!(footer.get_u16(), 0);
+    assert_eq!(footer.get_u16(), SST_FORMAT_VERSION);
+    assert_eq!(footer, b"mini-lsm");
+
+    let reopened = SsTable::open_for_test(open_file(&dir.path().join("1.sst"), mmap)).unwrap();
+    assert_eq!(reopened.num_of_blocks(), sst.num_of_blocks());
+    assert!(reopened.bloom.is_some());
+}
+
+fn test_sst_footer_bad_magic(mmap: bool) {
+    let (dir, _) = generate_sst(100, mmap);
+    let err = reopen_error(&dir, |data| *data.last_mut().unwrap() ^= 0xff, mmap);
+    assert!(err.contains("bad SST magic"), "{}", err);
+}
+
+fn test_sst_footer_unsupported_version(mmap: bool) {
+    let (dir, _) = generate_sst(100, mmap);
+    let err = reopen_error(
+        &dir,
+        |data| {
+            let version_offset = data.len() - 8 - 2;
+            data[version_offset..version_offset + 2]
+                .copy_from_slice(&(SST_FORMAT_VERSION + 1).to_be_bytes());
+        },
+        mmap,
+    );
+    assert!(
+        err.contains(&format!(
+            "unsupported SST format version {}",
+            SST_FORMAT_VERSION + 1
+        )),
+        "{}",
+        err
+    );
+}
+
+fn test_sst_footer_meta_checksum_mismatch(mmap: bool) {
+    let (dir, sst) = generate_sst(100, mmap);
+    let err = reopen_error(&dir, |data| data[sst.block_meta_offset + 10] ^= 0x01, mmap);
+    assert!(err.contains("checksum mismatch"), "{}", err);
+}
+
+fn test_sst_footer_offsets_out_of_range(mmap: bool) {
+    let (dir, _) = generate_sst(100, mmap);
+    let err = reopen_error(
+        &dir,
+        |data| {
+            let bloom_offset = data.len() - SST_FOOTER_SIZE + 4;
+            data[bloom_offset..bloom_offset + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        },
+        mmap,
+    );
+    assert!(err.contains("SST footer points to"), "{}", err);
+}
+
+fn test_sst_footer_truncated_file(mmap: bool) {
+    let (dir, sst) = generate_sst(100, mmap);
+    let err = reopen_error(&dir, |data| data.truncate(LEGACY_SST_FOOTER_SIZE - 1), mmap);
+    assert!(err.contains("too short"), "{}", err);
+    // cutting off the footer leaves the end of the bloom filter where the magic should be
+    let (dir, _) = generate_sst(100, mmap);
+    let err = reopen_error(&dir, |data| data.truncate(sst.block_meta_offset + 50), mmap);
+    assert!(err.contains("bad SST magic"), "{}", err);
+}
+
+/// Opens `data` as an SST, which should fail with an error rather than a panic.
+fn open_error(dir: &TempDir, data: &[u8], mmap: bool) -> String {
+    let path = dir.path().join("broken.sst");
+    std::fs::write(&path, data).unwrap();
+    match SsTable::open_for_test(open_file(&path, mmap)) {
+        Ok(_) => panic!("broken SST of {} bytes opened", data.len()),
+        Err(e) => format!("{:#}", e),
+    }
+}
+
+fn test_sst_open_garbage(mmap: bool) {
+    let dir = tempdir().unwrap();
+    let err = open_error(&dir, b"", mmap);
+    assert!(err.contains("too short to be an SST"), "{}", err);
+
+    let mut rng = StdRng::seed_from_u64(0);
+    for len in [1, 21, 22, 35, 36, 100, 4096, 65536] {
+        let data = (0..len).map(|_| rng.r#gen::<u8>()).collect::<Vec<_>>();
+        let err = open_error(&dir, &data, mmap);
+        assert!(err.contains("SST footer"), "{}", err);
+    }
+    // random bytes behind a footer that passes the magic and version checks
+    let (_, sst) = generate_sst(100, mmap);
+    let footer = sst.file.read(sst.table_size() - 10, 10).unwrap();
+    for _ in 0..100 {
+        let mut data = (0..1000).map(|_| rng.r#gen::<u8>()).collect::<Vec<_>>();
+        data.extend_from_slice(&footer);
+        open_error(&dir, &data, mmap);
+    }
+}
+
+fn test_sst_open_truncated(mmap: bool) {
+    for num_keys in [100, 10000] {
+        let (dir, sst) = generate_sst(num_keys, mmap);
+        let data = std::fs::read(dir.path().join("1.sst")).unwrap();
+        let footer = Footer::read(&sst.file).unwrap();
+        let offsets = [
+            1,
+            sst.block_meta_offset / 2,
+            sst.block_meta_offset,
+            sst.block_meta_offset + 1,
+            (sst.block_meta_offset + footer.bloom_offset as usize) / 2,
+            footer.bloom_offset as usize + 1,
+            data.len() - SST_FOOTER_SIZE - 1,
+            data.len() - 1,
+        ];
+        for offset in offsets {
+            let err = open_error(&dir, &data[..offset], mmap);
+            assert!(err.contains("footer"), "{}", err);
+            // cut off in the middle with the footer kept, as if the file was partially written
+            let mut cut = data[..offset].to_vec();
+            cut.extend_from_slice(&data[data.len() - SST_FOOTER_SIZE..]);
+            let err = open_error(&dir, &cut, mmap);
+            assert!(err.contains("SST"), "{}", err);
+        }
+    }
+}
+
+fn test_sst_open_corrupted_meta_and_bloom(mmap: bool) {
+    let (dir, sst) = generate_sst(100, mmap);
+    let path = dir.path().join("1.sst");
+    let data = std::fs::read(&path).unwrap();
+    let footer = Footer::read(&sst.file).unwrap();
+    let bloom_end = data.len() - SST_FOOTER_SIZE - SST_PROPERTIES_SIZE;
+    // every bit flip, in the number of blocks, the offsets, the keys, the number of hashes of
+    // the bloom filter or the length of the filter, fails the open
+    let sections = [
+        (
+            footer.block_meta_offset as usize,
+            footer.bloom_offset as usize,
+            "meta",
+        ),
+        (footer.bloom_offset as usize, bloom_end, "bloom"),
+    ];
+    for (start, end, section) in sections {
+        for pos in start..end {
+            let err = open_error(
+                &dir,
+                &{
+                    let mut corrupted = data.clone();
+                    corrupted[pos] ^= 1 << (pos % 8);
+                    corrupted
+                },
+                mmap,
+            );
+            let expected = format!("SST {} checksum mismatch", section);
+            assert!(err.contains(&expected), "byte {}: {}", pos, err);
+        }
+    }
+}
+
+fn test_sst_open_garbage_meta(mmap: bool) {
+    // meta sections of garbage that pass the checksum are rejected while they are decoded
+    let mut rng = StdRng::seed_from_u64(0);
+    let (dir, sst) = generate_sst(100, mmap);
+    let data = std::fs::read(dir.path().join("1.sst")).unwrap();
+    let footer = Footer::read(&sst.file).unwrap();
+    let meta_len = footer.bloom_offset as usize - sst.block_meta_offset;
+    for idx in 0..200 {
+        let mut garbage = (0..meta_len).map(|_| rng.r#gen::<u8>()).collect::<Vec<_>>();
+        // half of them have the flags of the current layout, the others take the legacy path
+        if idx % 2 == 0 {
+            garbage[0] |= 0xe0;
+        } else {
+            garbage[0] &= 0x7f;
+        }
+        let inner_len = meta_len - 4;
+        let checksum = if idx % 2 == 0 {
+            crc32fast::hash(&garbage[..inner_len])
+        } else {
+            crc32fast::hash(&garbage[4..inner_len])
+        };
+        garbage[inner_len..].copy_from_slice(&checksum.to_be_bytes());
+        let mut corrupted = data.clone();
+        corrupted[sst.block_meta_offset..footer.bloom_offset as usize].copy_from_slice(&garbage);
+        Footer {
+            meta_checksum: crc32fast::hash(&garbage),
+            ..Footer::read(&sst.file).unwrap()
+        }
+        .encode(&mut corrupted);
+        corrupted.drain(data.len() - SST_FOOTER_SIZE..data.len());
+        let err = open_error(&dir, &corrupted, mmap);
+        assert!(err.contains("failed to read the SST block meta"), "{}", err);
+    }
+}
+
+fn test_sst_legacy_layout_rejected(mmap: bool) {
+    let (dir, _) = generate_sst(100, mmap);
+    let err = reopen_error(
+        &dir,
+        |data| {
+            // | data | meta | meta offset (u32) | bloom | bloom offset (u32) | without a footer
+            let mut footer = &data[data.len() - SST_FOOTER_SIZE..];
+            let block_meta_offset = footer.get_u32();
+            let bloom_offset = footer.get_u32() as usize;
+            let bloom = data[bloom_offset..data.len() - SST_FOOTER_SIZE].to_vec();
+            data.truncate(bloom_offset);
+            data.put_u32(block_meta_offset);
+            let legacy_bloom_offset = data.len() as u32;
+            data.extend_from_slice(&bloom);
+            data.put_u32(legacy_bloom_offset);
+        },
+        mmap,
+    );
+    assert!(err.contains("legacy format"), "{}", err);
+}
+
+fn test_sst_read_mode(mmap: bool) {
+    let (dir, sst) = generate_sst(100, mmap);
+    assert_eq!(sst.file.is_mmap(), mmap);
+    let reopened = SsTable::open_for_test(open_file(&dir.path().join("1.sst"), mmap)).unwrap();
+    assert_eq!(reopened.file.is_mmap(), mmap);
+    // reads past the end of the file fail in both modes
+    assert!(sst.file.read_bytes(sst.table_size() - 2, 4).is_err());
+    assert_eq!(
+        sst.file.read_bytes(0, 16).unwrap(),
+        reopened.file.read(0, 16).unwrap()
+    );
+}
+
+/// Fails on any write that direct I/O would reject.
+struct AlignedSink(Vec<u8>);
+
+impl std::io::Write for AlignedSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        assert_eq!(buf.as_ptr() as usize % DIRECT_IO_ALIGNMENT, 0);
+        assert_eq!(buf.len() % DIRECT_IO_ALIGNMENT, 0);
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_aligned_writer() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let data = (0..5 * DIRECT_IO_ALIGNMENT + 123)
+        .map(|_| rng.r#gen::<u8>())
+        .collect::<Vec<u8>>();
+    // writes smaller than the alignment, across the buffer boundary and larger than the buffer
+    for sizes in [
+        [1, 4095, 4097, 100],
+        [3 * DIRECT_IO_ALIGNMENT + 1, 7, 8193, 1],
+    ] {
+        let mut writer =
+            AlignedWriter::with_capacity(AlignedSink(Vec::new()), 2 * DIRECT_IO_ALIGNMENT);
+        let mut written = 0;
+        for size in sizes.iter().cycle() {
+            let end = (written + size).min(data.len());
+            writer.write(&data[written..end]).unwrap();
+            written = end;
+            if written == data.len() {
+                break;
+            }
+        }
+        let (sink, len) = writer.finish().unwrap();
+        assert_eq!(
+            len as usize,
+            data.len().next_multiple_of(DIRECT_IO_ALIGNMENT)
+        );
+        assert_eq!(sink.0.len(), len as usize);
+        assert_eq!(&sink.0[..data.len()], &data[..]);
+        assert!(sink.0[data.len()..].iter().all(|x| *x == 0));
+    }
+
+    // the capacity is rounded up to the alignment, and aligned data isn't padded
+    let mut writer = AlignedWriter::with_capacity(AlignedSink(Vec::new()), 1);
+    writer.write(&data[..2 * DIRECT_IO_ALIGNMENT]).unwrap();
+    let (sink, len) = writer.finish().unwrap();
+    assert_eq!(len as usize, 2 * DIRECT_IO_ALIGNMENT);
+    assert_eq!(sink.0, &data[..2 * DIRECT_IO_ALIGNMENT]);
+    let (sink, len) = AlignedWriter::with_capacity(AlignedSink(Vec::new()), 1)
+        .finish()
+        .unwrap();
+    assert_eq!((sink.0.len(), len), (0, 0));
+}
+
+fn test_sst_direct_io(mmap: bool) {
+    let dir = tempdir().unwrap();
+    // falls back to buffered I/O where direct I/O isn't supported, the file is the same either way
+    let build = |direct_io: bool, id: usize| {
+        let mut builder = new_builder(128, mmap).with_direct_io(direct_io);
+        for idx in 0..1000 {
+            builder
+                .add(
+                    KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                    &value_of(idx),
+                )
+                .unwrap();
+        }
+        let path = dir.path().join(format!("{}.sst", id));
+        let sst = builder.build(id, None, &path).unwrap();
+        (sst, std::fs::read(&path).unwrap())
+    };
+    let (sst, data) = build(true, 1);
+    let (_, expected) = build(false, 2);
+    assert_ne!(data.len() % DIRECT_IO_ALIGNMENT, 0);
+    assert_eq!(data, expected);
+    assert_eq!(sst.table_size(), data.len() as u64);
+
+    let sst = SsTable::open(1, None, open_file(&dir.path().join("1.sst"), mmap)).unwrap();
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+    for idx in 0..1000 {
+        assert_eq!(iter.key().key_ref(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+fn test_sst_verify(mmap: bool) {
+    let (dir, sst) = generate_sst(100, mmap);
+    sst.verify().unwrap();
+    let footer = Footer::read(&sst.file).unwrap();
+    assert_eq!(footer.block_meta_offset as usize, sst.block_meta_offset);
+    assert_eq!(footer.version, SST_FORMAT_VERSION);
+
+    // open doesn't read the data blocks, but verify does
+    let offset = sst.block_meta(3).unwrap().offset + 2;
+    let sst = corrupt_and_reopen(&dir, offset, mmap);
+    let err = sst.verify().unwrap_err();
+    assert!(format!("{:#}", err).contains("block 3"), "{:#}", err);
+}
+
+fn test_sst_prewarm_range(mmap: bool) {
+    let (dir, sst) = generate_sst(100, mmap);
+    let num_blocks = sst.num_of_blocks();
+    assert!(num_blocks > 4);
+    let block_cache = Arc::new(BlockCache::new(1 << 20));
+    let sst = SsTable::open(
+        1,
+        Some(block_cache.clone()),
+        open_file(&dir.path().join("1.sst"), mmap),
+    )
+    .unwrap();
+
+    // only the blocks overlapping with the range
+    let lower = sst.block_meta[1].last_key.key_ref().to_vec();
+    let upper = sst.block_meta[3].first_key.key_ref().to_vec();
+    assert_eq!(
+        sst.prewarm(Bound::Included(&lower), Bound::Excluded(&upper))
+            .unwrap(),
+        2
+    );
+    assert_eq!(block_cache.stats().entry_count, 2);
+    let misses = block_cache.stats().misses;
+    sst.read_block_cached(1).unwrap();
+    sst.read_block_cached(2).unwrap();
+    assert_eq!(block_cache.stats().misses, misses);
+    assert_eq!(block_cache.stats().hits, 2);
+
+    assert_eq!(
+        sst.prewarm(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        num_blocks
+    );
+    assert_eq!(block_cache.stats().entry_count, num_blocks as u64);
+
+    // nowhere to warm the blocks into
+    let sst = SsTable::open(1, None, open_file(&dir.path().join("1.sst"), mmap)).unwrap();
+    assert_eq!(sst.prewarm(Bound::Unbounded, Bound::Unbounded).unwrap(), 0);
+}
+
+fn test_sst_iterator_reverse(mmap: bool) {
+    let (_dir, sst) = generate_sst(100, mmap);
+    let sst = Arc::new(sst);
+    assert!(sst.num_of_blocks() > 2);
+    let mut iter = SsTableIterator::create_and_seek_to_last(sst).unwrap();
+    for _ in 0..2 {
+        for idx in (0..100).rev() {
+            assert_eq!(iter.key().key_ref(), key_of(idx));
+            assert_eq!(iter.value(), value_of(idx));
+            iter.prev().unwrap();
+        }
+        assert!(!iter.is_valid());
+        iter.seek_to_last().unwrap();
+    }
+}
+
+fn test_sst_prev_across_block_boundary(mmap: bool) {
+    let (_dir, sst) = generate_sst(100, mmap);
+    let sst = Arc::new(sst);
+    for blk_idx in 1..sst.num_of_blocks() {
+        let mut iter = SsTableIterator::create_and_seek_to_key(
+            sst.clone(),
+            sst.block_meta[blk_idx].first_key.as_key_slice(),
+        )
+        .unwrap();
+        iter.prev().unwrap();
+        assert_eq!(
+            iter.key(),
+            sst.block_meta[blk_idx - 1].last_key.as_key_slice()
+        );
+        // and back again
+        iter.next().unwrap();
+        assert_eq!(iter.key(), sst.block_meta[blk_idx].first_key.as_key_slice());
+    }
+}
+
+fn test_sst_seek_for_prev(mmap: bool) {
+    let (_dir, sst) = generate_sst(100, mmap);
+    let sst = Arc::new(sst);
+    let seek = |key: &[u8]| {
+        let iter = SsTableIterator::create_and_seek_for_prev(
+            sst.clone(),
+            KeySlice::for_testing_from_slice_no_ts(key),
+        )
+        .unwrap();
+        iter.is_valid().then(|| iter.key().key_ref().to_vec())
+    };
+    // before the first key
+    assert_eq!(seek(b"a"), None);
+    for idx in 0..100 {
+        assert_eq!(seek(&key_of(idx)), Some(key_of(idx)));
+        // between two entries, which may be in different blocks
+        let mut between = key_of(idx);
+        between.push(b'a');
+        assert_eq!(seek(&between), Some(key_of(idx)));
+    }
+    // past the end
+    assert_eq!(seek(b"z"), Some(key_of(99)));
+
+    let mut iter = SsTableIterator::create_and_seek_for_prev(
+        sst,
+        KeySlice::for_testing_from_slice_no_ts(&key_of(50)),
+    )
+    .unwrap();
+    for idx in (0..=50).rev() {
+        assert_eq!(iter.key().key_ref(), key_of(idx));
+        iter.prev().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+fn test_sst_seek_for_prev_versions_across_blocks(mmap: bool) {
+    let mut builder = new_builder(128, mmap);
+    for idx in 0..20 {
+        for ts in (1..=10).rev() {
+            builder
+                .add(KeySlice::from_slice(&key_of(idx), ts * 2), b"value")
+                .unwrap();
+        }
+    }
+    let dir = tempdir().unwrap();
+    let sst = Arc::new(builder.build_for_test(dir.path().join("1.sst")).unwrap());
+
+    for idx in 0..20 {
+        for ts in 1..=21 {
+            let iter = SsTableIterator::create_and_seek_for_prev(
+                sst.clone(),
+                KeySlice::from_slice(&key_of(idx), ts),
+            )
+            .unwrap();
+            // versions are sorted from the newest, so the entry at or before `ts` is the oldest
+            // version that is not older than it, or the oldest version of the previous key
+            if ts <= 20 {
+                assert_eq!(iter.key(), KeySlice::from_slice(&key_of(idx), ts + ts % 2));
+            } else if idx > 0 {
+                assert_eq!(iter.key(), KeySlice::from_slice(&key_of(idx - 1), 2));
+            } else {
+                assert!(!iter.is_valid());
+            }
+        }
+    }
+
+    let mut iter = SsTableIterator::create_and_seek_to_last(sst).unwrap();
+    for idx in (0..20).rev() {
+        for ts in 1..=10 {
+            assert_eq!(iter.key(), KeySlice::from_slice(&key_of(idx), ts * 2));
+            iter.prev().unwrap();
+        }
+    }
+    assert!(!iter.is_valid());
+}
+
+/// Writes an SST whose meta section describes `num_blocks` blocks of 16 bytes, without writing the
+/// blocks themselves, which is enough to open it and look up blocks.
+fn write_synthetic_sst(path: &Path, num_blocks: usize) -> Vec<BlockMeta> {
+    let block_meta = (0..num_blocks)
+        .map(|idx| BlockMeta {
+            offset: idx * 16,
+            first_key: KeyBytes::from_bytes_with_ts(format!("key_{:08}", idx * 2).into(), 1),
+            last_key: KeyBytes::from_bytes_with_ts(format!("key_{:08}", idx * 2 + 1).into(), 1),
+            min_ts: 1,
+            max_ts: 1,
+        })
+        .collect::<Vec<_>>();
+    let mut buf = vec![0; num_blocks * 16];
+    let block_meta_offset = buf.len();
+    BlockMeta::encode_block_meta(&block_meta, 1, &mut buf);
+    let bloom_offset = buf.len();
+    // no bloom filters
+    buf.put_u32(0);
+    TableProperties::default().encode(&mut buf);
+    Footer {
+        version: SST_FORMAT_VERSION,
+        block_meta_offset: block_meta_offset as u32,
+        bloom_offset: bloom_offset as u32,
+        meta_checksum: crc32fast::hash(&buf[block_meta_offset..bloom_offset]),
+        index_offset: 0,
+        index_partition_len: 0,
+        compression_dict_offset: 0,
+        user_properties_offset: 0,
+        encryption_key_id: 0,
+        block_filter_offset: 0,
+        bloom_checksum: crc32fast::hash(&buf[bloom_offset..bloom_offset + SIZEOF_U32]),
+        flags: 0,
+    }
+    .encode(&mut buf);
+    std::fs::write(path, &buf).unwrap();
+    block_meta
+}
+
+fn test_sst_lazy_block_meta(mmap: bool) {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let num_blocks = 50000;
+    let expected = write_synthetic_sst(&path, num_blocks);
+    let sst = SsTable::open(1, None, open_file(&path, mmap)).unwrap();
+    let footer = Footer::read(&sst.file).unwrap();
+    assert!((footer.bloom_offset - footer.block_meta_offset) as u64 >= LAZY_BLOCK_META_SIZE);
+
+    // open only decodes the chunk with the last key
+    let lazy_block_meta = sst.lazy_block_meta.as_ref().unwrap();
+    assert!(sst.block_meta.is_empty());
+    assert_eq!(sst.num_of_blocks(), num_blocks);
+    assert_eq!(sst.first_key(), &expected[0].first_key);
+    assert_eq!(sst.last_key(), &expected[num_blocks - 1].last_key);
+    assert_eq!(sst.max_ts(), 1);
+    assert_eq!(lazy_block_meta.num_decoded_chunks(), 1);
+
+    // a lookup decodes one more chunk, and the chunks stay decoded
+    for idx in [20000, 20001, 20100] {
+        let key = expected[idx].last_key.as_key_slice();
+        assert_eq!(sst.find_block_idx(key).unwrap(), idx);
+        assert_eq!(sst.block_meta(idx).unwrap(), &expected[idx]);
+    }
+    assert_eq!(lazy_block_meta.num_decoded_chunks(), 2);
+    // before the first key
+    let key = KeySlice::for_testing_from_slice_no_ts(b"a");
+    assert_eq!(sst.find_block_idx(key).unwrap(), 0);
+    assert_eq!(lazy_block_meta.num_decoded_chunks(), 3);
+    assert!(sst.block_meta(num_blocks).is_err());
+
+    // the same section decoded eagerly
+    let raw_meta = sst
+        .file
+        .read(
+            footer.block_meta_offset as u64,
+            (footer.bloom_offset - footer.block_meta_offset) as u64,
+        )
+        .unwrap();
+    let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta).unwrap();
+    assert_eq!(block_meta, expected);
+    assert_eq!(max_ts, 1);
+
+    // small meta sections are still decoded by open
+    let (dir, _) = generate_sst(100, mmap);
+    let sst = SsTable::open(1, None, open_file(&dir.path().join("1.sst"), mmap)).unwrap();
+    assert!(sst.lazy_block_meta.is_none());
+    assert!(!sst.block_meta.is_empty());
+}
+
+fn test_sst_lazy_block_meta_corruption(mmap: bool) {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let num_blocks = 50000;
+    let expected = write_synthetic_sst(&path, num_blocks);
+    let sst = SsTable::open(1, None, open_file(&path, mmap)).unwrap();
+    // the middle of the meta section, far from the chunks read by open
+    let offset = sst.block_meta_offset + (sst.table_size() as usize - sst.block_meta_offset) / 2;
+    drop(sst);
+    let mut data = std::fs::read(&path).unwrap();
+    data[offset] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+
+    let sst = SsTable::open(1, None, open_file(&path, mmap)).unwrap();
+    assert_eq!(sst.block_meta(0).unwrap(), &expected[0]);
+    let errors = (0..num_blocks)
+        .filter(|idx| sst.block_meta(*idx).is_err())
+        .count();
+    assert!(errors > 0 && errors < num_blocks, "{}", errors);
+    assert!(sst.verify().is_err());
+}
+
+fn test_sst_lazy_block_meta_reads(mmap: bool) {
+    let num_keys = 50000;
+    // every entry gets a block of its own
+    let mut builder = new_builder(16, mmap);
+    for idx in 0..num_keys {
+        builder
+            .add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                &value_of(idx),
+            )
+            .unwrap();
+    }
+    let dir = tempdir().unwrap();
+    let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    let expected = sst.block_meta.clone();
+    let sst = Arc::new(SsTable::open(1, None, open_file(&dir.path().join("1.sst"), mmap)).unwrap());
+    assert!(sst.lazy_block_meta.is_some());
+    assert_eq!(sst.num_of_blocks(), num_keys);
+
+    let mut iter = SsTableIterator::create_and_seek_to_key(
+        sst.clone(),
+        KeySlice::for_testing_from_slice_no_ts(&key_of(30000)),
+    )
+    .unwrap();
+    for idx in 30000..30100 {
+        assert_eq!(iter.key().key_ref(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    sst.verify().unwrap();
+    for (idx, meta) in expected.iter().enumerate() {
+        assert_eq!(sst.block_meta(idx).unwrap(), meta);
+    }
+}
+
+fn test_sst_partitioned_index(mmap: bool) {
+    // only even keys, so that odd ones fall between blocks or inside them
+    let num_keys = 5000;
+    let mut builder = new_builder(128, mmap).with_index_partition_len(Some(64));
+    for idx in 0..num_keys {
+        builder
+            .add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(idx * 2)),
+                &value_of(idx * 2),
+            )
+            .unwrap();
+    }
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let built = builder.build_for_test(&path).unwrap();
+    let expected = built.block_meta.clone();
+    let num_partitions = expected.len().div_ceil(64);
+    assert!(num_partitions > 10, "{} partitions", num_partitions);
+
+    let footer = Footer::read(&built.file).unwrap();
+    assert!(footer.has_partitioned_index());
+    assert_eq!(footer.index_partition_len, 64);
+    // opened with only the top-level index even though the meta section is small
+    assert!(((footer.bloom_offset - footer.block_meta_offset) as u64) < LAZY_BLOCK_META_SIZE);
+    let open = || Arc::new(SsTable::open(1, None, open_file(&path, mmap)).unwrap());
+    let sst = open();
+    let lazy_block_meta = sst.lazy_block_meta.as_ref().unwrap();
+    assert_eq!(lazy_block_meta.num_chunks(), num_partitions);
+    assert_eq!(sst.num_of_blocks(), expected.len());
+    // the partition with the last key
+    assert_eq!(lazy_block_meta.num_decoded_chunks(), 1);
+
+    // a point lookup only decodes the partition of its block
+    let sst = open();
+    let key = key_of(2 * (num_keys / 2));
+    let (iter, result) = SsTableIterator::create_and_seek_to_key_ret(
+        sst.clone(),
+        KeySlice::for_testing_from_slice_no_ts(&key),
+        ChecksumVerification::OnFill,
+    )
+    .unwrap();
+    assert_eq!(result, SeekResult::Exact);
+    assert_eq!(iter.value(), value_of(num_keys / 2 * 2));
+    assert_eq!(
+        sst.lazy_block_meta.as_ref().unwrap().num_decoded_chunks(),
+        2
+    );
+
+    for idx in 0..num_keys {
+        let (iter, result) = SsTableIterator::create_and_seek_to_key_ret(
+            sst.clone(),
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx * 2)),
+            ChecksumVerification::OnFill,
+        )
+        .unwrap();
+        assert_eq!(result, SeekResult::Exact);
+        assert_eq!(iter.value(), value_of(idx * 2));
+    }
+    // seeks to the first and last keys of each block, and between blocks and partitions
+    for (idx, meta) in expected.iter().enumerate() {
+        assert_eq!(sst.block_meta(idx).unwrap(), meta);
+        assert_eq!(
+            sst.find_block_idx(meta.first_key.as_key_slice()).unwrap(),
+            idx
+        );
+        assert_eq!(
+            sst.find_block_idx(meta.last_key.as_key_slice()).unwrap(),
+            idx
+        );
+        let mut after_last = meta.last_key.key_ref().to_vec();
+        after_last.push(0);
+        let iter = SsTableIterator::create_and_seek_to_key(
+            sst.clone(),
+            KeySlice::for_testing_from_slice_no_ts(&after_last),
+        )
+        .unwrap();
+        match expected.get(idx + 1) {
+            Some(next) => assert_eq!(iter.key(), next.first_key.as_key_slice()),
+            None => assert!(!iter.is_valid()),
+        }
+    }
+    let iter = SsTableIterator::create_and_seek_to_key(
+        sst.clone(),
+        KeySlice::for_testing_from_slice_no_ts(b"a"),
+    )
+    .unwrap();
+    assert_eq!(iter.key().key_ref(), key_of(0));
+    sst.verify().unwrap();
+}
+
+#[test]
+fn test_prefix_extractor() {
+    let fixed = PrefixExtractor::FixedLength(3);
+    assert_eq!(fixed.extract(b"abcd"), Some(&b"abc"[..]));
+    assert_eq!(fixed.extract(b"ab"), None);
+    let delimiter = PrefixExtractor::Delimiter(b'/');
+    assert_eq!(delimiter.extract(b"t1/o1/x"), Some(&b"t1/"[..]));
+    assert_eq!(delimiter.extract(b"t1"), None);
+
+    let range = |lower: Bound<&'static [u8]>, upper: Bound<&'static [u8]>| {
+        delimiter.extract_range(lower, upper)
+    };
+    assert_eq!(
+        range(Bound::Included(b"t1/a"), Bound::Excluded(b"t1/z")),
+        Some(&b"t1/"[..])
+    );
+    assert_eq!(
+        range(Bound::Excluded(b"t1/"), Bound::Included(b"t1/\xff")),
+        Some(&b"t1/"[..])
+    );
+    assert_eq!(
+        range(Bound::Included(b"t1/a"), Bound::Excluded(b"t2/")),
+        None
+    );
+    assert_eq!(range(Bound::Included(b"t1/a"), Bound::Unbounded), None);
+    assert_eq!(range(Bound::Unbounded, Bound::Included(b"t1/a")), None);
+    assert_eq!(range(Bound::Included(b"t1"), Bound::Included(b"t1")), None);
+}
+
+fn test_sst_prefix_bloom(mmap: bool) {
+    let mut builder =
+        new_builder(128, mmap).with_prefix_extractor(Some(PrefixExtractor::FixedLength(8)));
+    for idx in 0..100 {
+        builder
+            .add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                &value_of(idx),
+            )
+            .unwrap();
+    }
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    builder.build_for_test(&path).unwrap();
+    let sst = SsTable::open(1, None, open_file(&path, mmap)).unwrap();
+    let prefix_bloom = sst.prefix_bloom().unwrap();
+    assert_eq!(prefix_bloom.extractor, PrefixExtractor::FixedLength(8));
+
+    let may_contain = |sst: &SsTable, lower: &[u8], upper: &[u8]| {
+        sst.may_contain_prefix(Bound::Included(lower), Bound::Included(upper))
+    };
+    // "key_0000" to "key_0009"
+    for idx in 0..100 {
+        assert!(may_contain(&sst, &key_of(idx), &key_of(idx)));
+    }
+    assert!(!may_contain(&sst, b"key_01000", b"key_01009"));
+    assert!(!may_contain(&sst, b"key_0200", b"key_0200"));
+    // the bounds don't share a prefix
+    assert!(may_contain(&sst, b"key_01000", b"key_02000"));
+    assert!(sst.may_contain_prefix(Bound::Included(b"key_01000"), Bound::Unbounded));
+
+    // SSTs from before the prefix bloom filter only have the key bloom filter in the section
+    let data = std::fs::read(&path).unwrap();
+    let footer = Footer::read(&sst.file).unwrap();
+    let bloom_offset = footer.bloom_offset as usize;
+    let bloom_len = (&data[bloom_offset..]).get_u32() as usize;
+    let mut legacy = data[..bloom_offset].to_vec();
+    legacy.extend_from_slice(&data[bloom_offset + 4..bloom_offset + 4 + bloom_len]);
+    Footer {
+        version: 1,
+        ..footer
+    }
+    .encode(&mut legacy);
+    std::fs::write(&path, &legacy).unwrap();
+    let sst = SsTable::open(1, None, open_file(&path, mmap)).unwrap();
+    assert!(sst.prefix_bloom().is_none());
+    assert!(may_contain(&sst, b"key_01000", b"key_01009"));
+    assert!(sst.may_contain(&key_of(42)));
+    assert!(!sst.may_contain(b"key_01000"));
+}
+
+fn test_sst_user_properties(mmap: bool) {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let mut builder = new_builder(128, mmap);
+    for idx in 0..100 {
+        builder
+            .add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                &value_of(idx),
+            )
+            .unwrap();
+    }
+    builder.add_property("shard_id", b"42");
+    builder.add_property("schema_version", b"1");
+    builder.add_property("schema_version", b"2");
+    builder.add_property("pipeline_run", &[0, 255, 1]);
+    builder.add_property("empty", b"");
+    let sst = builder.build_for_test(&path).unwrap();
+    let expected = HashMap::from([
+        ("shard_id".to_string(), Bytes::from_static(b"42")),
+        ("schema_version".to_string(), Bytes::from_static(b"2")),
+        ("pipeline_run".to_string(), Bytes::from_static(&[0, 255, 1])),
+        ("empty".to_string(), Bytes::new()),
+    ]);
+    assert_eq!(sst.user_properties(), &expected);
+    let footer = Footer::read(&sst.file).unwrap();
+    assert!(footer.user_properties_offset > footer.bloom_offset);
+    let sst = SsTable::open_for_test(open_file(&path, mmap)).unwrap();
+    assert_eq!(sst.user_properties(), &expected);
+    // the bloom filter still ends where the user properties start
+    assert!(sst.may_contain(&key_of(42)));
+    assert!(sst.properties().is_some());
+
+    // a corrupted section fails to open
+    let err = reopen_error(
+        &dir,
+        |data| {
+            data[footer.user_properties_offset as usize + 6] ^= 1;
+        },
+        mmap,
+    );
+    assert!(err.contains("user properties checksum"), "{}", err);
+
+    // SSTs without any have none
+    let (_dir, sst) = generate_sst(100, mmap);
+    assert!(sst.user_properties().is_empty());
+    assert_eq!(Footer::read(&sst.file).unwrap().user_properties_offset, 0);
+}
+
+fn open_encrypted(
+    dir: &TempDir,
+    provider: Option<Arc<AesGcmProvider>>,
+    mmap: bool,
+) -> anyhow::Result<SsTable> {
+    let file = open_file(&dir.path().join("1.sst"), mmap);
+    SsTable::open_with_encryption(1, None, file, provider.map(|p| p as _))
+}
+
+fn check_encrypted_sst(sst: SsTable) {
+    assert!(sst.may_contain(&key_of(42)));
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+    for idx in 0..1000 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+        assert_eq!(iter.value(), json_value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+fn test_sst_encryption(mmap: bool) {
+    let provider = AesGcmProvider::new(7, [1; 32]);
+    let (dir, sst) = build_sst(
+        new_builder(128, mmap)
+            .with_index_partition_len(Some(4))
+            .with_encryption(Some(provider.clone())),
+        0..1000,
+        json_value_of,
+        None,
+    );
+    let footer = Footer::read(&sst.file).unwrap();
+    assert!(footer.is_encrypted());
+    assert_eq!(footer.encryption_key_id, 7);
+    // an encrypted meta section can't be read a partition at a time
+    assert!(!footer.has_partitioned_index());
+    let data = std::fs::read(dir.path().join("1.sst")).unwrap();
+    assert!(!data.windows(9).any(|w| w == key_of(42)));
+    assert!(!data.windows(16).any(|w| w == &json_value_of(42)[..16]));
+    check_encrypted_sst(sst);
+    check_encrypted_sst(open_encrypted(&dir, Some(provider), mmap).unwrap());
+
+    let err = format!("{:#}", open_encrypted(&dir, None, mmap).err().unwrap());
+    assert!(err.contains("no encryption provider"), "{}", err);
+    let err = format!(
+        "{:#}",
+        open_encrypted(&dir, Some(AesGcmProvider::new(8, [1; 32])), mmap)
+            .err()
+            .unwrap()
+    );
+    assert!(err.contains("has key 8"), "{}", err);
+    assert!(open_encrypted(&dir, Some(AesGcmProvider::new(7, [2; 32])), mmap).is_err());
+
+    // unencrypted SSTs are opened as is
+    let (dir, _) = generate_sst(100, mmap);
+    let sst = open_encrypted(&dir, Some(AesGcmProvider::new(7, [1; 32])), mmap).unwrap();
+    assert!(!Footer::read(&sst.file).unwrap().is_encrypted());
+    assert!(sst.may_contain(&key_of(42)));
+}
+
+fn test_sst_encryption_with_compression_dict(mmap: bool) {
+    let provider = AesGcmProvider::new(1, [3; 32]);
+    let builder = new_builder(1024, mmap)
+        .with_compression(CompressionOptions::Zstd { level: 3 })
+        .with_compression_dict_size(4096)
+        .with_encryption(Some(provider.clone()));
+    let (dir, sst) = build_sst(builder, 0..1000, json_value_of, None);
+    assert!(sst.compression_dict().is_some());
+    check_encrypted_sst(sst);
+    let sst = open_encrypted(&dir, Some(provider), mmap).unwrap();
+    assert!(sst.compression_dict().is_some());
+    check_encrypted_sst(sst);
+}
+
+fn test_sst_properties(mmap: bool) {
+    let mut builder = new_builder(128, mmap);
+    for idx in 0..100 {
+        // every third key is deleted, on top of an older version
+        if idx % 3 == 0 {
+            builder
+                .add(KeySlice::from_slice(&key_of(idx), 2), b"")
+                .unwrap();
+        }
+        builder
+            .add(KeySlice::from_slice(&key_of(idx), 1), &value_of(idx))
+            .unwrap();
+    }
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let sst = builder.build_for_test(&path).unwrap();
+    let expected = TableProperties {
+        num_entries: 134,
+        num_tombstones: 34,
+        raw_key_size: 134 * 9,
+        raw_value_size: 100 * 16,
+    };
+    assert_eq!(sst.properties(), Some(&expected));
+    let sst = SsTable::open(1, None, open_file(&path, mmap)).unwrap();
+    assert_eq!(sst.properties(), Some(&expected));
+
+    // a corrupted properties section fails to open
+    let data = std::fs::read(&path).unwrap();
+    let properties_offset = data.len() - SST_FOOTER_SIZE - SST_PROPERTIES_SIZE;
+    let mut corrupted = data.clone();
+    corrupted[properties_offset] ^= 1;
+    std::fs::write(&path, &corrupted).unwrap();
+    let Err(err) = SsTable::open(1, None, open_file(&path, mmap)) else {
+        panic!("SST with corrupted properties was opened");
+    };
+    assert!(format!("{:#}", err).contains("properties checksum"));
+
+    // SSTs from before the properties section have none
+    let footer = Footer::read(&sst.file).unwrap();
+    let mut legacy = data[..properties_offset].to_vec();
+    Footer {
+        version: 2,
+        ..footer
+    }
+    .encode(&mut legacy);
+    std::fs::write(&path, &legacy).unwrap();
+    let sst = SsTable::open(1, None, open_file(&path, mmap)).unwrap();
+    assert_eq!(sst.properties(), None);
+    assert!(sst.may_contain(&key_of(42)));
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+    let mut num_entries = 0;
+    while iter.is_valid() {
+        num_entries += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(num_entries, expected.num_entries);
+}
+
+// the SST tests of week 1 day 4, which only read SSTs from their files there
+
+fn test_sst_build_single_key(mmap: bool) {
+    let mut builder = new_builder(16, mmap);
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"233"), b"233333")
+        .unwrap();
+    let dir = tempdir().unwrap();
+    builder.build_for_test(dir.path().join("1.sst")).unwrap();
+}
+
+fn test_sst_build_two_blocks(mmap: bool) {
+    let mut builder = new_builder(16, mmap);
+    for (key, value) in [
+        (b"11", b"11"),
+        (b"22", b"22"),
+        (b"33", b"11"),
+        (b"44", b"22"),
+        (b"55", b"11"),
+        (b"66", b"22"),
+    ] {
+        builder
+            .add(KeySlice::for_testing_from_slice_no_ts(key), value)
+            .unwrap();
+    }
+    assert!(builder.meta.len() >= 2);
+    let dir = tempdir().unwrap();
+    builder.build_for_test(dir.path().join("1.sst")).unwrap();
+}
+
+/// Every fifth key, so that seeks can land between them.
+fn generate_sparse_sst(mmap: bool) -> (TempDir, SsTable) {
+    build_sst(
+        new_builder(128, mmap),
+        (0..100).map(|idx| idx * 5),
+        value_of,
+        None,
+    )
+}
+
+fn test_sst_build_all(mmap: bool) {
+    let (_dir, sst) = generate_sparse_sst(mmap);
+    assert_eq!(sst.first_key().for_testing_key_ref(), key_of(0));
+    assert_eq!(sst.last_key().for_testing_key_ref(), key_of(495));
+}
+
+fn test_sst_decode(mmap: bool) {
+    let (_dir, sst) = generate_sparse_sst(mmap);
+    let meta = sst.block_meta.clone();
+    let new_sst = SsTable::open_for_test(sst.file).unwrap();
+    assert_eq!(new_sst.block_meta, meta);
+    assert_eq!(new_sst.first_key().for_testing_key_ref(), key_of(0));
+    assert_eq!(new_sst.last_key().for_testing_key_ref(), key_of(495));
+}
+
+fn test_sst_iterator(mmap: bool) {
+    let (_dir, sst) = generate_sparse_sst(mmap);
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(sst)).unwrap();
+    for _ in 0..5 {
+        for idx in (0..100).map(|idx| idx * 5) {
+            assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+            assert_eq!(iter.value(), value_of(idx));
+            iter.next().unwrap();
+        }
+        assert!(!iter.is_valid());
+        iter.seek_to_first().unwrap();
+    }
+}
+
+fn test_sst_seek_key(mmap: bool) {
+    let (_dir, sst) = generate_sparse_sst(mmap);
+    let mut iter = SsTableIterator::create_and_seek_to_key(
+        Arc::new(sst),
+        KeySlice::for_testing_from_slice_no_ts(&key_of(0)),
+    )
+    .unwrap();
+    for offset in 1..=5 {
+        for idx in (0..100).map(|idx| idx * 5) {
+            assert_eq!(iter.key().for_testing_key_ref(), key_of(idx));
+            assert_eq!(iter.value(), value_of(idx));
+            // between this key and the next, or the next one itself
+            iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(&key_of(
+                idx + offset,
+            )))
+            .unwrap();
+        }
+        iter.seek_to_key(KeySlice::for_testing_from_slice_no_ts(b"k"))
+            .unwrap();
+    }
+}
+
+mmap_tests! {
+    test_sst_build_single_key,
+    test_sst_build_two_blocks,
+    test_sst_build_all,
+    test_sst_decode,
+    test_sst_iterator,
+    test_sst_seek_key,
+    test_sst_block_checksum_detects_corruption,
+    test_sst_block_checksum_detects_corrupted_checksum,
+    test_sst_verify_checksums_modes,
+    test_sst_value_larger_than_u16,
+    test_sst_oversized_entry_gets_dedicated_block,
+    test_sst_max_entry_size,
+    test_sst_compression_round_trip,
+    test_sst_incompressible_block_stored_raw,
+    test_sst_compressed_block_checksum,
+    test_sst_rejects_out_of_order_key_in_block,
+    test_sst_block_with_unknown_format,
+    test_sst_rejects_out_of_order_key_across_blocks,
+    test_sst_build_catches_out_of_order_iterator,
+    #[should_panic(expected = "key \"a\"@0 is added after \"b\"@0")]
+    test_sst_paranoid_checks_panic_on_out_of_order_key,
+    test_sst_versions_across_blocks,
+    test_sst_seek_to_key_ret,
+    test_sst_seek_to_key_ret_versions_across_blocks,
+    test_block_cache_repeated_point_read,
+    test_block_cache_compaction_does_not_fill,
+    test_sst_iterator_readahead,
+    test_block_cache_capacity_in_bytes,
+    test_sst_block_meta_delta_encoding,
+    test_sst_block_meta_legacy_encoding,
+    test_sst_block_meta_corruption,
+    test_sst_block_ts_range,
+    test_sst_prunes_blocks_newer_than_read_ts,
+    test_sst_blocks_encoded_in_place,
+    test_sst_compression_dict,
+    test_sst_compression_dict_skipped_for_small_sst,
+    test_sst_bloom_false_positive_rate,
+    test_sst_bloom_per_block,
+    test_sst_bloom_per_block_cached,
+    test_sst_estimated_size,
+    test_sst_file_cache,
+    test_sst_approximate_offset_of,
+    test_sst_without_bloom_filter,
+    test_sst_footer_round_trip,
+    test_sst_footer_bad_magic,
+    test_sst_footer_unsupported_version,
+    test_sst_footer_meta_checksum_mismatch,
+    test_sst_footer_offsets_out_of_range,
+    test_sst_footer_truncated_file,
+    test_sst_open_garbage,
+    test_sst_open_truncated,
+    test_sst_open_corrupted_meta_and_bloom,
+    test_sst_open_garbage_meta,
+    test_sst_legacy_layout_rejected,
+    test_sst_read_mode,
+    test_sst_direct_io,
+    test_sst_verify,
+    test_sst_prewarm_range,
+    test_sst_iterator_reverse,
+    test_sst_prev_across_block_boundary,
+    test_sst_seek_for_prev,
+    test_sst_seek_for_prev_versions_across_blocks,
+    test_sst_lazy_block_meta,
+    test_sst_lazy_block_meta_corruption,
+    test_sst_lazy_block_meta_reads,
+    test_sst_partitioned_index,
+    test_sst_prefix_bloom,
+    test_sst_user_properties,
+    test_sst_encryption,
+    test_sst_encryption_with_compression_dict,
+    test_sst_properties,
+}
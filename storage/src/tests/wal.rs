@@ -0,0 +1,717 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut, Bytes};
+use tempfile::tempdir;
+
+use super::harness::AesGcmProvider;
+use crate::{
+    block::{SIZEOF_U32, SIZEOF_U64},
+    key::{KeyBytes, KeySlice},
+    table::{CompressionOptions, EncryptionProvider},
+    wal::{
+        BatchReader, RECORD_HEADER_LEN, RECOVERY_CHUNK_SIZE, WAL_FORMAT_CHECKED,
+        WAL_FORMAT_ENCRYPTED, Wal, encode_batch,
+    },
+};
+
+const LOG_NUMBER: u64 = 7;
+
+fn batch_of(prefix: &str, num: usize) -> Vec<(KeyBytes, Bytes)> {
+    (0..num)
+        .map(|idx| {
+            let key = Bytes::from(format!("{}_{}", prefix, idx));
+            // every other value is empty, the way a delete is written
+            let value = if idx % 2 == 0 {
+                Bytes::from(format!("value_{}", idx))
+            } else {
+                Bytes::new()
+            };
+            (KeyBytes::from_bytes_with_ts(key, 100 + idx as u64), value)
+        })
+        .collect()
+}
+
+/// The entries of the whole batches in `buf`, along with their length.
+fn decode_batches(buf: &[u8]) -> anyhow::Result<(Vec<(KeyBytes, Bytes)>, usize)> {
+    let mut reader = BatchReader::new(buf, LOG_NUMBER);
+    let mut entries = Vec::new();
+    while let Some(batch) = reader.next_batch()? {
+        entries.extend(batch);
+    }
+    Ok((entries, reader.len() as usize))
+}
+
+fn recover(path: impl AsRef<Path>) -> anyhow::Result<(Wal, Vec<(KeyBytes, Bytes)>)> {
+    let mut entries = Vec::new();
+    let wal = Wal::recover(path, LOG_NUMBER, |key, value| entries.push((key, value)))?;
+    Ok((wal, entries))
+}
+
+fn encode(batch: &[(KeyBytes, Bytes)], buf: &mut Vec<u8>) {
+    let data = batch
+        .iter()
+        .map(|(key, value)| (key.as_key_slice(), &value[..]))
+        .collect::<Vec<_>>();
+    encode_batch(LOG_NUMBER, CompressionOptions::None, None, &data, buf).unwrap();
+}
+
+#[test]
+fn test_wal_batch_round_trip() {
+    let batch = batch_of("key", 10);
+    let mut buf = Vec::new();
+    encode(&batch, &mut buf);
+
+    // | format (u8) | batch_size (u32) | log_number (u64) | compression (u8) | kv pairs | checksum (u32) |
+    assert_eq!(buf[0], WAL_FORMAT_CHECKED);
+    let batch_size = (&buf[1..]).get_u32() as usize;
+    assert_eq!(buf.len(), RECORD_HEADER_LEN + batch_size + SIZEOF_U32);
+    assert_eq!((&buf[1 + SIZEOF_U32..]).get_u64(), LOG_NUMBER);
+    assert_eq!(buf[RECORD_HEADER_LEN], 0);
+    // the checksum covers the format byte and the batch size as well
+    let checked = &buf[..RECORD_HEADER_LEN + batch_size];
+    let checksum = (&buf[RECORD_HEADER_LEN + batch_size..]).get_u32();
+    assert_eq!(checksum, crc32fast::hash(checked));
+    assert_eq!(decode_batches(&buf).unwrap(), (batch.clone(), buf.len()));
+
+    let other = batch_of("other", 3);
+    encode(&other, &mut buf);
+    let (entries, len) = decode_batches(&buf).unwrap();
+    assert_eq!(len, buf.len());
+    assert_eq!(entries[..10], batch[..]);
+    assert_eq!(entries[10..], other[..]);
+
+    // an empty batch takes only its header and footer
+    let mut buf = Vec::new();
+    encode_batch(LOG_NUMBER, CompressionOptions::None, None, &[], &mut buf).unwrap();
+    assert_eq!(buf.len(), RECORD_HEADER_LEN + 1 + SIZEOF_U32);
+    assert_eq!(decode_batches(&buf).unwrap(), (vec![], buf.len()));
+}
+
+#[test]
+fn test_wal_put_is_a_batch_of_one() {
+    let dir = tempdir().unwrap();
+    let wal = Wal::create(dir.path().join("put.wal"), LOG_NUMBER, 0).unwrap();
+    let key = KeySlice::for_testing_from_slice_with_ts(b"key", 42);
+    wal.put(key, b"value").unwrap();
+    drop(wal);
+
+    let mut buf = Vec::new();
+    encode_batch(
+        LOG_NUMBER,
+        CompressionOptions::None,
+        None,
+        &[(key, b"value")],
+        &mut buf,
+    )
+    .unwrap();
+    assert_eq!(std::fs::read(dir.path().join("put.wal")).unwrap(), buf);
+
+    let (_, entries) = recover(dir.path().join("put.wal")).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0.as_key_slice(), key);
+    assert_eq!(entries[0].1, Bytes::from_static(b"value"));
+}
+
+#[test]
+fn test_wal_torn_tail() {
+    let first = batch_of("first", 5);
+    let mut buf = Vec::new();
+    encode(&first, &mut buf);
+    let first_len = buf.len();
+    encode(&batch_of("last", 5), &mut buf);
+
+    // a crash in the middle of writing the last batch can leave any prefix of it
+    for len in first_len..buf.len() {
+        let decoded = decode_batches(&buf[..len]).unwrap();
+        assert_eq!(decoded, (first.clone(), first_len), "cut at {}", len);
+    }
+
+    // or its checksum unwritten
+    let mut torn = buf.clone();
+    *torn.last_mut().unwrap() ^= 0xff;
+    assert_eq!(decode_batches(&torn).unwrap(), (first.clone(), first_len));
+
+    // a single byte of the body flipped fails the checksum just the same
+    let mut torn = buf.clone();
+    torn[first_len + RECORD_HEADER_LEN] ^= 0xff;
+    assert_eq!(decode_batches(&torn).unwrap(), (first.clone(), first_len));
+}
+
+#[test]
+fn test_wal_recover_truncates_torn_tail() {
+    let first = batch_of("first", 5);
+    let mut buf = Vec::new();
+    encode(&first, &mut buf);
+    let first_len = buf.len();
+    encode(&batch_of("last", 5), &mut buf);
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("torn.wal");
+    let appended = batch_of("appended", 5);
+    for len in first_len..buf.len() {
+        std::fs::write(&path, &buf[..len]).unwrap();
+        let (wal, entries) = recover(&path).unwrap();
+        assert_eq!(entries, first, "cut at {}", len);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), first_len as u64);
+
+        // appends go right after the last whole batch
+        let data = appended
+            .iter()
+            .map(|(key, value)| (key.as_key_slice(), &value[..]))
+            .collect::<Vec<_>>();
+        wal.put_batch(&data).unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+        let (_, entries) = recover(&path).unwrap();
+        assert_eq!(entries[..5], first[..]);
+        assert_eq!(entries[5..], appended[..]);
+    }
+}
+
+#[test]
+fn test_wal_corruption_before_tail() {
+    let first = batch_of("first", 5);
+    let mut buf = Vec::new();
+    encode(&first, &mut buf);
+    let first_len = buf.len();
+    encode(&batch_of("last", 5), &mut buf);
+
+    // only the last batch could have been written when the crash happened
+    // whether it's the log number or the body
+    for corrupt_at in [1 + SIZEOF_U32, RECORD_HEADER_LEN, first_len - 1] {
+        let mut corrupted = buf.clone();
+        corrupted[corrupt_at] ^= 0xff;
+        assert!(decode_batches(&corrupted).is_err());
+    }
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("corrupted.wal");
+    let mut corrupted = buf.clone();
+    corrupted[first_len - 1] ^= 0xff;
+    std::fs::write(&path, &corrupted).unwrap();
+    assert!(recover(&path).is_err());
+}
+
+#[test]
+fn test_wal_group_commit() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("group.wal");
+    let wal = Wal::create(&path, LOG_NUMBER, 0).unwrap();
+    let (num_threads, num_writes) = (32, 50);
+    std::thread::scope(|s| {
+        for thread in 0..num_threads {
+            let wal = &wal;
+            s.spawn(move || {
+                for idx in 0..num_writes {
+                    let key = format!("thread_{}_{}", thread, idx);
+                    let key = KeySlice::for_testing_from_slice_with_ts(key.as_bytes(), 1);
+                    wal.put(key, b"value").unwrap();
+                    wal.sync().unwrap();
+                }
+            });
+        }
+    });
+    let num_syncs = wal.num_syncs();
+    assert!(
+        num_syncs < num_threads * num_writes / 4,
+        "{} syncs for {} writes",
+        num_syncs,
+        num_threads * num_writes
+    );
+    drop(wal);
+
+    let (_, entries) = recover(&path).unwrap();
+    assert_eq!(entries.len() as u64, num_threads * num_writes);
+}
+
+#[test]
+fn test_wal_recover_across_chunks() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("large.wal");
+    let wal = Wal::create(&path, LOG_NUMBER, 0).unwrap();
+    let mut written = Vec::new();
+    // batches of uneven sizes, so that they straddle the chunk boundaries at all sorts of offsets
+    let mut idx = 0;
+    while std::fs::metadata(&path).unwrap().len() < 4 * RECOVERY_CHUNK_SIZE as u64 {
+        let batch = (0..idx % 7 + 1)
+            .map(|entry| {
+                let key = Bytes::from(format!("key_{}_{}", idx, entry));
+                let value = Bytes::from(vec![b'v'; (idx * 997 + entry * 31) % 5000]);
+                (KeyBytes::from_bytes_with_ts(key, idx as u64), value)
+            })
+            .collect::<Vec<_>>();
+        let data = batch
+            .iter()
+            .map(|(key, value)| (key.as_key_slice(), &value[..]))
+            .collect::<Vec<_>>();
+        wal.put_batch(&data).unwrap();
+        wal.sync().unwrap();
+        written.extend(batch);
+        idx += 1;
+    }
+    drop(wal);
+    let (_, entries) = recover(&path).unwrap();
+    assert_eq!(entries, written);
+}
+
+#[test]
+fn test_wal_torn_tail_across_chunks() {
+    // fill the WAL up to just before a chunk boundary, so that the header of the last batch
+    // straddles it
+    let mut buf = Vec::new();
+    let mut first = Vec::new();
+    let mut idx = 0;
+    while buf.len() < RECOVERY_CHUNK_SIZE - 2 {
+        let key = Bytes::from(format!("key_{:05}", idx));
+        // key_len | key | ts | value_len | value, plus the header and footer of the batch
+        let overhead = 1 + 4 + 8 + 1 + 2 + key.len() + 8 + 2 + 4;
+        let value_len = (RECOVERY_CHUNK_SIZE - 2 - buf.len())
+            .saturating_sub(overhead)
+            .min(4096);
+        let batch = vec![(
+            KeyBytes::from_bytes_with_ts(key, 1),
+            Bytes::from(vec![b'v'; value_len]),
+        )];
+        encode(&batch, &mut buf);
+        first.extend(batch);
+        idx += 1;
+    }
+    assert_eq!(buf.len(), RECOVERY_CHUNK_SIZE - 2);
+    let first_len = buf.len();
+    encode(&batch_of("last", 5), &mut buf);
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("torn.wal");
+    for len in first_len..buf.len() {
+        std::fs::write(&path, &buf[..len]).unwrap();
+        let (_, entries) = recover(&path).unwrap();
+        assert_eq!(entries, first, "cut at {}", len);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), first_len as u64);
+    }
+}
+
+fn put_batches(wal: &Wal, batches: &[Vec<(KeyBytes, Bytes)>]) {
+    for batch in batches {
+        let data = batch
+            .iter()
+            .map(|(key, value)| (key.as_key_slice(), &value[..]))
+            .collect::<Vec<_>>();
+        wal.put_batch(&data).unwrap();
+    }
+    wal.sync().unwrap();
+}
+
+#[test]
+fn test_wal_preallocate() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("preallocated.wal");
+    let wal = Wal::create(&path, LOG_NUMBER, 1 << 20).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 1 << 20);
+    let batches = (0..10)
+        .map(|idx| batch_of(&format!("batch_{}", idx), 5))
+        .collect::<Vec<_>>();
+    put_batches(&wal, &batches);
+    // the writes go within the preallocated bytes
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 1 << 20);
+    drop(wal);
+
+    // the zeros after the last batch end the WAL
+    let (_, entries) = recover(&path).unwrap();
+    assert_eq!(entries, batches.concat());
+}
+
+#[test]
+fn test_wal_recycled_file_with_stale_records() {
+    let dir = tempdir().unwrap();
+    let old_path = dir.path().join("00001.wal");
+    let path = dir.path().join("00002.wal");
+    let old_batches = (0..20)
+        .map(|idx| batch_of(&format!("old_{:02}", idx), 5))
+        .collect::<Vec<_>>();
+    let new_batches = (0..5)
+        .map(|idx| batch_of(&format!("new_{:02}", idx), 5))
+        .collect::<Vec<_>>();
+
+    // the new batches end at a record boundary of the old ones, and then in the middle of one
+    for new_batches in [&new_batches[..], &new_batches[..4]] {
+        let wal = Wal::create(&old_path, LOG_NUMBER - 1, 0).unwrap();
+        put_batches(&wal, &old_batches);
+        drop(wal);
+        let old_len = std::fs::metadata(&old_path).unwrap().len();
+
+        let wal = Wal::recycle(&old_path, &path, LOG_NUMBER, 0).unwrap();
+        assert!(!old_path.exists());
+        put_batches(&wal, new_batches);
+        drop(wal);
+        // the old records are still in the file, past the new ones
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), old_len);
+
+        let (wal, entries) = recover(&path).unwrap();
+        assert_eq!(entries, new_batches.concat());
+        put_batches(&wal, &old_batches[..1]);
+        drop(wal);
+        let (_, entries) = recover(&path).unwrap();
+        assert_eq!(
+            entries,
+            [new_batches.concat(), old_batches[0].clone()].concat()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // a torn batch followed by the old records
+    let wal = Wal::create(&old_path, LOG_NUMBER - 1, 0).unwrap();
+    put_batches(&wal, &old_batches);
+    drop(wal);
+    let mut buf = Vec::new();
+    for batch in new_batches.iter() {
+        encode(batch, &mut buf);
+    }
+    let wal = Wal::recycle(&old_path, &path, LOG_NUMBER, 0).unwrap();
+    drop(wal);
+    let mut file = std::fs::read(&path).unwrap();
+    file[..buf.len() - 1].copy_from_slice(&buf[..buf.len() - 1]);
+    std::fs::write(&path, &file).unwrap();
+    let (_, entries) = recover(&path).unwrap();
+    assert_eq!(entries, new_batches[..4].concat());
+}
+
+fn json_batch(idx: usize) -> Vec<(KeyBytes, Bytes)> {
+    (0..5)
+        .map(|entry| {
+            let key = Bytes::from(format!("key_{}_{}", idx, entry));
+            let value = format!(
+                r#"{{"id": {}, "name": "user_{}", "tags": ["verbose", "verbose", "verbose"], "active": true}}"#,
+                idx * 5 + entry,
+                entry
+            )
+            .repeat(4);
+            (KeyBytes::from_bytes_with_ts(key, idx as u64), Bytes::from(value))
+        })
+        .collect()
+}
+
+#[test]
+fn test_wal_compression() {
+    let dir = tempdir().unwrap();
+    let batches = (0..20).map(json_batch).collect::<Vec<_>>();
+    let file_len = |compression| {
+        let path = dir.path().join(format!("{:?}.wal", compression));
+        let wal = Wal::create(&path, LOG_NUMBER, 0)
+            .unwrap()
+            .with_compression(compression);
+        put_batches(&wal, &batches);
+        drop(wal);
+        let (_, entries) = recover(&path).unwrap();
+        assert_eq!(entries, batches.concat());
+        std::fs::metadata(&path).unwrap().len()
+    };
+    let uncompressed = file_len(CompressionOptions::None);
+    assert!(file_len(CompressionOptions::Lz4) < uncompressed / 2);
+    assert!(file_len(CompressionOptions::Zstd { level: 0 }) < uncompressed / 2);
+}
+
+#[test]
+fn test_wal_mixed_compression() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("mixed.wal");
+    let batches = (0..6).map(json_batch).collect::<Vec<_>>();
+    // toggled between the WAL being created and recovered
+    let wal = Wal::create(&path, LOG_NUMBER, 0)
+        .unwrap()
+        .with_compression(CompressionOptions::Lz4);
+    put_batches(&wal, &batches[..2]);
+    let wal = wal.with_compression(CompressionOptions::None);
+    put_batches(&wal, &batches[2..4]);
+    drop(wal);
+    let (wal, entries) = recover(&path).unwrap();
+    assert_eq!(entries, batches[..4].concat());
+    let wal = wal.with_compression(CompressionOptions::Lz4);
+    put_batches(&wal, &batches[4..]);
+    drop(wal);
+    let (_, entries) = recover(&path).unwrap();
+    assert_eq!(entries, batches.concat());
+}
+
+#[test]
+fn test_wal_compression_of_random_values() {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let batches = (0..20)
+        .map(|idx| {
+            (0..5)
+                .map(|entry| {
+                    let key = Bytes::from(format!("key_{}_{}", idx, entry));
+                    let mut value = vec![0; rng.gen_range(1..2000)];
+                    rng.fill(&mut value[..]);
+                    (KeyBytes::from_bytes_with_ts(key, idx), Bytes::from(value))
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("random.wal");
+    let wal = Wal::create(&path, LOG_NUMBER, 0)
+        .unwrap()
+        .with_compression(CompressionOptions::Lz4);
+    put_batches(&wal, &batches);
+    drop(wal);
+    let (_, entries) = recover(&path).unwrap();
+    assert_eq!(entries, batches.concat());
+
+    // never larger than stored as is, which the batches are when compressing doesn't help
+    let mut buf = Vec::new();
+    for batch in batches.iter() {
+        encode(batch, &mut buf);
+    }
+    assert!(std::fs::metadata(&path).unwrap().len() <= buf.len() as u64);
+}
+
+#[test]
+fn test_wal_compressed_corruption() {
+    let mut buf = Vec::new();
+    for idx in 0..2 {
+        let batch = json_batch(idx);
+        let data = batch
+            .iter()
+            .map(|(key, value)| (key.as_key_slice(), &value[..]))
+            .collect::<Vec<_>>();
+        encode_batch(LOG_NUMBER, CompressionOptions::Lz4, None, &data, &mut buf).unwrap();
+    }
+    let first_len = RECORD_HEADER_LEN + (&buf[1..]).get_u32() as usize + SIZEOF_U32;
+    // the checksum catches a flipped byte of the compressed bytes before they're decompressed
+    for corrupt_at in RECORD_HEADER_LEN..first_len - SIZEOF_U32 {
+        let mut corrupted = buf.clone();
+        corrupted[corrupt_at] ^= 0xff;
+        let error = decode_batches(&corrupted).unwrap_err();
+        assert!(error.to_string().contains("checksum"), "{}", error);
+    }
+}
+
+#[test]
+fn test_wal_corrupted_length_fields() {
+    let (first, second) = (batch_of("first", 5), batch_of("second", 5));
+    let mut buf = Vec::new();
+    encode(&first, &mut buf);
+    let first_len = buf.len();
+    encode(&second, &mut buf);
+    let second_len = buf.len();
+    encode(&batch_of("last", 5), &mut buf);
+    // key_len is the first field of the body, value_len follows the key and its ts
+    let key_len_at = RECORD_HEADER_LEN + 1;
+    let value_len_at = key_len_at + 2 + b"second_0".len() + SIZEOF_U64;
+    assert_eq!((&buf[first_len + value_len_at..]).get_u16(), 7);
+
+    // a corrupted batch size fails the checksum of the record, whatever it's read as, so the WAL
+    // ends there unless a batch is found where the record would end
+    for bit in 0..8 * (1 + SIZEOF_U32) {
+        let mut corrupted = buf.clone();
+        corrupted[first_len + bit / 8] ^= 1 << (bit % 8);
+        if let Ok(decoded) = decode_batches(&corrupted) {
+            assert_eq!(decoded, (first.clone(), first_len), "bit {} flipped", bit);
+        }
+        // it's never read past the end of the batches
+        let mut corrupted = buf[..second_len].to_vec();
+        corrupted[first_len + bit / 8] ^= 1 << (bit % 8);
+        assert_eq!(
+            decode_batches(&corrupted).unwrap(),
+            (first.clone(), first_len),
+            "bit {} flipped",
+            bit
+        );
+    }
+
+    // the lengths in the body fail it too, and the batch after it tells it's not a torn tail
+    for at in [key_len_at, key_len_at + 1, value_len_at, value_len_at + 1] {
+        let mut corrupted = buf.clone();
+        corrupted[first_len + at] ^= 0x01;
+        let error = decode_batches(&corrupted).unwrap_err();
+        assert!(error.to_string().contains("checksum"), "{}", error);
+
+        let mut corrupted = buf[..second_len].to_vec();
+        corrupted[first_len + at] ^= 0x01;
+        assert_eq!(
+            decode_batches(&corrupted).unwrap(),
+            (first.clone(), first_len)
+        );
+    }
+
+    // recovery truncates the WAL to the batches before the corrupted size
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("corrupted.wal");
+    let mut corrupted = buf[..second_len].to_vec();
+    corrupted[first_len + 1] ^= 0x80;
+    std::fs::write(&path, &corrupted).unwrap();
+    let (_, entries) = recover(&path).unwrap();
+    assert_eq!(entries, first);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), first_len as u64);
+}
+
+/// Appends a batch the way it was before records had a format byte, with the checksum over the
+/// log number and the body only.
+fn encode_without_format(batch: &[(KeyBytes, Bytes)], buf: &mut Vec<u8>) {
+    let start = buf.len();
+    buf.put_u32(0);
+    buf.put_u64(LOG_NUMBER);
+    buf.put_u8(0);
+    for (key, value) in batch {
+        buf.put_u16(key.key_len() as u16);
+        buf.put(key.key_ref());
+        buf.put_u64(key.ts());
+        buf.put_u16(value.len() as u16);
+        buf.put(&value[..]);
+    }
+    let batch_size = (buf.len() - start - SIZEOF_U32 - SIZEOF_U64) as u32;
+    buf[start..start + SIZEOF_U32].copy_from_slice(&batch_size.to_be_bytes());
+    let checksum = crc32fast::hash(&buf[start + SIZEOF_U32..]);
+    buf.put_u32(checksum);
+}
+
+#[test]
+fn test_wal_recovers_records_without_format() {
+    let batches = (0..4)
+        .map(|idx| batch_of(&format!("batch_{}", idx), 5))
+        .collect::<Vec<_>>();
+    let mut buf = Vec::new();
+    for batch in batches.iter() {
+        encode_without_format(batch, &mut buf);
+    }
+    assert_eq!(decode_batches(&buf).unwrap(), (batches.concat(), buf.len()));
+
+    // the torn tail of such a WAL is still dropped, and corruption before it is still an error
+    let mut torn = Vec::new();
+    for batch in batches[..3].iter() {
+        encode_without_format(batch, &mut torn);
+    }
+    let three_len = torn.len();
+    for len in three_len..buf.len() {
+        assert_eq!(
+            decode_batches(&buf[..len]).unwrap(),
+            (batches[..3].concat(), three_len)
+        );
+    }
+    let mut corrupted = buf.clone();
+    corrupted[SIZEOF_U32 + SIZEOF_U64 + 1] ^= 0xff;
+    assert!(decode_batches(&corrupted).is_err());
+
+    // a WAL recovered from such records is appended to in the current format
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("earlier.wal");
+    std::fs::write(&path, &buf).unwrap();
+    let (wal, entries) = recover(&path).unwrap();
+    assert_eq!(entries, batches.concat());
+    let appended = batch_of("appended", 5);
+    put_batches(&wal, std::slice::from_ref(&appended));
+    drop(wal);
+    let file = std::fs::read(&path).unwrap();
+    assert_eq!(file[buf.len()], WAL_FORMAT_CHECKED);
+    let (_, entries) = recover(&path).unwrap();
+    assert_eq!(entries, [batches.concat(), appended].concat());
+}
+
+#[test]
+fn test_wal_encryption() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("encrypted.wal");
+    let encryption: Arc<dyn EncryptionProvider> = AesGcmProvider::new(1, [7; 32]);
+    let batches = (0..6).map(json_batch).collect::<Vec<_>>();
+    let recover_with = |encryption: Option<Arc<dyn EncryptionProvider>>| {
+        let mut entries = Vec::new();
+        let wal = Wal::recover_with_encryption(&path, LOG_NUMBER, encryption, |key, value| {
+            entries.push((key, value))
+        })?;
+        anyhow::Ok((wal, entries))
+    };
+    // records written before encryption was turned on are still read
+    let wal = Wal::create(&path, LOG_NUMBER, 0).unwrap();
+    put_batches(&wal, &batches[..1]);
+    let wal = wal.with_encryption(Some(encryption.clone()));
+    put_batches(&wal, &batches[1..2]);
+    let wal = wal.with_compression(CompressionOptions::Lz4);
+    put_batches(&wal, &batches[2..4]);
+    drop(wal);
+    let file = std::fs::read(&path).unwrap();
+    let first_len = RECORD_HEADER_LEN + (&file[1..]).get_u32() as usize + SIZEOF_U32;
+    assert_eq!(file[first_len], WAL_FORMAT_ENCRYPTED);
+    let contains = |needle: &[u8]| file.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"key_0_0"));
+    assert!((1..4).all(|idx| !contains(format!("key_{}_0", idx).as_bytes())));
+
+    // appending goes on after the recovered records, encrypted at their own offsets
+    let (wal, entries) = recover_with(Some(encryption.clone())).unwrap();
+    assert_eq!(entries, batches[..4].concat());
+    put_batches(&wal, &batches[4..]);
+    drop(wal);
+    let (_, entries) = recover_with(Some(encryption.clone())).unwrap();
+    assert_eq!(entries, batches.concat());
+
+    // the checksum covers the ciphertext, so a torn record is dropped before it's decrypted, and
+    // recovery fails on the whole ones before
+    let file_len = std::fs::metadata(&path).unwrap().len();
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(file_len - 3).unwrap();
+    drop(file);
+    let Err(error) = recover_with(None) else {
+        panic!("recovered with the wrong key");
+    };
+    assert!(
+        error.to_string().contains("no encryption provider"),
+        "{}",
+        error
+    );
+    let Err(error) = recover_with(Some(AesGcmProvider::new(2, [7; 32]))) else {
+        panic!("recovered with the wrong key");
+    };
+    assert!(
+        error.to_string().contains("encrypted with key 1"),
+        "{}",
+        error
+    );
+    let Err(error) = recover_with(Some(AesGcmProvider::new(1, [8; 32]))) else {
+        panic!("recovered with the wrong key");
+    };
+    assert!(error.to_string().contains("failed to decrypt"), "{}", error);
+    let (_, entries) = recover_with(Some(encryption)).unwrap();
+    assert_eq!(entries, batches[..batches.len() - 1].concat());
+}
+
+#[test]
+fn test_wal_encrypted_record_bound_to_offset() {
+    let encryption = AesGcmProvider::new(1, [7; 32]);
+    let batch = batch_of("key", 10);
+    let data = batch
+        .iter()
+        .map(|(key, value)| (key.as_key_slice(), &value[..]))
+        .collect::<Vec<_>>();
+    let mut buf = Vec::new();
+    encode(&batch, &mut buf);
+    // encrypted as if it were the first record, but read as the second
+    encode_batch(
+        LOG_NUMBER,
+        CompressionOptions::None,
+        Some((encryption.as_ref(), 0)),
+        &data,
+        &mut buf,
+    )
+    .unwrap();
+    let mut reader = BatchReader::new(&buf[..], LOG_NUMBER).with_encryption(Some(encryption));
+    assert_eq!(reader.next_batch().unwrap(), Some(batch));
+    let error = reader.next_batch().unwrap_err();
+    assert!(error.to_string().contains("failed to decrypt"), "{}", error);
+}
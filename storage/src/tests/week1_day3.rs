@@ -17,43 +17,61 @@ use std::sync::Arc;
 use bytes::Bytes;
 
 use crate::{
-    block::{Block, BlockBuilder, BlockIterator},
+    block::{Block, BlockAddResult, BlockBuilder, BlockIterator},
     key::{KeySlice, KeyVec},
 };
 
 #[test]
 fn test_block_build_single_key() {
     let mut builder = BlockBuilder::new(16);
-    assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(b"233"), b"233333"));
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_no_ts(b"233"), b"233333"),
+        BlockAddResult::Added
+    );
     builder.build();
 }
 
 #[test]
 fn test_block_build_full() {
     let mut builder = BlockBuilder::new(16);
-    assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(b"11"), b"11"));
-    assert!(!builder.add(KeySlice::for_testing_from_slice_no_ts(b"22"), b"22"));
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_no_ts(b"11"), b"11"),
+        BlockAddResult::Added
+    );
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_no_ts(b"22"), b"22"),
+        BlockAddResult::BlockFull
+    );
     builder.build();
 }
 
 #[test]
 fn test_block_build_large_1() {
     let mut builder = BlockBuilder::new(16);
-    assert!(builder.add(
-        KeySlice::for_testing_from_slice_no_ts(b"11"),
-        &b"1".repeat(100)
-    ));
+    assert_eq!(
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(b"11"),
+            &b"1".repeat(100)
+        ),
+        BlockAddResult::Added
+    );
     builder.build();
 }
 
 #[test]
 fn test_block_build_large_2() {
     let mut builder = BlockBuilder::new(16);
-    assert!(builder.add(KeySlice::for_testing_from_slice_no_ts(b"11"), b"1"));
-    assert!(!builder.add(
-        KeySlice::for_testing_from_slice_no_ts(b"11"),
-        &b"1".repeat(100)
-    ));
+    assert_eq!(
+        builder.add(KeySlice::for_testing_from_slice_no_ts(b"11"), b"1"),
+        BlockAddResult::Added
+    );
+    assert_eq!(
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(b"11"),
+            &b"1".repeat(100)
+        ),
+        BlockAddResult::OutOfOrder
+    );
 }
 
 fn key_of(idx: usize) -> KeyVec {
@@ -73,7 +91,10 @@ fn generate_block() -> Block {
     for idx in 0..num_of_keys() {
         let key = key_of(idx);
         let value = value_of(idx);
-        assert!(builder.add(key.as_key_slice(), &value[..]));
+        assert_eq!(
+            builder.add(key.as_key_slice(), &value[..]),
+            BlockAddResult::Added
+        );
     }
     builder.build()
 }
@@ -93,7 +114,7 @@ fn test_block_encode() {
 fn test_block_decode() {
     let block = generate_block();
     let encoded = block.encode();
-    let decoded_block = Block::decode(&encoded);
+    let decoded_block = Block::decode(&encoded).unwrap();
     assert_eq!(block.offsets, decoded_block.offsets);
     assert_eq!(block.data, decoded_block.data);
 }
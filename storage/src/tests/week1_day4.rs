@@ -24,7 +24,9 @@ use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
 #[test]
 fn test_sst_build_single_key() {
     let mut builder = SsTableBuilder::new(16);
-    builder.add(KeySlice::for_testing_from_slice_no_ts(b"233"), b"233333");
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"233"), b"233333")
+        .unwrap();
     let dir = tempdir().unwrap();
     builder.build_for_test(dir.path().join("1.sst")).unwrap();
 }
@@ -32,12 +34,24 @@ fn test_sst_build_single_key() {
 #[test]
 fn test_sst_build_two_blocks() {
     let mut builder = SsTableBuilder::new(16);
-    builder.add(KeySlice::for_testing_from_slice_no_ts(b"11"), b"11");
-    builder.add(KeySlice::for_testing_from_slice_no_ts(b"22"), b"22");
-    builder.add(KeySlice::for_testing_from_slice_no_ts(b"33"), b"11");
-    builder.add(KeySlice::for_testing_from_slice_no_ts(b"44"), b"22");
-    builder.add(KeySlice::for_testing_from_slice_no_ts(b"55"), b"11");
-    builder.add(KeySlice::for_testing_from_slice_no_ts(b"66"), b"22");
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"11"), b"11")
+        .unwrap();
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"22"), b"22")
+        .unwrap();
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"33"), b"11")
+        .unwrap();
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"44"), b"22")
+        .unwrap();
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"55"), b"11")
+        .unwrap();
+    builder
+        .add(KeySlice::for_testing_from_slice_no_ts(b"66"), b"22")
+        .unwrap();
     assert!(builder.meta.len() >= 2);
     let dir = tempdir().unwrap();
     builder.build_for_test(dir.path().join("1.sst")).unwrap();
@@ -60,7 +74,7 @@ fn generate_sst() -> (TempDir, SsTable) {
     for idx in 0..num_of_keys() {
         let key = key_of(idx);
         let value = value_of(idx);
-        builder.add(key.as_key_slice(), &value[..]);
+        builder.add(key.as_key_slice(), &value[..]).unwrap();
     }
     let dir = tempdir().unwrap();
     let path = dir.path().join("1.sst");
@@ -27,7 +27,6 @@ use crate::{
 };
 
 #[test]
-#[ignore = "leveled compaction was not implemented"]
 fn test_integration_leveled() {
     test_integration(CompactionOptions::Leveled(LeveledCompactionOptions {
         level_size_multiplier: 2,
@@ -63,7 +62,6 @@ fn test_integration_simple() {
 /// should NOT be sorted inside the `apply_compaction_result` function, because we don't have any actual SST loaded at the
 /// point where this function is called during manifest recovery.
 #[test]
-#[ignore = "leveled compaction was not implemented"]
 fn test_multiple_compacted_ssts_leveled() {
     let compaction_options = CompactionOptions::Leveled(LeveledCompactionOptions {
         level_size_multiplier: 4,
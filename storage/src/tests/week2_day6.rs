@@ -24,7 +24,6 @@ use crate::{
 };
 
 #[test]
-#[ignore = "leveled compaction was not implemented"]
 fn test_integration_leveled() {
     test_integration(CompactionOptions::Leveled(LeveledCompactionOptions {
         level_size_multiplier: 2,
@@ -58,6 +57,8 @@ fn test_integration(compaction_options: CompactionOptions) {
     let dir = tempdir().unwrap();
     let mut options = LsmStorageOptions::default_for_week2_test(compaction_options);
     options.enable_wal = true;
+    // the flush thread is woken up by each freeze, it only leaves memtables unflushed below the limit
+    options.num_memtable_limit = 10;
     let storage = MiniLsm::open(&dir, options.clone()).unwrap();
     for i in 0..=20 {
         storage.put(b"0", format!("v{}", i).as_bytes()).unwrap();
@@ -0,0 +1,190 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use bytes::{Buf, BufMut, Bytes};
+use parking_lot::{Mutex, RwLock};
+
+/// The first byte of an encoded `ValuePointer`.
+const VALUE_POINTER_TAG: u8 = 0xfe;
+
+// | tag (u8) | file_id (u32) | offset (u64) | len (u32) | checksum (u32) |
+const VALUE_POINTER_LEN: usize = 1 + 4 + 8 + 4 + 4;
+
+/// Where a value moved to the value log is, stored in the memtable, the WAL and SSTs in place of
+/// the value. A value that would decode as a pointer is always moved to the value log itself, so
+/// that the two can't be confused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValuePointer {
+    pub file_id: u32,
+    pub offset: u64,
+    pub len: u32,
+    /// crc32 of the value.
+    pub checksum: u32,
+}
+
+impl ValuePointer {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = Vec::with_capacity(VALUE_POINTER_LEN);
+        buf.put_u8(VALUE_POINTER_TAG);
+        buf.put_u32(self.file_id);
+        buf.put_u64(self.offset);
+        buf.put_u32(self.len);
+        buf.put_u32(self.checksum);
+        buf.into()
+    }
+
+    /// `None` if the value is stored in place.
+    pub fn decode(mut value: &[u8]) -> Option<Self> {
+        if value.len() != VALUE_POINTER_LEN || value[0] != VALUE_POINTER_TAG {
+            return None;
+        }
+        value.advance(1);
+        Some(Self {
+            file_id: value.get_u32(),
+            offset: value.get_u64(),
+            len: value.get_u32(),
+            checksum: value.get_u32(),
+        })
+    }
+}
+
+/// The append-only files that large values are written to, see
+/// `LsmStorageOptions::large_value_threshold`. Values are appended to the file with the largest id,
+/// which is created on the first append. Space of the values no longer referred to isn't reclaimed.
+pub struct ValueLog {
+    path: PathBuf,
+    files: RwLock<HashMap<u32, File>>,
+    /// The id and length of the file appended to.
+    active: Mutex<Option<(u32, u64)>>,
+}
+
+impl ValueLog {
+    /// Opens the value logs in the directory.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut files = HashMap::new();
+        let mut active = None;
+        for entry in std::fs::read_dir(path)? {
+            let file_path = entry?.path();
+            if file_path.extension().is_none_or(|ext| ext != "vlog") {
+                continue;
+            }
+            let Some(file_id) = file_path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            let file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(&file_path)
+                .with_context(|| format!("failed to open value log {}", file_path.display()))?;
+            let len = file.metadata()?.len();
+            if active.is_none_or(|(active_id, _)| active_id < file_id) {
+                active = Some((file_id, len));
+            }
+            files.insert(file_id, file);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            files: RwLock::new(files),
+            active: Mutex::new(active),
+        })
+    }
+
+    fn path_of(&self, file_id: u32) -> PathBuf {
+        self.path.join(format!("{:05}.vlog", file_id))
+    }
+
+    /// Appends the value and syncs it, so that it's durable before any WAL or SST refers to it.
+    pub fn append(&self, value: &[u8]) -> Result<ValuePointer> {
+        let mut active = self.active.lock();
+        let file_id = match *active {
+            Some((file_id, _)) => file_id,
+            None => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .append(true)
+                    .create_new(true)
+                    .open(self.path_of(0))
+                    .context("failed to create value log")?;
+                File::open(&self.path)?.sync_all()?;
+                self.files.write().insert(0, file);
+                0
+            }
+        };
+        let files = self.files.read();
+        let mut file = &files[&file_id];
+        // a failed append may have left part of its value behind, so the value goes wherever the
+        // file ends rather than where the last successful append did
+        let offset = file.metadata()?.len();
+        if let Err(e) = file.write_all(value).and_then(|_| file.sync_data()) {
+            if let Ok(metadata) = file.metadata() {
+                *active = Some((file_id, metadata.len()));
+            }
+            return Err(e.into());
+        }
+        *active = Some((file_id, offset + value.len() as u64));
+        Ok(ValuePointer {
+            file_id,
+            offset,
+            len: value.len() as u32,
+            checksum: crc32fast::hash(value),
+        })
+    }
+
+    /// Reads the value the pointer refers to, verifying its checksum.
+    pub fn read(&self, pointer: &ValuePointer) -> Result<Bytes> {
+        let files = self.files.read();
+        let Some(file) = files.get(&pointer.file_id) else {
+            bail!("value log {} doesn't exist", pointer.file_id);
+        };
+        let mut value = vec![0; pointer.len as usize];
+        file.read_exact_at(&mut value, pointer.offset)
+            .with_context(|| format!("failed to read value at {:?}", pointer))?;
+        if crc32fast::hash(&value) != pointer.checksum {
+            bail!("checksum of the value at {:?} doesn't match", pointer);
+        }
+        Ok(value.into())
+    }
+
+    /// Checks that the pointer is within a value log, without reading the value.
+    pub fn validate(&self, pointer: &ValuePointer) -> Result<()> {
+        let files = self.files.read();
+        let Some(file) = files.get(&pointer.file_id) else {
+            bail!("value log {} doesn't exist", pointer.file_id);
+        };
+        if pointer.offset + pointer.len as u64 > file.metadata()?.len() {
+            bail!("value at {:?} is beyond the end of its value log", pointer);
+        }
+        Ok(())
+    }
+
+    /// The value read from the value log if `value` is a pointer, `None` if it's stored in place.
+    pub fn resolve(&self, value: &[u8]) -> Result<Option<Bytes>> {
+        match ValuePointer::decode(value) {
+            Some(pointer) => self.read(&pointer).map(Some),
+            None => Ok(None),
+        }
+    }
+}
@@ -15,122 +15,580 @@
 
 use anyhow::{Context, Result, bail};
 use bytes::{Buf, BufMut, Bytes};
-use crossbeam_skiplist::SkipMap;
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
 use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::Path;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
+use crate::block::{SIZEOF_U32, SIZEOF_U64};
 use crate::key::{KeyBytes, KeySlice};
+use crate::table::{
+    COMPRESSION_NONE, CompressionOptions, EncryptionProvider, compress_block, decompress_block,
+};
+
+// week 3, day 5: Atomic WAL
+// |               HEADER               |                                     BODY                                          |  FOOTER  |
+// |   u8   |     u32    |     u64     |      u8     |   u16   | var | u64 |    u16    |  var  |           ...            |    u32   |
+// | format | batch_size | log_number  | compression | key_len | key | ts  | value_len | value | more key-value pairs ... | checksum |
+//
+// Records written before the format byte have the same layout without it, and their checksum
+// doesn't cover the batch size. The body of an encrypted record is:
+// |     u32    |                         var                         |
+// |   key_id   | ciphertext of the compression byte and key-value pairs |
+/// The format byte of a record whose checksum covers the whole record. The batch size of a record
+/// without it is its first byte, which is never as large, as a batch is far smaller than 4GB.
+pub(crate) const WAL_FORMAT_CHECKED: u8 = 0xff;
+/// The format byte of a record like `WAL_FORMAT_CHECKED` whose body is encrypted.
+pub(crate) const WAL_FORMAT_ENCRYPTED: u8 = 0xfe;
+
+/// Appends a batch to `buf` as one record, with a single checksum over the rest of the record, so
+/// that a corrupted batch size fails it rather than being followed. The log number tells the
+/// records of a WAL from those left in the file by the WAL it recycled. The key-value pairs are
+/// compressed as a data block of an SST is, so the checksum covers the compressed bytes.
+///
+/// With `encryption`, the provider and the offset of the record in the WAL, the compressed body is
+/// encrypted as the block at that offset. The checksum covers the ciphertext, so that a torn
+/// record is told without the key.
+pub(crate) fn encode_batch(
+    log_number: u64,
+    compression: CompressionOptions,
+    encryption: Option<(&dyn EncryptionProvider, u64)>,
+    data: &[(KeySlice, &[u8])],
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    let batch_start = buf.len();
+    buf.put_u8(if encryption.is_some() {
+        WAL_FORMAT_ENCRYPTED
+    } else {
+        WAL_FORMAT_CHECKED
+    });
+    // filled in once the body is written
+    buf.put_u32(0);
+    buf.put_u64(log_number);
+    let body_start = buf.len();
+    if compression == CompressionOptions::None {
+        buf.put_u8(COMPRESSION_NONE);
+        encode_entries(data, buf);
+    } else {
+        let mut entries = Vec::new();
+        encode_entries(data, &mut entries);
+        compress_block(compression, &entries, buf)?;
+    }
+    if let Some((encryption, offset)) = encryption {
+        let ciphertext = encryption.encrypt(offset, &buf[body_start..])?;
+        buf.truncate(body_start);
+        buf.put_u32(encryption.key_id());
+        buf.put_slice(&ciphertext);
+    }
+    let batch_size = (buf.len() - batch_start - RECORD_HEADER_LEN) as u32;
+    buf[batch_start + 1..batch_start + 1 + SIZEOF_U32].copy_from_slice(&batch_size.to_be_bytes());
+    let checksum = crc32fast::hash(&buf[batch_start..]);
+    buf.put_u32(checksum);
+    Ok(())
+}
+
+fn encode_entries(data: &[(KeySlice, &[u8])], buf: &mut Vec<u8>) {
+    for (key, value) in data {
+        // key
+        buf.put_u16(key.key_len() as u16);
+        buf.put(key.key_ref());
+        buf.put_u64(key.ts());
+        // value
+        buf.put_u16(value.len() as u16);
+        buf.put(*value);
+    }
+}
+
+// | format (u8) | batch_size (u32) | log_number (u64) |
+pub(crate) const RECORD_HEADER_LEN: usize = 1 + SIZEOF_U32 + SIZEOF_U64;
+
+/// Bytes read from the WAL at a time on recovery.
+pub(crate) const RECOVERY_CHUNK_SIZE: usize = 64 << 10;
+
+/// What `BatchReader::read_record` found.
+enum Record {
+    /// A batch of the WAL, the record is in the buffer with its body from the given offset.
+    Batch(usize),
+    /// A batch cut short by the end of the file, or no batch at all.
+    End,
+    /// A batch that fails its checksum or was written by another WAL.
+    Bad,
+}
+
+/// Reads the batches written one after another by `encode_batch`, one at a time. The WAL ends at
+/// the first batch that's cut short, fails its checksum or has another log number, which is what a
+/// crash in the middle of writing it leaves, or the zeros of a preallocated file, or the records of
+/// the WAL it recycled. It's an error if a batch of the WAL follows such a batch, as a crash
+/// can only tear the last one. Records without the format byte, written by earlier versions, are
+/// read as well.
+pub(crate) struct BatchReader<R> {
+    reader: R,
+    log_number: u64,
+    encryption: Option<Arc<dyn EncryptionProvider>>,
+    /// The batch being read, reused across batches.
+    buf: Vec<u8>,
+    /// Bytes of the whole batches read so far.
+    len: u64,
+}
+
+impl<R: BufRead> BatchReader<R> {
+    pub(crate) fn new(reader: R, log_number: u64) -> Self {
+        Self {
+            reader,
+            log_number,
+            encryption: None,
+            buf: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Decrypts the encrypted batches with `encryption`. It's an error to read one without it, or
+    /// with a provider of another key.
+    pub(crate) fn with_encryption(
+        mut self,
+        encryption: Option<Arc<dyn EncryptionProvider>>,
+    ) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Bytes of the whole batches read so far, where the WAL ends once `next_batch` returns `None`.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns the entries of the next batch, or `None` at the end of the WAL.
+    pub(crate) fn next_batch(&mut self) -> Result<Option<Vec<(KeyBytes, Bytes)>>> {
+        let body_start = match self.read_record()? {
+            Record::Batch(body_start) => body_start,
+            Record::End => return Ok(None),
+            Record::Bad => {
+                if let Record::Batch(_) = self.read_record()? {
+                    bail!("checksum doesn't match!");
+                }
+                return Ok(None);
+            }
+        };
+
+        let body = &self.buf[body_start..self.buf.len() - SIZEOF_U32];
+        let body = if self.buf[0] == WAL_FORMAT_ENCRYPTED {
+            self.decrypt(body)?.into()
+        } else {
+            Bytes::copy_from_slice(body)
+        };
+        let body = decompress_block(body, None)?;
+        let mut body = &body[..];
+        let mut entries = Vec::new();
+        while body.has_remaining() {
+            let key_len = body.get_u16() as usize;
+            let key = Bytes::copy_from_slice(&body[..key_len]);
+            body.advance(key_len);
+            let ts = body.get_u64();
+
+            let value_len = body.get_u16() as usize;
+            let value = Bytes::copy_from_slice(&body[..value_len]);
+            body.advance(value_len);
+
+            entries.push((KeyBytes::from_bytes_with_ts(key, ts), value));
+        }
+        self.len += self.buf.len() as u64;
+        Ok(Some(entries))
+    }
+
+    /// Decrypts the body of the encrypted batch being read, whose checksum already matched.
+    fn decrypt(&self, mut body: &[u8]) -> Result<Vec<u8>> {
+        // the offset of the batch, which it was encrypted as
+        let offset = self.len;
+        if body.len() < SIZEOF_U32 {
+            bail!("encrypted batch at {} is too short", offset);
+        }
+        let key_id = body.get_u32();
+        match &self.encryption {
+            Some(encryption) if encryption.key_id() == key_id => encryption
+                .decrypt(offset, body)
+                .with_context(|| format!("failed to decrypt the batch at {}", offset)),
+            Some(encryption) => bail!(
+                "WAL is encrypted with key {}, but the encryption provider has key {}",
+                key_id,
+                encryption.key_id()
+            ),
+            None => bail!(
+                "WAL is encrypted with key {}, but no encryption provider is configured",
+                key_id
+            ),
+        }
+    }
+
+    /// Reads the next record into `buf`, in either format.
+    fn read_record(&mut self) -> Result<Record> {
+        // as long as the header of a record in the earlier format, which has no format byte
+        let mut header = [0; 1 + SIZEOF_U32];
+        if read_full(&mut self.reader, &mut header)? < header.len() {
+            return Ok(Record::End);
+        }
+        // the checksum of a record in the earlier format starts at the log number
+        let (batch_size, checked_from, body_start) =
+            if header[0] == WAL_FORMAT_CHECKED || header[0] == WAL_FORMAT_ENCRYPTED {
+                ((&header[1..]).get_u32() as usize, 0, RECORD_HEADER_LEN)
+            } else {
+                // the last byte read is the first of the log number
+                (
+                    (&header[..SIZEOF_U32]).get_u32() as usize,
+                    SIZEOF_U32,
+                    SIZEOF_U32 + SIZEOF_U64,
+                )
+            };
+        let record_len = body_start + batch_size + SIZEOF_U32;
+        // only grows as far as the bytes actually there, in case the header itself is torn
+        self.buf.clear();
+        self.buf.extend_from_slice(&header);
+        (&mut self.reader)
+            .take((record_len - header.len()) as u64)
+            .read_to_end(&mut self.buf)?;
+        if self.buf.len() < record_len {
+            return Ok(Record::End);
+        }
+        let (checked, mut footer) = self.buf.split_at(record_len - SIZEOF_U32);
+        if (&checked[body_start - SIZEOF_U64..]).get_u64() != self.log_number
+            || footer.get_u32() != crc32fast::hash(&checked[checked_from..])
+        {
+            return Ok(Record::Bad);
+        }
+        Ok(Record::Batch(body_start))
+    }
+}
+
+/// Reads until `buf` is full or the reader runs out, returning the bytes read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// When the writes buffered in the WAL are flushed to the file and synced. After a crash, only the
+/// writes since the last sync may be lost, and the WAL is still read up to the last whole batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalSyncPolicy {
+    /// Sync on every write, before it's visible to readers.
+    Always,
+    /// Sync in the background every given milliseconds.
+    EveryMillis(u64),
+    /// Sync only when asked to, with `MiniLsm::sync`.
+    Manual,
+}
+
+/// Counters of the writes and syncs of WALs, shared by the WALs of all the memtables so that they
+/// add up across them.
+#[derive(Debug, Default)]
+pub struct WalMetrics {
+    bytes_written: AtomicU64,
+    records_written: AtomicU64,
+    syncs: AtomicU64,
+    sync_nanos: AtomicU64,
+    max_sync_nanos: AtomicU64,
+}
+
+/// A snapshot of `WalMetrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalMetricsSnapshot {
+    pub bytes_written: u64,
+    /// Each batch is one record.
+    pub records_written: u64,
+    /// Syncs of the files, each for a group of writes, see `Wal::sync`.
+    pub syncs: u64,
+    /// Time spent flushing the write buffer and syncing, over all the syncs.
+    pub total_sync_latency: Duration,
+    pub max_sync_latency: Duration,
+}
+
+impl WalMetrics {
+    fn record_write(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.records_written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_sync(&self, latency: Duration) {
+        let nanos = latency.as_nanos() as u64;
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        self.sync_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_sync_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> WalMetricsSnapshot {
+        WalMetricsSnapshot {
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            records_written: self.records_written.load(Ordering::Relaxed),
+            syncs: self.syncs.load(Ordering::Relaxed),
+            total_sync_latency: Duration::from_nanos(self.sync_nanos.load(Ordering::Relaxed)),
+            max_sync_latency: Duration::from_nanos(self.max_sync_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Sets the counters back to zero. A write or sync in progress may be counted either way.
+    pub fn reset(&self) {
+        for counter in [
+            &self.bytes_written,
+            &self.records_written,
+            &self.syncs,
+            &self.sync_nanos,
+            &self.max_sync_nanos,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
 
 pub struct Wal {
     file: Arc<Mutex<BufWriter<File>>>,
+    /// Written into each record, see `encode_batch`.
+    log_number: u64,
+    compression: CompressionOptions,
+    encryption: Option<Arc<dyn EncryptionProvider>>,
+    /// Bytes appended to the WAL, counted under the lock of `file`. The file itself may be longer,
+    /// preallocated or recycled.
+    appended: AtomicU64,
+    /// Another handle to the file, to sync it without holding the lock of `file`.
+    sync_file: File,
+    group: Mutex<SyncGroup>,
+    group_synced: Condvar,
+    num_syncs: AtomicU64,
+    metrics: Arc<WalMetrics>,
+}
+
+/// The progress of the group commit, see `Wal::sync`.
+struct SyncGroup {
+    /// Bytes of the WAL known to be synced.
+    synced: u64,
+    /// Whether a leader is syncing for the group.
+    syncing: bool,
 }
 
 impl Wal {
-    pub fn create(_path: impl AsRef<Path>) -> Result<Self> {
+    /// Creates a WAL, with the first `preallocate_size` bytes of the file allocated up front so
+    /// that appending to them doesn't have to sync the file's metadata as well.
+    pub fn create(_path: impl AsRef<Path>, log_number: u64, preallocate_size: u64) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .create_new(true)
+            .write(true)
+            .open(_path)
+            .context("failed to create wal file")?;
+        preallocate(&file, preallocate_size)?;
+        Self::open(file, log_number, 0)
+    }
+
+    /// Creates a WAL by renaming the file of a WAL no longer needed and writing over it from the
+    /// start. Its records are told apart by the log number, which must be new.
+    pub fn recycle(
+        old_path: impl AsRef<Path>,
+        path: impl AsRef<Path>,
+        log_number: u64,
+        preallocate_size: u64,
+    ) -> Result<Self> {
+        std::fs::rename(old_path, &path).context("failed to recycle wal file")?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .context("failed to open recycled wal file")?;
+        preallocate(&file, preallocate_size)?;
+        Self::open(file, log_number, 0)
+    }
+
+    fn open(file: File, log_number: u64, len: u64) -> Result<Self> {
         Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(_path)
-                    .context("failed to create wal file")?,
-            ))),
+            sync_file: file.try_clone()?,
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            log_number,
+            compression: CompressionOptions::None,
+            encryption: None,
+            appended: AtomicU64::new(len),
+            group: Mutex::new(SyncGroup {
+                synced: 0,
+                syncing: false,
+            }),
+            group_synced: Condvar::new(),
+            num_syncs: AtomicU64::new(0),
+            metrics: Arc::default(),
         })
     }
 
-    pub fn recover(_path: impl AsRef<Path>, _skiplist: &SkipMap<KeyBytes, Bytes>) -> Result<Self> {
+    /// Opens a WAL to append to, passing the entries of the batches already in it to `on_entry`
+    /// in order, see `BatchReader`. A torn batch at the end is truncated away.
+    pub fn recover(
+        path: impl AsRef<Path>,
+        log_number: u64,
+        on_entry: impl FnMut(KeyBytes, Bytes),
+    ) -> Result<Self> {
+        Self::recover_with_encryption(path, log_number, None, on_entry)
+    }
+
+    /// Recovers a WAL whose batches may be encrypted with `encryption`, which encrypts the batches
+    /// appended to it as well.
+    pub fn recover_with_encryption(
+        _path: impl AsRef<Path>,
+        log_number: u64,
+        encryption: Option<Arc<dyn EncryptionProvider>>,
+        mut on_entry: impl FnMut(KeyBytes, Bytes),
+    ) -> Result<Self> {
         let path = _path.as_ref();
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .read(true)
             .append(true)
             .open(path)
             .context("failed to open wal file")?;
 
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
-        let mut rbuf = buf.as_slice();
-        while rbuf.has_remaining() {
-            let mut checksum_buf = Vec::new();
-            let batch_size = rbuf.get_u32() as usize;
-            let mut body_rbuf = &rbuf[..batch_size];
-            checksum_buf.extend(body_rbuf);
-            let mut entries = Vec::new();
-
-            while body_rbuf.has_remaining() {
-                let key_len = body_rbuf.get_u16() as usize;
-                let key = Bytes::copy_from_slice(&body_rbuf[..key_len]);
-                body_rbuf.advance(key_len);
-                let ts = body_rbuf.get_u64();
-
-                let value_len = body_rbuf.get_u16() as usize;
-                let value = Bytes::copy_from_slice(&body_rbuf[..value_len]);
-                body_rbuf.advance(value_len);
-
-                entries.push((KeyBytes::from_bytes_with_ts(key, ts), value));
+        let mut reader = BatchReader::new(
+            BufReader::with_capacity(RECOVERY_CHUNK_SIZE, &file),
+            log_number,
+        )
+        .with_encryption(encryption.clone());
+        while let Some(batch) = reader.next_batch()? {
+            for (key, value) in batch {
+                on_entry(key, value);
             }
-            rbuf.advance(batch_size);
+        }
+        let len = reader.len();
+        if len < file.metadata()?.len() {
+            // drop the torn batch, or the batches appended after it would be read as corruption,
+            // along with the rest of a preallocated or recycled file
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        Ok(Self::open(file, log_number, len)?.with_encryption(encryption))
+    }
 
-            let checksum = rbuf.get_u32();
-            if checksum != crc32fast::hash(&checksum_buf) {
-                bail!("checksum doesn't match!");
-            }
+    /// Compresses the batches appended from now on. The WAL is read the same whichever batches
+    /// are compressed.
+    pub fn with_compression(mut self, compression: CompressionOptions) -> Self {
+        self.compression = compression;
+        self
+    }
 
-            // insert everything from entries to _skiplist since it passed checksum check
-            for entry in entries {
-                _skiplist.insert(entry.0, entry.1);
-            }
-        }
-        Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(file))),
-        })
+    /// Encrypts the batches appended from now on, see `encode_batch`. The WAL is then recovered
+    /// only with a provider of the same key.
+    pub fn with_encryption(mut self, encryption: Option<Arc<dyn EncryptionProvider>>) -> Self {
+        self.encryption = encryption;
+        self
     }
 
-    // week 2, day 6
-    // | key_len (exclude ts len) (u16) | key | ts (u64) | value_len (u16) | value | checksum (u32) |
+    /// Counts the writes and syncs from now on into `metrics`, instead of counters of its own.
+    pub fn with_metrics(mut self, metrics: Arc<WalMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Appends a batch of one entry, see `put_batch`.
     pub fn put(&self, _key: KeySlice, _value: &[u8]) -> Result<()> {
         self.put_batch(&[(_key, _value)])
     }
 
     /// Implement this in week 3, day 5; if you want to implement this earlier, use `&[u8]` as the key type.
-    // week 3, day 5: Atomic WAL
-    // |   HEADER   |                          BODY                                      |  FOOTER  |
-    // |     u32    |   u16   | var | u64 |    u16    |  var  |           ...            |    u32   |
-    // | batch_size | key_len | key | ts  | value_len | value | more key-value pairs ... | checksum |
     pub fn put_batch(&self, _data: &[(KeySlice, &[u8])]) -> Result<()> {
         let mut file = self.file.lock();
-        let mut buf: Vec<u8> = Vec::new();
-        let mut body_buf: Vec<u8> = Vec::new();
-        // prepare body
-        for (key, value) in _data {
-            // key
-            body_buf.put_u16(key.key_len() as u16);
-            body_buf.put(key.key_ref());
-            body_buf.put_u64(key.ts());
-            // value
-            body_buf.put_u16(value.len() as u16);
-            body_buf.put(*value);
-        }
-        let checksum = crc32fast::hash(&body_buf);
-        // header
-        buf.put_u32(body_buf.len() as u32);
-        // body
-        buf.extend(body_buf);
-        // footer
-        buf.put_u32(checksum);
-
+        let mut buf = Vec::new();
+        // the batch is written at the end of what was appended, which `file` is locked for
+        let encryption = self
+            .encryption
+            .as_deref()
+            .map(|encryption| (encryption, self.appended.load(Ordering::Acquire)));
+        encode_batch(
+            self.log_number,
+            self.compression,
+            encryption,
+            _data,
+            &mut buf,
+        )?;
+        // a batch is written to the file as a whole, either when it doesn't fit in the buffer or
+        // on `sync`
         file.write_all(&buf)?;
-        file.flush().unwrap();
+        self.appended.fetch_add(buf.len() as u64, Ordering::Release);
+        self.metrics.record_write(buf.len());
 
         Ok(())
     }
 
+    /// Bytes of the write buffer, which takes memory as long as the WAL is open.
+    pub fn buffer_capacity(&self) -> usize {
+        self.file.lock().capacity()
+    }
+
+    /// Syncs the batches appended so far. Concurrent calls are committed as a group: one of them
+    /// leads, flushing and syncing the batches of the whole group at once, while the others wait
+    /// for it.
     pub fn sync(&self) -> Result<()> {
-        let mut file = self.file.lock();
-        file.get_mut().sync_all()?;
-        Ok(())
+        let target = self.appended.load(Ordering::Acquire);
+        let mut group = self.group.lock();
+        loop {
+            if group.synced >= target {
+                return Ok(());
+            }
+            if !group.syncing {
+                break;
+            }
+            self.group_synced.wait(&mut group);
+        }
+        group.syncing = true;
+        drop(group);
+
+        let result = self.flush_and_sync();
+        let mut group = self.group.lock();
+        group.syncing = false;
+        if let Ok(synced) = result {
+            group.synced = group.synced.max(synced);
+        }
+        // on an error, the waiters take the lead in turn and find it out themselves
+        self.group_synced.notify_all();
+        result.map(|_| ())
+    }
+
+    /// Returns the bytes synced.
+    fn flush_and_sync(&self) -> Result<u64> {
+        let start = Instant::now();
+        let appended = {
+            let mut file = self.file.lock();
+            file.flush()?;
+            self.appended.load(Ordering::Acquire)
+        };
+        // the length of the file is synced as well whenever it grows
+        self.sync_file.sync_data()?;
+        self.num_syncs.fetch_add(1, Ordering::Relaxed);
+        self.metrics.record_sync(start.elapsed());
+        Ok(appended)
+    }
+
+    /// Number of times the WAL was synced, each for a group of writes.
+    pub fn num_syncs(&self) -> u64 {
+        self.num_syncs.load(Ordering::Relaxed)
+    }
+}
+
+/// Allocates the file up to `len` bytes, unless it's already as long. Falls back to extending it
+/// sparsely where `fallocate` isn't supported.
+fn preallocate(file: &File, len: u64) -> Result<()> {
+    if file.metadata()?.len() >= len {
+        return Ok(());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        // SAFETY: the file descriptor stays open while `file` is borrowed
+        let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+        if ret == 0 {
+            return Ok(());
+        }
     }
+    file.set_len(len)?;
+    Ok(())
 }